@@ -0,0 +1,973 @@
+//! Cross-program invocation helpers for programs calling the AMM
+//!
+//! Programs depending on this crate with the `no-entrypoint` feature can
+//! fill the typed account structs below instead of ordering the swap,
+//! deposit and withdraw accounts by hand. Each wrapper builds the
+//! instruction with the matching builder of `instruction` and invokes it.
+//!
+//! `signer_seeds` are passed to `invoke_signed`, leave them empty when the
+//! user wallet signs the outer transaction. `remaining_accounts` are
+//! appended as given, e.g. the observation account or the trailing
+//! `[spl_token_2022, coin_mint, pc_mint]` accounts of Spl Token 2022 pools.
+//!
+//! The results can be read back with `get_return_data` and decoded with
+//! `state::AmmReturnData::unpack`.
+
+use crate::instruction;
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
+};
+
+/// Accounts of a 'swap_base_in' or 'swap_base_out' instruction
+pub struct Swap<'a> {
+    pub token_program: AccountInfo<'a>,
+    pub amm: AccountInfo<'a>,
+    pub amm_authority: AccountInfo<'a>,
+    pub amm_open_orders: AccountInfo<'a>,
+    pub amm_coin_vault: AccountInfo<'a>,
+    pub amm_pc_vault: AccountInfo<'a>,
+    pub market_program: AccountInfo<'a>,
+    pub market: AccountInfo<'a>,
+    pub market_bids: AccountInfo<'a>,
+    pub market_asks: AccountInfo<'a>,
+    pub market_event_queue: AccountInfo<'a>,
+    pub market_coin_vault: AccountInfo<'a>,
+    pub market_pc_vault: AccountInfo<'a>,
+    pub market_vault_signer: AccountInfo<'a>,
+    pub user_token_source: AccountInfo<'a>,
+    pub user_token_destination: AccountInfo<'a>,
+    pub user_source_owner: AccountInfo<'a>,
+}
+
+impl<'a> Swap<'a> {
+    fn to_account_infos(&self) -> Vec<AccountInfo<'a>> {
+        vec![
+            self.token_program.clone(),
+            self.amm.clone(),
+            self.amm_authority.clone(),
+            self.amm_open_orders.clone(),
+            self.amm_coin_vault.clone(),
+            self.amm_pc_vault.clone(),
+            self.market_program.clone(),
+            self.market.clone(),
+            self.market_bids.clone(),
+            self.market_asks.clone(),
+            self.market_event_queue.clone(),
+            self.market_coin_vault.clone(),
+            self.market_pc_vault.clone(),
+            self.market_vault_signer.clone(),
+            self.user_token_source.clone(),
+            self.user_token_destination.clone(),
+            self.user_source_owner.clone(),
+        ]
+    }
+}
+
+/// Accounts of a 'swap_base_in_v2', 'swap_base_out_v2' or 'swap_base_in_limit' instruction,
+/// for pools without orderbook. A 'swap_base_in_limit' through an orderbook pool passes the
+/// market accounts first in its remaining accounts.
+pub struct SwapV2<'a> {
+    pub token_program: AccountInfo<'a>,
+    pub amm: AccountInfo<'a>,
+    pub amm_authority: AccountInfo<'a>,
+    pub amm_coin_vault: AccountInfo<'a>,
+    pub amm_pc_vault: AccountInfo<'a>,
+    pub user_token_source: AccountInfo<'a>,
+    pub user_token_destination: AccountInfo<'a>,
+    pub user_source_owner: AccountInfo<'a>,
+}
+
+impl<'a> SwapV2<'a> {
+    fn to_account_infos(&self) -> Vec<AccountInfo<'a>> {
+        vec![
+            self.token_program.clone(),
+            self.amm.clone(),
+            self.amm_authority.clone(),
+            self.amm_coin_vault.clone(),
+            self.amm_pc_vault.clone(),
+            self.user_token_source.clone(),
+            self.user_token_destination.clone(),
+            self.user_source_owner.clone(),
+        ]
+    }
+}
+
+/// Accounts of a 'deposit' instruction
+pub struct Deposit<'a> {
+    pub token_program: AccountInfo<'a>,
+    pub amm: AccountInfo<'a>,
+    pub amm_authority: AccountInfo<'a>,
+    pub amm_open_orders: AccountInfo<'a>,
+    pub amm_target_orders: AccountInfo<'a>,
+    pub amm_lp_mint: AccountInfo<'a>,
+    pub amm_coin_vault: AccountInfo<'a>,
+    pub amm_pc_vault: AccountInfo<'a>,
+    pub market: AccountInfo<'a>,
+    pub market_event_queue: AccountInfo<'a>,
+    pub user_token_coin: AccountInfo<'a>,
+    pub user_token_pc: AccountInfo<'a>,
+    pub user_token_lp: AccountInfo<'a>,
+    pub user_owner: AccountInfo<'a>,
+}
+
+impl<'a> Deposit<'a> {
+    fn to_account_infos(&self) -> Vec<AccountInfo<'a>> {
+        vec![
+            self.token_program.clone(),
+            self.amm.clone(),
+            self.amm_authority.clone(),
+            self.amm_open_orders.clone(),
+            self.amm_target_orders.clone(),
+            self.amm_lp_mint.clone(),
+            self.amm_coin_vault.clone(),
+            self.amm_pc_vault.clone(),
+            self.market.clone(),
+            self.user_token_coin.clone(),
+            self.user_token_pc.clone(),
+            self.user_token_lp.clone(),
+            self.user_owner.clone(),
+            self.market_event_queue.clone(),
+        ]
+    }
+}
+
+/// Accounts of a 'withdraw' or 'withdraw_base_out' instruction
+pub struct Withdraw<'a> {
+    pub token_program: AccountInfo<'a>,
+    pub amm: AccountInfo<'a>,
+    pub amm_authority: AccountInfo<'a>,
+    pub amm_open_orders: AccountInfo<'a>,
+    pub amm_target_orders: AccountInfo<'a>,
+    pub amm_lp_mint: AccountInfo<'a>,
+    pub amm_coin_vault: AccountInfo<'a>,
+    pub amm_pc_vault: AccountInfo<'a>,
+    pub market_program: AccountInfo<'a>,
+    pub market: AccountInfo<'a>,
+    pub market_coin_vault: AccountInfo<'a>,
+    pub market_pc_vault: AccountInfo<'a>,
+    pub market_vault_signer: AccountInfo<'a>,
+    pub user_token_lp: AccountInfo<'a>,
+    pub user_token_coin: AccountInfo<'a>,
+    pub user_token_pc: AccountInfo<'a>,
+    pub user_owner: AccountInfo<'a>,
+    pub market_event_queue: AccountInfo<'a>,
+    pub market_bids: AccountInfo<'a>,
+    pub market_asks: AccountInfo<'a>,
+}
+
+impl<'a> Withdraw<'a> {
+    fn to_account_infos(&self) -> Vec<AccountInfo<'a>> {
+        vec![
+            self.token_program.clone(),
+            self.amm.clone(),
+            self.amm_authority.clone(),
+            self.amm_open_orders.clone(),
+            self.amm_target_orders.clone(),
+            self.amm_lp_mint.clone(),
+            self.amm_coin_vault.clone(),
+            self.amm_pc_vault.clone(),
+            self.market_program.clone(),
+            self.market.clone(),
+            self.market_coin_vault.clone(),
+            self.market_pc_vault.clone(),
+            self.market_vault_signer.clone(),
+            self.user_token_lp.clone(),
+            self.user_token_coin.clone(),
+            self.user_token_pc.clone(),
+            self.user_owner.clone(),
+            self.market_event_queue.clone(),
+            self.market_bids.clone(),
+            self.market_asks.clone(),
+        ]
+    }
+}
+
+/// Appends the remaining accounts to the instruction and invokes it,
+/// passing the amm program account along for the runtime.
+fn invoke_amm<'a>(
+    amm_program: &AccountInfo<'a>,
+    mut ix: Instruction,
+    mut account_infos: Vec<AccountInfo<'a>>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    for account in remaining_accounts {
+        ix.accounts.push(if account.is_writable {
+            AccountMeta::new(*account.key, account.is_signer)
+        } else {
+            AccountMeta::new_readonly(*account.key, account.is_signer)
+        });
+        account_infos.push(account.clone());
+    }
+    account_infos.push(amm_program.clone());
+    invoke_signed(&ix, &account_infos, signer_seeds)
+}
+
+/// Invokes a 'swap_base_in' of the amm program.
+pub fn swap_base_in<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: Swap<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> ProgramResult {
+    let ix = instruction::swap_base_in(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_open_orders.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.market_program.key,
+        accounts.market.key,
+        accounts.market_bids.key,
+        accounts.market_asks.key,
+        accounts.market_event_queue.key,
+        accounts.market_coin_vault.key,
+        accounts.market_pc_vault.key,
+        accounts.market_vault_signer.key,
+        accounts.user_token_source.key,
+        accounts.user_token_destination.key,
+        accounts.user_source_owner.key,
+        amount_in,
+        minimum_amount_out,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'swap_base_out' of the amm program.
+pub fn swap_base_out<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: Swap<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    max_amount_in: u64,
+    amount_out: u64,
+) -> ProgramResult {
+    let ix = instruction::swap_base_out(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_open_orders.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.market_program.key,
+        accounts.market.key,
+        accounts.market_bids.key,
+        accounts.market_asks.key,
+        accounts.market_event_queue.key,
+        accounts.market_coin_vault.key,
+        accounts.market_pc_vault.key,
+        accounts.market_vault_signer.key,
+        accounts.user_token_source.key,
+        accounts.user_token_destination.key,
+        accounts.user_source_owner.key,
+        max_amount_in,
+        amount_out,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'swap_base_in_v2' of the amm program.
+pub fn swap_base_in_v2<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: SwapV2<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> ProgramResult {
+    let ix = instruction::swap_base_in_v2(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.user_token_source.key,
+        accounts.user_token_destination.key,
+        accounts.user_source_owner.key,
+        amount_in,
+        minimum_amount_out,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'swap_base_out_v2' of the amm program.
+pub fn swap_base_out_v2<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: SwapV2<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    max_amount_in: u64,
+    amount_out: u64,
+) -> ProgramResult {
+    let ix = instruction::swap_base_out_v2(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.user_token_source.key,
+        accounts.user_token_destination.key,
+        accounts.user_source_owner.key,
+        max_amount_in,
+        amount_out,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'swap_base_in_limit' of the amm program.
+pub fn swap_base_in_limit<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: SwapV2<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    amount_in: u64,
+    minimum_amount_out: u64,
+    limit_price: u64,
+) -> ProgramResult {
+    let ix = instruction::swap_base_in_limit(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.user_token_source.key,
+        accounts.user_token_destination.key,
+        accounts.user_source_owner.key,
+        amount_in,
+        minimum_amount_out,
+        limit_price,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'deposit' of the amm program.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: Deposit<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    max_coin_amount: u64,
+    max_pc_amount: u64,
+    base_side: u64,
+    other_amount_min: Option<u64>,
+) -> ProgramResult {
+    let ix = instruction::deposit(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_open_orders.key,
+        accounts.amm_target_orders.key,
+        accounts.amm_lp_mint.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.market.key,
+        accounts.market_event_queue.key,
+        accounts.user_token_coin.key,
+        accounts.user_token_pc.key,
+        accounts.user_token_lp.key,
+        accounts.user_owner.key,
+        max_coin_amount,
+        max_pc_amount,
+        base_side,
+        other_amount_min,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'deposit' of the amm program minting at least min_lp_out.
+#[allow(clippy::too_many_arguments)]
+pub fn deposit_with_min_lp_out<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: Deposit<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    max_coin_amount: u64,
+    max_pc_amount: u64,
+    base_side: u64,
+    other_amount_min: Option<u64>,
+    min_lp_out: u64,
+) -> ProgramResult {
+    let ix = instruction::deposit_with_min_lp_out(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_open_orders.key,
+        accounts.amm_target_orders.key,
+        accounts.amm_lp_mint.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.market.key,
+        accounts.market_event_queue.key,
+        accounts.user_token_coin.key,
+        accounts.user_token_pc.key,
+        accounts.user_token_lp.key,
+        accounts.user_owner.key,
+        max_coin_amount,
+        max_pc_amount,
+        base_side,
+        other_amount_min,
+        min_lp_out,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'withdraw' of the amm program.
+pub fn withdraw<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: Withdraw<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+    min_coin_amount: Option<u64>,
+    min_pc_amount: Option<u64>,
+) -> ProgramResult {
+    let ix = instruction::withdraw(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_open_orders.key,
+        accounts.amm_target_orders.key,
+        accounts.amm_lp_mint.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.market_program.key,
+        accounts.market.key,
+        accounts.market_coin_vault.key,
+        accounts.market_pc_vault.key,
+        accounts.market_vault_signer.key,
+        accounts.user_token_lp.key,
+        accounts.user_token_coin.key,
+        accounts.user_token_pc.key,
+        accounts.user_owner.key,
+        accounts.market_event_queue.key,
+        accounts.market_bids.key,
+        accounts.market_asks.key,
+        None,
+        amount,
+        min_coin_amount,
+        min_pc_amount,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+/// Invokes a 'withdraw_base_out' of the amm program.
+pub fn withdraw_base_out<'a>(
+    amm_program: &AccountInfo<'a>,
+    accounts: Withdraw<'a>,
+    remaining_accounts: &[AccountInfo<'a>],
+    signer_seeds: &[&[&[u8]]],
+    max_lp_amount: u64,
+    amount_out: u64,
+    base_side: u64,
+) -> ProgramResult {
+    let ix = instruction::withdraw_base_out(
+        amm_program.key,
+        accounts.amm.key,
+        accounts.amm_authority.key,
+        accounts.amm_open_orders.key,
+        accounts.amm_target_orders.key,
+        accounts.amm_lp_mint.key,
+        accounts.amm_coin_vault.key,
+        accounts.amm_pc_vault.key,
+        accounts.market_program.key,
+        accounts.market.key,
+        accounts.market_coin_vault.key,
+        accounts.market_pc_vault.key,
+        accounts.market_vault_signer.key,
+        accounts.user_token_lp.key,
+        accounts.user_token_coin.key,
+        accounts.user_token_pc.key,
+        accounts.user_owner.key,
+        accounts.market_event_queue.key,
+        accounts.market_bids.key,
+        accounts.market_asks.key,
+        None,
+        max_lp_amount,
+        amount_out,
+        base_side,
+    )?;
+    invoke_amm(
+        amm_program,
+        ix,
+        accounts.to_account_infos(),
+        remaining_accounts,
+        signer_seeds,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::{AmmInstruction, DepositInstruction};
+    use solana_program::{program_stubs, pubkey::Pubkey};
+    use std::{cell::RefCell, sync::Once};
+
+    thread_local! {
+        static INVOKED: RefCell<Vec<(Instruction, Vec<Pubkey>)>> = RefCell::new(Vec::new());
+    }
+
+    struct CpiStubs;
+    impl program_stubs::SyscallStubs for CpiStubs {
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            _signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            let keys = account_infos.iter().map(|info| *info.key).collect();
+            INVOKED.with(|invoked| invoked.borrow_mut().push((instruction.clone(), keys)));
+            Ok(())
+        }
+    }
+
+    fn set_cpi_stubs() {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(CpiStubs));
+        });
+    }
+
+    fn account_with_key(key: Pubkey, is_writable: bool) -> AccountInfo<'static> {
+        AccountInfo::new(
+            Box::leak(Box::new(key)),
+            false,
+            is_writable,
+            Box::leak(Box::new(0u64)),
+            Box::leak(Vec::new().into_boxed_slice()),
+            Box::leak(Box::new(Pubkey::default())),
+            false,
+            0,
+        )
+    }
+
+    fn account(is_writable: bool) -> AccountInfo<'static> {
+        account_with_key(Pubkey::new_unique(), is_writable)
+    }
+
+    /// Appends the remaining accounts to an instruction of the `instruction` builders,
+    /// as a cpi wrapper does.
+    fn with_remaining(mut ix: Instruction, remaining_accounts: &[AccountInfo]) -> Instruction {
+        ix.accounts
+            .extend(remaining_accounts.iter().map(|account| AccountMeta {
+                pubkey: *account.key,
+                is_signer: account.is_signer,
+                is_writable: account.is_writable,
+            }));
+        ix
+    }
+
+    /// Checks the invoked instruction against the expected one, and the account infos handed
+    /// to the runtime against its account metas, in order, followed by the program account.
+    fn check_invoked(expected: Instruction) -> Instruction {
+        let (ix, keys) = INVOKED.with(|invoked| invoked.borrow_mut().pop().unwrap());
+        assert_eq!(ix, expected);
+        let meta_keys = ix
+            .accounts
+            .iter()
+            .map(|meta| meta.pubkey)
+            .chain(std::iter::once(ix.program_id))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, meta_keys);
+        ix
+    }
+
+    fn swap_ix(
+        amm_program: &Pubkey,
+        accounts: &Swap,
+        base_in: bool,
+        amount: u64,
+        other_amount: u64,
+    ) -> Instruction {
+        let builder = if base_in {
+            instruction::swap_base_in
+        } else {
+            instruction::swap_base_out
+        };
+        builder(
+            amm_program,
+            accounts.amm.key,
+            accounts.amm_authority.key,
+            accounts.amm_open_orders.key,
+            accounts.amm_coin_vault.key,
+            accounts.amm_pc_vault.key,
+            accounts.market_program.key,
+            accounts.market.key,
+            accounts.market_bids.key,
+            accounts.market_asks.key,
+            accounts.market_event_queue.key,
+            accounts.market_coin_vault.key,
+            accounts.market_pc_vault.key,
+            accounts.market_vault_signer.key,
+            accounts.user_token_source.key,
+            accounts.user_token_destination.key,
+            accounts.user_source_owner.key,
+            amount,
+            other_amount,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cpi_swap() {
+        set_cpi_stubs();
+        let amm_program = account(false);
+        let remaining_accounts = [account(false), account(true)];
+        let swap = || Swap {
+            token_program: account_with_key(spl_token::id(), false),
+            amm: account(true),
+            amm_authority: account(false),
+            amm_open_orders: account(true),
+            amm_coin_vault: account(true),
+            amm_pc_vault: account(true),
+            market_program: account(false),
+            market: account(true),
+            market_bids: account(true),
+            market_asks: account(true),
+            market_event_queue: account(true),
+            market_coin_vault: account(true),
+            market_pc_vault: account(true),
+            market_vault_signer: account(false),
+            user_token_source: account(true),
+            user_token_destination: account(true),
+            user_source_owner: account(false),
+        };
+        let accounts = swap();
+        let expected = with_remaining(
+            swap_ix(amm_program.key, &accounts, true, 100, 90),
+            &remaining_accounts,
+        );
+        let amm = *accounts.amm.key;
+        swap_base_in(&amm_program, accounts, &remaining_accounts, &[], 100, 90).unwrap();
+        let ix = check_invoked(expected);
+        assert_eq!(ix.accounts[1].pubkey, amm);
+        let accounts = swap();
+        let expected = swap_ix(amm_program.key, &accounts, false, 100, 90);
+        swap_base_out(&amm_program, accounts, &[], &[], 100, 90).unwrap();
+        check_invoked(expected);
+
+        let swap_v2 = || SwapV2 {
+            token_program: account_with_key(spl_token::id(), false),
+            amm: account(true),
+            amm_authority: account(false),
+            amm_coin_vault: account(true),
+            amm_pc_vault: account(true),
+            user_token_source: account(true),
+            user_token_destination: account(true),
+            user_source_owner: account(false),
+        };
+        for base_in in [true, false] {
+            let accounts = swap_v2();
+            let builder = if base_in {
+                instruction::swap_base_in_v2
+            } else {
+                instruction::swap_base_out_v2
+            };
+            let expected = with_remaining(
+                builder(
+                    amm_program.key,
+                    accounts.amm.key,
+                    accounts.amm_authority.key,
+                    accounts.amm_coin_vault.key,
+                    accounts.amm_pc_vault.key,
+                    accounts.user_token_source.key,
+                    accounts.user_token_destination.key,
+                    accounts.user_source_owner.key,
+                    100,
+                    90,
+                )
+                .unwrap(),
+                &remaining_accounts,
+            );
+            if base_in {
+                swap_base_in_v2(&amm_program, accounts, &remaining_accounts, &[], 100, 90)
+            } else {
+                swap_base_out_v2(&amm_program, accounts, &remaining_accounts, &[], 100, 90)
+            }
+            .unwrap();
+            check_invoked(expected);
+        }
+        let accounts = swap_v2();
+        let expected = with_remaining(
+            instruction::swap_base_in_limit(
+                amm_program.key,
+                accounts.amm.key,
+                accounts.amm_authority.key,
+                accounts.amm_coin_vault.key,
+                accounts.amm_pc_vault.key,
+                accounts.user_token_source.key,
+                accounts.user_token_destination.key,
+                accounts.user_source_owner.key,
+                100,
+                90,
+                1,
+            )
+            .unwrap(),
+            &remaining_accounts,
+        );
+        swap_base_in_limit(&amm_program, accounts, &remaining_accounts, &[], 100, 90, 1).unwrap();
+        check_invoked(expected);
+    }
+
+    #[test]
+    fn test_cpi_deposit_withdraw() {
+        set_cpi_stubs();
+        let amm_program = account(false);
+        let remaining_accounts = [account(false), account(false), account(false)];
+        let deposit_accounts = || Deposit {
+            token_program: account_with_key(spl_token::id(), false),
+            amm: account(true),
+            amm_authority: account(false),
+            amm_open_orders: account(false),
+            amm_target_orders: account(true),
+            amm_lp_mint: account(true),
+            amm_coin_vault: account(true),
+            amm_pc_vault: account(true),
+            market: account(false),
+            market_event_queue: account(false),
+            user_token_coin: account(true),
+            user_token_pc: account(true),
+            user_token_lp: account(true),
+            user_owner: account(false),
+        };
+        let accounts = deposit_accounts();
+        let expected = with_remaining(
+            instruction::deposit(
+                amm_program.key,
+                accounts.amm.key,
+                accounts.amm_authority.key,
+                accounts.amm_open_orders.key,
+                accounts.amm_target_orders.key,
+                accounts.amm_lp_mint.key,
+                accounts.amm_coin_vault.key,
+                accounts.amm_pc_vault.key,
+                accounts.market.key,
+                accounts.market_event_queue.key,
+                accounts.user_token_coin.key,
+                accounts.user_token_pc.key,
+                accounts.user_token_lp.key,
+                accounts.user_owner.key,
+                100,
+                100,
+                0,
+                None,
+            )
+            .unwrap(),
+            &remaining_accounts,
+        );
+        let user_owner = *accounts.user_owner.key;
+        deposit(
+            &amm_program,
+            accounts,
+            &remaining_accounts,
+            &[],
+            100,
+            100,
+            0,
+            None,
+        )
+        .unwrap();
+        let ix = check_invoked(expected);
+        assert_eq!(ix.accounts[12].pubkey, user_owner);
+        assert!(ix.accounts[12].is_signer);
+        let accounts = deposit_accounts();
+        let expected = with_remaining(
+            instruction::deposit_with_min_lp_out(
+                amm_program.key,
+                accounts.amm.key,
+                accounts.amm_authority.key,
+                accounts.amm_open_orders.key,
+                accounts.amm_target_orders.key,
+                accounts.amm_lp_mint.key,
+                accounts.amm_coin_vault.key,
+                accounts.amm_pc_vault.key,
+                accounts.market.key,
+                accounts.market_event_queue.key,
+                accounts.user_token_coin.key,
+                accounts.user_token_pc.key,
+                accounts.user_token_lp.key,
+                accounts.user_owner.key,
+                100,
+                100,
+                0,
+                Some(2),
+                1,
+            )
+            .unwrap(),
+            &remaining_accounts,
+        );
+        deposit_with_min_lp_out(
+            &amm_program,
+            accounts,
+            &remaining_accounts,
+            &[],
+            100,
+            100,
+            0,
+            Some(2),
+            1,
+        )
+        .unwrap();
+        let ix = check_invoked(expected);
+        assert_eq!(
+            AmmInstruction::unpack(&ix.data),
+            Ok(AmmInstruction::Deposit(DepositInstruction {
+                max_coin_amount: 100,
+                max_pc_amount: 100,
+                base_side: 0,
+                other_amount_min: Some(2),
+                deadline: None,
+                min_lp_out: Some(1),
+            }))
+        );
+
+        let withdraw_accounts = || Withdraw {
+            token_program: account_with_key(spl_token::id(), false),
+            amm: account(true),
+            amm_authority: account(false),
+            amm_open_orders: account(true),
+            amm_target_orders: account(true),
+            amm_lp_mint: account(true),
+            amm_coin_vault: account(true),
+            amm_pc_vault: account(true),
+            market_program: account(false),
+            market: account(true),
+            market_coin_vault: account(true),
+            market_pc_vault: account(true),
+            market_vault_signer: account(false),
+            user_token_lp: account(true),
+            user_token_coin: account(true),
+            user_token_pc: account(true),
+            user_owner: account(false),
+            market_event_queue: account(true),
+            market_bids: account(true),
+            market_asks: account(true),
+        };
+        let accounts = withdraw_accounts();
+        let expected = with_remaining(
+            instruction::withdraw(
+                amm_program.key,
+                accounts.amm.key,
+                accounts.amm_authority.key,
+                accounts.amm_open_orders.key,
+                accounts.amm_target_orders.key,
+                accounts.amm_lp_mint.key,
+                accounts.amm_coin_vault.key,
+                accounts.amm_pc_vault.key,
+                accounts.market_program.key,
+                accounts.market.key,
+                accounts.market_coin_vault.key,
+                accounts.market_pc_vault.key,
+                accounts.market_vault_signer.key,
+                accounts.user_token_lp.key,
+                accounts.user_token_coin.key,
+                accounts.user_token_pc.key,
+                accounts.user_owner.key,
+                accounts.market_event_queue.key,
+                accounts.market_bids.key,
+                accounts.market_asks.key,
+                None,
+                100,
+                None,
+                None,
+            )
+            .unwrap(),
+            &remaining_accounts,
+        );
+        withdraw(
+            &amm_program,
+            accounts,
+            &remaining_accounts,
+            &[],
+            100,
+            None,
+            None,
+        )
+        .unwrap();
+        check_invoked(expected);
+        let accounts = withdraw_accounts();
+        let expected = instruction::withdraw_base_out(
+            amm_program.key,
+            accounts.amm.key,
+            accounts.amm_authority.key,
+            accounts.amm_open_orders.key,
+            accounts.amm_target_orders.key,
+            accounts.amm_lp_mint.key,
+            accounts.amm_coin_vault.key,
+            accounts.amm_pc_vault.key,
+            accounts.market_program.key,
+            accounts.market.key,
+            accounts.market_coin_vault.key,
+            accounts.market_pc_vault.key,
+            accounts.market_vault_signer.key,
+            accounts.user_token_lp.key,
+            accounts.user_token_coin.key,
+            accounts.user_token_pc.key,
+            accounts.user_owner.key,
+            accounts.market_event_queue.key,
+            accounts.market_bids.key,
+            accounts.market_asks.key,
+            None,
+            100,
+            10,
+            1,
+        )
+        .unwrap();
+        withdraw_base_out(&amm_program, accounts, &[], &[], 100, 10, 1).unwrap();
+        check_invoked(expected);
+    }
+}
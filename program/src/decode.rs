@@ -0,0 +1,466 @@
+//! Account decoders for the `client` feature
+//!
+//! `AmmInfo`, `TargetOrders` and `AmmConfig` are packed Pod structs without
+//! serde support. The functions below copy raw account data, or the base64
+//! data returned by the rpc, into these structs, and the `Decoded*` structs
+//! give them a stable JSON representation:
+//! - pubkeys are base58 strings
+//! - `status`, `state` and `reset_flag` are named after the `AmmStatus`,
+//!   `AmmState` and `AmmResetFlag` variants, the invalid state being
+//!   `InvalidState`, and `Unknown(n)` for values out of range
+//! - u128 values are decimal strings, as JSON numbers lose their precision
+//! - padding and pending fields are left out
+
+use crate::{
+    error::AmmError,
+    state::{
+        AmmConfig, AmmInfo, AmmResetFlag, AmmState, AmmStatus, Loadable, TargetOrder, TargetOrders,
+    },
+};
+use serde::{Deserialize, Serialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+use std::{convert::identity, mem::size_of};
+
+/// Copies the account data of `T`, which must have the exact account size.
+pub fn decode_account<T: Loadable>(data: &[u8]) -> Result<T, ProgramError> {
+    if data.len() != size_of::<T>() {
+        return Err(AmmError::ExpectedAccount.into());
+    }
+    Ok(*T::load_from_bytes(data)?)
+}
+
+/// Copies the base64 account data of `T`, as returned by the getAccountInfo rpc.
+pub fn decode_account_base64<T: Loadable>(data: &str) -> Result<T, ProgramError> {
+    let data = base64::decode_config(data, base64::STANDARD).map_err(|_| AmmError::InvalidInput)?;
+    decode_account(&data)
+}
+
+/// JSON of an amm, target orders or amm config account, told apart by the data size.
+pub fn account_to_json(data: &[u8]) -> Result<String, ProgramError> {
+    match data.len() {
+        len if len == size_of::<AmmInfo>() => {
+            Ok(DecodedAmmInfo::from(&decode_account::<AmmInfo>(data)?).to_json())
+        }
+        len if len == size_of::<TargetOrders>() => {
+            Ok(DecodedTargetOrders::from(&decode_account::<TargetOrders>(data)?).to_json())
+        }
+        len if len == size_of::<AmmConfig>() => {
+            Ok(DecodedAmmConfig::from(&decode_account::<AmmConfig>(data)?).to_json())
+        }
+        _ => Err(AmmError::ExpectedAccount.into()),
+    }
+}
+
+// the names are spelled out rather than taken from Debug, so that renaming a variant
+// does not change the JSON, and matched on the enums so that a new variant has to be named.
+// `from_u64` panics on values out of range, which an account can hold, they are checked first.
+fn status_name(status: u64) -> String {
+    if status != AmmStatus::Uninitialized.into_u64() && !AmmStatus::valid_status(status) {
+        return format!("Unknown({})", status);
+    }
+    let name = match AmmStatus::from_u64(status) {
+        AmmStatus::Uninitialized => "Uninitialized",
+        AmmStatus::Initialized => "Initialized",
+        AmmStatus::Disabled => "Disabled",
+        AmmStatus::WithdrawOnly => "WithdrawOnly",
+        AmmStatus::LiquidityOnly => "LiquidityOnly",
+        AmmStatus::OrderBookOnly => "OrderBookOnly",
+        AmmStatus::SwapOnly => "SwapOnly",
+        AmmStatus::WaitingTrade => "WaitingTrade",
+    };
+    name.to_string()
+}
+
+fn state_name(state: u64) -> String {
+    if !AmmState::valid_state(state) {
+        return format!("Unknown({})", state);
+    }
+    let name = match AmmState::from_u64(state) {
+        AmmState::InvlidState => "InvalidState",
+        AmmState::IdleState => "IdleState",
+        AmmState::CancelAllOrdersState => "CancelAllOrdersState",
+        AmmState::PlanOrdersState => "PlanOrdersState",
+        AmmState::CancelOrderState => "CancelOrderState",
+        AmmState::PlaceOrdersState => "PlaceOrdersState",
+        AmmState::PurgeOrderState => "PurgeOrderState",
+    };
+    name.to_string()
+}
+
+fn reset_flag_name(reset_flag: u64) -> String {
+    if reset_flag > AmmResetFlag::ResetNo.into_u64() {
+        return format!("Unknown({})", reset_flag);
+    }
+    let name = match AmmResetFlag::from_u64(reset_flag) {
+        AmmResetFlag::ResetYes => "ResetYes",
+        AmmResetFlag::ResetNo => "ResetNo",
+    };
+    name.to_string()
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DecodedFees {
+    pub min_separate_numerator: u64,
+    pub min_separate_denominator: u64,
+    pub trade_fee_numerator: u64,
+    pub trade_fee_denominator: u64,
+    pub pnl_numerator: u64,
+    pub pnl_denominator: u64,
+    pub swap_fee_numerator: u64,
+    pub swap_fee_denominator: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DecodedStateData {
+    pub need_take_pnl_coin: u64,
+    pub need_take_pnl_pc: u64,
+    pub total_pnl_pc: u64,
+    pub total_pnl_coin: u64,
+    pub pool_open_time: u64,
+    pub protocol_fee_coin: u64,
+    pub protocol_fee_pc: u64,
+    pub orderbook_to_init_time: u64,
+    pub swap_coin_in_amount: String,
+    pub swap_pc_out_amount: String,
+    pub swap_acc_pc_fee: u64,
+    pub swap_pc_in_amount: String,
+    pub swap_coin_out_amount: String,
+    pub swap_acc_coin_fee: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DecodedAmmInfo {
+    pub status: String,
+    pub nonce: u64,
+    pub order_num: u64,
+    pub depth: u64,
+    pub coin_decimals: u64,
+    pub pc_decimals: u64,
+    pub state: String,
+    pub reset_flag: String,
+    pub min_size: u64,
+    pub vol_max_cut_ratio: u64,
+    pub amount_wave: u64,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub min_price_multiplier: u64,
+    pub max_price_multiplier: u64,
+    pub sys_decimal_value: u64,
+    pub fees: DecodedFees,
+    pub state_data: DecodedStateData,
+    pub coin_vault: String,
+    pub pc_vault: String,
+    pub coin_vault_mint: String,
+    pub pc_vault_mint: String,
+    pub lp_mint: String,
+    pub open_orders: String,
+    pub market: String,
+    pub market_program: String,
+    pub target_orders: String,
+    pub coin_price_cumulative: String,
+    pub flash_loan_coin_repay: u64,
+    pub flash_loan_pc_repay: u64,
+    pub price_cumulative_time: u64,
+    pub dynamic_fee_min_numerator: u32,
+    pub dynamic_fee_max_numerator: u32,
+    pub volatility_accumulator: u64,
+    pub volatility_update_time: u64,
+    pub amm_owner: String,
+    pub lp_amount: u64,
+    pub client_order_id: u64,
+    pub recent_epoch: u64,
+    pub flash_loan_fee_numerator: u32,
+    pub protocol_fee_rate: u32,
+}
+impl From<&AmmInfo> for DecodedAmmInfo {
+    fn from(amm: &AmmInfo) -> Self {
+        DecodedAmmInfo {
+            status: status_name(amm.status),
+            nonce: amm.nonce,
+            order_num: amm.order_num,
+            depth: amm.depth,
+            coin_decimals: amm.coin_decimals,
+            pc_decimals: amm.pc_decimals,
+            state: state_name(amm.state),
+            reset_flag: reset_flag_name(amm.reset_flag),
+            min_size: amm.min_size,
+            vol_max_cut_ratio: amm.vol_max_cut_ratio,
+            amount_wave: amm.amount_wave,
+            coin_lot_size: amm.coin_lot_size,
+            pc_lot_size: amm.pc_lot_size,
+            min_price_multiplier: amm.min_price_multiplier,
+            max_price_multiplier: amm.max_price_multiplier,
+            sys_decimal_value: amm.sys_decimal_value,
+            fees: DecodedFees {
+                min_separate_numerator: amm.fees.min_separate_numerator,
+                min_separate_denominator: amm.fees.min_separate_denominator,
+                trade_fee_numerator: amm.fees.trade_fee_numerator,
+                trade_fee_denominator: amm.fees.trade_fee_denominator,
+                pnl_numerator: amm.fees.pnl_numerator,
+                pnl_denominator: amm.fees.pnl_denominator,
+                swap_fee_numerator: amm.fees.swap_fee_numerator,
+                swap_fee_denominator: amm.fees.swap_fee_denominator,
+            },
+            state_data: DecodedStateData {
+                need_take_pnl_coin: amm.state_data.need_take_pnl_coin,
+                need_take_pnl_pc: amm.state_data.need_take_pnl_pc,
+                total_pnl_pc: amm.state_data.total_pnl_pc,
+                total_pnl_coin: amm.state_data.total_pnl_coin,
+                pool_open_time: amm.state_data.pool_open_time,
+                protocol_fee_coin: amm.state_data.protocol_fee_coin,
+                protocol_fee_pc: amm.state_data.protocol_fee_pc,
+                orderbook_to_init_time: amm.state_data.orderbook_to_init_time,
+                swap_coin_in_amount: identity(amm.state_data.swap_coin_in_amount).to_string(),
+                swap_pc_out_amount: identity(amm.state_data.swap_pc_out_amount).to_string(),
+                swap_acc_pc_fee: amm.state_data.swap_acc_pc_fee,
+                swap_pc_in_amount: identity(amm.state_data.swap_pc_in_amount).to_string(),
+                swap_coin_out_amount: identity(amm.state_data.swap_coin_out_amount).to_string(),
+                swap_acc_coin_fee: amm.state_data.swap_acc_coin_fee,
+            },
+            coin_vault: amm.coin_vault.to_string(),
+            pc_vault: amm.pc_vault.to_string(),
+            coin_vault_mint: amm.coin_vault_mint.to_string(),
+            pc_vault_mint: amm.pc_vault_mint.to_string(),
+            lp_mint: amm.lp_mint.to_string(),
+            open_orders: amm.open_orders.to_string(),
+            market: amm.market.to_string(),
+            market_program: amm.market_program.to_string(),
+            target_orders: amm.target_orders.to_string(),
+            coin_price_cumulative: identity(amm.coin_price_cumulative).to_string(),
+            flash_loan_coin_repay: amm.flash_loan_coin_repay,
+            flash_loan_pc_repay: amm.flash_loan_pc_repay,
+            price_cumulative_time: amm.price_cumulative_time,
+            dynamic_fee_min_numerator: amm.dynamic_fee_min_numerator,
+            dynamic_fee_max_numerator: amm.dynamic_fee_max_numerator,
+            volatility_accumulator: amm.volatility_accumulator,
+            volatility_update_time: amm.volatility_update_time,
+            amm_owner: amm.amm_owner.to_string(),
+            lp_amount: amm.lp_amount,
+            client_order_id: amm.client_order_id,
+            recent_epoch: amm.recent_epoch,
+            flash_loan_fee_numerator: amm.flash_loan_fee_numerator,
+            protocol_fee_rate: amm.protocol_fee_rate,
+        }
+    }
+}
+impl DecodedAmmInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Self {
+        serde_json::from_str(data).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DecodedTargetOrder {
+    pub price: u64,
+    pub vol: u64,
+}
+impl From<&TargetOrder> for DecodedTargetOrder {
+    fn from(order: &TargetOrder) -> Self {
+        DecodedTargetOrder {
+            price: order.price,
+            vol: order.vol,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DecodedTargetOrders {
+    pub owner: String,
+    pub buy_orders: Vec<DecodedTargetOrder>,
+    pub target_x: String,
+    pub target_y: String,
+    pub plan_x_buy: String,
+    pub plan_y_buy: String,
+    pub plan_x_sell: String,
+    pub plan_y_sell: String,
+    pub placed_x: String,
+    pub placed_y: String,
+    pub calc_pnl_x: String,
+    pub calc_pnl_y: String,
+    pub sell_orders: Vec<DecodedTargetOrder>,
+    pub replace_buy_client_id: Vec<u64>,
+    pub replace_sell_client_id: Vec<u64>,
+    pub last_order_numerator: u64,
+    pub last_order_denominator: u64,
+    pub plan_orders_cur: u64,
+    pub place_orders_cur: u64,
+    pub valid_buy_order_num: u64,
+    pub valid_sell_order_num: u64,
+    pub free_slot_bits: String,
+}
+impl From<&TargetOrders> for DecodedTargetOrders {
+    fn from(target: &TargetOrders) -> Self {
+        DecodedTargetOrders {
+            owner: Pubkey::new_from_array(bytemuck::cast(identity(target.owner))).to_string(),
+            buy_orders: identity(target.buy_orders).iter().map(Into::into).collect(),
+            target_x: identity(target.target_x).to_string(),
+            target_y: identity(target.target_y).to_string(),
+            plan_x_buy: identity(target.plan_x_buy).to_string(),
+            plan_y_buy: identity(target.plan_y_buy).to_string(),
+            plan_x_sell: identity(target.plan_x_sell).to_string(),
+            plan_y_sell: identity(target.plan_y_sell).to_string(),
+            placed_x: identity(target.placed_x).to_string(),
+            placed_y: identity(target.placed_y).to_string(),
+            calc_pnl_x: identity(target.calc_pnl_x).to_string(),
+            calc_pnl_y: identity(target.calc_pnl_y).to_string(),
+            sell_orders: identity(target.sell_orders)
+                .iter()
+                .map(Into::into)
+                .collect(),
+            replace_buy_client_id: identity(target.replace_buy_client_id).to_vec(),
+            replace_sell_client_id: identity(target.replace_sell_client_id).to_vec(),
+            last_order_numerator: target.last_order_numerator,
+            last_order_denominator: target.last_order_denominator,
+            plan_orders_cur: target.plan_orders_cur,
+            place_orders_cur: target.place_orders_cur,
+            valid_buy_order_num: target.valid_buy_order_num,
+            valid_sell_order_num: target.valid_sell_order_num,
+            free_slot_bits: identity(target.free_slot_bits).to_string(),
+        }
+    }
+}
+impl DecodedTargetOrders {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Self {
+        serde_json::from_str(data).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DecodedAmmConfig {
+    pub pnl_owner: String,
+    pub cancel_owner: String,
+    pub protocol_fee_rate: u64,
+    pub create_pool_fee: u64,
+}
+impl From<&AmmConfig> for DecodedAmmConfig {
+    fn from(config: &AmmConfig) -> Self {
+        DecodedAmmConfig {
+            pnl_owner: config.pnl_owner.to_string(),
+            cancel_owner: config.cancel_owner.to_string(),
+            protocol_fee_rate: config.protocol_fee_rate,
+            create_pool_fee: config.create_pool_fee,
+        }
+    }
+}
+impl DecodedAmmConfig {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Self {
+        serde_json::from_str(data).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{AmmState, AmmStatus};
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn test_decode_amm_info() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(254, 0, 9, 6, 1000000, 1).unwrap();
+        amm.status = AmmStatus::SwapOnly.into_u64();
+        amm.coin_price_cumulative = u128::MAX;
+        amm.lp_mint = Pubkey::new_unique();
+        let data = bytemuck::bytes_of(&amm);
+
+        let decoded = decode_account::<AmmInfo>(data).unwrap();
+        assert!(decoded == amm);
+        assert!(decode_account_base64::<AmmInfo>(&base64::encode(data)).unwrap() == amm);
+        assert_eq!(
+            decode_account::<AmmInfo>(&data[1..]).err(),
+            Some(AmmError::ExpectedAccount.into())
+        );
+
+        let json = account_to_json(data).unwrap();
+        let decoded = DecodedAmmInfo::from_json(&json);
+        assert_eq!(decoded, DecodedAmmInfo::from(&amm));
+        assert_eq!(decoded.status, "SwapOnly");
+        assert_eq!(decoded.state, "IdleState");
+        assert_eq!(decoded.reset_flag, "ResetNo");
+        assert_eq!(decoded.lp_mint, amm.lp_mint.to_string());
+        assert_eq!(decoded.coin_price_cumulative, u128::MAX.to_string());
+        assert_eq!(decoded.fees.swap_fee_numerator, 25);
+
+        amm.status = AmmStatus::Uninitialized.into_u64();
+        assert_eq!(DecodedAmmInfo::from(&amm).status, "Uninitialized");
+        amm.status = 100;
+        assert_eq!(DecodedAmmInfo::from(&amm).status, "Unknown(100)");
+        amm.state = AmmState::InvlidState.into_u64();
+        assert_eq!(DecodedAmmInfo::from(&amm).state, "InvalidState");
+        amm.state = 7;
+        amm.reset_flag = 2;
+        let decoded = DecodedAmmInfo::from(&amm);
+        assert_eq!(decoded.state, "Unknown(7)");
+        assert_eq!(decoded.reset_flag, "Unknown(2)");
+    }
+
+    #[test]
+    fn test_decode_target_orders() {
+        let owner = Pubkey::new_unique();
+        let mut target = TargetOrders::zeroed();
+        target.owner = bytemuck::cast(owner.to_bytes());
+        target.buy_orders[1] = TargetOrder {
+            price: 150,
+            vol: 1_000,
+        };
+        target.target_x = u128::MAX;
+        target.replace_sell_client_id[2] = 42;
+        target.valid_buy_order_num = 2;
+        let data = bytemuck::bytes_of(&target);
+
+        let decoded = DecodedTargetOrders::from_json(&account_to_json(data).unwrap());
+        assert_eq!(decoded, DecodedTargetOrders::from(&target));
+        assert_eq!(decoded.owner, owner.to_string());
+        assert_eq!(decoded.buy_orders.len(), identity(target.buy_orders).len());
+        assert_eq!(
+            decoded.buy_orders[1],
+            DecodedTargetOrder {
+                price: 150,
+                vol: 1_000
+            }
+        );
+        assert_eq!(decoded.target_x, u128::MAX.to_string());
+        assert_eq!(decoded.replace_sell_client_id[2], 42);
+        assert_eq!(decoded.valid_buy_order_num, 2);
+    }
+
+    #[test]
+    fn test_decode_amm_config() {
+        let config = AmmConfig {
+            pnl_owner: Pubkey::new_unique(),
+            cancel_owner: Pubkey::new_unique(),
+            protocol_fee_rate: 2_000,
+            create_pool_fee: 400_000_000,
+            ..Default::default()
+        };
+        let data = bytemuck::bytes_of(&config);
+        assert!(decode_account::<AmmConfig>(data).unwrap() == config);
+
+        let decoded = DecodedAmmConfig::from_json(&account_to_json(data).unwrap());
+        assert_eq!(decoded, DecodedAmmConfig::from(&config));
+        assert_eq!(decoded.pnl_owner, config.pnl_owner.to_string());
+        assert_eq!(decoded.protocol_fee_rate, 2_000);
+        assert_eq!(decoded.create_pool_fee, 400_000_000);
+
+        // accounts of another size are not told apart
+        let mut data = data.to_vec();
+        data.push(0);
+        assert_eq!(
+            account_to_json(&data).err(),
+            Some(AmmError::ExpectedAccount.into())
+        );
+        assert_eq!(
+            account_to_json(&[]).err(),
+            Some(AmmError::ExpectedAccount.into())
+        );
+    }
+}
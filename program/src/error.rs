@@ -24,6 +24,8 @@ use thiserror::Error;
 /// - Mathematical operation errors (25-39) 
 /// - Token/vault validation errors (40-54)
 /// - Configuration and status errors (55-59)
+/// - Oracle and flash loan errors (60-63)
+/// - Transaction validity errors (64)
 #[derive(Clone, Debug, Eq, Error, FromPrimitive, PartialEq)]
 pub enum AmmError {
     // === ACCOUNT VALIDATION ERRORS (0-4) ===
@@ -234,6 +236,26 @@ pub enum AmmError {
     /// Unknown Amm Error
     #[error("Unknown Amm Error")]
     UnknownAmmError,
+    /// The token-2022 mint carries an extension the pool does not support.
+    #[error("Token-2022 mint has an unsupported extension")]
+    UnsupportedMintExtension,
+
+    // 60
+    /// Invalid observation account
+    #[error("Invalid observation account")]
+    InvalidObservationAccount,
+    /// A flash loan is open on the pool
+    #[error("Flash loan is in progress")]
+    FlashLoanInProgress,
+    /// Flash loan is disabled or not closed by a FlashLoanEnd in the same transaction
+    #[error("Invalid flash loan")]
+    InvalidFlashLoan,
+    /// The vaults were not repaid with the flash loan fee
+    #[error("Flash loan not repaid")]
+    FlashLoanNotRepaid,
+    /// The instruction landed after its deadline
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
 }
 
 /// Convert AmmError to Solana's ProgramError
@@ -341,6 +363,12 @@ impl PrintProgramError for AmmError {
             AmmError::MarketLotSizeIsTooLarge => msg!("Error: Market lotSize is too large"),
             AmmError::InitLpAmountTooLess => msg!("Error: Init lp amount is too less(Because 10**lp_decimals amount lp will be locked)"),
             AmmError::UnknownAmmError => msg!("Error: UnknownAmmError"),
+            AmmError::UnsupportedMintExtension => msg!("Error: UnsupportedMintExtension"),
+            AmmError::InvalidObservationAccount => msg!("Error: InvalidObservationAccount"),
+            AmmError::FlashLoanInProgress => msg!("Error: FlashLoanInProgress"),
+            AmmError::InvalidFlashLoan => msg!("Error: InvalidFlashLoan"),
+            AmmError::FlashLoanNotRepaid => msg!("Error: FlashLoanNotRepaid"),
+            AmmError::DeadlineExceeded => msg!("Error: DeadlineExceeded"),
         }
     }
 }
@@ -13,6 +13,7 @@
 #![allow(clippy::too_many_arguments)]
 #![allow(deprecated)]
 
+use crate::error::AmmError;
 use crate::state::{AmmParams, Fees, LastOrderDistance, SimulateParams};
 use arrayref::array_ref;
 use solana_program::{
@@ -88,6 +89,26 @@ pub struct DepositInstruction {
     pub base_side: u64,
     /// Minimum amount of the other token (slippage protection)
     pub other_amount_min: Option<u64>,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
+    /// Minimum LP tokens to mint (slippage protection). Optional trailing data
+    pub min_lp_out: Option<u64>,
+}
+
+/// Single sided deposit instruction
+///
+/// This instruction deposits only coin or only pc. Part of the amount is
+/// swapped against the pool first, so that the rest and the swap output
+/// are added in the pool ratio.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ZapDepositInstruction {
+    /// Amount of the user source token, coin or pc, to deposit
+    pub amount_in: u64,
+    /// Minimum LP tokens to receive (slippage protection)
+    pub min_lp_amount: u64,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
 }
 
 /// Withdraw liquidity instruction  
@@ -104,6 +125,40 @@ pub struct WithdrawInstruction {
     pub min_coin_amount: Option<u64>,
     /// Minimum PC amount to receive (slippage protection)
     pub min_pc_amount: Option<u64>,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
+}
+
+/// Withdraw liquidity by exact output instruction
+///
+/// This instruction pays out an exact amount of one side and the matching
+/// amount of the other side, burning the LP tokens needed, rounded up.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WithdrawInstructionBaseOut {
+    /// Maximum LP tokens to burn (slippage protection)
+    pub max_lp_amount: u64,
+    /// Exact amount of the base side to receive
+    pub amount_out: u64,
+    /// Which token side amount_out is, 0 for coin and 1 for pc
+    pub base_side: u64,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
+}
+
+/// Single sided withdraw instruction
+///
+/// This instruction burns LP tokens like a withdraw, then swaps the side the
+/// user doesn't want through the pool, so that only coin or only pc is paid out.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ZapWithdrawInstruction {
+    /// Amount of LP tokens to burn for withdrawal
+    pub amount: u64,
+    /// Minimum amount of the user destination token to receive (slippage protection)
+    pub min_amount_out: u64,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
 }
 
 #[repr(C)]
@@ -134,6 +189,11 @@ pub struct SwapInstructionBaseIn {
     pub amount_in: u64,
     /// Minimum amount of destination token to receive (slippage protection)
     pub minimum_amount_out: u64,
+    /// Integrator fee in basis points of amount_in, paid to the referral token account.
+    /// Zero when absent, only carried by the SwapBaseIn tag 31 and the V2 data
+    pub referral_fee_bps: u16,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
 }
 
 /// Swap instruction with exact output amount
@@ -148,6 +208,29 @@ pub struct SwapInstructionBaseOut {
     pub max_amount_in: u64,
     /// Exact amount of destination token to receive
     pub amount_out: u64,
+    /// Integrator fee in basis points of the amount in, paid on top of it to the referral
+    /// token account. Zero when absent, only carried by the SwapBaseOut tag 32 and the V2 data
+    pub referral_fee_bps: u16,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
+}
+
+/// Swap instruction with an exact input amount and a limit price
+///
+/// This instruction swaps as much of the input amount as the pool can take
+/// without its price crossing the limit. The rest is left in the user
+/// source account.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SwapInstructionBaseInLimit {
+    /// Most source token to swap
+    pub amount_in: u64,
+    /// Minimum amount of destination token to receive for the filled part (slippage protection)
+    pub minimum_amount_out: u64,
+    /// Pool price the swap may not cross, pc per coin normalized like the orderbook prices
+    pub limit_price: u64,
+    /// Unix timestamp after which the instruction fails. Optional trailing data
+    pub deadline: Option<u64>,
 }
 
 #[repr(C)]
@@ -156,6 +239,8 @@ pub struct SimulateInstruction {
     pub param: u8,
     pub swap_base_in_value: Option<SwapInstructionBaseIn>,
     pub swap_base_out_value: Option<SwapInstructionBaseOut>,
+    pub deposit_value: Option<DepositInstruction>,
+    pub withdraw_value: Option<WithdrawInstruction>,
 }
 
 #[repr(C)]
@@ -164,6 +249,36 @@ pub struct AdminCancelOrdersInstruction {
     pub limit: u16,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GetTwapInstruction {
+    /// seconds the TWAP window reaches back at least, zero to only read the current cumulative
+    pub window: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InitializeObservationInstruction {
+    /// number of observation slots to allocate
+    pub capacity: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GrowObservationInstruction {
+    /// new number of observation slots, larger than the current one
+    pub capacity: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlashLoanInstruction {
+    /// coin amount lent from the coin vault
+    pub coin_amount: u64,
+    /// pc amount lent from the pc vault
+    pub pc_amount: u64,
+}
+
 /// Update config acccount params
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
@@ -171,6 +286,7 @@ pub struct ConfigArgs {
     pub param: u8,
     pub owner: Option<Pubkey>,
     pub create_pool_fee: Option<u64>,
+    pub protocol_fee_rate: Option<u64>,
 }
 
 /// All instructions supported by the Raydium AMM program
@@ -181,10 +297,18 @@ pub struct ConfigArgs {
 ///
 /// Instructions are categorized as:
 /// - Pool Management: Initialize2, SetParams, MigrateToOpenBook
-/// - Liquidity Operations: Deposit, Withdraw, WithdrawPnl
-/// - Trading Operations: SwapBaseIn, SwapBaseOut  
+/// - Liquidity Operations: Deposit, ZapDeposit, Withdraw, WithdrawBaseOut, ZapWithdraw, WithdrawPnl,
+///   WithdrawProtocolFee
+/// - Trading Operations: SwapBaseIn, SwapBaseOut, SwapBaseInV2, SwapBaseOutV2, SwapBaseInLimit,
+///   RouteSwapBaseIn, FlashLoanBegin, FlashLoanEnd
 /// - Monitoring: MonitorStep, SimulateInfo
+/// - Price Oracle: GetTwap, InitializeObservation, GrowObservation, WriteObservation
 /// - Administration: CreateConfigAccount, UpdateConfigAccount, AdminCancelOrders
+///
+/// Pools with a Spl Token 2022 coin or pc vault take the trailing accounts
+/// `[]` Spl Token 2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account
+/// after all the other accounts of the instructions moving or pricing vault tokens,
+/// see `append_token_2022_accounts` for the instructions concerned.
 #[repr(C)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum AmmInstruction {
@@ -196,6 +320,10 @@ pub enum AmmInstruction {
 
     ///   Initializes a new AMM pool.
     ///
+    ///   The coin and pc mints may be owned by Spl Token 2022, the lp mint is always created
+    ///   with Spl Token. A pool with a Spl Token 2022 vault can't be given a status with
+    ///   orderbook permission, openbook settles Spl Token vaults only.
+    ///
     ///   0. `[]` Spl Token program id
     ///   1. `[]` Associated Token program id
     ///   2. `[]` Sys program id
@@ -203,7 +331,7 @@ pub enum AmmInstruction {
     ///   4. `[writable]` New AMM Account to create.
     ///   5. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
     ///   6. `[writable]` AMM open orders Account
-    ///   7. `[writable]` AMM lp mint Account
+    ///   7. `[writable]` AMM lp mint Account, a Spl Token mint
     ///   8. `[]` AMM coin mint Account
     ///   9. `[]` AMM pc mint Account
     ///   10. `[writable]` AMM coin vault Account. Must be non zero, owned by $authority.
@@ -217,6 +345,7 @@ pub enum AmmInstruction {
     ///   18. `[]` User token coin Account
     ///   19. '[]` User token pc Account
     ///   20. `[writable]` User destination lp token ATA Account
+    ///   21. `[]` (optional) Spl Token 2022 program id, required if the coin or pc mint is owned by it
     Initialize2(InitializeInstruction2),
 
     ///   MonitorStep. To monitor place Amm order state machine turn around step by step.
@@ -244,7 +373,11 @@ pub enum AmmInstruction {
     MonitorStep(MonitorStepInstruction),
 
     ///   Deposit some tokens into the pool.  The output is a "pool" token representing ownership
-    ///   into the pool. Inputs are converted to the current ratio.
+    ///   into the pool. Inputs are converted to the current ratio. An optional min_lp_out bounds
+    ///   the pool tokens minted.
+    ///
+    ///   Packed with tag 3 and an optional trailing other_amount_min, or with tag 29 and a presence
+    ///   byte ahead of other_amount_min, deadline and min_lp_out once either of the last two is set.
     ///
     ///   0. `[]` Spl Token program id
     ///   1. `[writable]` AMM Account
@@ -257,13 +390,18 @@ pub enum AmmInstruction {
     ///   8. `[]` Market Account. Market program is the owner.
     ///   9. `[writable]` User coin token Account to deposit into.
     ///   10. `[writable]` User pc token Account to deposit into.
-    ///   11. `[writable]` User lp token. To deposit the generated tokens, user is the owner.
+    ///   11. `[writable]` Recipient lp token. To deposit the generated tokens, any owner.
     ///   12. '[signer]` User wallet Account
     ///   13. `[]` Market event queue Account.
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
     Deposit(DepositInstruction),
 
     ///   Withdraw the vault tokens from the pool at the current ratio.
     ///
+    ///   Packed with tag 4 and optional trailing min_coin_amount and min_pc_amount, or with tag 30
+    ///   and a presence byte ahead of min_coin_amount, min_pc_amount and deadline otherwise.
+    ///
     ///   0. `[]` Spl Token program id
     ///   1. `[writable]` AMM Account
     ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
@@ -278,12 +416,14 @@ pub enum AmmInstruction {
     ///   11. `[writable]` Market pc vault Account
     ///   12. '[]` Market vault signer Account
     ///   13. `[writable]` User lp token Account.
-    ///   14. `[writable]` User token coin Account. user Account to credit.
-    ///   15. `[writable]` User token pc Account. user Account to credit.
+    ///   14. `[writable]` User token coin Account. Account to credit, any owner.
+    ///   15. `[writable]` User token pc Account. Account to credit, any owner.
     ///   16. `[signer]` User wallet Account
     ///   17. `[writable]` Market event queue Account
     ///   18. `[writable]` Market bids Account
     ///   19. `[writable]` Market asks Account
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
     Withdraw(WithdrawInstruction),
 
     ///   Migrate the associated market from Serum to OpenBook.
@@ -329,7 +469,11 @@ pub enum AmmInstruction {
     ///   13. `[writable]` Market bids Account
     ///   14. `[writable]` Market asks Account
     ///   15. `[signer]` Admin Account
-    ///   16. `[]` (optional) New AMM open orders Account to replace old AMM open orders Account
+    ///   16. `[]` (optional) Account of the param, the only one taking an account at this position:
+    ///       - UpdateOpenOrder: new AMM open orders Account to replace old AMM open orders Account
+    ///       - ProtocolFeeRate: AMM config Account, derived from
+    ///         `find_program_address(&[&&AMM_CONFIG_SEED])`, whose protocol fee rate is copied
+    ///         into the pool. That param carries no value
     SetParams(SetParamsInstruction),
 
     ///   Withdraw Pnl from pool by protocol
@@ -352,6 +496,8 @@ pub enum AmmInstruction {
     ///   15. `[writable]` Market pc vault Account
     ///   16. '[]` Market vault signer Account
     ///   17. `[]` (optional) the referrer pc account used for settle back referrer
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
     WithdrawPnl,
 
     ///   Withdraw (M)SRM from the (M)SRM Account used for fee discounts by admin
@@ -366,6 +512,9 @@ pub enum AmmInstruction {
 
     /// Swap coin or pc from pool, base amount_in with a slippage of minimum_amount_out
     ///
+    ///   Packed with tag 9 without a referral fee nor deadline, or with tag 31 followed by the
+    ///   referral fee bps and an optional deadline.
+    ///
     ///   0. `[]` Spl Token program id
     ///   1. `[writable]` AMM Account
     ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
@@ -384,6 +533,10 @@ pub enum AmmInstruction {
     ///   15. `[writable]` User source token Account.
     ///   16. `[writable]` User destination token Account.
     ///   17. `[signer]` User wallet Account
+    ///   18. `[writable]` (optional) Referral token Account of the source mint, only given with a referral fee
+    ///   19. `[writable]` (optional) AMM observation Account, written with the pool price before the swap
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
     SwapBaseIn(SwapInstructionBaseIn),
 
     ///   Continue Initializes a new Amm pool because of compute units limit.
@@ -393,6 +546,9 @@ pub enum AmmInstruction {
 
     /// Swap coin or pc from pool, base amount_out with a slippage of max_amount_in
     ///
+    ///   Packed with tag 11 without a referral fee nor deadline, or with tag 32 followed by the
+    ///   referral fee bps and an optional deadline.
+    ///
     ///   0. `[]` Spl Token program id
     ///   1. `[writable]` AMM Account
     ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
@@ -411,6 +567,10 @@ pub enum AmmInstruction {
     ///   15. `[writable]` User source token Account.
     ///   16. `[writable]` User destination token Account.
     ///   17. `[signer]` User wallet Account
+    ///   18. `[writable]` (optional) Referral token Account of the source mint, only given with a referral fee
+    ///   19. `[writable]` (optional) AMM observation Account, written with the pool price before the swap
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
     SwapBaseOut(SwapInstructionBaseOut),
 
     SimulateInfo(SimulateInstruction),
@@ -420,8 +580,275 @@ pub enum AmmInstruction {
     /// Create amm config account by admin
     CreateConfigAccount,
 
-    /// Update amm config account by admin
+    /// Update amm config account by admin, param 0 the pnl owner, 1 the cancel owner,
+    /// 2 the create pool fee and 3 the protocol fee rate. Pools copy the protocol fee rate
+    /// at initialization and keep charging the copy, so a new rate has to be pushed to every
+    /// existing pool with a SetParams ProtocolFeeRate, see `set_protocol_fee_rate`
     UpdateConfigAccount(ConfigArgs),
+
+    /// Read the pool TWAP over the last `window` seconds, returned as `AmmReturnData::Twap`
+    /// through the return data. The window starts at the latest observation of the
+    /// observation account written at or before `now - window`.
+    ///
+    ///   0. `[]` AMM Account
+    ///   1. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   2. `[]` AMM open orders Account
+    ///   3. `[]` AMM coin vault Account
+    ///   4. `[]` AMM pc vault Account
+    ///   5. `[]` Market Account. Market program is the owner.
+    ///   6. `[]` Market event queue Account
+    ///   7. `[]` Observation Account of the amm
+    GetTwap(GetTwapInstruction),
+
+    /// Create the observation ring buffer account of an amm, written by the swaps it is passed to
+    /// and by anyone with WriteObservation.
+    ///
+    /// Swaps without the optional observation account, and the other instructions moving the
+    /// pool, leave no observation. They still update the price cumulative of the amm, so a TWAP
+    /// between two observations is exact however far apart they are, a gap only moves the start
+    /// of a `GetTwap` window further back. Keepers close the gaps with WriteObservation.
+    ///
+    ///   0. `[writable, signer]` Payer Account
+    ///   1. `[]` AMM Account
+    ///   2. `[writable]` Observation Account, derived from `get_associated_address_and_bump_seed(&program_id, &amm, OBSERVATION_ASSOCIATED_SEED, &program_id)`
+    ///   3. `[]` Sys program id
+    InitializeObservation(InitializeObservationInstruction),
+
+    /// Grow the observation ring buffer account of an amm.
+    ///
+    ///   0. `[writable, signer]` Payer Account
+    ///   1. `[]` AMM Account
+    ///   2. `[writable]` Observation Account
+    ///   3. `[]` Sys program id
+    GrowObservation(GrowObservationInstruction),
+
+    /// Accumulate the pool price up to now and write it to the observation account, by anyone.
+    /// At most one observation is written per second, like the swaps.
+    ///
+    ///   0. `[writable]` AMM Account
+    ///   1. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   2. `[]` AMM open orders Account
+    ///   3. `[]` AMM coin vault Account
+    ///   4. `[]` AMM pc vault Account
+    ///   5. `[]` Market Account. Market program is the owner.
+    ///   6. `[]` Market event queue Account
+    ///   7. `[writable]` Observation Account of the amm
+    WriteObservation,
+
+    /// Swap through several pools, base amount_in with a slippage of minimum_amount_out
+    /// checked on the final leg only. The return data holds the result of each hop.
+    /// Referral fees are not supported, a nonzero referral_fee_bps is refused.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[signer]` User wallet Account
+    ///   2. `[writable]` User source token Account of the first hop.
+    ///
+    ///   Followed by the accounts of each hop:
+    ///   0. `[writable]` AMM Account
+    ///   1. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   2. `[writable]` AMM coin vault Account to swap FROM or To.
+    ///   3. `[writable]` AMM pc vault Account to swap FROM or To.
+    ///   4. `[writable]` User destination token Account of the hop, the source of the next hop.
+    ///
+    ///   Hops through a pool with orderbook permission are followed by its market accounts:
+    ///   0. `[writable]` AMM open orders Account
+    ///   1. `[]` Market program id
+    ///   2. `[writable]` Market Account. Market program is the owner.
+    ///   3. `[writable]` Market bids Account
+    ///   4. `[writable]` Market asks Account
+    ///   5. `[writable]` Market event queue Account
+    ///   6. `[writable]` Market coin vault Account
+    ///   7. `[writable]` Market pc vault Account
+    ///   8. `[]` Market vault signer Account
+    ///
+    ///   Hops through a pool with a Spl Token 2022 coin or pc vault are then followed by
+    ///   `[]` Spl Token 2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    RouteSwapBaseIn(SwapInstructionBaseIn),
+
+    /// Lend coin and pc from the pool vaults until the FlashLoanEnd of the same amm,
+    /// which must follow as a top level instruction of the same transaction.
+    /// The FlashLoanEnd must carry the same amounts. The fee is
+    /// `flash_loan_fee_numerator / TEN_THOUSAND` of the amounts, rounded up, and stays in the
+    /// vaults for the liquidity providers. FlashLoanEnd counts it in the `swap_acc_coin_fee` and
+    /// `swap_acc_pc_fee` pool statistics once the repayment is checked.
+    /// Refused while the pool has orders planned or placed in the orderbook.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM coin vault Account
+    ///   4. `[writable]` AMM pc vault Account
+    ///   5. `[writable]` User coin token Account to receive the coin amount
+    ///   6. `[writable]` User pc token Account to receive the pc amount
+    ///   7. `[]` Instructions sysvar
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    FlashLoanBegin(FlashLoanInstruction),
+
+    /// Check the vaults were repaid the amounts lent by FlashLoanBegin plus the fee,
+    /// and close the flash loan. Carries the amounts of the FlashLoanBegin, which the fee
+    /// credited to the pool statistics is computed from.
+    ///
+    ///   0. `[writable]` AMM Account
+    ///   1. `[]` AMM coin vault Account
+    ///   2. `[]` AMM pc vault Account
+    FlashLoanEnd(FlashLoanInstruction),
+
+    /// Swap coin or pc from a pool without orderbook, base amount_in with a slippage of
+    /// minimum_amount_out. Takes no market accounts, pools with orderbook permission are refused.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM coin vault Account to swap FROM or To.
+    ///   4. `[writable]` AMM pc vault Account to swap FROM or To.
+    ///   5. `[writable]` User source token Account.
+    ///   6. `[writable]` User destination token Account.
+    ///   7. `[signer]` User wallet Account
+    ///   8. `[writable]` (optional) Referral token Account of the source mint, only given with a referral fee
+    ///   9. `[writable]` (optional) AMM observation Account, written with the pool price before the swap
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    SwapBaseInV2(SwapInstructionBaseIn),
+
+    /// Swap coin or pc from a pool without orderbook, base amount_out with a slippage of
+    /// max_amount_in. Takes no market accounts, pools with orderbook permission are refused.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM coin vault Account to swap FROM or To.
+    ///   4. `[writable]` AMM pc vault Account to swap FROM or To.
+    ///   5. `[writable]` User source token Account.
+    ///   6. `[writable]` User destination token Account.
+    ///   7. `[signer]` User wallet Account
+    ///   8. `[writable]` (optional) Referral token Account of the source mint, only given with a referral fee
+    ///   9. `[writable]` (optional) AMM observation Account, written with the pool price before the swap
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    SwapBaseOutV2(SwapInstructionBaseOut),
+
+    /// Swap coin or pc, up to amount_in without the pool price crossing limit_price. The
+    /// unfilled input stays in the user source token Account, the filled amount is reported in
+    /// the return data. For a pool with orderbook permission the amm orders on the side the
+    /// swap takes from are cancelled.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM coin vault Account to swap FROM or To.
+    ///   4. `[writable]` AMM pc vault Account to swap FROM or To.
+    ///   5. `[writable]` User source token Account.
+    ///   6. `[writable]` User destination token Account.
+    ///   7. `[signer]` User wallet Account
+    ///
+    ///   Pools with orderbook permission also take their market accounts, in the order of a
+    ///   RouteSwapBaseIn hop: AMM open orders, Market program id, Market, Market bids,
+    ///   Market asks, Market event queue, Market coin vault, Market pc vault, Market vault signer.
+    ///
+    ///   The optional AMM observation Account, written with the pool price before the swap,
+    ///   follows them.
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    SwapBaseInLimit(SwapInstructionBaseInLimit),
+
+    /// Deposit only coin or only pc into a pool. The part of amount_in that balances the pool
+    /// ratio, fee included, is swapped and the rest deposited with the swap output, with a
+    /// slippage of min_lp_amount. For a pool with orderbook permission the amm orders on the
+    /// side the swap takes from are cancelled.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM target orders Account
+    ///   4. `[writable]` AMM lp mint Account
+    ///   5. `[writable]` AMM coin vault Account
+    ///   6. `[writable]` AMM pc vault Account
+    ///   7. `[writable]` User source coin or pc token Account
+    ///   8. `[writable]` Recipient lp token Account, any owner
+    ///   9. `[signer]` User wallet Account
+    ///
+    ///   Pools with orderbook permission also take their market accounts, in the order of a
+    ///   RouteSwapBaseIn hop: AMM open orders, Market program id, Market, Market bids,
+    ///   Market asks, Market event queue, Market coin vault, Market pc vault, Market vault signer.
+    ///
+    ///   The optional AMM observation Account, written with the pool price before the swap,
+    ///   follows them.
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    ZapDeposit(ZapDepositInstruction),
+
+    ///   Withdraw the vault tokens like Withdraw, then swap the side that doesn't match the user
+    ///   destination mint into the other through the pool, with a slippage of min_amount_out.
+    ///   Orders are cancelled and settled the same way as Withdraw.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM open orders Account
+    ///   4. `[writable]` AMM target orders Account
+    ///   5. `[writable]` AMM lp mint Account. Owned by $authority.
+    ///   6. `[writable]` AMM coin vault Account to withdraw FROM,
+    ///   7. `[writable]` AMM pc vault Account to withdraw FROM,
+    ///   8. `[]` Market program id
+    ///   9. `[writable]` Market Account. Market program is the owner.
+    ///   10. `[writable]` Market coin vault Account
+    ///   11. `[writable]` Market pc vault Account
+    ///   12. '[]` Market vault signer Account
+    ///   13. `[writable]` User lp token Account.
+    ///   14. `[writable]` User token coin or pc Account. Account to credit, any owner.
+    ///   15. `[signer]` User wallet Account
+    ///   16. `[writable]` Market event queue Account
+    ///   17. `[writable]` Market bids Account
+    ///   18. `[writable]` Market asks Account
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    ZapWithdraw(ZapWithdrawInstruction),
+
+    ///   Withdraw exactly amount_out of the base side and the matching other side at the current
+    ///   ratio, burning at most max_lp_amount. The accounts are the same as Withdraw.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM open orders Account
+    ///   4. `[writable]` AMM target orders Account
+    ///   5. `[writable]` AMM lp mint Account. Owned by $authority.
+    ///   6. `[writable]` AMM coin vault Account to withdraw FROM,
+    ///   7. `[writable]` AMM pc vault Account to withdraw FROM,
+    ///   8. `[]` Market program id
+    ///   9. `[writable]` Market Account. Market program is the owner.
+    ///   10. `[writable]` Market coin vault Account
+    ///   11. `[writable]` Market pc vault Account
+    ///   12. '[]` Market vault signer Account
+    ///   13. `[writable]` User lp token Account.
+    ///   14. `[writable]` User token coin Account. Account to credit, any owner.
+    ///   15. `[writable]` User token pc Account. Account to credit, any owner.
+    ///   16. `[signer]` User wallet Account
+    ///   17. `[writable]` Market event queue Account
+    ///   18. `[writable]` Market bids Account
+    ///   19. `[writable]` Market asks Account
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    WithdrawBaseOut(WithdrawInstructionBaseOut),
+
+    ///   Withdraw the protocol share of the swap fees, accrued in the pool state data, by the
+    ///   pnl owner. Amounts the vaults lack, still in the orderbook, stay claimable.
+    ///   The AMM config Account only names the pnl owner, a new protocol fee rate reaches the
+    ///   pool through a SetParams ProtocolFeeRate, see `set_protocol_fee_rate`.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` AMM config Account, derived from `find_program_address(&[&&AMM_CONFIG_SEED])`.
+    ///   3. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   4. `[writable]` AMM coin vault account to withdraw FROM,
+    ///   5. `[writable]` AMM pc vault account to withdraw FROM,
+    ///   6. `[writable]` User coin token Account to withdraw to
+    ///   7. `[writable]` User pc token Account to withdraw to
+    ///   8. `[signer]` Pnl owner wallet account
+    ///
+    ///   Pools with a Spl Token 2022 vault also take the trailing accounts, see `AmmInstruction`.
+    WithdrawProtocolFee,
 }
 
 impl AmmInstruction {
@@ -484,6 +911,8 @@ impl AmmInstruction {
                     max_pc_amount,
                     base_side,
                     other_amount_min,
+                    deadline: None,
+                    min_lp_out: None,
                 })
             }
             4 => {
@@ -499,6 +928,7 @@ impl AmmInstruction {
                     amount,
                     min_coin_amount,
                     min_pc_amount,
+                    deadline: None,
                 })
             }
             5 => Self::MigrateToOpenBook,
@@ -552,6 +982,13 @@ impl AmmInstruction {
                             return Err(ProgramError::InvalidInstructionData.into());
                         }
                     }
+                    AmmParams::ProtocolFeeRate => Self::SetParams(SetParamsInstruction {
+                        param,
+                        value: None,
+                        new_pubkey: None,
+                        fees: None,
+                        last_order_distance: None,
+                    }),
                     _ => {
                         if rest.len() >= 8 {
                             let (value, _rest) = Self::unpack_u64(rest)?;
@@ -579,6 +1016,8 @@ impl AmmInstruction {
                 Self::SwapBaseIn(SwapInstructionBaseIn {
                     amount_in,
                     minimum_amount_out,
+                    referral_fee_bps: 0,
+                    deadline: None,
                 })
             }
             10 => {
@@ -591,29 +1030,67 @@ impl AmmInstruction {
                 Self::SwapBaseOut(SwapInstructionBaseOut {
                     max_amount_in,
                     amount_out,
+                    referral_fee_bps: 0,
+                    deadline: None,
                 })
             }
             12 => {
                 let (param, rest) = Self::unpack_u8(rest)?;
-                match SimulateParams::from_u64(param as u64) {
+                match SimulateParams::from_u64(param as u64)? {
                     SimulateParams::PoolInfo | SimulateParams::RunCrankInfo => {
                         Self::SimulateInfo(SimulateInstruction {
                             param,
                             swap_base_in_value: None,
                             swap_base_out_value: None,
+                            deposit_value: None,
+                            withdraw_value: None,
                         })
                     }
-                    SimulateParams::SwapBaseInInfo => {
+                    SimulateParams::SwapBaseInInfo | SimulateParams::RouteSwapBaseIn => {
                         let (amount_in, rest) = Self::unpack_u64(rest)?;
                         let (minimum_amount_out, _rest) = Self::unpack_u64(rest)?;
                         let swap_base_in = Some(SwapInstructionBaseIn {
                             amount_in,
                             minimum_amount_out,
+                            referral_fee_bps: 0,
+                            deadline: None,
                         });
                         Self::SimulateInfo(SimulateInstruction {
                             param,
                             swap_base_in_value: swap_base_in,
                             swap_base_out_value: None,
+                            deposit_value: None,
+                            withdraw_value: None,
+                        })
+                    }
+                    SimulateParams::DepositInfo => {
+                        let (max_coin_amount, rest) = Self::unpack_u64(rest)?;
+                        let (max_pc_amount, rest) = Self::unpack_u64(rest)?;
+                        let (base_side, _rest) = Self::unpack_u64(rest)?;
+                        Self::SimulateInfo(SimulateInstruction {
+                            param,
+                            swap_base_in_value: None,
+                            swap_base_out_value: None,
+                            deposit_value: Some(DepositInstruction {
+                                max_coin_amount,
+                                max_pc_amount,
+                                base_side,
+                                ..Default::default()
+                            }),
+                            withdraw_value: None,
+                        })
+                    }
+                    SimulateParams::WithdrawInfo => {
+                        let (amount, _rest) = Self::unpack_u64(rest)?;
+                        Self::SimulateInfo(SimulateInstruction {
+                            param,
+                            swap_base_in_value: None,
+                            swap_base_out_value: None,
+                            deposit_value: None,
+                            withdraw_value: Some(WithdrawInstruction {
+                                amount,
+                                ..Default::default()
+                            }),
                         })
                     }
                     SimulateParams::SwapBaseOutInfo => {
@@ -622,11 +1099,15 @@ impl AmmInstruction {
                         let swap_base_out = Some(SwapInstructionBaseOut {
                             max_amount_in,
                             amount_out,
+                            referral_fee_bps: 0,
+                            deadline: None,
                         });
                         Self::SimulateInfo(SimulateInstruction {
                             param,
                             swap_base_in_value: None,
                             swap_base_out_value: swap_base_out,
+                            deposit_value: None,
+                            withdraw_value: None,
                         })
                     }
                 }
@@ -645,6 +1126,7 @@ impl AmmInstruction {
                             param,
                             owner: Some(Pubkey::new_from_array(*pubkey)),
                             create_pool_fee: None,
+                            protocol_fee_rate: None,
                         })
                     }
                     2 => {
@@ -653,6 +1135,16 @@ impl AmmInstruction {
                             param,
                             owner: None,
                             create_pool_fee: Some(create_pool_fee),
+                            protocol_fee_rate: None,
+                        })
+                    }
+                    3 => {
+                        let (protocol_fee_rate, _rest) = Self::unpack_u64(rest)?;
+                        Self::UpdateConfigAccount(ConfigArgs {
+                            param,
+                            owner: None,
+                            create_pool_fee: None,
+                            protocol_fee_rate: Some(protocol_fee_rate),
                         })
                     }
                     _ => {
@@ -660,10 +1152,233 @@ impl AmmInstruction {
                     }
                 }
             }
+            16 => {
+                let (window, _rest) = Self::unpack_u64(rest)?;
+                Self::GetTwap(GetTwapInstruction { window })
+            }
+            17 => {
+                let (capacity, _rest) = Self::unpack_u16(rest)?;
+                Self::InitializeObservation(InitializeObservationInstruction { capacity })
+            }
+            18 => {
+                let (capacity, _rest) = Self::unpack_u16(rest)?;
+                Self::GrowObservation(GrowObservationInstruction { capacity })
+            }
+            19 => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let (deadline, _rest) = Self::unpack_deadline(rest)?;
+                Self::RouteSwapBaseIn(SwapInstructionBaseIn {
+                    amount_in,
+                    minimum_amount_out,
+                    referral_fee_bps: 0,
+                    deadline,
+                })
+            }
+            20 => {
+                let (coin_amount, rest) = Self::unpack_u64(rest)?;
+                let (pc_amount, _rest) = Self::unpack_u64(rest)?;
+                Self::FlashLoanBegin(FlashLoanInstruction {
+                    coin_amount,
+                    pc_amount,
+                })
+            }
+            21 => {
+                let (coin_amount, rest) = Self::unpack_u64(rest)?;
+                let (pc_amount, _rest) = Self::unpack_u64(rest)?;
+                Self::FlashLoanEnd(FlashLoanInstruction {
+                    coin_amount,
+                    pc_amount,
+                })
+            }
+            22 => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let (referral_fee_bps, deadline) = Self::unpack_referral_and_deadline(rest, false)?;
+                Self::SwapBaseInV2(SwapInstructionBaseIn {
+                    amount_in,
+                    minimum_amount_out,
+                    referral_fee_bps,
+                    deadline,
+                })
+            }
+            23 => {
+                let (max_amount_in, rest) = Self::unpack_u64(rest)?;
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                let (referral_fee_bps, deadline) = Self::unpack_referral_and_deadline(rest, false)?;
+                Self::SwapBaseOutV2(SwapInstructionBaseOut {
+                    max_amount_in,
+                    amount_out,
+                    referral_fee_bps,
+                    deadline,
+                })
+            }
+            24 => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let (limit_price, rest) = Self::unpack_u64(rest)?;
+                let (deadline, _rest) = Self::unpack_deadline(rest)?;
+                Self::SwapBaseInLimit(SwapInstructionBaseInLimit {
+                    amount_in,
+                    minimum_amount_out,
+                    limit_price,
+                    deadline,
+                })
+            }
+            25 => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (min_lp_amount, rest) = Self::unpack_u64(rest)?;
+                let (deadline, _rest) = Self::unpack_deadline(rest)?;
+                Self::ZapDeposit(ZapDepositInstruction {
+                    amount_in,
+                    min_lp_amount,
+                    deadline,
+                })
+            }
+            26 => {
+                let (amount, rest) = Self::unpack_u64(rest)?;
+                let (min_amount_out, rest) = Self::unpack_u64(rest)?;
+                let (deadline, _rest) = Self::unpack_deadline(rest)?;
+                Self::ZapWithdraw(ZapWithdrawInstruction {
+                    amount,
+                    min_amount_out,
+                    deadline,
+                })
+            }
+            27 => {
+                let (max_lp_amount, rest) = Self::unpack_u64(rest)?;
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                let (base_side, rest) = Self::unpack_u64(rest)?;
+                let (deadline, _rest) = Self::unpack_deadline(rest)?;
+                Self::WithdrawBaseOut(WithdrawInstructionBaseOut {
+                    max_lp_amount,
+                    amount_out,
+                    base_side,
+                    deadline,
+                })
+            }
+            28 => Self::WithdrawProtocolFee,
+            // a Deposit whose optional fields are read behind a presence byte
+            29 => {
+                let (max_coin_amount, rest) = Self::unpack_u64(rest)?;
+                let (max_pc_amount, rest) = Self::unpack_u64(rest)?;
+                let (base_side, rest) = Self::unpack_u64(rest)?;
+                let mut optional = [None; 3];
+                Self::unpack_optional_u64s(rest, &mut optional)?;
+                let [other_amount_min, deadline, min_lp_out] = optional;
+                Self::Deposit(DepositInstruction {
+                    max_coin_amount,
+                    max_pc_amount,
+                    base_side,
+                    other_amount_min,
+                    deadline,
+                    min_lp_out,
+                })
+            }
+            // a Withdraw whose optional fields are read behind a presence byte
+            30 => {
+                let (amount, rest) = Self::unpack_u64(rest)?;
+                let mut optional = [None; 3];
+                Self::unpack_optional_u64s(rest, &mut optional)?;
+                let [min_coin_amount, min_pc_amount, deadline] = optional;
+                Self::Withdraw(WithdrawInstruction {
+                    amount,
+                    min_coin_amount,
+                    min_pc_amount,
+                    deadline,
+                })
+            }
+            // a SwapBaseIn with a referral fee or a deadline
+            31 => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let (referral_fee_bps, deadline) = Self::unpack_referral_and_deadline(rest, true)?;
+                Self::SwapBaseIn(SwapInstructionBaseIn {
+                    amount_in,
+                    minimum_amount_out,
+                    referral_fee_bps,
+                    deadline,
+                })
+            }
+            // a SwapBaseOut with a referral fee or a deadline
+            32 => {
+                let (max_amount_in, rest) = Self::unpack_u64(rest)?;
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                let (referral_fee_bps, deadline) = Self::unpack_referral_and_deadline(rest, true)?;
+                Self::SwapBaseOut(SwapInstructionBaseOut {
+                    max_amount_in,
+                    amount_out,
+                    referral_fee_bps,
+                    deadline,
+                })
+            }
+            33 => Self::WriteObservation,
             _ => return Err(ProgramError::InvalidInstructionData.into()),
         })
     }
 
+    /// Reads the optional trailing deadline of an instruction.
+    fn unpack_deadline(input: &[u8]) -> Result<(Option<u64>, &[u8]), ProgramError> {
+        if input.is_empty() {
+            Ok((None, input))
+        } else {
+            // a truncated deadline is refused, it must not read as no deadline
+            let (deadline, rest) = Self::unpack_u64(input)?;
+            Ok((Some(deadline), rest))
+        }
+    }
+
+    /// Reads a presence byte, bit i flagging fields[i], followed by the present fields in order.
+    fn unpack_optional_u64s<'a>(
+        input: &'a [u8],
+        fields: &mut [Option<u64>],
+    ) -> Result<&'a [u8], ProgramError> {
+        let (flags, mut rest) = Self::unpack_u8(input)?;
+        if flags >> fields.len() != 0 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        for (i, field) in fields.iter_mut().enumerate() {
+            if flags & (1 << i) != 0 {
+                let (value, next) = Self::unpack_u64(rest)?;
+                *field = Some(value);
+                rest = next;
+            }
+        }
+        Ok(rest)
+    }
+
+    /// Writes the presence byte and the present fields read by `unpack_optional_u64s`.
+    fn pack_optional_u64s(buf: &mut Vec<u8>, fields: &[Option<u64>]) {
+        let flags = fields
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| field.is_some())
+            .fold(0u8, |flags, (i, _)| flags | 1 << i);
+        buf.push(flags);
+        for field in fields.iter().flatten() {
+            buf.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    /// Reads the trailing referral fee of a swap followed by its optional deadline. The referral
+    /// fee, zero when absent, is given with any deadline, so the tail is exactly 0, 2 or 10
+    /// bytes long. A deadline sent without the referral fee is refused rather than misread.
+    fn unpack_referral_and_deadline(
+        input: &[u8],
+        referral_required: bool,
+    ) -> Result<(u16, Option<u64>), ProgramError> {
+        match input.len() {
+            0 if !referral_required => Ok((0, None)),
+            2 => Ok((Self::unpack_u16(input)?.0, None)),
+            10 => {
+                let (referral_fee_bps, rest) = Self::unpack_u16(input)?;
+                let (deadline, _rest) = Self::unpack_u64(rest)?;
+                Ok((referral_fee_bps, Some(deadline)))
+            }
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
     fn unpack_u8(input: &[u8]) -> Result<(u8, &[u8]), ProgramError> {
         if input.len() >= 1 {
             let (amount, rest) = input.split_at(1);
@@ -750,27 +1465,52 @@ impl AmmInstruction {
                 max_pc_amount,
                 base_side,
                 other_amount_min,
+                deadline,
+                min_lp_out,
             }) => {
-                buf.push(3);
+                // the original layout only carries other_amount_min
+                let legacy = deadline.is_none() && min_lp_out.is_none();
+                buf.push(if legacy { 3 } else { 29 });
                 buf.extend_from_slice(&max_coin_amount.to_le_bytes());
                 buf.extend_from_slice(&max_pc_amount.to_le_bytes());
                 buf.extend_from_slice(&base_side.to_le_bytes());
-                if other_amount_min.is_some() {
-                    buf.extend_from_slice(&other_amount_min.unwrap().to_le_bytes());
+                if legacy {
+                    if let Some(other_amount_min) = other_amount_min {
+                        buf.extend_from_slice(&other_amount_min.to_le_bytes());
+                    }
+                } else {
+                    Self::pack_optional_u64s(
+                        &mut buf,
+                        &[*other_amount_min, *deadline, *min_lp_out],
+                    );
                 }
             }
             Self::Withdraw(WithdrawInstruction {
                 amount,
                 min_coin_amount,
                 min_pc_amount,
-            }) => {
-                buf.push(4);
-                buf.extend_from_slice(&amount.to_le_bytes());
-                if min_coin_amount.is_some() && min_pc_amount.is_some() {
-                    buf.extend_from_slice(&min_coin_amount.unwrap().to_le_bytes());
-                    buf.extend_from_slice(&min_pc_amount.unwrap().to_le_bytes());
+                deadline,
+            }) => match (min_coin_amount, min_pc_amount, deadline) {
+                // the original layout carries both slippage bounds or none
+                (None, None, None) => {
+                    buf.push(4);
+                    buf.extend_from_slice(&amount.to_le_bytes());
                 }
-            }
+                (Some(min_coin_amount), Some(min_pc_amount), None) => {
+                    buf.push(4);
+                    buf.extend_from_slice(&amount.to_le_bytes());
+                    buf.extend_from_slice(&min_coin_amount.to_le_bytes());
+                    buf.extend_from_slice(&min_pc_amount.to_le_bytes());
+                }
+                _ => {
+                    buf.push(30);
+                    buf.extend_from_slice(&amount.to_le_bytes());
+                    Self::pack_optional_u64s(
+                        &mut buf,
+                        &[*min_coin_amount, *min_pc_amount, *deadline],
+                    );
+                }
+            },
             Self::MigrateToOpenBook => {
                 buf.push(5);
             }
@@ -808,6 +1548,8 @@ impl AmmInstruction {
                         buf.extend_from_slice(&distance.last_order_numerator.to_le_bytes());
                         buf.extend_from_slice(&distance.last_order_denominator.to_le_bytes());
                     }
+                    // the rate is read from the config account, the param carries no value
+                    AmmParams::ProtocolFeeRate => {}
                     _ => {
                         let value = match value {
                             Some(a) => a,
@@ -827,10 +1569,20 @@ impl AmmInstruction {
             Self::SwapBaseIn(SwapInstructionBaseIn {
                 amount_in,
                 minimum_amount_out,
+                referral_fee_bps,
+                deadline,
             }) => {
-                buf.push(9);
+                // the original layout carries neither a referral fee nor a deadline
+                let legacy = *referral_fee_bps == 0 && deadline.is_none();
+                buf.push(if legacy { 9 } else { 31 });
                 buf.extend_from_slice(&amount_in.to_le_bytes());
                 buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                if !legacy {
+                    buf.extend_from_slice(&referral_fee_bps.to_le_bytes());
+                }
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
             }
             Self::PreInitialize(PreInitializeInstruction { nonce }) => {
                 buf.push(10);
@@ -839,21 +1591,33 @@ impl AmmInstruction {
             Self::SwapBaseOut(SwapInstructionBaseOut {
                 max_amount_in,
                 amount_out,
+                referral_fee_bps,
+                deadline,
             }) => {
-                buf.push(11);
+                // the original layout carries neither a referral fee nor a deadline
+                let legacy = *referral_fee_bps == 0 && deadline.is_none();
+                buf.push(if legacy { 11 } else { 32 });
                 buf.extend_from_slice(&max_amount_in.to_le_bytes());
                 buf.extend_from_slice(&amount_out.to_le_bytes());
+                if !legacy {
+                    buf.extend_from_slice(&referral_fee_bps.to_le_bytes());
+                }
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
             }
             Self::SimulateInfo(SimulateInstruction {
                 param,
                 swap_base_in_value,
                 swap_base_out_value,
+                deposit_value,
+                withdraw_value,
             }) => {
                 buf.push(12);
                 buf.push(*param);
-                match SimulateParams::from_u64(*param as u64) {
+                match SimulateParams::from_u64(*param as u64)? {
                     SimulateParams::PoolInfo | SimulateParams::RunCrankInfo => {}
-                    SimulateParams::SwapBaseInInfo => {
+                    SimulateParams::SwapBaseInInfo | SimulateParams::RouteSwapBaseIn => {
                         let swap_base_in = match swap_base_in_value {
                             Some(a) => a,
                             None => return Err(ProgramError::InvalidInstructionData.into()),
@@ -869,6 +1633,22 @@ impl AmmInstruction {
                         buf.extend_from_slice(&swap_base_out.max_amount_in.to_le_bytes());
                         buf.extend_from_slice(&swap_base_out.amount_out.to_le_bytes());
                     }
+                    SimulateParams::DepositInfo => {
+                        let deposit = match deposit_value {
+                            Some(a) => a,
+                            None => return Err(ProgramError::InvalidInstructionData.into()),
+                        };
+                        buf.extend_from_slice(&deposit.max_coin_amount.to_le_bytes());
+                        buf.extend_from_slice(&deposit.max_pc_amount.to_le_bytes());
+                        buf.extend_from_slice(&deposit.base_side.to_le_bytes());
+                    }
+                    SimulateParams::WithdrawInfo => {
+                        let withdraw = match withdraw_value {
+                            Some(a) => a,
+                            None => return Err(ProgramError::InvalidInstructionData.into()),
+                        };
+                        buf.extend_from_slice(&withdraw.amount.to_le_bytes());
+                    }
                 }
             }
             Self::AdminCancelOrders(AdminCancelOrdersInstruction { limit }) => {
@@ -882,6 +1662,7 @@ impl AmmInstruction {
                 param,
                 owner,
                 create_pool_fee,
+                protocol_fee_rate,
             }) => {
                 buf.push(15);
                 buf.push(*param);
@@ -906,9 +1687,151 @@ impl AmmInstruction {
                         };
                         buf.extend_from_slice(&create_pool_fee.to_le_bytes());
                     }
+                    3 => {
+                        let protocol_fee_rate = match protocol_fee_rate {
+                            Some(protocol_fee_rate) => protocol_fee_rate,
+                            None => return Err(ProgramError::InvalidInstructionData.into()),
+                        };
+                        buf.extend_from_slice(&protocol_fee_rate.to_le_bytes());
+                    }
                     _ => return Err(ProgramError::InvalidInstructionData.into()),
                 }
             }
+            Self::GetTwap(GetTwapInstruction { window }) => {
+                buf.push(16);
+                buf.extend_from_slice(&window.to_le_bytes());
+            }
+            Self::InitializeObservation(InitializeObservationInstruction { capacity }) => {
+                buf.push(17);
+                buf.extend_from_slice(&capacity.to_le_bytes());
+            }
+            Self::GrowObservation(GrowObservationInstruction { capacity }) => {
+                buf.push(18);
+                buf.extend_from_slice(&capacity.to_le_bytes());
+            }
+            Self::WriteObservation => {
+                buf.push(33);
+            }
+            Self::RouteSwapBaseIn(SwapInstructionBaseIn {
+                amount_in,
+                minimum_amount_out,
+                referral_fee_bps,
+                deadline,
+            }) => {
+                // hops carry no referral account
+                if *referral_fee_bps != 0 {
+                    return Err(ProgramError::InvalidInstructionData.into());
+                }
+                buf.push(19);
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
+            }
+            Self::FlashLoanBegin(FlashLoanInstruction {
+                coin_amount,
+                pc_amount,
+            }) => {
+                buf.push(20);
+                buf.extend_from_slice(&coin_amount.to_le_bytes());
+                buf.extend_from_slice(&pc_amount.to_le_bytes());
+            }
+            Self::FlashLoanEnd(FlashLoanInstruction {
+                coin_amount,
+                pc_amount,
+            }) => {
+                buf.push(21);
+                buf.extend_from_slice(&coin_amount.to_le_bytes());
+                buf.extend_from_slice(&pc_amount.to_le_bytes());
+            }
+            Self::SwapBaseInV2(SwapInstructionBaseIn {
+                amount_in,
+                minimum_amount_out,
+                referral_fee_bps,
+                deadline,
+            }) => {
+                buf.push(22);
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                if *referral_fee_bps != 0 || deadline.is_some() {
+                    buf.extend_from_slice(&referral_fee_bps.to_le_bytes());
+                }
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
+            }
+            Self::SwapBaseOutV2(SwapInstructionBaseOut {
+                max_amount_in,
+                amount_out,
+                referral_fee_bps,
+                deadline,
+            }) => {
+                buf.push(23);
+                buf.extend_from_slice(&max_amount_in.to_le_bytes());
+                buf.extend_from_slice(&amount_out.to_le_bytes());
+                if *referral_fee_bps != 0 || deadline.is_some() {
+                    buf.extend_from_slice(&referral_fee_bps.to_le_bytes());
+                }
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
+            }
+            Self::SwapBaseInLimit(SwapInstructionBaseInLimit {
+                amount_in,
+                minimum_amount_out,
+                limit_price,
+                deadline,
+            }) => {
+                buf.push(24);
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                buf.extend_from_slice(&limit_price.to_le_bytes());
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
+            }
+            Self::ZapDeposit(ZapDepositInstruction {
+                amount_in,
+                min_lp_amount,
+                deadline,
+            }) => {
+                buf.push(25);
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&min_lp_amount.to_le_bytes());
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
+            }
+            Self::ZapWithdraw(ZapWithdrawInstruction {
+                amount,
+                min_amount_out,
+                deadline,
+            }) => {
+                buf.push(26);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&min_amount_out.to_le_bytes());
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
+            }
+            Self::WithdrawBaseOut(WithdrawInstructionBaseOut {
+                max_lp_amount,
+                amount_out,
+                base_side,
+                deadline,
+            }) => {
+                buf.push(27);
+                buf.extend_from_slice(&max_lp_amount.to_le_bytes());
+                buf.extend_from_slice(&amount_out.to_le_bytes());
+                buf.extend_from_slice(&base_side.to_le_bytes());
+                if let Some(deadline) = deadline {
+                    buf.extend_from_slice(&deadline.to_le_bytes());
+                }
+            }
+            Self::WithdrawProtocolFee => {
+                buf.push(28);
+            }
         }
         Ok(buf)
     }
@@ -1040,6 +1963,8 @@ pub fn deposit(
         max_pc_amount,
         base_side,
         other_amount_min,
+        deadline: None,
+        min_lp_out: None,
     })
     .pack()?;
 
@@ -1071,6 +1996,108 @@ pub fn deposit(
     })
 }
 
+/// Creates a 'deposit' instruction that fails with `AmmError::ExceededSlippage`
+/// when it would mint less than min_lp_out.
+pub fn deposit_with_min_lp_out(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    market: &Pubkey,
+    market_event_queue: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+    user_token_lp: &Pubkey,
+    user_owner: &Pubkey,
+    max_coin_amount: u64,
+    max_pc_amount: u64,
+    base_side: u64,
+    other_amount_min: Option<u64>,
+    min_lp_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = deposit(
+        amm_program,
+        amm_pool,
+        amm_authority,
+        amm_open_orders,
+        amm_target_orders,
+        amm_lp_mint,
+        amm_coin_vault,
+        amm_pc_vault,
+        market,
+        market_event_queue,
+        user_token_coin,
+        user_token_pc,
+        user_token_lp,
+        user_owner,
+        max_coin_amount,
+        max_pc_amount,
+        base_side,
+        other_amount_min,
+    )?;
+    instruction.data = AmmInstruction::Deposit(DepositInstruction {
+        max_coin_amount,
+        max_pc_amount,
+        base_side,
+        other_amount_min,
+        deadline: None,
+        min_lp_out: Some(min_lp_out),
+    })
+    .pack()?;
+    Ok(instruction)
+}
+
+/// Creates a 'zap_deposit' instruction. Pools with orderbook permission also take their
+/// market accounts, appended with `append_market_accounts`.
+pub fn zap_deposit(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_source: &Pubkey,
+    user_token_lp: &Pubkey,
+    user_owner: &Pubkey,
+
+    amount_in: u64,
+    min_lp_amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::ZapDeposit(ZapDepositInstruction {
+        amount_in,
+        min_lp_amount,
+        deadline: None,
+    })
+    .pack()?;
+
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new(*amm_lp_mint, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_token_lp, false),
+        AccountMeta::new_readonly(*user_owner, true),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
 /// Creates a 'withdraw' instruction.
 pub fn withdraw(
     amm_program: &Pubkey,
@@ -1104,6 +2131,7 @@ pub fn withdraw(
         amount,
         min_coin_amount,
         min_pc_amount,
+        deadline: None,
     })
     .pack()?;
 
@@ -1145,6 +2173,71 @@ pub fn withdraw(
     })
 }
 
+/// Creates a 'withdraw_base_out' instruction.
+pub fn withdraw_base_out(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    market_program: &Pubkey,
+    market: &Pubkey,
+    market_coin_vault: &Pubkey,
+    market_pc_vault: &Pubkey,
+    market_vault_signer: &Pubkey,
+    user_token_lp: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+    user_owner: &Pubkey,
+    market_event_queue: &Pubkey,
+    market_bids: &Pubkey,
+    market_asks: &Pubkey,
+
+    referrer_pc_account: Option<&Pubkey>,
+
+    max_lp_amount: u64,
+    amount_out: u64,
+    base_side: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = withdraw(
+        amm_program,
+        amm_pool,
+        amm_authority,
+        amm_open_orders,
+        amm_target_orders,
+        amm_lp_mint,
+        amm_coin_vault,
+        amm_pc_vault,
+        market_program,
+        market,
+        market_coin_vault,
+        market_pc_vault,
+        market_vault_signer,
+        user_token_lp,
+        user_token_coin,
+        user_token_pc,
+        user_owner,
+        market_event_queue,
+        market_bids,
+        market_asks,
+        referrer_pc_account,
+        max_lp_amount,
+        None,
+        None,
+    )?;
+    instruction.data = AmmInstruction::WithdrawBaseOut(WithdrawInstructionBaseOut {
+        max_lp_amount,
+        amount_out,
+        base_side,
+        deadline: None,
+    })
+    .pack()?;
+    Ok(instruction)
+}
+
 /// Creates a 'swap base in' instruction.
 pub fn swap_base_in(
     amm_program: &Pubkey,
@@ -1171,6 +2264,8 @@ pub fn swap_base_in(
     let data = AmmInstruction::SwapBaseIn(SwapInstructionBaseIn {
         amount_in,
         minimum_amount_out,
+        referral_fee_bps: 0,
+        deadline: None,
     })
     .pack()?;
 
@@ -1232,6 +2327,8 @@ pub fn swap_base_out(
     let data = AmmInstruction::SwapBaseOut(SwapInstructionBaseOut {
         max_amount_in,
         amount_out,
+        referral_fee_bps: 0,
+        deadline: None,
     })
     .pack()?;
 
@@ -1267,59 +2364,40 @@ pub fn swap_base_out(
     })
 }
 
-/// Creates a 'migrate_to_openbook' instruction.
-pub fn migrate_to_openbook(
+/// Creates a 'swap base in v2' instruction, for pools without orderbook.
+pub fn swap_base_in_v2(
     amm_program: &Pubkey,
     amm_pool: &Pubkey,
     amm_authority: &Pubkey,
-    amm_open_orders: &Pubkey,
     amm_coin_vault: &Pubkey,
     amm_pc_vault: &Pubkey,
-    amm_target_orders: &Pubkey,
-    market_program: &Pubkey,
-    market: &Pubkey,
-    market_bids: &Pubkey,
-    market_asks: &Pubkey,
-    market_event_queue: &Pubkey,
-    market_coin_vault: &Pubkey,
-    market_pc_vault: &Pubkey,
-    market_vault_signer: &Pubkey,
-
-    new_amm_open_orders: &Pubkey,
-    new_market_program: &Pubkey,
-    new_market: &Pubkey,
+    user_token_source: &Pubkey,
+    user_token_destination: &Pubkey,
+    user_source_owner: &Pubkey,
 
-    admin: &Pubkey,
+    amount_in: u64,
+    minimum_amount_out: u64,
 ) -> Result<Instruction, ProgramError> {
-    let data = AmmInstruction::MigrateToOpenBook.pack()?;
+    let data = AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn {
+        amount_in,
+        minimum_amount_out,
+        referral_fee_bps: 0,
+        deadline: None,
+    })
+    .pack()?;
 
     let accounts = vec![
         // spl token
         AccountMeta::new_readonly(spl_token::id(), false),
-        AccountMeta::new_readonly(solana_program::system_program::id(), false),
-        AccountMeta::new_readonly(sysvar::rent::id(), false),
         // amm
         AccountMeta::new(*amm_pool, false),
         AccountMeta::new_readonly(*amm_authority, false),
-        AccountMeta::new(*amm_open_orders, false),
         AccountMeta::new(*amm_coin_vault, false),
         AccountMeta::new(*amm_pc_vault, false),
-        AccountMeta::new(*amm_target_orders, false),
-        // old market
-        AccountMeta::new_readonly(*market_program, false),
-        AccountMeta::new(*market, false),
-        AccountMeta::new(*market_bids, false),
-        AccountMeta::new(*market_asks, false),
-        AccountMeta::new(*market_event_queue, false),
-        AccountMeta::new(*market_coin_vault, false),
-        AccountMeta::new(*market_pc_vault, false),
-        AccountMeta::new_readonly(*market_vault_signer, false),
-        // new market
-        AccountMeta::new(*new_amm_open_orders, false),
-        AccountMeta::new_readonly(*new_market_program, false),
-        AccountMeta::new_readonly(*new_market, false),
-        // admin
-        AccountMeta::new(*admin, true),
+        // user
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_token_destination, false),
+        AccountMeta::new_readonly(*user_source_owner, true),
     ];
 
     Ok(Instruction {
@@ -1329,34 +2407,539 @@ pub fn migrate_to_openbook(
     })
 }
 
-/// Creates a 'withdrawpnl' instruction
-pub fn withdrawpnl(
+/// Creates a 'swap base out v2' instruction, for pools without orderbook.
+pub fn swap_base_out_v2(
     amm_program: &Pubkey,
     amm_pool: &Pubkey,
-    amm_config: &Pubkey,
     amm_authority: &Pubkey,
-    amm_open_orders: &Pubkey,
     amm_coin_vault: &Pubkey,
     amm_pc_vault: &Pubkey,
-    user_token_coin: &Pubkey,
-    user_token_pc: &Pubkey,
-    user_owner: &Pubkey,
-    amm_target_orders: &Pubkey,
-    market_program: &Pubkey,
-    market: &Pubkey,
-    market_event_queue: &Pubkey,
-    market_coin_vault: &Pubkey,
-    market_pc_vault: &Pubkey,
-    market_vault_signer: &Pubkey,
+    user_token_source: &Pubkey,
+    user_token_destination: &Pubkey,
+    user_source_owner: &Pubkey,
+
+    max_amount_in: u64,
+    amount_out: u64,
 ) -> Result<Instruction, ProgramError> {
-    let data = AmmInstruction::WithdrawPnl.pack()?;
+    let data = AmmInstruction::SwapBaseOutV2(SwapInstructionBaseOut {
+        max_amount_in,
+        amount_out,
+        referral_fee_bps: 0,
+        deadline: None,
+    })
+    .pack()?;
+
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_token_destination, false),
+        AccountMeta::new_readonly(*user_source_owner, true),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'swap base in limit' instruction. Pools with orderbook permission also take
+/// their market accounts, appended with `append_market_accounts`.
+pub fn swap_base_in_limit(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_source: &Pubkey,
+    user_token_destination: &Pubkey,
+    user_source_owner: &Pubkey,
+
+    amount_in: u64,
+    minimum_amount_out: u64,
+    limit_price: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SwapBaseInLimit(SwapInstructionBaseInLimit {
+        amount_in,
+        minimum_amount_out,
+        limit_price,
+        deadline: None,
+    })
+    .pack()?;
+
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_token_destination, false),
+        AccountMeta::new_readonly(*user_source_owner, true),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Accounts of one 'route_swap_base_in' hop
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RouteSwapHop {
+    pub amm_pool: Pubkey,
+    pub amm_authority: Pubkey,
+    pub amm_coin_vault: Pubkey,
+    pub amm_pc_vault: Pubkey,
+    /// receives the hop output, and is the source of the next hop
+    pub user_token_destination: Pubkey,
+    /// only for pools with orderbook permission
+    pub market: Option<MarketAccounts>,
+    /// coin and pc mints, only for pools with a Spl Token 2022 coin or pc vault
+    pub token_2022_mints: Option<(Pubkey, Pubkey)>,
+}
+
+/// Open orders and market accounts of a 'route_swap_base_in' hop or a 'zap_deposit'
+/// through a pool with orderbook permission
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MarketAccounts {
+    pub amm_open_orders: Pubkey,
+    pub market_program: Pubkey,
+    pub market: Pubkey,
+    pub market_bids: Pubkey,
+    pub market_asks: Pubkey,
+    pub market_event_queue: Pubkey,
+    pub market_coin_vault: Pubkey,
+    pub market_pc_vault: Pubkey,
+    pub market_vault_signer: Pubkey,
+}
+
+impl MarketAccounts {
+    fn account_metas(&self) -> [AccountMeta; 9] {
+        [
+            AccountMeta::new(self.amm_open_orders, false),
+            AccountMeta::new_readonly(self.market_program, false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.market_bids, false),
+            AccountMeta::new(self.market_asks, false),
+            AccountMeta::new(self.market_event_queue, false),
+            AccountMeta::new(self.market_coin_vault, false),
+            AccountMeta::new(self.market_pc_vault, false),
+            AccountMeta::new_readonly(self.market_vault_signer, false),
+        ]
+    }
+}
+
+/// Creates a 'route_swap_base_in' instruction.
+pub fn route_swap_base_in(
+    amm_program: &Pubkey,
+    user_source_owner: &Pubkey,
+    user_token_source: &Pubkey,
+    hops: &[RouteSwapHop],
+
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::RouteSwapBaseIn(SwapInstructionBaseIn {
+        amount_in,
+        minimum_amount_out,
+        referral_fee_bps: 0,
+        deadline: None,
+    })
+    .pack()?;
+
+    let mut accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // user
+        AccountMeta::new_readonly(*user_source_owner, true),
+        AccountMeta::new(*user_token_source, false),
+    ];
+    for hop in hops {
+        accounts.extend([
+            AccountMeta::new(hop.amm_pool, false),
+            AccountMeta::new_readonly(hop.amm_authority, false),
+            AccountMeta::new(hop.amm_coin_vault, false),
+            AccountMeta::new(hop.amm_pc_vault, false),
+            AccountMeta::new(hop.user_token_destination, false),
+        ]);
+        if let Some(market) = hop.market {
+            accounts.extend(market.account_metas());
+        }
+        if let Some((amm_coin_mint, amm_pc_mint)) = hop.token_2022_mints {
+            accounts.extend([
+                AccountMeta::new_readonly(spl_token_2022::id(), false),
+                AccountMeta::new_readonly(amm_coin_mint, false),
+                AccountMeta::new_readonly(amm_pc_mint, false),
+            ]);
+        }
+    }
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'flash_loan_begin' instruction.
+pub fn flash_loan_begin(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+
+    coin_amount: u64,
+    pc_amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::FlashLoanBegin(FlashLoanInstruction {
+        coin_amount,
+        pc_amount,
+    })
+    .pack()?;
+
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_coin, false),
+        AccountMeta::new(*user_token_pc, false),
+        // sysvar
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'flash_loan_end' instruction, with the amounts of its 'flash_loan_begin'.
+pub fn flash_loan_end(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+
+    coin_amount: u64,
+    pc_amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::FlashLoanEnd(FlashLoanInstruction {
+        coin_amount,
+        pc_amount,
+    })
+    .pack()?;
+
+    let accounts = vec![
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_coin_vault, false),
+        AccountMeta::new_readonly(*amm_pc_vault, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'migrate_to_openbook' instruction.
+pub fn migrate_to_openbook(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    amm_target_orders: &Pubkey,
+    market_program: &Pubkey,
+    market: &Pubkey,
+    market_bids: &Pubkey,
+    market_asks: &Pubkey,
+    market_event_queue: &Pubkey,
+    market_coin_vault: &Pubkey,
+    market_pc_vault: &Pubkey,
+    market_vault_signer: &Pubkey,
+
+    new_amm_open_orders: &Pubkey,
+    new_market_program: &Pubkey,
+    new_market: &Pubkey,
+
+    admin: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::MigrateToOpenBook.pack()?;
+
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_open_orders, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        AccountMeta::new(*amm_target_orders, false),
+        // old market
+        AccountMeta::new_readonly(*market_program, false),
+        AccountMeta::new(*market, false),
+        AccountMeta::new(*market_bids, false),
+        AccountMeta::new(*market_asks, false),
+        AccountMeta::new(*market_event_queue, false),
+        AccountMeta::new(*market_coin_vault, false),
+        AccountMeta::new(*market_pc_vault, false),
+        AccountMeta::new_readonly(*market_vault_signer, false),
+        // new market
+        AccountMeta::new(*new_amm_open_orders, false),
+        AccountMeta::new_readonly(*new_market_program, false),
+        AccountMeta::new_readonly(*new_market, false),
+        // admin
+        AccountMeta::new(*admin, true),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Appends the trailing accounts required by pools with a Spl Token 2022 coin or pc vault
+/// to a 'deposit', 'zap_deposit', 'withdraw', 'withdraw_base_out', 'zap_withdraw', 'withdrawpnl',
+/// 'swap_base_in', 'swap_base_out', 'swap_base_in_v2', 'swap_base_out_v2', 'swap_base_in_limit',
+/// 'flash_loan_begin', 'simulate_deposit', 'simulate_withdraw', 'simulate_swap_base_in' or
+/// 'simulate_swap_base_out' instruction
+pub fn append_token_2022_accounts(
+    instruction: &mut Instruction,
+    amm_coin_mint: &Pubkey,
+    amm_pc_mint: &Pubkey,
+) {
+    instruction.accounts.extend([
+        AccountMeta::new_readonly(spl_token_2022::id(), false),
+        AccountMeta::new_readonly(*amm_coin_mint, false),
+        AccountMeta::new_readonly(*amm_pc_mint, false),
+    ]);
+}
+
+/// Appends the market accounts required by pools with orderbook permission to a
+/// 'zap_deposit' or 'swap_base_in_limit' instruction, before any observation or Spl Token 2022
+/// trailing accounts
+pub fn append_market_accounts(instruction: &mut Instruction, market: &MarketAccounts) {
+    instruction.accounts.extend(market.account_metas());
+}
+
+/// Sets the referral fee of a 'swap_base_in', 'swap_base_out', 'swap_base_in_v2' or
+/// 'swap_base_out_v2' instruction and appends the referral token account, before any
+/// observation or Spl Token 2022 trailing accounts
+pub fn append_referral_account(
+    instruction: &mut Instruction,
+    referral_token_account: &Pubkey,
+    referral_fee_bps: u16,
+) -> Result<(), ProgramError> {
+    let data = match AmmInstruction::unpack(&instruction.data)? {
+        AmmInstruction::SwapBaseIn(swap) => AmmInstruction::SwapBaseIn(SwapInstructionBaseIn {
+            referral_fee_bps,
+            ..swap
+        }),
+        AmmInstruction::SwapBaseOut(swap) => AmmInstruction::SwapBaseOut(SwapInstructionBaseOut {
+            referral_fee_bps,
+            ..swap
+        }),
+        AmmInstruction::SwapBaseInV2(swap) => AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn {
+            referral_fee_bps,
+            ..swap
+        }),
+        AmmInstruction::SwapBaseOutV2(swap) => {
+            AmmInstruction::SwapBaseOutV2(SwapInstructionBaseOut {
+                referral_fee_bps,
+                ..swap
+            })
+        }
+        _ => return Err(ProgramError::InvalidInstructionData),
+    };
+    instruction.data = data.pack()?;
+    instruction
+        .accounts
+        .push(AccountMeta::new(*referral_token_account, false));
+    Ok(())
+}
+
+/// Sets the unix timestamp after which a 'deposit', 'zap_deposit', 'withdraw',
+/// 'withdraw_base_out', 'zap_withdraw', 'swap_base_in', 'swap_base_out', 'swap_base_in_v2',
+/// 'swap_base_out_v2', 'swap_base_in_limit' or 'route_swap_base_in' instruction fails with
+/// `AmmError::DeadlineExceeded`
+pub fn set_deadline(instruction: &mut Instruction, deadline: u64) -> Result<(), ProgramError> {
+    let deadline = Some(deadline);
+    let data = match AmmInstruction::unpack(&instruction.data)? {
+        AmmInstruction::Deposit(deposit) => AmmInstruction::Deposit(DepositInstruction {
+            deadline,
+            ..deposit
+        }),
+        AmmInstruction::Withdraw(withdraw) => AmmInstruction::Withdraw(WithdrawInstruction {
+            deadline,
+            ..withdraw
+        }),
+        AmmInstruction::SwapBaseIn(swap) => {
+            AmmInstruction::SwapBaseIn(SwapInstructionBaseIn { deadline, ..swap })
+        }
+        AmmInstruction::SwapBaseOut(swap) => {
+            AmmInstruction::SwapBaseOut(SwapInstructionBaseOut { deadline, ..swap })
+        }
+        AmmInstruction::SwapBaseInV2(swap) => {
+            AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn { deadline, ..swap })
+        }
+        AmmInstruction::SwapBaseOutV2(swap) => {
+            AmmInstruction::SwapBaseOutV2(SwapInstructionBaseOut { deadline, ..swap })
+        }
+        AmmInstruction::RouteSwapBaseIn(swap) => {
+            AmmInstruction::RouteSwapBaseIn(SwapInstructionBaseIn { deadline, ..swap })
+        }
+        AmmInstruction::SwapBaseInLimit(swap) => {
+            AmmInstruction::SwapBaseInLimit(SwapInstructionBaseInLimit { deadline, ..swap })
+        }
+        AmmInstruction::ZapDeposit(zap) => {
+            AmmInstruction::ZapDeposit(ZapDepositInstruction { deadline, ..zap })
+        }
+        AmmInstruction::ZapWithdraw(zap) => {
+            AmmInstruction::ZapWithdraw(ZapWithdrawInstruction { deadline, ..zap })
+        }
+        AmmInstruction::WithdrawBaseOut(withdraw) => {
+            AmmInstruction::WithdrawBaseOut(WithdrawInstructionBaseOut {
+                deadline,
+                ..withdraw
+            })
+        }
+        _ => return Err(ProgramError::InvalidInstructionData),
+    };
+    instruction.data = data.pack()?;
+    Ok(())
+}
+
+/// Appends the observation account to a 'swap_base_in', 'swap_base_out', 'swap_base_in_v2',
+/// 'swap_base_out_v2', 'swap_base_in_limit' or 'zap_deposit' instruction, before any Spl Token
+/// 2022 trailing accounts
+pub fn append_observation_account(instruction: &mut Instruction, observation: &Pubkey) {
+    instruction
+        .accounts
+        .push(AccountMeta::new(*observation, false));
+}
+
+/// Creates a 'zap_withdraw' instruction.
+pub fn zap_withdraw(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    market_program: &Pubkey,
+    market: &Pubkey,
+    market_coin_vault: &Pubkey,
+    market_pc_vault: &Pubkey,
+    market_vault_signer: &Pubkey,
+    user_token_lp: &Pubkey,
+    user_token_destination: &Pubkey,
+    user_owner: &Pubkey,
+    market_event_queue: &Pubkey,
+    market_bids: &Pubkey,
+    market_asks: &Pubkey,
+
+    amount: u64,
+    min_amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::ZapWithdraw(ZapWithdrawInstruction {
+        amount,
+        min_amount_out,
+        deadline: None,
+    })
+    .pack()?;
 
     let accounts = vec![
         // spl token
         AccountMeta::new_readonly(spl_token::id(), false),
         // amm
         AccountMeta::new(*amm_pool, false),
-        AccountMeta::new_readonly(*amm_config, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_open_orders, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new(*amm_lp_mint, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // market
+        AccountMeta::new_readonly(*market_program, false),
+        AccountMeta::new(*market, false),
+        AccountMeta::new(*market_coin_vault, false),
+        AccountMeta::new(*market_pc_vault, false),
+        AccountMeta::new_readonly(*market_vault_signer, false),
+        // user
+        AccountMeta::new(*user_token_lp, false),
+        AccountMeta::new(*user_token_destination, false),
+        AccountMeta::new_readonly(*user_owner, true),
+        // market
+        AccountMeta::new(*market_event_queue, false),
+        AccountMeta::new(*market_bids, false),
+        AccountMeta::new(*market_asks, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'withdrawpnl' instruction
+pub fn withdrawpnl(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_config: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+    user_owner: &Pubkey,
+    amm_target_orders: &Pubkey,
+    market_program: &Pubkey,
+    market: &Pubkey,
+    market_event_queue: &Pubkey,
+    market_coin_vault: &Pubkey,
+    market_pc_vault: &Pubkey,
+    market_vault_signer: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::WithdrawPnl.pack()?;
+
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_config, false),
         AccountMeta::new_readonly(*amm_authority, false),
         AccountMeta::new(*amm_open_orders, false),
         AccountMeta::new(*amm_coin_vault, false),
@@ -1365,15 +2948,124 @@ pub fn withdrawpnl(
         AccountMeta::new(*user_token_pc, false),
         AccountMeta::new_readonly(*user_owner, true),
         AccountMeta::new(*amm_target_orders, false),
-        // serum
+        // serum
+        AccountMeta::new_readonly(*market_program, false),
+        AccountMeta::new(*market, false),
+        AccountMeta::new_readonly(*market_event_queue, false),
+        AccountMeta::new(*market_coin_vault, false),
+        AccountMeta::new(*market_pc_vault, false),
+        AccountMeta::new_readonly(*market_vault_signer, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'withdraw_protocol_fee' instruction.
+pub fn withdraw_protocol_fee(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_config: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+    pnl_owner: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::WithdrawProtocolFee.pack()?;
+
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_config, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_coin, false),
+        AccountMeta::new(*user_token_pc, false),
+        AccountMeta::new_readonly(*pnl_owner, true),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'SetParams' instruction.
+pub fn set_params(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    admin: &Pubkey,
+    param: u8,
+    value: Option<u64>,
+    new_pubkey: Option<Pubkey>,
+    amm_target_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    amm_open_orders: &Pubkey,
+    market_program: &Pubkey,
+    market: &Pubkey,
+    market_coin_vault: &Pubkey,
+    market_pc_vault: &Pubkey,
+    market_vault_signer: &Pubkey,
+    market_event_queue: &Pubkey,
+    market_bids: &Pubkey,
+    market_asks: &Pubkey,
+    new_amm_open_orders: Option<Pubkey>,
+    fees: Option<Fees>,
+    last_order_distance: Option<LastOrderDistance>,
+) -> Result<Instruction, ProgramError> {
+    // the protocol fee rate needs the config account, built by `set_protocol_fee_rate`
+    if param == AmmParams::ProtocolFeeRate.into_u64() as u8 {
+        return Err(AmmError::InvalidInput.into());
+    }
+    let data = AmmInstruction::SetParams(SetParamsInstruction {
+        param,
+        value,
+        new_pubkey,
+        fees,
+        last_order_distance,
+    })
+    .pack()?;
+
+    let mut accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_open_orders, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // market
         AccountMeta::new_readonly(*market_program, false),
         AccountMeta::new(*market, false),
-        AccountMeta::new_readonly(*market_event_queue, false),
         AccountMeta::new(*market_coin_vault, false),
         AccountMeta::new(*market_pc_vault, false),
         AccountMeta::new_readonly(*market_vault_signer, false),
+        AccountMeta::new(*market_event_queue, false),
+        AccountMeta::new(*market_bids, false),
+        AccountMeta::new(*market_asks, false),
+        // admin
+        AccountMeta::new_readonly(*admin, true),
     ];
-
+    if param == AmmParams::UpdateOpenOrder.into_u64() as u8 {
+        accounts.push(AccountMeta::new_readonly(
+            new_amm_open_orders.unwrap(),
+            false,
+        ));
+    }
     Ok(Instruction {
         program_id: *amm_program,
         accounts,
@@ -1381,15 +3073,12 @@ pub fn withdrawpnl(
     })
 }
 
-/// Creates a 'SetParams' instruction.
-pub fn set_params(
+/// Creates a 'SetParams' instruction copying the protocol fee rate of the amm config into the pool.
+pub fn set_protocol_fee_rate(
     amm_program: &Pubkey,
     amm_pool: &Pubkey,
     amm_authority: &Pubkey,
     admin: &Pubkey,
-    param: u8,
-    value: Option<u64>,
-    new_pubkey: Option<Pubkey>,
     amm_target_orders: &Pubkey,
     amm_coin_vault: &Pubkey,
     amm_pc_vault: &Pubkey,
@@ -1402,20 +3091,18 @@ pub fn set_params(
     market_event_queue: &Pubkey,
     market_bids: &Pubkey,
     market_asks: &Pubkey,
-    new_amm_open_orders: Option<Pubkey>,
-    fees: Option<Fees>,
-    last_order_distance: Option<LastOrderDistance>,
+    amm_config: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let data = AmmInstruction::SetParams(SetParamsInstruction {
-        param,
-        value,
-        new_pubkey,
-        fees,
-        last_order_distance,
+        param: AmmParams::ProtocolFeeRate.into_u64() as u8,
+        value: None,
+        new_pubkey: None,
+        fees: None,
+        last_order_distance: None,
     })
     .pack()?;
 
-    let mut accounts = vec![
+    let accounts = vec![
         // spl token
         AccountMeta::new_readonly(spl_token::id(), false),
         // amm
@@ -1436,13 +3123,9 @@ pub fn set_params(
         AccountMeta::new(*market_asks, false),
         // admin
         AccountMeta::new_readonly(*admin, true),
+        // config
+        AccountMeta::new_readonly(*amm_config, false),
     ];
-    if param == AmmParams::UpdateOpenOrder.into_u64() as u8 {
-        accounts.push(AccountMeta::new_readonly(
-            new_amm_open_orders.unwrap(),
-            false,
-        ));
-    }
     Ok(Instruction {
         program_id: *amm_program,
         accounts,
@@ -1568,6 +3251,8 @@ pub fn simulate_get_pool_info(
         param: SimulateParams::PoolInfo as u8,
         swap_base_in_value: None,
         swap_base_out_value: None,
+        deposit_value: None,
+        withdraw_value: None,
     })
     .pack()?;
 
@@ -1618,8 +3303,12 @@ pub fn simulate_swap_base_in(
         swap_base_in_value: Some(SwapInstructionBaseIn {
             amount_in,
             minimum_amount_out: 0,
+            referral_fee_bps: 0,
+            deadline: None,
         }),
         swap_base_out_value: None,
+        deposit_value: None,
+        withdraw_value: None,
     })
     .pack()?;
 
@@ -1673,7 +3362,11 @@ pub fn simulate_swap_base_out(
         swap_base_out_value: Some(SwapInstructionBaseOut {
             max_amount_in: 0,
             amount_out,
+            referral_fee_bps: 0,
+            deadline: None,
         }),
+        deposit_value: None,
+        withdraw_value: None,
     })
     .pack()?;
 
@@ -1722,6 +3415,8 @@ pub fn simulate_run_crank(
         param: SimulateParams::RunCrankInfo as u8,
         swap_base_in_value: None,
         swap_base_out_value: None,
+        deposit_value: None,
+        withdraw_value: None,
     })
     .pack()?;
 
@@ -1751,6 +3446,158 @@ pub fn simulate_run_crank(
     })
 }
 
+/// Create a 'simulate_route_swap_base_in' instruction, from the 'simulate_swap_base_in'
+/// instruction of each hop in order, with the Spl Token 2022 accounts appended to the hops
+/// through a Spl Token 2022 vault. Their amounts are ignored.
+pub fn simulate_route_swap_base_in(
+    amm_program: &Pubkey,
+    hops: &[Instruction],
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SimulateInfo(SimulateInstruction {
+        param: SimulateParams::RouteSwapBaseIn as u8,
+        swap_base_in_value: Some(SwapInstructionBaseIn {
+            amount_in,
+            minimum_amount_out,
+            referral_fee_bps: 0,
+            deadline: None,
+        }),
+        swap_base_out_value: None,
+        deposit_value: None,
+        withdraw_value: None,
+    })
+    .pack()?;
+
+    let accounts = hops
+        .iter()
+        .flat_map(|hop| hop.accounts.iter().cloned())
+        .collect();
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Create a 'simulate_deposit' instruction
+pub fn simulate_deposit(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    market: &Pubkey,
+    market_event_queue: &Pubkey,
+    max_coin_amount: u64,
+    max_pc_amount: u64,
+    base_side: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SimulateInfo(SimulateInstruction {
+        param: SimulateParams::DepositInfo as u8,
+        swap_base_in_value: None,
+        swap_base_out_value: None,
+        deposit_value: Some(DepositInstruction {
+            max_coin_amount,
+            max_pc_amount,
+            base_side,
+            ..Default::default()
+        }),
+        withdraw_value: None,
+    })
+    .pack()?;
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts: simulate_liquidity_accounts(
+            amm_pool,
+            amm_authority,
+            amm_open_orders,
+            amm_target_orders,
+            amm_coin_vault,
+            amm_pc_vault,
+            amm_lp_mint,
+            market,
+            market_event_queue,
+        ),
+        data,
+    })
+}
+
+/// Create a 'simulate_withdraw' instruction
+pub fn simulate_withdraw(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    market: &Pubkey,
+    market_event_queue: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SimulateInfo(SimulateInstruction {
+        param: SimulateParams::WithdrawInfo as u8,
+        swap_base_in_value: None,
+        swap_base_out_value: None,
+        deposit_value: None,
+        withdraw_value: Some(WithdrawInstruction {
+            amount,
+            ..Default::default()
+        }),
+    })
+    .pack()?;
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts: simulate_liquidity_accounts(
+            amm_pool,
+            amm_authority,
+            amm_open_orders,
+            amm_target_orders,
+            amm_coin_vault,
+            amm_pc_vault,
+            amm_lp_mint,
+            market,
+            market_event_queue,
+        ),
+        data,
+    })
+}
+
+/// Accounts of the 'simulate_deposit' and 'simulate_withdraw' instructions
+fn simulate_liquidity_accounts(
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    market: &Pubkey,
+    market_event_queue: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        // amm
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new_readonly(*amm_open_orders, false),
+        AccountMeta::new_readonly(*amm_target_orders, false),
+        AccountMeta::new_readonly(*amm_coin_vault, false),
+        AccountMeta::new_readonly(*amm_pc_vault, false),
+        AccountMeta::new_readonly(*amm_lp_mint, false),
+        // market
+        AccountMeta::new_readonly(*market, false),
+        AccountMeta::new_readonly(*market_event_queue, false),
+    ]
+}
+
 pub fn admin_cancel_orders(
     amm_program: &Pubkey,
     amm_pool: &Pubkey,
@@ -1855,3 +3702,569 @@ pub fn update_config_account(
         data,
     })
 }
+
+/// Creates a 'get_twap' instruction.
+pub fn get_twap(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    market: &Pubkey,
+    market_event_queue: &Pubkey,
+    amm_observation: &Pubkey,
+    window: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::GetTwap(GetTwapInstruction { window }).pack()?;
+    let accounts = vec![
+        // amm
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new_readonly(*amm_open_orders, false),
+        AccountMeta::new_readonly(*amm_coin_vault, false),
+        AccountMeta::new_readonly(*amm_pc_vault, false),
+        // market
+        AccountMeta::new_readonly(*market, false),
+        AccountMeta::new_readonly(*market_event_queue, false),
+        // observation
+        AccountMeta::new_readonly(*amm_observation, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an 'initialize_observation' instruction.
+pub fn initialize_observation(
+    amm_program: &Pubkey,
+    payer: &Pubkey,
+    amm_pool: &Pubkey,
+    observation: &Pubkey,
+    capacity: u16,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::InitializeObservation(InitializeObservationInstruction { capacity })
+        .pack()?;
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new(*observation, false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'grow_observation' instruction.
+pub fn grow_observation(
+    amm_program: &Pubkey,
+    payer: &Pubkey,
+    amm_pool: &Pubkey,
+    observation: &Pubkey,
+    capacity: u16,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::GrowObservation(GrowObservationInstruction { capacity }).pack()?;
+    let accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new(*observation, false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'write_observation' instruction.
+pub fn write_observation(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    market: &Pubkey,
+    market_event_queue: &Pubkey,
+    amm_observation: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::WriteObservation.pack()?;
+    let accounts = vec![
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new_readonly(*amm_open_orders, false),
+        AccountMeta::new_readonly(*amm_coin_vault, false),
+        AccountMeta::new_readonly(*amm_pc_vault, false),
+        // market
+        AccountMeta::new_readonly(*market, false),
+        AccountMeta::new_readonly(*market_event_queue, false),
+        // observation
+        AccountMeta::new(*amm_observation, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unpack_optional_deadline() {
+        // data packed before the deadline existed still unpacks
+        let mut data = vec![9u8];
+        data.extend_from_slice(&100u64.to_le_bytes());
+        data.extend_from_slice(&90u64.to_le_bytes());
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap(),
+            AmmInstruction::SwapBaseIn(SwapInstructionBaseIn {
+                amount_in: 100,
+                minimum_amount_out: 90,
+                referral_fee_bps: 0,
+                deadline: None,
+            })
+        );
+        // and the bytes after its fields are still ignored, never read as a referral fee
+        data.extend_from_slice(&[50, 0]);
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap(),
+            AmmInstruction::SwapBaseIn(SwapInstructionBaseIn {
+                amount_in: 100,
+                minimum_amount_out: 90,
+                referral_fee_bps: 0,
+                deadline: None,
+            })
+        );
+        let swap = AmmInstruction::SwapBaseIn(SwapInstructionBaseIn {
+            amount_in: 100,
+            minimum_amount_out: 90,
+            referral_fee_bps: 50,
+            deadline: None,
+        });
+        let data = swap.pack().unwrap();
+        assert_eq!(data[0], 31);
+        assert_eq!(AmmInstruction::unpack(&data).unwrap(), swap);
+
+        let swap = AmmInstruction::SwapBaseOut(SwapInstructionBaseOut {
+            max_amount_in: 100,
+            amount_out: 90,
+            referral_fee_bps: 0,
+            deadline: Some(1_700_000_000),
+        });
+        assert_eq!(AmmInstruction::unpack(&swap.pack().unwrap()).unwrap(), swap);
+
+        // a deadline sent without the referral fee ahead of it is refused, never misread
+        for tag in [22u8, 23, 31, 32] {
+            let mut data = vec![tag];
+            data.extend_from_slice(&100u64.to_le_bytes());
+            data.extend_from_slice(&90u64.to_le_bytes());
+            data.extend_from_slice(&1_700_000_000u64.to_le_bytes());
+            assert_eq!(
+                AmmInstruction::unpack(&data),
+                Err(ProgramError::InvalidInstructionData)
+            );
+        }
+        let swap = AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn {
+            amount_in: 100,
+            minimum_amount_out: 90,
+            referral_fee_bps: 0,
+            deadline: Some(1_700_000_000),
+        });
+        let data = swap.pack().unwrap();
+        assert_eq!(data.len(), 1 + 2 * 8 + 2 + 8);
+        assert_eq!(AmmInstruction::unpack(&data).unwrap(), swap);
+        assert_eq!(
+            AmmInstruction::unpack(&data[..data.len() - 1]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        // the V2 referral fee is optional, the one behind tags 31 and 32 is not
+        assert_eq!(
+            AmmInstruction::unpack(&data[..1 + 2 * 8]).unwrap(),
+            AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn {
+                amount_in: 100,
+                minimum_amount_out: 90,
+                referral_fee_bps: 0,
+                deadline: None,
+            })
+        );
+        let mut data = vec![31u8];
+        data.extend_from_slice(&100u64.to_le_bytes());
+        data.extend_from_slice(&90u64.to_le_bytes());
+        assert_eq!(
+            AmmInstruction::unpack(&data),
+            Err(ProgramError::InvalidInstructionData)
+        );
+
+        let deposit = AmmInstruction::Deposit(DepositInstruction {
+            max_coin_amount: 1,
+            max_pc_amount: 2,
+            base_side: 0,
+            other_amount_min: Some(3),
+            deadline: Some(1_700_000_000),
+            min_lp_out: None,
+        });
+        assert_eq!(
+            AmmInstruction::unpack(&deposit.pack().unwrap()).unwrap(),
+            deposit
+        );
+
+        // an absent deadline ahead of min_lp_out is read back as absent
+        let deposit = AmmInstruction::Deposit(DepositInstruction {
+            max_coin_amount: 1,
+            max_pc_amount: 2,
+            base_side: 0,
+            other_amount_min: Some(3),
+            deadline: None,
+            min_lp_out: Some(4),
+        });
+        assert_eq!(
+            AmmInstruction::unpack(&deposit.pack().unwrap()).unwrap(),
+            deposit
+        );
+
+        // absent slippage bounds ahead of the deadline are read back as absent
+        let withdraw = AmmInstruction::Withdraw(WithdrawInstruction {
+            amount: 1,
+            min_coin_amount: None,
+            min_pc_amount: None,
+            deadline: Some(1_700_000_000),
+        });
+        assert_eq!(
+            AmmInstruction::unpack(&withdraw.pack().unwrap()).unwrap(),
+            withdraw
+        );
+    }
+
+    #[test]
+    fn test_unpack_truncated_deadline() {
+        let mut data = vec![24u8];
+        for value in [100u64, 90, 1 << 63] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        assert!(AmmInstruction::unpack(&data).is_ok());
+        let deadline = 1_700_000_000u64.to_le_bytes();
+        for len in 1..8 {
+            let mut truncated = data.clone();
+            truncated.extend_from_slice(&deadline[..len]);
+            assert_eq!(
+                AmmInstruction::unpack(&truncated),
+                Err(ProgramError::InvalidInstructionData)
+            );
+        }
+        data.extend_from_slice(&deadline);
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap(),
+            AmmInstruction::SwapBaseInLimit(SwapInstructionBaseInLimit {
+                amount_in: 100,
+                minimum_amount_out: 90,
+                limit_price: 1 << 63,
+                deadline: Some(1_700_000_000),
+            })
+        );
+    }
+
+    #[test]
+    fn test_deposit_with_min_lp_out() {
+        let keys = (0..14).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let ix = deposit(
+            &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], &keys[7],
+            &keys[8], &keys[9], &keys[10], &keys[11], &keys[12], &keys[13], 100, 200, 0, None,
+        )
+        .unwrap();
+        // the original data layout, without any optional field
+        assert_eq!(ix.data.len(), 1 + 3 * 8);
+        let ix_min_lp_out = deposit_with_min_lp_out(
+            &keys[0], &keys[1], &keys[2], &keys[3], &keys[4], &keys[5], &keys[6], &keys[7],
+            &keys[8], &keys[9], &keys[10], &keys[11], &keys[12], &keys[13], 100, 200, 0, None, 5,
+        )
+        .unwrap();
+        assert_eq!(ix_min_lp_out.accounts, ix.accounts);
+        assert_eq!(
+            AmmInstruction::unpack(&ix_min_lp_out.data).unwrap(),
+            AmmInstruction::Deposit(DepositInstruction {
+                max_coin_amount: 100,
+                max_pc_amount: 200,
+                base_side: 0,
+                other_amount_min: None,
+                deadline: None,
+                min_lp_out: Some(5),
+            })
+        );
+    }
+
+    #[test]
+    fn test_deposit_withdraw_optional_fields_round_trip() {
+        let values = [None, Some(0), Some(7)];
+        for other_amount_min in values {
+            for deadline in values {
+                for min_lp_out in values {
+                    let deposit = AmmInstruction::Deposit(DepositInstruction {
+                        max_coin_amount: 1,
+                        max_pc_amount: 2,
+                        base_side: 1,
+                        other_amount_min,
+                        deadline,
+                        min_lp_out,
+                    });
+                    assert_eq!(
+                        AmmInstruction::unpack(&deposit.pack().unwrap()).unwrap(),
+                        deposit
+                    );
+                }
+            }
+        }
+        for min_coin_amount in values {
+            for min_pc_amount in values {
+                for deadline in values {
+                    let withdraw = AmmInstruction::Withdraw(WithdrawInstruction {
+                        amount: 1,
+                        min_coin_amount,
+                        min_pc_amount,
+                        deadline,
+                    });
+                    assert_eq!(
+                        AmmInstruction::unpack(&withdraw.pack().unwrap()).unwrap(),
+                        withdraw
+                    );
+                }
+            }
+        }
+
+        // data of clients without the optional fields keeps its layout
+        let deposit = AmmInstruction::Deposit(DepositInstruction {
+            max_coin_amount: 1,
+            max_pc_amount: 2,
+            base_side: 0,
+            other_amount_min: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(deposit.pack().unwrap().len(), 1 + 4 * 8);
+        let withdraw = AmmInstruction::Withdraw(WithdrawInstruction {
+            amount: 1,
+            min_coin_amount: Some(2),
+            min_pc_amount: Some(3),
+            deadline: None,
+        });
+        assert_eq!(withdraw.pack().unwrap().len(), 1 + 3 * 8);
+        // the original tags ignore the bytes after the fields they read
+        let mut data = deposit.pack().unwrap();
+        data.extend_from_slice(&[0b110, 1, 2, 3]);
+        assert_eq!(AmmInstruction::unpack(&data).unwrap(), deposit);
+        let mut data = withdraw.pack().unwrap();
+        data.extend_from_slice(&[0b100; 9]);
+        assert_eq!(AmmInstruction::unpack(&data).unwrap(), withdraw);
+        let mut data = vec![4u8];
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&[0b100; 9]);
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap(),
+            AmmInstruction::Withdraw(WithdrawInstruction {
+                amount: 1,
+                ..Default::default()
+            })
+        );
+        // unknown presence bits are rejected
+        let mut data = vec![30u8];
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.push(0b1000);
+        assert_eq!(
+            AmmInstruction::unpack(&data),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    #[test]
+    fn test_protocol_fee_instructions() {
+        let update = AmmInstruction::UpdateConfigAccount(ConfigArgs {
+            param: 3,
+            owner: None,
+            create_pool_fee: None,
+            protocol_fee_rate: Some(2_000),
+        });
+        let data = update.pack().unwrap();
+        assert_eq!(data.len(), 2 + 8);
+        assert_eq!(AmmInstruction::unpack(&data).unwrap(), update);
+        assert!(AmmInstruction::UpdateConfigAccount(ConfigArgs {
+            param: 3,
+            ..ConfigArgs::default()
+        })
+        .pack()
+        .is_err());
+
+        let withdraw = AmmInstruction::WithdrawProtocolFee;
+        assert_eq!(withdraw.pack().unwrap(), vec![28]);
+        assert_eq!(AmmInstruction::unpack(&[28]).unwrap(), withdraw);
+
+        // the pool takes the config rate from the trailing config account
+        let key = Pubkey::new_unique();
+        let amm_config = Pubkey::new_unique();
+        let ix = set_protocol_fee_rate(
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &amm_config,
+        )
+        .unwrap();
+        assert_eq!(ix.accounts.len(), 17);
+        assert_eq!(ix.accounts[16].pubkey, amm_config);
+        assert!(!ix.accounts[16].is_writable);
+        // the param carries no value
+        let param = AmmParams::ProtocolFeeRate.into_u64() as u8;
+        assert_eq!(ix.data, vec![6, param]);
+        assert_eq!(
+            AmmInstruction::unpack(&ix.data).unwrap(),
+            AmmInstruction::SetParams(SetParamsInstruction {
+                param,
+                ..SetParamsInstruction::default()
+            })
+        );
+        // set_params can't append the config account
+        assert_eq!(
+            set_params(
+                &key, &key, &key, &key, param, None, None, &key, &key, &key, &key, &key, &key,
+                &key, &key, &key, &key, &key, &key, None, None, None,
+            ),
+            Err(AmmError::InvalidInput.into())
+        );
+    }
+
+    #[test]
+    fn test_unknown_simulate_param() {
+        assert_eq!(
+            AmmInstruction::unpack(&[12, 7]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        let simulate = AmmInstruction::SimulateInfo(SimulateInstruction {
+            param: 7,
+            swap_base_in_value: None,
+            swap_base_out_value: None,
+            deposit_value: None,
+            withdraw_value: None,
+        });
+        assert_eq!(simulate.pack(), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn test_route_swap_base_in_accounts() {
+        let amm_program = Pubkey::new_unique();
+        let market = MarketAccounts {
+            amm_open_orders: Pubkey::new_unique(),
+            market_vault_signer: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        let hops = [
+            RouteSwapHop {
+                amm_pool: Pubkey::new_unique(),
+                market: Some(market),
+                ..Default::default()
+            },
+            RouteSwapHop {
+                amm_pool: Pubkey::new_unique(),
+                token_2022_mints: Some((Pubkey::new_unique(), Pubkey::new_unique())),
+                ..Default::default()
+            },
+        ];
+        let ix = route_swap_base_in(
+            &amm_program,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &hops,
+            100,
+            90,
+        )
+        .unwrap();
+        // the orderbook hop carries its market accounts, the other its mints
+        assert_eq!(ix.accounts.len(), 3 + 5 + 9 + 5 + 3);
+        assert_eq!(ix.accounts[3].pubkey, hops[0].amm_pool);
+        assert_eq!(ix.accounts[8].pubkey, market.amm_open_orders);
+        assert!(ix.accounts[8].is_writable);
+        assert_eq!(ix.accounts[16].pubkey, market.market_vault_signer);
+        assert!(!ix.accounts[16].is_writable);
+        assert_eq!(ix.accounts[17].pubkey, hops[1].amm_pool);
+        assert_eq!(ix.accounts[22].pubkey, spl_token_2022::id());
+
+        // a route swap has no referral account to pay
+        let route_swap = AmmInstruction::RouteSwapBaseIn(SwapInstructionBaseIn {
+            amount_in: 100,
+            minimum_amount_out: 90,
+            referral_fee_bps: 50,
+            deadline: None,
+        });
+        assert_eq!(route_swap.pack(), Err(ProgramError::InvalidInstructionData));
+    }
+
+    #[test]
+    fn test_zap_deposit_market_accounts() {
+        let key = Pubkey::new_unique();
+        let mut ix = zap_deposit(
+            &key, &key, &key, &key, &key, &key, &key, &key, &key, &key, 100, 1,
+        )
+        .unwrap();
+        assert_eq!(ix.accounts.len(), 10);
+        let market = MarketAccounts {
+            amm_open_orders: Pubkey::new_unique(),
+            market_vault_signer: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        append_market_accounts(&mut ix, &market);
+        append_token_2022_accounts(&mut ix, &key, &key);
+        // the market accounts follow the user wallet, the token-2022 accounts trail them
+        assert_eq!(ix.accounts.len(), 10 + 9 + 3);
+        assert_eq!(ix.accounts[10].pubkey, market.amm_open_orders);
+        assert_eq!(ix.accounts[18].pubkey, market.market_vault_signer);
+        assert_eq!(ix.accounts[19].pubkey, spl_token_2022::id());
+    }
+
+    #[test]
+    fn test_write_observation() {
+        assert_eq!(AmmInstruction::WriteObservation.pack().unwrap(), vec![33]);
+        assert_eq!(
+            AmmInstruction::unpack(&[33]).unwrap(),
+            AmmInstruction::WriteObservation
+        );
+        let key = Pubkey::new_unique();
+        let (amm_pool, amm_observation) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let ix = write_observation(
+            &key,
+            &amm_pool,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &key,
+            &amm_observation,
+        )
+        .unwrap();
+        // the pool price cumulative and the ring are both written
+        assert_eq!(ix.accounts.len(), 8);
+        assert_eq!(ix.accounts[0].pubkey, amm_pool);
+        assert!(ix.accounts[0].is_writable);
+        assert_eq!(ix.accounts[7].pubkey, amm_observation);
+        assert!(ix.accounts[7].is_writable);
+        assert!(ix.accounts.iter().all(|account| !account.is_signer));
+    }
+}
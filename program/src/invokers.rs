@@ -245,6 +245,65 @@ impl Invokers {
         )
     }
 
+    /// Issue a `TransferChecked` instruction, valid for both spl_token and spl_token_2022.
+    pub fn token_transfer_checked<'a>(
+        token_program: AccountInfo<'a>,
+        source: AccountInfo<'a>,
+        mint: AccountInfo<'a>,
+        destination: AccountInfo<'a>,
+        owner: AccountInfo<'a>,
+        amount: u64,
+        decimals: u8,
+    ) -> Result<(), ProgramError> {
+        let ix = spl_token_2022::instruction::transfer_checked(
+            token_program.key,
+            source.key,
+            mint.key,
+            destination.key,
+            owner.key,
+            &[],
+            amount,
+            decimals,
+        )?;
+        solana_program::program::invoke_signed(
+            &ix,
+            &[source, mint, destination, owner, token_program],
+            &[],
+        )
+    }
+
+    /// Issue a `TransferChecked` instruction signed by the AMM authority (PDA),
+    /// valid for both spl_token and spl_token_2022.
+    pub fn token_transfer_checked_with_authority<'a>(
+        token_program: AccountInfo<'a>,
+        source: AccountInfo<'a>,
+        mint: AccountInfo<'a>,
+        destination: AccountInfo<'a>,
+        authority: AccountInfo<'a>,
+        amm_seed: &[u8],
+        nonce: u8,
+        amount: u64,
+        decimals: u8,
+    ) -> Result<(), ProgramError> {
+        let authority_signature_seeds = [amm_seed, &[nonce]];
+        let signers = &[&authority_signature_seeds[..]];
+        let ix = spl_token_2022::instruction::transfer_checked(
+            token_program.key,
+            source.key,
+            mint.key,
+            destination.key,
+            authority.key,
+            &[],
+            amount,
+            decimals,
+        )?;
+        solana_program::program::invoke_signed(
+            &ix,
+            &[source, mint, destination, authority, token_program],
+            signers,
+        )
+    }
+
     pub fn token_set_authority<'a>(
         token_program: AccountInfo<'a>,
         account: AccountInfo<'a>, // mint or token account
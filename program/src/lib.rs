@@ -22,17 +22,25 @@
 //! - `math`: Mathematical operations for AMM calculations
 //! - `error`: Comprehensive error definitions
 //! - `invokers`: Cross-program invocation utilities
+//! - `cpi`: Typed helpers for other programs invoking the AMM
 //! - `log`: Structured logging and event emission
+//! - `quote`: Off-chain swap quotes from account snapshots (`client` feature)
+//! - `decode`: Account data decoders with a JSON representation (`client` feature)
 
 #[macro_use]
 pub mod log;
 
+pub mod cpi;
+#[cfg(feature = "client")]
+pub mod decode;
 mod entrypoint;
 pub mod error;
 pub mod instruction;
 pub mod invokers;
 pub mod math;
 pub mod processor;
+#[cfg(feature = "client")]
+pub mod quote;
 pub mod state;
 
 // Export current solana-sdk types for downstream users who may also be building with a different solana-sdk version
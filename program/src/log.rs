@@ -11,6 +11,10 @@
 //! - Withdraw: Liquidity removal events
 //! - SwapBaseIn: Token swaps with exact input
 //! - SwapBaseOut: Token swaps with exact output
+//! - RouteSwapBaseIn: Multi-hop token swaps with exact input
+//! - FlashLoan: Vault tokens lent until the end of the transaction
+//! - ZapDeposit: Single sided liquidity provision events
+//! - ZapWithdraw: Single sided liquidity removal events
 //!
 //! All events include relevant pool state and operation parameters
 //! for comprehensive tracking and analytics.
@@ -84,6 +88,14 @@ pub enum LogType {
     SwapBaseIn,
     /// Token swap with exact output amount
     SwapBaseOut,
+    /// Multi-hop token swap with exact input amount
+    RouteSwapBaseIn,
+    /// Flash loan of the pool vaults
+    FlashLoan,
+    /// Single sided liquidity deposit event
+    ZapDeposit,
+    /// Single sided liquidity withdrawal event
+    ZapWithdraw,
 }
 
 impl LogType {
@@ -94,6 +106,10 @@ impl LogType {
             2 => LogType::Withdraw,
             3 => LogType::SwapBaseIn,
             4 => LogType::SwapBaseOut,
+            5 => LogType::RouteSwapBaseIn,
+            6 => LogType::FlashLoan,
+            7 => LogType::ZapDeposit,
+            8 => LogType::ZapWithdraw,
             _ => unreachable!(),
         }
     }
@@ -105,6 +121,10 @@ impl LogType {
             LogType::Withdraw => 2u8,
             LogType::SwapBaseIn => 3u8,
             LogType::SwapBaseOut => 4u8,
+            LogType::RouteSwapBaseIn => 5u8,
+            LogType::FlashLoan => 6u8,
+            LogType::ZapDeposit => 7u8,
+            LogType::ZapWithdraw => 8u8,
         }
     }
 }
@@ -168,6 +188,8 @@ pub struct DepositLog {
     pub deduct_pc: u64,
     /// LP tokens minted to user
     pub mint_lp: u64,
+    /// Owner of the LP token account credited
+    pub recipient: Pubkey,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -186,6 +208,9 @@ pub struct WithdrawLog {
     // calc result
     pub out_coin: u64,
     pub out_pc: u64,
+    // owners of the coin and pc token accounts credited
+    pub coin_recipient: Pubkey,
+    pub pc_recipient: Pubkey,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -202,6 +227,8 @@ pub struct SwapBaseInLog {
     pub pool_pc: u64,
     // calc result
     pub out_amount: u64,
+    // taken from amount_in for the referral account
+    pub referral_fee: u64,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -218,6 +245,85 @@ pub struct SwapBaseOutLog {
     pub pool_pc: u64,
     // calc result
     pub deduct_in: u64,
+    // taken on top of the pool input for the referral account
+    pub referral_fee: u64,
+    // debited from the user source, deduct_in plus the referral fee
+    pub user_deduct_in: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RouteSwapBaseInLog {
+    pub log_type: u8,
+    // input
+    pub amount_in: u64,
+    pub minimum_out: u64,
+    pub hops: u64,
+    // user info
+    pub user_source: u64,
+    // route endpoints
+    pub first_amm: Pubkey,
+    pub last_amm: Pubkey,
+    // calc result, received by the user on the final leg
+    pub out_amount: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlashLoanLog {
+    pub log_type: u8,
+    // input
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    // calc result, repaid on top of the amounts
+    pub coin_fee: u64,
+    pub pc_fee: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ZapDepositLog {
+    pub log_type: u8,
+    // input
+    pub amount_in: u64,
+    pub min_lp: u64,
+    pub direction: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    pub pool_lp: u64,
+    pub calc_pnl_x: u128,
+    pub calc_pnl_y: u128,
+    // calc result, swap_amount is the part of amount_in swapped into swap_out
+    pub swap_amount: u64,
+    pub swap_out: u64,
+    pub mint_lp: u64,
+    // owner of the lp token account credited
+    pub recipient: Pubkey,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ZapWithdrawLog {
+    pub log_type: u8,
+    // input
+    pub withdraw_lp: u64,
+    pub min_out: u64,
+    pub direction: u64,
+    // user info
+    pub user_lp: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    pub pool_lp: u64,
+    pub calc_pnl_x: u128,
+    pub calc_pnl_y: u128,
+    // calc result, the withdrawn side in direction is swapped into swap_out
+    pub out_coin: u64,
+    pub out_pc: u64,
+    pub swap_out: u64,
+    pub out_amount: u64,
+    // owner of the token account credited
+    pub recipient: Pubkey,
 }
 
 /// Encodes and emits a structured log event
@@ -278,5 +384,61 @@ pub fn decode_ray_log(log: &str) {
             let log: SwapBaseOutLog = bincode::deserialize(&bytes).unwrap();
             println!("{:?}", log);
         }
+        LogType::RouteSwapBaseIn => {
+            let log: RouteSwapBaseInLog = bincode::deserialize(&bytes).unwrap();
+            println!("{:?}", log);
+        }
+        LogType::FlashLoan => {
+            let log: FlashLoanLog = bincode::deserialize(&bytes).unwrap();
+            println!("{:?}", log);
+        }
+        LogType::ZapDeposit => {
+            let log: ZapDepositLog = bincode::deserialize(&bytes).unwrap();
+            println!("{:?}", log);
+        }
+        LogType::ZapWithdraw => {
+            let log: ZapWithdrawLog = bincode::deserialize(&bytes).unwrap();
+            println!("{:?}", log);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_swap_base_out_log_layout() {
+        let log = SwapBaseOutLog {
+            log_type: LogType::SwapBaseOut.into_u8(),
+            deduct_in: 1_000,
+            referral_fee: 5,
+            user_deduct_in: 1_005,
+            ..Default::default()
+        };
+        let bytes = bincode::serialize(&log).unwrap();
+        // readers of the earlier layout still find deduct_in, which excludes the referral fee
+        assert_eq!(bytes.len(), 1 + 9 * 8);
+        assert_eq!(bytes[49..57], 1_000u64.to_le_bytes());
+        assert_eq!(bytes[65..73], 1_005u64.to_le_bytes());
+        assert_eq!(bincode::deserialize::<SwapBaseOutLog>(&bytes).unwrap(), log);
+    }
+
+    #[test]
+    fn test_withdraw_log_recipients() {
+        let log = WithdrawLog {
+            log_type: LogType::Withdraw.into_u8(),
+            out_coin: 7,
+            coin_recipient: Pubkey::new_unique(),
+            pc_recipient: Pubkey::new_unique(),
+            ..Default::default()
+        };
+        let bytes = bincode::serialize(&log).unwrap();
+        // the recipients follow the fields of the earlier layout
+        assert_eq!(bytes.len(), 1 + 7 * 8 + 2 * 16 + 2 * 32);
+        assert_eq!(bytes[73..81], 7u64.to_le_bytes());
+        assert_eq!(bytes[89..121], log.coin_recipient.to_bytes());
+        assert_eq!(bytes[121..153], log.pc_recipient.to_bytes());
+        assert_eq!(bincode::deserialize::<WithdrawLog>(&bytes).unwrap(), log);
     }
 }
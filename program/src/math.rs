@@ -17,13 +17,16 @@
 #![allow(clippy::unknown_clippy_lints)]
 #![allow(clippy::manual_range_contains)]
 
-use crate::{error::AmmError, state::AmmInfo};
+use crate::{
+    error::AmmError,
+    state::{AmmInfo, TEN_THOUSAND},
+};
 use num_traits::CheckedDiv;
 use serum_dex::{
     matching::Side,
-    state::{EventView, MarketState, OpenOrders, ToAlignedBytes},
+    state::{Event, EventView, MarketState, OpenOrders, ToAlignedBytes},
 };
-use solana_program::{account_info::AccountInfo, log::sol_log_compute_units, msg};
+use solana_program::{account_info::AccountInfo, log::sol_log_compute_units, msg, pubkey::Pubkey};
 use std::{cmp::Eq, convert::identity, convert::TryInto};
 use uint::construct_uint;
 
@@ -283,12 +286,27 @@ impl Calculator {
         amm_open_account: &'a AccountInfo,
     ) -> Result<(u64, u64), AmmError> {
         let event_q = market_state.load_event_queue_mut(event_q_account).unwrap();
-        let mut native_pc_total = open_orders.native_pc_total;
-        let mut native_coin_total = open_orders.native_coin_total;
         msg!("calc_exact len:{}", event_q.len());
         sol_log_compute_units();
-        for event in event_q.iter() {
-            if identity(event.owner) != (*amm_open_account.key).to_aligned_bytes() {
+        let (native_pc_total, native_coin_total) = Self::apply_serum_fills(
+            open_orders.native_pc_total,
+            open_orders.native_coin_total,
+            event_q.iter(),
+            amm_open_account.key,
+        );
+        sol_log_compute_units();
+        Ok((native_pc_total, native_coin_total))
+    }
+
+    /// Applies the fills of `events` not yet consumed by the event queue to the open orders totals.
+    pub fn apply_serum_fills<'a>(
+        mut native_pc_total: u64,
+        mut native_coin_total: u64,
+        events: impl Iterator<Item = &'a Event>,
+        amm_open_orders: &Pubkey,
+    ) -> (u64, u64) {
+        for event in events {
+            if identity(event.owner) != amm_open_orders.to_aligned_bytes() {
                 continue;
             }
             // msg!("{:?}", event.as_view().unwrap());
@@ -322,8 +340,7 @@ impl Calculator {
                 }
             }
         }
-        sol_log_compute_units();
-        Ok((native_pc_total, native_coin_total))
+        (native_pc_total, native_coin_total)
     }
 
     pub fn calc_total_without_take_pnl<'a>(
@@ -346,11 +363,15 @@ impl Calculator {
             .checked_add(pc_total_in_serum)
             .ok_or(AmmError::CheckedAddOverflow)?
             .checked_sub(amm.state_data.need_take_pnl_pc)
+            .ok_or(AmmError::CheckedSubOverflow)?
+            .checked_sub(amm.state_data.protocol_fee_pc)
             .ok_or(AmmError::CheckedSubOverflow)?;
         let total_coin_without_take_pnl = coin_amount
             .checked_add(coin_total_in_serum)
             .ok_or(AmmError::CheckedAddOverflow)?
             .checked_sub(amm.state_data.need_take_pnl_coin)
+            .ok_or(AmmError::CheckedSubOverflow)?
+            .checked_sub(amm.state_data.protocol_fee_coin)
             .ok_or(AmmError::CheckedSubOverflow)?;
         Ok((total_pc_without_take_pnl, total_coin_without_take_pnl))
     }
@@ -362,9 +383,13 @@ impl Calculator {
     ) -> Result<(u64, u64), AmmError> {
         let total_pc_without_take_pnl = pc_amount
             .checked_sub(amm.state_data.need_take_pnl_pc)
+            .ok_or(AmmError::CheckedSubOverflow)?
+            .checked_sub(amm.state_data.protocol_fee_pc)
             .ok_or(AmmError::CheckedSubOverflow)?;
         let total_coin_without_take_pnl = coin_amount
             .checked_sub(amm.state_data.need_take_pnl_coin)
+            .ok_or(AmmError::CheckedSubOverflow)?
+            .checked_sub(amm.state_data.protocol_fee_coin)
             .ok_or(AmmError::CheckedSubOverflow)?;
         Ok((total_pc_without_take_pnl, total_coin_without_take_pnl))
     }
@@ -458,6 +483,182 @@ impl Calculator {
         return amount_out;
     }
 
+    /// Largest amount in, swap fee included, the pool can take before its price crosses
+    /// `limit_price`, returns zero when the price is already at or beyond it.
+    /// The swap fee stays in the pool, so the whole amount in counts in the post trade price
+    /// while only the part after the fee moves the curve.
+    /// `limit_price` is pc per coin normalized like the orderbook prices,
+    /// i.e. `x * sys_decimal_value / y` of the normalized pool amounts.
+    pub fn swap_amount_in_to_price(
+        limit_price: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        amm: &AmmInfo,
+        swap_fee_numerator: u64,
+        swap_direction: SwapDirection,
+    ) -> u64 {
+        // price = pc * sys_decimal_value * 10^coin_decimals / (coin * 10^pc_decimals)
+        // => at the limit, pc / coin = limit_price * 10^pc_decimals / (sys_decimal_value * 10^coin_decimals)
+        let k = U256::from(total_pc_without_take_pnl) * U256::from(total_coin_without_take_pnl);
+        let pc_scale = U256::from(limit_price) * U256::from(10).pow(amm.pc_decimals.into());
+        let coin_scale =
+            U256::from(amm.sys_decimal_value) * U256::from(10).pow(amm.coin_decimals.into());
+        if pc_scale.is_zero() || swap_fee_numerator >= amm.fees.swap_fee_denominator {
+            return 0;
+        }
+        // with r the reserve in, g the amount in and f = n / d, the curve takes g * (1 - f)
+        // so the reserve out becomes k / (r + g * (1 - f)) while the reserve in becomes r + g
+        let (r, t) = match swap_direction {
+            // selling coin lowers the price, pc' / coin' >= limit
+            // => (r + g * (1 - f)) * (r + g) <= k * coin_scale / pc_scale
+            SwapDirection::Coin2PC => (
+                U256::from(total_coin_without_take_pnl),
+                k * coin_scale / pc_scale,
+            ),
+            // buying coin raises the price, pc' / coin' <= limit
+            // => (r + g * (1 - f)) * (r + g) <= k * pc_scale / coin_scale
+            SwapDirection::PC2Coin => (
+                U256::from(total_pc_without_take_pnl),
+                k * pc_scale / coin_scale,
+            ),
+        };
+        if t <= r * r {
+            return 0;
+        }
+        // => (d - n) * g^2 + (2d - n) * r * g - d * (t - r^2) <= 0
+        // => g <= (sqrt(r^2 * (2d - n)^2 + 4 * (d - n) * d * (t - r^2)) - r * (2d - n)) / (2 * (d - n))
+        let n = U256::from(swap_fee_numerator);
+        let d = U256::from(amm.fees.swap_fee_denominator);
+        let b = r * (d + d - n);
+        let discriminant = b * b + U256::from(4) * (d - n) * d * (t - r * r);
+        let amount_in = (discriminant.integer_sqrt() - b) / ((d - n) * U256::from(2));
+        Self::to_u64(amount_in.min(u64::MAX.into()).as_u128()).unwrap()
+    }
+
+    /// Part of a single sided deposit to swap so that the rest and the swap output are
+    /// in the pool ratio after the swap, the swap fee included.
+    pub fn zap_swap_amount(
+        amount_in: u64,
+        reserve_in: u64,
+        swap_fee_numerator: u64,
+        swap_fee_denominator: u64,
+    ) -> Result<u64, AmmError> {
+        // with f = n / d, solving (a - s) / (r + s) = (1 - f) * s / r for s
+        // => (1 - f) * s^2 + (2 - f) * r * s - a * r = 0
+        // => s = (sqrt(r^2 * (2d - n)^2 + 4 * (d - n) * d * a * r) - r * (2d - n)) / (2 * (d - n))
+        if amount_in == 0 || swap_fee_numerator >= swap_fee_denominator {
+            return Ok(0);
+        }
+        let a = U256::from(amount_in);
+        let r = U256::from(reserve_in);
+        let n = U256::from(swap_fee_numerator);
+        let d = U256::from(swap_fee_denominator);
+        let b = r * (d + d - n);
+        let discriminant = b * b + U256::from(4) * (d - n) * d * a * r;
+        let swap_amount = (discriminant.integer_sqrt() - b) / ((d - n) * U256::from(2));
+        Self::to_u64(swap_amount.min(a).as_u128())
+    }
+
+    /// Swap fee charged on `amount_in`, rounded up.
+    pub fn swap_fee_amount_in(
+        amount_in: u64,
+        swap_fee_numerator: u64,
+        swap_fee_denominator: u64,
+    ) -> U128 {
+        U128::from(amount_in)
+            .checked_mul(swap_fee_numerator.into())
+            .unwrap()
+            .checked_ceil_div(swap_fee_denominator.into())
+            .unwrap()
+            .0
+    }
+
+    /// Amount in that leaves `swap_in_before_add_fee` once the swap fee is charged, rounded up.
+    pub fn swap_amount_in_add_fee(
+        swap_in_before_add_fee: U128,
+        swap_fee_numerator: u64,
+        swap_fee_denominator: u64,
+    ) -> u64 {
+        swap_in_before_add_fee
+            .checked_mul(swap_fee_denominator.into())
+            .unwrap()
+            .checked_ceil_div(
+                (swap_fee_denominator
+                    .checked_sub(swap_fee_numerator)
+                    .unwrap())
+                .into(),
+            )
+            .unwrap()
+            .0
+            .as_u64()
+    }
+
+    /// Pool price of one coin in pc, decimal adjusted and scaled by `sys_decimal_value`.
+    pub fn spot_price(
+        total_pc: u64,
+        total_coin: u64,
+        pc_decimals: u64,
+        coin_decimals: u64,
+        sys_decimal_value: u64,
+    ) -> u64 {
+        if total_coin == 0 {
+            return 0;
+        }
+        let price = U256::from(total_pc)
+            * U256::from(10).pow(coin_decimals.into())
+            * U256::from(sys_decimal_value)
+            / (U256::from(total_coin) * U256::from(10).pow(pc_decimals.into()));
+        if price > U256::from(u64::MAX) {
+            u64::MAX
+        } else {
+            price.as_u64()
+        }
+    }
+
+    /// Q64.64 price of the raw pc per coin amounts, decimal adjusted and scaled by
+    /// `sys_decimal_value` like `spot_price`.
+    pub fn q64_price_to_spot_price(
+        q64_price: u128,
+        pc_decimals: u64,
+        coin_decimals: u64,
+        sys_decimal_value: u64,
+    ) -> u64 {
+        let price = U256::from(q64_price)
+            .checked_mul(U256::from(10).pow(coin_decimals.into()))
+            .and_then(|price| price.checked_mul(U256::from(sys_decimal_value)))
+            .map(|price| (price / U256::from(10).pow(pc_decimals.into())) >> 64);
+        match price {
+            Some(price) if price <= U256::from(u64::MAX) => price.as_u64(),
+            _ => u64::MAX,
+        }
+    }
+
+    /// Relative move of the pc per coin price between two pool states, in basis points.
+    pub fn price_move_bps(
+        total_pc_before: u64,
+        total_coin_before: u64,
+        total_pc_after: u64,
+        total_coin_after: u64,
+    ) -> u64 {
+        // |pc_after / coin_after - pc_before / coin_before| / (pc_before / coin_before)
+        let after = U256::from(total_pc_after) * U256::from(total_coin_before);
+        let before = U256::from(total_pc_before) * U256::from(total_coin_after);
+        if before.is_zero() {
+            return 0;
+        }
+        let diff = if after > before {
+            after - before
+        } else {
+            before - after
+        };
+        let bps = diff * U256::from(TEN_THOUSAND) / before;
+        if bps > U256::from(u64::MAX) {
+            u64::MAX
+        } else {
+            bps.as_u64()
+        }
+    }
+
     pub fn swap_token_amount_base_out(
         amount_out: U128,
         total_pc_without_take_pnl: U128,
@@ -16,6 +16,14 @@
 //! ## Trading Operations
 //! - `process_swap_base_in`: Executes swaps with exact input amounts
 //! - `process_swap_base_out`: Executes swaps with exact output amounts
+//! - `process_route_swap_base_in`: Executes exact input swaps across several pools
+//! - `process_flash_loan_begin`, `process_flash_loan_end`: Lend the vaults within one transaction
+//!
+//! ## Price Oracle
+//! - `process_get_twap`: Reads the time weighted average pool price
+//! - `process_initialize_observation`, `process_grow_observation`: Manage the
+//!   observation ring buffer written by swaps
+//! - `process_write_observation`: Writes the pool price to the observation ring, by anyone
 //!
 //! ## Order Book Integration
 //! - `process_monitor_step`: Manages OpenBook order placement and lifecycle
@@ -35,8 +43,12 @@ use crate::{
     error::AmmError,
     instruction::{
         AdminCancelOrdersInstruction, AmmInstruction, ConfigArgs, DepositInstruction,
-        InitializeInstruction2, MonitorStepInstruction, SetParamsInstruction, SimulateInstruction,
-        SwapInstructionBaseIn, SwapInstructionBaseOut, WithdrawInstruction, WithdrawSrmInstruction,
+        FlashLoanInstruction, GetTwapInstruction, GrowObservationInstruction,
+        InitializeInstruction2, InitializeObservationInstruction, MonitorStepInstruction,
+        SetParamsInstruction, SimulateInstruction, SwapInstructionBaseIn,
+        SwapInstructionBaseInLimit, SwapInstructionBaseOut, WithdrawInstruction,
+        WithdrawInstructionBaseOut, WithdrawSrmInstruction, ZapDepositInstruction,
+        ZapWithdrawInstruction,
     },
     invokers::Invokers,
     math::{
@@ -44,9 +56,11 @@ use crate::{
         U128, U256,
     },
     state::{
-        AmmConfig, AmmInfo, AmmParams, AmmResetFlag, AmmState, AmmStatus, GetPoolData,
-        GetSwapBaseInData, GetSwapBaseOutData, Loadable, RunCrankData, SimulateParams,
-        TargetOrders, MAX_ORDER_LIMIT, TEN_THOUSAND,
+        AmmConfig, AmmInfo, AmmParams, AmmResetFlag, AmmReturnData, AmmState, AmmStatus,
+        DepositReturnData, GetDepositData, GetPoolData, GetRouteSwapData, GetRouteSwapHopData,
+        GetSwapBaseInData, GetSwapBaseOutData, GetTwapData, GetWithdrawData, Loadable, Observation,
+        ObservationState, ObservationStateRef, RunCrankData, SimulateParams, SimulateReturnData,
+        SwapReturnData, TargetOrders, WithdrawReturnData, MAX_ORDER_LIMIT, TEN_THOUSAND,
     },
 };
 
@@ -58,9 +72,9 @@ use serum_dex::{
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     clock::Clock,
-    entrypoint::ProgramResult,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
     msg,
-    program::{invoke, invoke_signed},
+    program::{invoke, invoke_signed, set_return_data},
     // log::sol_log_compute_units,
     program_error::ProgramError,
     program_option::COption,
@@ -68,7 +82,15 @@ use solana_program::{
     pubkey::Pubkey,
     rent::Rent,
     system_instruction,
-    sysvar::{clock, Sysvar},
+    sysvar::{
+        clock,
+        instructions::{self, load_current_index_checked, load_instruction_at_checked},
+        Sysvar,
+    },
+};
+
+use spl_token_2022::extension::{
+    transfer_fee::TransferFeeConfig, BaseStateWithExtensions, ExtensionType, StateWithExtensions,
 };
 
 use super::log::*;
@@ -154,6 +176,8 @@ pub const PC_VAULT_ASSOCIATED_SEED: &'static [u8] = b"pc_vault_associated_seed";
 pub const LP_MINT_ASSOCIATED_SEED: &'static [u8] = b"lp_mint_associated_seed";
 /// Amm config seed
 pub const AMM_CONFIG_SEED: &'static [u8] = b"amm_config_account_seed";
+/// Suffix for observation associated seed
+pub const OBSERVATION_ASSOCIATED_SEED: &'static [u8] = b"observation_associated_seed";
 
 pub fn get_associated_address_and_bump_seed(
     info_id: &Pubkey,
@@ -171,6 +195,160 @@ pub fn get_associated_address_and_bump_seed(
     )
 }
 
+/// Highest integrator fee a swap may pay to its referral token account, in basis points
+pub const MAX_REFERRAL_FEE_BPS: u16 = 300;
+
+/// Number of optional trailing accounts `[spl_token_2022, coin_mint, pc_mint]`
+pub const TOKEN_2022_ACCOUNT_LEN: usize = 3;
+
+/// Number of accounts of a simulate_swap_base_in or simulate_swap_base_out, before the Spl Token
+/// 2022 accounts
+pub const SIMULATE_SWAP_ACCOUNT_LEN: usize = 13;
+
+/// Trailing accounts required to move the tokens of a token-2022 pool vault
+#[derive(Clone, Copy)]
+pub struct Token2022Accounts<'a, 'b: 'a> {
+    pub token_program: &'a AccountInfo<'b>,
+    pub coin_mint: &'a AccountInfo<'b>,
+    pub pc_mint: &'a AccountInfo<'b>,
+}
+
+/// Token program, mint and decimals used to move the tokens of one pool vault
+pub struct VaultToken<'a, 'b: 'a> {
+    pub token_program: &'a AccountInfo<'b>,
+    /// Only set for token-2022 vaults, which are moved with `transfer_checked`
+    pub mint: Option<&'a AccountInfo<'b>>,
+    pub decimals: u8,
+}
+
+/// Amount fixed by a swap
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapAmount {
+    /// exact amount debited from the user, referral fee included
+    BaseIn(u64),
+    /// exact amount credited to the user
+    BaseOut(u64),
+}
+
+/// Amounts moved by a swap, computed by `Processor::swap_amounts`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SwapAmounts {
+    /// debited from the user, referral fee included
+    pub user_amount_in: u64,
+    /// transferred to the source vault
+    pub amount_in: u64,
+    /// reaches the source vault, swap fee included
+    pub amount_in_received: u64,
+    /// transferred from the destination vault
+    pub amount_out: u64,
+    /// reaches the user destination
+    pub amount_out_received: u64,
+    /// charged in the source token, out of amount_in_received
+    pub swap_fee: u64,
+    /// charged in the source token, transferred to the referral apart from amount_in
+    pub referral_fee: u64,
+}
+
+/// Amounts moved by a deposit, computed by `Processor::deposit_amounts`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DepositAmounts {
+    /// debited from the user
+    pub deduct_coin_amount: u64,
+    pub deduct_pc_amount: u64,
+    /// reach the vaults, after the token-2022 transfer fees
+    pub coin_amount_received: u64,
+    pub pc_amount_received: u64,
+    pub mint_lp_amount: u64,
+}
+
+/// Amounts of a zap withdraw, computed by `Processor::zap_withdraw_amounts`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ZapWithdrawAmounts {
+    /// pool the swap leg trades against
+    pub pool_pc_after_withdraw: u64,
+    pub pool_coin_after_withdraw: u64,
+    /// swap of the withdrawn side the user doesn't take
+    pub swap: SwapAmounts,
+    /// transferred from the destination vault, the withdrawn side plus the swap output
+    pub amount_out: u64,
+    /// reaches the user destination
+    pub amount_out_received: u64,
+}
+
+/// What a withdraw pays out of the vaults
+#[derive(Clone, Copy)]
+enum WithdrawOut {
+    /// coin and pc at the pool ratio
+    Both,
+    /// exactly amount_out of the base side, the other side at the pool ratio
+    BaseOut(WithdrawInstructionBaseOut),
+    /// a single side, the other side being swapped into it through the pool
+    Zap { min_amount_out: u64 },
+}
+
+/// Number of accounts of each `RouteSwapBaseIn` hop, before its optional market and
+/// token-2022 accounts
+pub const ROUTE_HOP_ACCOUNT_LEN: usize = 5;
+
+/// Number of market accounts of a `RouteSwapBaseIn` hop or a `ZapDeposit` through an orderbook
+/// pool, starting with the amm open orders
+pub const SWAP_MARKET_ACCOUNT_LEN: usize = 9;
+
+/// Open orders and market accounts of a `RouteSwapBaseIn` hop or a `ZapDeposit` through an
+/// orderbook pool
+#[derive(Clone, Copy)]
+pub struct SwapMarketAccounts<'a, 'b: 'a> {
+    pub amm_open_orders_info: &'a AccountInfo<'b>,
+    pub market_program_info: &'a AccountInfo<'b>,
+    pub market_info: &'a AccountInfo<'b>,
+    pub market_bids_info: &'a AccountInfo<'b>,
+    pub market_asks_info: &'a AccountInfo<'b>,
+    pub market_event_queue_info: &'a AccountInfo<'b>,
+    pub market_coin_vault_info: &'a AccountInfo<'b>,
+    pub market_pc_vault_info: &'a AccountInfo<'b>,
+    pub market_vault_signer: &'a AccountInfo<'b>,
+}
+
+/// Accounts of one `RouteSwapBaseIn` hop
+pub struct RouteHopAccounts<'a, 'b: 'a> {
+    pub amm_info: &'a AccountInfo<'b>,
+    pub amm_authority_info: &'a AccountInfo<'b>,
+    pub amm_coin_vault_info: &'a AccountInfo<'b>,
+    pub amm_pc_vault_info: &'a AccountInfo<'b>,
+    /// receives the hop output, and is the source of the next hop
+    pub user_destination_info: &'a AccountInfo<'b>,
+    /// only for a pool with orderbook permission
+    pub market_accounts: Option<SwapMarketAccounts<'a, 'b>>,
+    pub token_2022_accounts: Option<Token2022Accounts<'a, 'b>>,
+}
+
+/// Accounts of a swap against one pool, run by `Processor::process_swap`
+pub struct SwapAccounts<'a, 'b: 'a> {
+    pub token_program_info: &'a AccountInfo<'b>,
+    pub amm_info: &'a AccountInfo<'b>,
+    pub amm_authority_info: &'a AccountInfo<'b>,
+    pub amm_coin_vault_info: &'a AccountInfo<'b>,
+    pub amm_pc_vault_info: &'a AccountInfo<'b>,
+    pub user_source_info: &'a AccountInfo<'b>,
+    pub user_destination_info: &'a AccountInfo<'b>,
+    pub user_source_owner: &'a AccountInfo<'b>,
+    /// only read for a pool with orderbook permission
+    pub market_accounts: Option<SwapMarketAccounts<'a, 'b>>,
+    pub token_2022_accounts: Option<Token2022Accounts<'a, 'b>>,
+    pub observation_info: Option<&'a AccountInfo<'b>>,
+    pub referral_info: Option<&'a AccountInfo<'b>>,
+}
+
+/// Swap run by `Processor::process_swap`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapRequest {
+    BaseIn(SwapInstructionBaseIn),
+    BaseOut(SwapInstructionBaseOut),
+    BaseInLimit(SwapInstructionBaseInLimit),
+    /// a RouteSwapBaseIn hop spending an exact input, logged and slippage checked by the route
+    RouteHop(u64),
+}
+
 /// Program state handler.
 pub struct Processor {}
 impl Processor {
@@ -182,7 +360,18 @@ impl Processor {
         return Ok(());
     }
 
+    /// Returns the owner of a token account or mint, which must be spl_token or spl_token_2022.
+    #[inline]
+    pub fn token_program_id<'a>(account_info: &AccountInfo<'a>) -> Result<&'a Pubkey, AmmError> {
+        if *account_info.owner == spl_token::id() || *account_info.owner == spl_token_2022::id() {
+            Ok(account_info.owner)
+        } else {
+            Err(AmmError::InvalidSplTokenProgram)
+        }
+    }
+
     /// Unpacks a spl_token `Account`.
+    /// Token-2022 accounts are checked with their extensions and read through the base state.
     #[inline]
     pub fn unpack_token_account(
         account_info: &AccountInfo,
@@ -190,6 +379,12 @@ impl Processor {
     ) -> Result<spl_token::state::Account, AmmError> {
         if account_info.owner != token_program_id {
             Err(AmmError::InvalidSplTokenProgram)
+        } else if *token_program_id == spl_token_2022::id() {
+            let data = account_info.data.borrow();
+            StateWithExtensions::<spl_token_2022::state::Account>::unpack(&data)
+                .map_err(|_| AmmError::ExpectedAccount)?;
+            spl_token::state::Account::unpack_from_slice(&data[..spl_token::state::Account::LEN])
+                .map_err(|_| AmmError::ExpectedAccount)
         } else {
             spl_token::state::Account::unpack(&account_info.data.borrow())
                 .map_err(|_| AmmError::ExpectedAccount)
@@ -197,6 +392,7 @@ impl Processor {
     }
 
     /// Unpacks a spl_token `Mint`.
+    /// Token-2022 mints are rejected if they carry an unsupported extension.
     #[inline]
     pub fn unpack_mint(
         account_info: &AccountInfo,
@@ -204,12 +400,445 @@ impl Processor {
     ) -> Result<spl_token::state::Mint, AmmError> {
         if account_info.owner != token_program_id {
             Err(AmmError::InvalidSplTokenProgram)
+        } else if *token_program_id == spl_token_2022::id() {
+            let data = account_info.data.borrow();
+            let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)
+                .map_err(|_| AmmError::ExpectedMint)?;
+            let extensions = mint
+                .get_extension_types()
+                .map_err(|_| AmmError::ExpectedMint)?;
+            if !extensions.iter().all(Self::is_supported_mint_extension) {
+                return Err(AmmError::UnsupportedMintExtension);
+            }
+            spl_token::state::Mint::unpack_from_slice(&data[..spl_token::state::Mint::LEN])
+                .map_err(|_| AmmError::ExpectedMint)
         } else {
             spl_token::state::Mint::unpack(&account_info.data.borrow())
                 .map_err(|_| AmmError::ExpectedMint)
         }
     }
 
+    /// Token-2022 mint extensions the pool supports.
+    /// Transfer fees are accounted for on the amounts moved in and out of the vaults.
+    /// The pool prices raw amounts, so an interest bearing mint isn't supported.
+    fn is_supported_mint_extension(extension: &ExtensionType) -> bool {
+        matches!(
+            extension,
+            ExtensionType::MetadataPointer
+                | ExtensionType::TokenMetadata
+                | ExtensionType::GroupPointer
+                | ExtensionType::TokenGroup
+                | ExtensionType::GroupMemberPointer
+                | ExtensionType::TokenGroupMember
+                | ExtensionType::TransferFeeConfig
+        )
+    }
+
+    /// Splits the optional trailing `[spl_token_2022, coin_mint, pc_mint]` accounts off the
+    /// instruction accounts.
+    fn split_token_2022_accounts<'a, 'b: 'a>(
+        accounts: &'a [AccountInfo<'b>],
+    ) -> (&'a [AccountInfo<'b>], Option<Token2022Accounts<'a, 'b>>) {
+        let len = accounts.len();
+        if len >= TOKEN_2022_ACCOUNT_LEN
+            && *accounts[len - TOKEN_2022_ACCOUNT_LEN].key == spl_token_2022::id()
+        {
+            let (accounts, token_2022_accounts) = accounts.split_at(len - TOKEN_2022_ACCOUNT_LEN);
+            (
+                accounts,
+                Some(Token2022Accounts {
+                    token_program: &token_2022_accounts[0],
+                    coin_mint: &token_2022_accounts[1],
+                    pc_mint: &token_2022_accounts[2],
+                }),
+            )
+        } else {
+            (accounts, None)
+        }
+    }
+
+    /// Splits the optional trailing observation account off the swap accounts,
+    /// recognized by being owned by the amm program. Its address is checked
+    /// against the amm when the observation is written.
+    fn split_observation_account<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> (&'a [AccountInfo<'b>], Option<&'a AccountInfo<'b>>) {
+        match accounts.split_last() {
+            Some((last, accounts)) if last.owner == program_id => (accounts, Some(last)),
+            _ => (accounts, None),
+        }
+    }
+
+    /// Fails unless the observation account is the observation PDA of the amm.
+    fn check_observation_address(
+        program_id: &Pubkey,
+        amm_key: &Pubkey,
+        observation_info: &AccountInfo,
+    ) -> ProgramResult {
+        let (observation_address, _) = get_associated_address_and_bump_seed(
+            program_id,
+            amm_key,
+            OBSERVATION_ASSOCIATED_SEED,
+            program_id,
+        );
+        check_assert_eq!(
+            *observation_info.key,
+            observation_address,
+            "observation",
+            AmmError::InvalidObservationAccount
+        );
+        Ok(())
+    }
+
+    /// Fails once the clock is past the deadline of the instruction, if it has one.
+    fn check_deadline(deadline: Option<u64>) -> ProgramResult {
+        if let Some(deadline) = deadline {
+            // a deadline past i64::MAX never expires
+            if let Ok(deadline) = i64::try_from(deadline) {
+                if Clock::get()?.unix_timestamp > deadline {
+                    return Err(AmmError::DeadlineExceeded.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails when a withdraw pays out less than any of the minimums it was sent with.
+    fn check_withdraw_slippage(
+        withdraw: &WithdrawInstruction,
+        coin_amount: u64,
+        pc_amount: u64,
+    ) -> ProgramResult {
+        if let Some(min_coin_amount) = withdraw.min_coin_amount {
+            if min_coin_amount > coin_amount {
+                return Err(AmmError::ExceededSlippage.into());
+            }
+        }
+        if let Some(min_pc_amount) = withdraw.min_pc_amount {
+            if min_pc_amount > pc_amount {
+                return Err(AmmError::ExceededSlippage.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the referral token account off the swap accounts when the swap pays a referral fee.
+    fn split_referral_account<'a, 'b: 'a>(
+        accounts: &'a [AccountInfo<'b>],
+        referral_fee_bps: u16,
+    ) -> Result<(&'a [AccountInfo<'b>], Option<&'a AccountInfo<'b>>), ProgramError> {
+        if referral_fee_bps == 0 {
+            return Ok((accounts, None));
+        }
+        if referral_fee_bps > MAX_REFERRAL_FEE_BPS {
+            return Err(AmmError::InvalidInput.into());
+        }
+        match accounts.split_last() {
+            Some((last, accounts)) => Ok((accounts, Some(last))),
+            None => Err(AmmError::WrongAccountsNumber.into()),
+        }
+    }
+
+    /// Checks the referral token account holds the swap source mint and is not a pool vault.
+    fn check_referral_account(
+        amm: &AmmInfo,
+        referral_info: Option<&AccountInfo>,
+        source_mint: &Pubkey,
+    ) -> ProgramResult {
+        let referral_info = match referral_info {
+            Some(referral_info) => referral_info,
+            None => return Ok(()),
+        };
+        if *referral_info.key == amm.pc_vault || *referral_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        let referral =
+            Self::unpack_token_account(referral_info, Self::token_program_id(referral_info)?)?;
+        if referral.mint != *source_mint {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        Ok(())
+    }
+
+    /// Referral fee of `amount`, rounded down.
+    pub fn referral_fee(amount: u64, referral_fee_bps: u16) -> u64 {
+        (amount as u128 * referral_fee_bps as u128 / TEN_THOUSAND as u128) as u64
+    }
+
+    /// Writes the pool price cumulatives and liquidity to the observation account, if given.
+    fn write_observation(
+        program_id: &Pubkey,
+        amm_info: &AccountInfo,
+        amm: &AmmInfo,
+        observation_info: Option<&AccountInfo>,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+    ) -> ProgramResult {
+        let observation_info = match observation_info {
+            Some(observation_info) => observation_info,
+            None => return Ok(()),
+        };
+        Self::check_observation_address(program_id, amm_info.key, observation_info)?;
+        let ObservationState {
+            mut header,
+            mut observations,
+        } = ObservationState::load_mut_checked(observation_info, program_id, amm_info.key)?;
+        let liquidity = Calculator::to_u64(
+            U128::from(total_pc_without_take_pnl)
+                .checked_mul(total_coin_without_take_pnl.into())
+                .unwrap()
+                .integer_sqrt()
+                .as_u128(),
+        )?;
+        header.write(
+            &mut observations,
+            Observation {
+                timestamp: amm.price_cumulative_time,
+                coin_price_cumulative: amm.coin_price_cumulative,
+                liquidity,
+            },
+        );
+        Ok(())
+    }
+
+    /// Openbook settles spl_token vaults only, a pool with orderbook permission can't hold a
+    /// token-2022 vault.
+    pub fn check_orderbook_vault_program(
+        status: AmmStatus,
+        vault_program_id: &Pubkey,
+    ) -> ProgramResult {
+        if status.orderbook_permission() && *vault_program_id != spl_token::id() {
+            msg!(&format!(
+                "orderbook status {} with a token-2022 vault",
+                status.into_u64()
+            ));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        Ok(())
+    }
+
+    /// Resolves how the coin vault tokens are moved.
+    fn coin_vault_token<'a, 'b: 'a>(
+        amm: &AmmInfo,
+        amm_coin_vault_info: &AccountInfo<'b>,
+        token_program_info: &'a AccountInfo<'b>,
+        token_2022_accounts: Option<&Token2022Accounts<'a, 'b>>,
+    ) -> Result<VaultToken<'a, 'b>, ProgramError> {
+        if *amm_coin_vault_info.owner == spl_token::id() {
+            return Ok(VaultToken {
+                token_program: token_program_info,
+                mint: None,
+                decimals: amm.coin_decimals as u8,
+            });
+        }
+        check_assert_eq!(
+            *amm_coin_vault_info.owner,
+            spl_token_2022::id(),
+            "coin_vault_owner",
+            AmmError::InvalidSplTokenProgram
+        );
+        Self::check_orderbook_vault_program(
+            AmmStatus::from_u64(amm.status),
+            amm_coin_vault_info.owner,
+        )?;
+        let token_2022_accounts = token_2022_accounts.ok_or(AmmError::WrongAccountsNumber)?;
+        check_assert_eq!(
+            *token_2022_accounts.coin_mint.key,
+            amm.coin_vault_mint,
+            "coin_mint",
+            AmmError::InvalidCoinMint
+        );
+        Ok(VaultToken {
+            token_program: token_2022_accounts.token_program,
+            mint: Some(token_2022_accounts.coin_mint),
+            decimals: amm.coin_decimals as u8,
+        })
+    }
+
+    /// Resolves how the pc vault tokens are moved.
+    fn pc_vault_token<'a, 'b: 'a>(
+        amm: &AmmInfo,
+        amm_pc_vault_info: &AccountInfo<'b>,
+        token_program_info: &'a AccountInfo<'b>,
+        token_2022_accounts: Option<&Token2022Accounts<'a, 'b>>,
+    ) -> Result<VaultToken<'a, 'b>, ProgramError> {
+        if *amm_pc_vault_info.owner == spl_token::id() {
+            return Ok(VaultToken {
+                token_program: token_program_info,
+                mint: None,
+                decimals: amm.pc_decimals as u8,
+            });
+        }
+        check_assert_eq!(
+            *amm_pc_vault_info.owner,
+            spl_token_2022::id(),
+            "pc_vault_owner",
+            AmmError::InvalidSplTokenProgram
+        );
+        Self::check_orderbook_vault_program(
+            AmmStatus::from_u64(amm.status),
+            amm_pc_vault_info.owner,
+        )?;
+        let token_2022_accounts = token_2022_accounts.ok_or(AmmError::WrongAccountsNumber)?;
+        check_assert_eq!(
+            *token_2022_accounts.pc_mint.key,
+            amm.pc_vault_mint,
+            "pc_mint",
+            AmmError::InvalidPCMint
+        );
+        Ok(VaultToken {
+            token_program: token_2022_accounts.token_program,
+            mint: Some(token_2022_accounts.pc_mint),
+            decimals: amm.pc_decimals as u8,
+        })
+    }
+
+    /// Returns the transfer fee config of a token-2022 vault mint, if it has one.
+    fn vault_transfer_fee_config(
+        vault_token: &VaultToken,
+    ) -> Result<Option<TransferFeeConfig>, ProgramError> {
+        match vault_token.mint {
+            Some(mint_info) => Self::mint_transfer_fee_config(mint_info),
+            None => Ok(None),
+        }
+    }
+
+    /// Transfer fee extension of a Spl Token 2022 mint, if any.
+    fn mint_transfer_fee_config(
+        mint_info: &AccountInfo,
+    ) -> Result<Option<TransferFeeConfig>, ProgramError> {
+        let data = mint_info.data.borrow();
+        let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)
+            .map_err(|_| AmmError::ExpectedMint)?;
+        Ok(mint.get_extension::<TransferFeeConfig>().ok().copied())
+    }
+
+    /// Amount that reaches the destination when `amount` of the vault token is transferred.
+    fn transfer_amount_received(
+        vault_token: &VaultToken,
+        amount: u64,
+        epoch: u64,
+    ) -> Result<u64, ProgramError> {
+        Self::amount_received_after_transfer_fee(
+            Self::vault_transfer_fee_config(vault_token)?.as_ref(),
+            amount,
+            epoch,
+        )
+    }
+
+    /// Amount that reaches the destination when `amount` of a mint with a transfer fee is sent.
+    pub fn amount_received_after_transfer_fee(
+        transfer_fee_config: Option<&TransferFeeConfig>,
+        amount: u64,
+        epoch: u64,
+    ) -> Result<u64, ProgramError> {
+        match transfer_fee_config {
+            Some(config) => {
+                let fee = config
+                    .calculate_epoch_fee(epoch, amount)
+                    .ok_or(AmmError::CalculationExRateFailure)?;
+                Ok(amount
+                    .checked_sub(fee)
+                    .ok_or(AmmError::CheckedSubOverflow)?)
+            }
+            None => Ok(amount),
+        }
+    }
+
+    /// Amount of the vault token to transfer so that `received_amount` reaches the destination.
+    fn transfer_amount_required(
+        vault_token: &VaultToken,
+        received_amount: u64,
+        epoch: u64,
+    ) -> Result<u64, ProgramError> {
+        Self::amount_required_before_transfer_fee(
+            Self::vault_transfer_fee_config(vault_token)?.as_ref(),
+            received_amount,
+            epoch,
+        )
+    }
+
+    /// Amount of a mint with a transfer fee to send so that `received_amount` reaches the destination.
+    pub fn amount_required_before_transfer_fee(
+        transfer_fee_config: Option<&TransferFeeConfig>,
+        received_amount: u64,
+        epoch: u64,
+    ) -> Result<u64, ProgramError> {
+        match transfer_fee_config {
+            Some(config) => {
+                let fee = config
+                    .calculate_inverse_epoch_fee(epoch, received_amount)
+                    .ok_or(AmmError::CalculationExRateFailure)?;
+                Ok(received_amount
+                    .checked_add(fee)
+                    .ok_or(AmmError::CheckedAddOverflow)?)
+            }
+            None => Ok(received_amount),
+        }
+    }
+
+    /// Transfers user tokens of a vault mint into the pool vault, or to a referral account.
+    fn token_transfer_to_vault<'a>(
+        vault_token: &VaultToken<'_, 'a>,
+        source: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        owner: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        match vault_token.mint {
+            Some(mint) => Invokers::token_transfer_checked(
+                vault_token.token_program.clone(),
+                source.clone(),
+                mint.clone(),
+                vault.clone(),
+                owner.clone(),
+                amount,
+                vault_token.decimals,
+            ),
+            None => Invokers::token_transfer(
+                vault_token.token_program.clone(),
+                source.clone(),
+                vault.clone(),
+                owner.clone(),
+                amount,
+            ),
+        }
+    }
+
+    /// Transfers tokens out of a pool vault, signed by the amm authority.
+    fn token_transfer_from_vault<'a>(
+        vault_token: &VaultToken<'_, 'a>,
+        vault: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        nonce: u8,
+        amount: u64,
+    ) -> ProgramResult {
+        match vault_token.mint {
+            Some(mint) => Invokers::token_transfer_checked_with_authority(
+                vault_token.token_program.clone(),
+                vault.clone(),
+                mint.clone(),
+                destination.clone(),
+                authority.clone(),
+                AUTHORITY_AMM,
+                nonce,
+                amount,
+                vault_token.decimals,
+            ),
+            None => Invokers::token_transfer_with_authority(
+                vault_token.token_program.clone(),
+                vault.clone(),
+                destination.clone(),
+                authority.clone(),
+                AUTHORITY_AMM,
+                nonce,
+                amount,
+            ),
+        }
+    }
+
     pub fn load_serum_market_order<'a>(
         market_acc: &AccountInfo<'a>,
         open_orders_acc: &AccountInfo<'a>,
@@ -606,6 +1235,19 @@ impl Processor {
         Ok(())
     }
 
+    /// Picks the token program account owning `mint_info`.
+    fn mint_token_program<'a, 'b: 'a>(
+        mint_info: &AccountInfo<'b>,
+        token_program_info: &'a AccountInfo<'b>,
+        token_program_2022_info: Option<&'a AccountInfo<'b>>,
+    ) -> Result<&'a AccountInfo<'b>, ProgramError> {
+        if *mint_info.owner == spl_token_2022::id() {
+            Ok(token_program_2022_info.ok_or(AmmError::InvalidSplTokenProgram)?)
+        } else {
+            Ok(token_program_info)
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn generate_amm_associated_spl_token<'a, 'b: 'a>(
         program_id: &Pubkey,
@@ -637,9 +1279,22 @@ impl Processor {
                 associated_seed,
                 &[bump_seed],
             ];
+            // token-2022 accounts must have room for the extensions required by their mint
+            let account_len = if *spl_token_program_id == spl_token_2022::id() {
+                let mint_data = token_mint_account.data.borrow();
+                let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+                let required_extensions = ExtensionType::get_required_init_account_extensions(
+                    &mint.get_extension_types()?,
+                );
+                ExtensionType::try_calculate_account_len::<spl_token_2022::state::Account>(
+                    &required_extensions,
+                )?
+            } else {
+                spl_token::state::Account::LEN
+            };
             let rent = &Rent::from_account_info(rent_sysvar_account)?;
             let required_lamports = rent
-                .minimum_balance(spl_token::state::Account::LEN)
+                .minimum_balance(account_len)
                 .max(1)
                 .saturating_sub(associated_token_account.lamports());
             if required_lamports > 0 {
@@ -657,10 +1312,7 @@ impl Processor {
                 )?;
             }
             invoke_signed(
-                &system_instruction::allocate(
-                    associated_token_account.key,
-                    spl_token::state::Account::LEN as u64,
-                ),
+                &system_instruction::allocate(associated_token_account.key, account_len as u64),
                 &[
                     associated_token_account.clone(),
                     system_program_account.clone(),
@@ -677,7 +1329,7 @@ impl Processor {
             )?;
 
             invoke(
-                &spl_token::instruction::initialize_account(
+                &spl_token_2022::instruction::initialize_account(
                     spl_token_program_id,
                     associated_token_account.key,
                     token_mint_account.key,
@@ -698,6 +1350,8 @@ impl Processor {
         Ok(())
     }
 
+    /// Creates the lp mint. It is always a spl_token mint, deposits and withdrawals mint
+    /// and burn it with the spl_token program.
     #[allow(clippy::too_many_arguments)]
     fn generate_amm_associated_spl_mint<'a, 'b: 'a>(
         program_id: &Pubkey,
@@ -891,6 +1545,8 @@ impl Processor {
         let user_token_coin_info = next_account_info(account_info_iter)?;
         let user_token_pc_info = next_account_info(account_info_iter)?;
         let user_token_lp_info = next_account_info(account_info_iter)?;
+        // only required when the coin or pc mint is owned by token-2022
+        let token_program_2022_info = next_account_info(account_info_iter).ok();
 
         let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
         if pda != *amm_config_info.key || amm_config_info.owner != program_id {
@@ -908,6 +1564,14 @@ impl Processor {
             AmmError::InvalidSplTokenProgram
         );
         let spl_token_program_id = token_program_info.key;
+        if let Some(token_program_2022_info) = token_program_2022_info {
+            check_assert_eq!(
+                *token_program_2022_info.key,
+                spl_token_2022::id(),
+                "spl_token_2022_program",
+                AmmError::InvalidSplTokenProgram
+            );
+        }
         check_assert_eq!(
             *ata_token_program_info.key,
             spl_associated_token_account::id(),
@@ -961,10 +1625,31 @@ impl Processor {
             )?;
         }
 
+        let coin_token_program_info = Self::mint_token_program(
+            amm_coin_mint_info,
+            token_program_info,
+            token_program_2022_info,
+        )?;
+        let pc_token_program_info = Self::mint_token_program(
+            amm_pc_mint_info,
+            token_program_info,
+            token_program_2022_info,
+        )?;
         // unpack and check coin_mint
-        let coin_mint = Self::unpack_mint(&amm_coin_mint_info, spl_token_program_id)?;
+        let coin_mint = Self::unpack_mint(&amm_coin_mint_info, coin_token_program_info.key)?;
         // unpack and check pc_mint
-        let pc_mint = Self::unpack_mint(&amm_pc_mint_info, spl_token_program_id)?;
+        let pc_mint = Self::unpack_mint(&amm_pc_mint_info, pc_token_program_info.key)?;
+        let coin_vault_token = VaultToken {
+            token_program: coin_token_program_info,
+            mint: (*coin_token_program_info.key == spl_token_2022::id())
+                .then_some(amm_coin_mint_info),
+            decimals: coin_mint.decimals,
+        };
+        let pc_vault_token = VaultToken {
+            token_program: pc_token_program_info,
+            mint: (*pc_token_program_info.key == spl_token_2022::id()).then_some(amm_pc_mint_info),
+            decimals: pc_mint.decimals,
+        };
 
         // create target_order account
         Self::generate_amm_associated_account(
@@ -997,28 +1682,28 @@ impl Processor {
         // create coin vault account
         Self::generate_amm_associated_spl_token(
             program_id,
-            spl_token_program_id,
+            coin_token_program_info.key,
             market_info,
             amm_coin_vault_info,
             amm_coin_mint_info,
             user_wallet_info,
             system_program_info,
             rent_sysvar_info,
-            token_program_info,
+            coin_token_program_info,
             amm_authority_info,
             COIN_VAULT_ASSOCIATED_SEED,
         )?;
         // create pc vault account
         Self::generate_amm_associated_spl_token(
             program_id,
-            spl_token_program_id,
+            pc_token_program_info.key,
             market_info,
             amm_pc_vault_info,
             amm_pc_mint_info,
             user_wallet_info,
             system_program_info,
             rent_sysvar_info,
-            token_program_info,
+            pc_token_program_info,
             amm_authority_info,
             PC_VAULT_ASSOCIATED_SEED,
         )?;
@@ -1070,18 +1755,18 @@ impl Processor {
         )?;
 
         // transfer user tokens to vault
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_token_coin_info.clone(),
-            amm_coin_vault_info.clone(),
-            user_wallet_info.clone(),
+        Self::token_transfer_to_vault(
+            &coin_vault_token,
+            user_token_coin_info,
+            amm_coin_vault_info,
+            user_wallet_info,
             init.init_coin_amount,
         )?;
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_token_pc_info.clone(),
-            amm_pc_vault_info.clone(),
-            user_wallet_info.clone(),
+        Self::token_transfer_to_vault(
+            &pc_vault_token,
+            user_token_pc_info,
+            amm_pc_vault_info,
+            user_wallet_info,
             init.init_pc_amount,
         )?;
 
@@ -1093,7 +1778,7 @@ impl Processor {
 
         // unpack and check token_coin
         let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
+            Self::unpack_token_account(&amm_coin_vault_info, coin_token_program_info.key)?;
         check_assert_eq!(
             amm_coin_vault.owner,
             *amm_authority_info.key,
@@ -1116,7 +1801,8 @@ impl Processor {
             AmmError::InvalidCoinMint
         );
         // unpack and check token_pc
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_token_program_info.key)?;
         check_assert_eq!(
             amm_pc_vault.owner,
             *amm_authority_info.key,
@@ -1203,6 +1889,7 @@ impl Processor {
             market_state.coin_lot_size,
             market_state.pc_lot_size,
         )?;
+        amm.protocol_fee_rate = amm_config.protocol_fee_rate as u32;
         encode_ray_log(InitLog {
             log_type: LogType::Init.into_u8(),
             time: init.open_time,
@@ -1247,18 +1934,152 @@ impl Processor {
         } else {
             AmmStatus::SwapOnly.into_u64()
         };
+        Self::check_orderbook_vault_program(
+            AmmStatus::from_u64(amm.status),
+            coin_token_program_info.key,
+        )?;
+        Self::check_orderbook_vault_program(
+            AmmStatus::from_u64(amm.status),
+            pc_token_program_info.key,
+        )?;
         amm.reset_flag = AmmResetFlag::ResetYes.into_u64();
 
         Ok(())
     }
 
+    /// Computes the amounts of a deposit of the base side max amount, base_side 0 for coin.
+    /// Token-2022 transfer fees are withheld on the way into the vaults, so the ratio and
+    /// the lp minted follow the amounts the vaults receive.
+    #[allow(clippy::too_many_arguments)]
+    pub fn deposit_amounts(
+        amm: &AmmInfo,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        max_coin_amount: u64,
+        max_pc_amount: u64,
+        base_side: u64,
+        coin_transfer_fee_config: Option<&TransferFeeConfig>,
+        pc_transfer_fee_config: Option<&TransferFeeConfig>,
+        epoch: u64,
+    ) -> Result<DepositAmounts, ProgramError> {
+        let invariant = InvariantToken {
+            token_coin: total_coin_without_take_pnl,
+            token_pc: total_pc_without_take_pnl,
+        };
+        if base_side == 0 {
+            // base coin
+            let coin_amount_received = Self::amount_received_after_transfer_fee(
+                coin_transfer_fee_config,
+                max_coin_amount,
+                epoch,
+            )?;
+            let pc_amount_received = invariant
+                .exchange_coin_to_pc(coin_amount_received, RoundDirection::Ceiling)
+                .ok_or(AmmError::CalculationExRateFailure)?;
+            // coin_amount/ (total_coin_amount + coin_amount)  = output / (lp_mint.supply + output) =>  output = coin_amount / total_coin_amount * lp_mint.supply
+            let mint_lp_amount = InvariantPool {
+                token_input: coin_amount_received,
+                token_total: total_coin_without_take_pnl,
+            }
+            .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+            Ok(DepositAmounts {
+                deduct_coin_amount: max_coin_amount,
+                deduct_pc_amount: Self::amount_required_before_transfer_fee(
+                    pc_transfer_fee_config,
+                    pc_amount_received,
+                    epoch,
+                )?,
+                coin_amount_received,
+                pc_amount_received,
+                mint_lp_amount,
+            })
+        } else {
+            // base pc
+            let pc_amount_received = Self::amount_received_after_transfer_fee(
+                pc_transfer_fee_config,
+                max_pc_amount,
+                epoch,
+            )?;
+            let coin_amount_received = invariant
+                .exchange_pc_to_coin(pc_amount_received, RoundDirection::Ceiling)
+                .ok_or(AmmError::CalculationExRateFailure)?;
+            // pc_amount/ (total_pc_amount + pc_amount)  = output / (lp_mint.supply + output) =>  output = pc_amount / total_pc_amount * lp_mint.supply
+            let mint_lp_amount = InvariantPool {
+                token_input: pc_amount_received,
+                token_total: total_pc_without_take_pnl,
+            }
+            .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+            Ok(DepositAmounts {
+                deduct_coin_amount: Self::amount_required_before_transfer_fee(
+                    coin_transfer_fee_config,
+                    coin_amount_received,
+                    epoch,
+                )?,
+                deduct_pc_amount: max_pc_amount,
+                coin_amount_received,
+                pc_amount_received,
+                mint_lp_amount,
+            })
+        }
+    }
+
+    /// Checks the side of a deposit paired with the base side is within the user bounds:
+    /// at most its max amount, and at least `other_amount_min` if given.
+    pub fn check_deposit_amounts(
+        amounts: &DepositAmounts,
+        max_coin_amount: u64,
+        max_pc_amount: u64,
+        base_side: u64,
+        other_amount_min: Option<u64>,
+    ) -> Result<(), AmmError> {
+        let (other_amount, other_amount_max) = if base_side == 0 {
+            (amounts.deduct_pc_amount, max_pc_amount)
+        } else {
+            (amounts.deduct_coin_amount, max_coin_amount)
+        };
+        if other_amount > other_amount_max {
+            return Err(AmmError::ExceededSlippage);
+        }
+        if let Some(other_amount_min) = other_amount_min {
+            if other_amount < other_amount_min {
+                return Err(AmmError::ExceededSlippage);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return data of a deposit of `amounts`, pool_lp_supply being the lp supply after the mint.
+    pub fn deposit_return_data(
+        amounts: &DepositAmounts,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        pool_lp_supply: u64,
+    ) -> Result<DepositReturnData, ProgramError> {
+        Ok(DepositReturnData {
+            coin_amount: amounts.deduct_coin_amount,
+            pc_amount: amounts.deduct_pc_amount,
+            lp_amount: amounts.mint_lp_amount,
+            pool_coin_amount: total_coin_without_take_pnl
+                .checked_add(amounts.coin_amount_received)
+                .ok_or(AmmError::CheckedAddOverflow)?,
+            pool_pc_amount: total_pc_without_take_pnl
+                .checked_add(amounts.pc_amount_received)
+                .ok_or(AmmError::CheckedAddOverflow)?,
+            pool_lp_supply,
+        })
+    }
+
     /// Processes an [Deposit](enum.Instruction.html).
     pub fn process_deposit(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         deposit: DepositInstruction,
     ) -> ProgramResult {
+        Self::check_deadline(deposit.deadline)?;
         const ACCOUNT_LEN: usize = 14;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
         let input_account_len = accounts.len();
         if input_account_len != ACCOUNT_LEN && input_account_len != ACCOUNT_LEN + 1 {
             return Err(AmmError::WrongAccountsNumber.into());
@@ -1282,22 +2103,8 @@ impl Processor {
         let source_owner_info = next_account_info(account_info_iter)?;
         let market_event_queue_info = next_account_info(account_info_iter)?;
         let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        if deposit.max_coin_amount == 0 || deposit.max_pc_amount == 0 {
-            encode_ray_log(DepositLog {
-                log_type: LogType::Deposit.into_u8(),
-                max_coin: deposit.max_coin_amount,
-                max_pc: deposit.max_pc_amount,
-                base: deposit.base_side,
-                pool_coin: 0,
-                pool_pc: 0,
-                pool_lp: 0,
-                calc_pnl_x: 0,
-                calc_pnl_y: 0,
-                deduct_coin: 0,
-                deduct_pc: 0,
-                mint_lp: 0,
-            });
-            return Err(AmmError::InvalidInput.into());
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
         }
         if !source_owner_info.is_signer {
             return Err(AmmError::InvalidSignAccount.into());
@@ -1323,7 +2130,6 @@ impl Processor {
             "spl_token_program",
             AmmError::InvalidSplTokenProgram
         );
-        let spl_token_program_id = token_program_info.key;
         // token_coin must be amm.coin_vault or token_source_coin must not be amm.coin_vault
         if *amm_coin_vault_info.key != amm.coin_vault
             || *user_source_coin_info.key == amm.coin_vault
@@ -1346,13 +2152,46 @@ impl Processor {
             "target_orders",
             AmmError::InvalidTargetOrders
         );
+        let coin_vault_token = Self::coin_vault_token(
+            &amm,
+            amm_coin_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let pc_vault_token = Self::pc_vault_token(
+            &amm,
+            amm_pc_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
         let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.token_program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.token_program.key)?;
         let user_source_coin =
-            Self::unpack_token_account(&user_source_coin_info, spl_token_program_id)?;
+            Self::unpack_token_account(&user_source_coin_info, coin_vault_token.token_program.key)?;
         let user_source_pc =
-            Self::unpack_token_account(&user_source_pc_info, spl_token_program_id)?;
+            Self::unpack_token_account(&user_source_pc_info, pc_vault_token.token_program.key)?;
+        let user_dest_lp = Self::unpack_token_account(&user_dest_lp_info, token_program_info.key)?;
+        let recipient = Self::deposit_recipient(&user_dest_lp, &amm.lp_mint)?;
+        if deposit.max_coin_amount == 0 || deposit.max_pc_amount == 0 {
+            encode_ray_log(DepositLog {
+                log_type: LogType::Deposit.into_u8(),
+                max_coin: deposit.max_coin_amount,
+                max_pc: deposit.max_pc_amount,
+                base: deposit.base_side,
+                pool_coin: 0,
+                pool_pc: 0,
+                pool_lp: 0,
+                calc_pnl_x: 0,
+                calc_pnl_y: 0,
+                deduct_coin: 0,
+                deduct_pc: 0,
+                mint_lp: 0,
+                recipient,
+            });
+            return Err(AmmError::InvalidInput.into());
+        }
         let mut target_orders =
             TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
         // calc the remaining total_pc & total_coin
@@ -1413,6 +2252,11 @@ impl Processor {
             amm.coin_decimals,
             amm.sys_decimal_value,
         );
+        amm.update_price_cumulative(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        );
         // calc and update pnl
         let (delta_x, delta_y) = Self::calc_take_pnl(
             &target_orders,
@@ -1422,11 +2266,6 @@ impl Processor {
             x1.as_u128().into(),
             y1.as_u128().into(),
         )?;
-        let invariant = InvariantToken {
-            token_coin: total_coin_without_take_pnl,
-            token_pc: total_pc_without_take_pnl,
-        };
-
         // let lp_mint  = Self::unpack_mint(&lp_mint_info, spl_token_program_id)?;
         if amm.lp_amount == 0 {
             encode_ray_log(DepositLog {
@@ -1442,115 +2281,48 @@ impl Processor {
                 deduct_coin: 0,
                 deduct_pc: 0,
                 mint_lp: 0,
+                recipient,
             });
             return Err(AmmError::NotAllowZeroLP.into());
         }
-        let deduct_pc_amount;
-        let deduct_coin_amount;
-        let mint_lp_amount;
-        if deposit.base_side == 0 {
-            // base coin
-            deduct_pc_amount = invariant
-                .exchange_coin_to_pc(deposit.max_coin_amount, RoundDirection::Ceiling)
-                .ok_or(AmmError::CalculationExRateFailure)?;
-            deduct_coin_amount = deposit.max_coin_amount;
-            if deduct_pc_amount > deposit.max_pc_amount {
-                encode_ray_log(DepositLog {
-                    log_type: LogType::Deposit.into_u8(),
-                    max_coin: deposit.max_coin_amount,
-                    max_pc: deposit.max_pc_amount,
-                    base: deposit.base_side,
-                    pool_coin: total_coin_without_take_pnl,
-                    pool_pc: total_pc_without_take_pnl,
-                    pool_lp: amm.lp_amount,
-                    calc_pnl_x: target_orders.calc_pnl_x,
-                    calc_pnl_y: target_orders.calc_pnl_y,
-                    deduct_coin: deduct_coin_amount,
-                    deduct_pc: deduct_pc_amount,
-                    mint_lp: 0,
-                });
-                return Err(AmmError::ExceededSlippage.into());
-            }
-            // base coin, check other_amount_min if need
-            if deposit.other_amount_min.is_some() {
-                if deduct_pc_amount < deposit.other_amount_min.unwrap() {
-                    encode_ray_log(DepositLog {
-                        log_type: LogType::Deposit.into_u8(),
-                        max_coin: deposit.max_coin_amount,
-                        max_pc: deposit.max_pc_amount,
-                        base: deposit.base_side,
-                        pool_coin: total_coin_without_take_pnl,
-                        pool_pc: total_pc_without_take_pnl,
-                        pool_lp: amm.lp_amount,
-                        calc_pnl_x: target_orders.calc_pnl_x,
-                        calc_pnl_y: target_orders.calc_pnl_y,
-                        deduct_coin: deduct_coin_amount,
-                        deduct_pc: deduct_pc_amount,
-                        mint_lp: 0,
-                    });
-                    return Err(AmmError::ExceededSlippage.into());
-                }
-            }
-            // coin_amount/ (total_coin_amount + coin_amount)  = output / (lp_mint.supply + output) =>  output = coin_amount / total_coin_amount * lp_mint.supply
-            let invariant_coin = InvariantPool {
-                token_input: deduct_coin_amount,
-                token_total: total_coin_without_take_pnl,
-            };
-            mint_lp_amount = invariant_coin
-                .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
-                .ok_or(AmmError::CalculationExRateFailure)?;
-        } else {
-            // base pc
-            deduct_coin_amount = invariant
-                .exchange_pc_to_coin(deposit.max_pc_amount, RoundDirection::Ceiling)
-                .ok_or(AmmError::CalculationExRateFailure)?;
-            deduct_pc_amount = deposit.max_pc_amount;
-            if deduct_coin_amount > deposit.max_coin_amount {
-                encode_ray_log(DepositLog {
-                    log_type: LogType::Deposit.into_u8(),
-                    max_coin: deposit.max_coin_amount,
-                    max_pc: deposit.max_pc_amount,
-                    base: deposit.base_side,
-                    pool_coin: total_coin_without_take_pnl,
-                    pool_pc: total_pc_without_take_pnl,
-                    pool_lp: amm.lp_amount,
-                    calc_pnl_x: target_orders.calc_pnl_x,
-                    calc_pnl_y: target_orders.calc_pnl_y,
-                    deduct_coin: deduct_coin_amount,
-                    deduct_pc: deduct_pc_amount,
-                    mint_lp: 0,
-                });
-                return Err(AmmError::ExceededSlippage.into());
-            }
-            // base pc, check other_amount_min if need
-            if deposit.other_amount_min.is_some() {
-                if deduct_coin_amount < deposit.other_amount_min.unwrap() {
-                    encode_ray_log(DepositLog {
-                        log_type: LogType::Deposit.into_u8(),
-                        max_coin: deposit.max_coin_amount,
-                        max_pc: deposit.max_pc_amount,
-                        base: deposit.base_side,
-                        pool_coin: total_coin_without_take_pnl,
-                        pool_pc: total_pc_without_take_pnl,
-                        pool_lp: amm.lp_amount,
-                        calc_pnl_x: target_orders.calc_pnl_x,
-                        calc_pnl_y: target_orders.calc_pnl_y,
-                        deduct_coin: deduct_coin_amount,
-                        deduct_pc: deduct_pc_amount,
-                        mint_lp: 0,
-                    });
-                    return Err(AmmError::ExceededSlippage.into());
-                }
-            }
-
-            let invariant_pc = InvariantPool {
-                token_input: deduct_pc_amount,
-                token_total: total_pc_without_take_pnl,
-            };
-            // pc_amount/ (total_pc_amount + pc_amount)  = output / (lp_mint.supply + output) =>  output = pc_amount / total_pc_amount * lp_mint.supply
-            mint_lp_amount = invariant_pc
-                .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
-                .ok_or(AmmError::CalculationExRateFailure)?;
+        let epoch = Clock::get()?.epoch;
+        let amounts = Self::deposit_amounts(
+            &amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            deposit.max_coin_amount,
+            deposit.max_pc_amount,
+            deposit.base_side,
+            Self::vault_transfer_fee_config(&coin_vault_token)?.as_ref(),
+            Self::vault_transfer_fee_config(&pc_vault_token)?.as_ref(),
+            epoch,
+        )?;
+        let deduct_coin_amount = amounts.deduct_coin_amount;
+        let deduct_pc_amount = amounts.deduct_pc_amount;
+        let mint_lp_amount = amounts.mint_lp_amount;
+        if let Err(e) = Self::check_deposit_amounts(
+            &amounts,
+            deposit.max_coin_amount,
+            deposit.max_pc_amount,
+            deposit.base_side,
+            deposit.other_amount_min,
+        ) {
+            encode_ray_log(DepositLog {
+                log_type: LogType::Deposit.into_u8(),
+                max_coin: deposit.max_coin_amount,
+                max_pc: deposit.max_pc_amount,
+                base: deposit.base_side,
+                pool_coin: total_coin_without_take_pnl,
+                pool_pc: total_pc_without_take_pnl,
+                pool_lp: amm.lp_amount,
+                calc_pnl_x: target_orders.calc_pnl_x,
+                calc_pnl_y: target_orders.calc_pnl_y,
+                deduct_coin: deduct_coin_amount,
+                deduct_pc: deduct_pc_amount,
+                mint_lp: 0,
+                recipient,
+            });
+            return Err(e.into());
         }
         encode_ray_log(DepositLog {
             log_type: LogType::Deposit.into_u8(),
@@ -1565,7 +2337,13 @@ impl Processor {
             deduct_coin: deduct_coin_amount,
             deduct_pc: deduct_pc_amount,
             mint_lp: mint_lp_amount,
+            recipient,
         });
+        if let Some(min_lp_out) = deposit.min_lp_out {
+            if mint_lp_amount < min_lp_out {
+                return Err(AmmError::ExceededSlippage.into());
+            }
+        }
 
         if deduct_coin_amount > user_source_coin.amount || deduct_pc_amount > user_source_pc.amount
         {
@@ -1575,18 +2353,18 @@ impl Processor {
             return Err(AmmError::InvalidInput.into());
         }
 
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_source_coin_info.clone(),
-            amm_coin_vault_info.clone(),
-            source_owner_info.clone(),
+        Self::token_transfer_to_vault(
+            &coin_vault_token,
+            user_source_coin_info,
+            amm_coin_vault_info,
+            source_owner_info,
             deduct_coin_amount,
         )?;
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_source_pc_info.clone(),
-            amm_pc_vault_info.clone(),
-            source_owner_info.clone(),
+        Self::token_transfer_to_vault(
+            &pc_vault_token,
+            user_source_pc_info,
+            amm_pc_vault_info,
+            source_owner_info,
             deduct_pc_amount,
         )?;
         Invokers::token_mint_to(
@@ -1600,9 +2378,10 @@ impl Processor {
         )?;
         amm.lp_amount = amm.lp_amount.checked_add(mint_lp_amount).unwrap();
 
+        // the pnl baseline follows what the vaults receive
         target_orders.calc_pnl_x = x1
             .checked_add(Calculator::normalize_decimal_v2(
-                deduct_pc_amount,
+                amounts.pc_amount_received,
                 amm.pc_decimals,
                 amm.sys_decimal_value,
             ))
@@ -1612,7 +2391,7 @@ impl Processor {
             .as_u128();
         target_orders.calc_pnl_y = y1
             .checked_add(Calculator::normalize_decimal_v2(
-                deduct_coin_amount,
+                amounts.coin_amount_received,
                 amm.coin_decimals,
                 amm.sys_decimal_value,
             ))
@@ -1620,87 +2399,90 @@ impl Processor {
             .checked_sub(U128::from(delta_y))
             .unwrap()
             .as_u128();
+        let deposit_data = Self::deposit_return_data(
+            &amounts,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            amm.lp_amount,
+        )?;
+        set_return_data(&AmmReturnData::Deposit(deposit_data).pack());
         amm.recent_epoch = Clock::get()?.epoch;
         Ok(())
     }
 
-    pub fn process_withdrawpnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-        const ACCOUNT_LEN: usize = 17;
-        let input_account_len = accounts.len();
-        if input_account_len != ACCOUNT_LEN && input_account_len != ACCOUNT_LEN + 1 {
-            return Err(AmmError::WrongAccountsNumber.into());
-        }
+    /// Deposits only coin or only pc into a pool. The part of the input that balances the pool
+    /// ratio is swapped inside the pool and never leaves the vaults. A pool with orderbook
+    /// permission takes its market accounts, and its amm orders on the side the swap takes
+    /// from are cancelled.
+    pub fn process_zap_deposit(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        zap: ZapDepositInstruction,
+    ) -> ProgramResult {
+        Self::check_deadline(zap.deadline)?;
+        const ACCOUNT_LEN: usize = 10;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let market_accounts = match accounts.len() {
+            ACCOUNT_LEN => None,
+            len if len == ACCOUNT_LEN + SWAP_MARKET_ACCOUNT_LEN => {
+                Some(Self::swap_market_accounts(&accounts[ACCOUNT_LEN..]))
+            }
+            _ => return Err(AmmError::WrongAccountsNumber.into()),
+        };
         let account_info_iter = &mut accounts.iter();
         let token_program_info = next_account_info(account_info_iter)?;
-
         let amm_info = next_account_info(account_info_iter)?;
-        let amm_config_info = next_account_info(account_info_iter)?;
         let amm_authority_info = next_account_info(account_info_iter)?;
-        let amm_open_orders_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+        let amm_lp_mint_info = next_account_info(account_info_iter)?;
         let amm_coin_vault_info = next_account_info(account_info_iter)?;
         let amm_pc_vault_info = next_account_info(account_info_iter)?;
-        let user_pnl_coin_info = next_account_info(account_info_iter)?;
-        let user_pnl_pc_info = next_account_info(account_info_iter)?;
-        let pnl_owner_info = next_account_info(account_info_iter)?;
-        let amm_target_orders_info = next_account_info(account_info_iter)?;
-
-        let market_program_info = next_account_info(account_info_iter)?;
-        let market_info = next_account_info(account_info_iter)?;
-        let market_event_queue_info = next_account_info(account_info_iter)?;
-        let _market_coin_vault_info = next_account_info(account_info_iter)?;
-        let _market_pc_vault_info = next_account_info(account_info_iter)?;
-        let _market_vault_signer = next_account_info(account_info_iter)?;
-        let mut _referrer_pc_wallet = None;
-        if input_account_len == ACCOUNT_LEN + 1 {
-            _referrer_pc_wallet = Some(next_account_info(account_info_iter)?);
-            let referrer_pc_token =
-                Self::unpack_token_account(&_referrer_pc_wallet.unwrap(), token_program_info.key)?;
-            check_assert_eq!(
-                referrer_pc_token.owner,
-                config_feature::referrer_pc_wallet::id(),
-                "referrer_pc_owner",
-                AmmError::InvalidOwner
-            );
-        }
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_dest_lp_info = next_account_info(account_info_iter)?;
+        let source_owner_info = next_account_info(account_info_iter)?;
 
         let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        if *amm_authority_info.key
-            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
-        {
-            return Err(AmmError::InvalidProgramAddress.into());
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
         }
-        if amm_info.owner != program_id {
-            return Err(AmmError::InvalidOwner.into());
+        if zap.amount_in == 0 {
+            return Err(AmmError::InvalidInput.into());
         }
-        let enable_orderbook;
-        if AmmStatus::from_u64(amm.status).orderbook_permission() {
-            enable_orderbook = true;
-        } else {
-            enable_orderbook = false;
+        if !source_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
         }
-
-        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
-        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
-            return Err(AmmError::InvalidConfigAccount.into());
+        let status = AmmStatus::from_u64(amm.status);
+        if !status.deposit_permission() || !status.swap_permission() {
+            return Err(AmmError::InvalidStatus.into());
         }
-        let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
-
-        if !pnl_owner_info.is_signer
-            || (*pnl_owner_info.key != config_feature::amm_owner::ID
-                && *pnl_owner_info.key != amm_config.pnl_owner)
+        if amm.status == AmmStatus::WaitingTrade.into_u64()
+            && (Clock::get()?.unix_timestamp as u64) < amm.state_data.pool_open_time
         {
-            return Err(AmmError::InvalidSignAccount.into());
-        }
-        // withdrawpnl in all status except Uninitialized
-        if amm.status == AmmStatus::Uninitialized.into_u64() {
-            msg!(&format!("withdrawpnl: status {}", identity(amm.status)));
             return Err(AmmError::InvalidStatus.into());
         }
+        // the market accounts are only read for a pool with orderbook permission
+        let market_accounts = if status.orderbook_permission() {
+            match market_accounts {
+                Some(market) => Some(market),
+                None => {
+                    msg!("zap_deposit: missing market accounts of an orderbook pool");
+                    return Err(AmmError::WrongAccountsNumber.into());
+                }
+            }
+        } else {
+            None
+        };
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
         check_assert_eq!(
-            *market_info.key,
-            amm.market,
-            "market",
-            AmmError::InvalidMarket
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
         );
         check_assert_eq!(
             *amm_coin_vault_info.key,
@@ -1714,24 +2496,14 @@ impl Processor {
             "pc_vault",
             AmmError::InvalidPCVault
         );
+        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
         check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
-        );
-        let spl_token_program_id = token_program_info.key;
-        check_assert_eq!(
-            *market_program_info.key,
-            amm.market_program,
-            "market_program",
-            AmmError::InvalidMarketProgram
-        );
-        check_assert_eq!(
-            *amm_open_orders_info.key,
-            amm.open_orders,
-            "open_orders",
-            AmmError::InvalidOpenOrders
+            *amm_lp_mint_info.key,
+            amm.lp_mint,
+            "lp_mint",
+            AmmError::InvalidPoolMint
         );
         check_assert_eq!(
             *amm_target_orders_info.key,
@@ -1739,35 +2511,442 @@ impl Processor {
             "target_orders",
             AmmError::InvalidTargetOrders
         );
+        let coin_vault_token = Self::coin_vault_token(
+            &amm,
+            amm_coin_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let pc_vault_token = Self::pc_vault_token(
+            &amm,
+            amm_pc_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
         let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-        let user_pnl_coin = Self::unpack_token_account(&user_pnl_coin_info, spl_token_program_id)?;
-        let user_pnl_pc = Self::unpack_token_account(&user_pnl_pc_info, spl_token_program_id)?;
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.token_program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.token_program.key)?;
+        let user_source = Self::unpack_token_account(
+            &user_source_info,
+            Self::token_program_id(user_source_info)?,
+        )?;
+        // the input side follows the user source mint, the other side is the swap output
+        let swap_direction = if user_source.mint == amm_coin_vault.mint {
+            SwapDirection::Coin2PC
+        } else if user_source.mint == amm_pc_vault.mint {
+            SwapDirection::PC2Coin
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
+        };
+        let user_dest_lp = Self::unpack_token_account(&user_dest_lp_info, token_program_info.key)?;
+        let recipient = Self::deposit_recipient(&user_dest_lp, &amm.lp_mint)?;
         let mut target_orders =
             TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-        if amm_coin_vault.mint != amm.coin_vault_mint || user_pnl_coin.mint != amm.coin_vault_mint {
-            return Err(AmmError::InvalidCoinMint.into());
-        }
-        if amm_pc_vault.mint != amm.pc_vault_mint || user_pnl_pc.mint != amm.pc_vault_mint {
-            return Err(AmmError::InvalidPCMint.into());
-        }
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl, bids, asks) =
+            match market_accounts.as_ref() {
+                Some(market) => Self::load_swap_market(
+                    &amm,
+                    market,
+                    amm_authority_info,
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                )?,
+                None => {
+                    let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                        Calculator::calc_total_without_take_pnl_no_orderbook(
+                            amm_pc_vault.amount,
+                            amm_coin_vault.amount,
+                            &amm,
+                        )?;
+                    (
+                        total_pc_without_take_pnl,
+                        total_coin_without_take_pnl,
+                        Vec::new(),
+                        Vec::new(),
+                    )
+                }
+            };
 
-        // calc the remaining total_pc & total_coin
-        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) = if enable_orderbook {
-            check_assert_eq!(
-                *market_info.key,
-                amm.market,
-                "market",
-                AmmError::InvalidMarket
-            );
-            check_assert_eq!(
-                *amm_open_orders_info.key,
-                amm.open_orders,
-                "open_orders",
-                AmmError::InvalidOpenOrders
-            );
-            let (market_state, open_orders) = Self::load_serum_market_order(
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        let unix_timestamp = Clock::get()?.unix_timestamp as u64;
+        amm.update_price_cumulative(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            unix_timestamp,
+        );
+        // the swap half moves the pool price like a swap does
+        Self::write_observation(
+            program_id,
+            amm_info,
+            &amm,
+            observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        )?;
+        // calc and update pnl
+        let (delta_x, delta_y) = Self::calc_take_pnl(
+            &target_orders,
+            &mut amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            x1.as_u128().into(),
+            y1.as_u128().into(),
+        )?;
+        if amm.lp_amount == 0 {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+
+        let epoch = Clock::get()?.epoch;
+        let (source_vault_token, reserve_in, reserve_out) = match swap_direction {
+            SwapDirection::Coin2PC => (
+                &coin_vault_token,
+                total_coin_without_take_pnl,
+                total_pc_without_take_pnl,
+            ),
+            SwapDirection::PC2Coin => (
+                &pc_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+            ),
+        };
+        let amount_in_received =
+            Self::transfer_amount_received(source_vault_token, zap.amount_in, epoch)?;
+        let swap_amount = Calculator::zap_swap_amount(
+            amount_in_received,
+            reserve_in,
+            amm.swap_fee_numerator_at(unix_timestamp),
+            amm.fees.swap_fee_denominator,
+        )?;
+        // the swap leg trades tokens already in the vault, no transfer fee applies
+        let swap_amounts = Self::swap_amounts(
+            &amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            SwapAmount::BaseIn(swap_amount),
+            0,
+            None,
+            None,
+            epoch,
+            unix_timestamp,
+        )?;
+        let swap_amount_out = swap_amounts.amount_out;
+        // the swap output and the rest of the input are deposited against the pool after the swap
+        let reserve_in_after_swap = reserve_in
+            .checked_add(swap_amount)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        let reserve_out_after_swap = reserve_out
+            .checked_sub(swap_amount_out)
+            .ok_or(AmmError::CheckedSubOverflow)?;
+        let deposit_in_amount = amount_in_received
+            .checked_sub(swap_amount)
+            .ok_or(AmmError::CheckedSubOverflow)?;
+        let mint_lp_by_in = InvariantPool {
+            token_input: deposit_in_amount,
+            token_total: reserve_in_after_swap,
+        }
+        .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
+        .ok_or(AmmError::CalculationExRateFailure)?;
+        let mint_lp_by_out = InvariantPool {
+            token_input: swap_amount_out,
+            token_total: reserve_out_after_swap,
+        }
+        .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
+        .ok_or(AmmError::CalculationExRateFailure)?;
+        let mint_lp_amount = std::cmp::min(mint_lp_by_in, mint_lp_by_out);
+        encode_ray_log(ZapDepositLog {
+            log_type: LogType::ZapDeposit.into_u8(),
+            amount_in: zap.amount_in,
+            min_lp: zap.min_lp_amount,
+            direction: swap_direction as u64,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            pool_lp: amm.lp_amount,
+            calc_pnl_x: target_orders.calc_pnl_x,
+            calc_pnl_y: target_orders.calc_pnl_y,
+            swap_amount,
+            swap_out: swap_amount_out,
+            mint_lp: mint_lp_amount,
+            recipient,
+        });
+        if zap.amount_in > user_source.amount {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if mint_lp_amount < zap.min_lp_amount {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if mint_lp_amount == 0 || swap_amount_out == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        if let Some(market) = market_accounts.as_ref() {
+            // the swap moves the pool price, cancel the orders on the side it takes from,
+            // its output stays in the vault for the deposit so nothing needs settling
+            let orders = match swap_direction {
+                SwapDirection::Coin2PC => &bids,
+                SwapDirection::PC2Coin => &asks,
+            };
+            Self::cancel_swap_side_orders(
+                &amm,
+                market,
+                token_program_info,
+                amm_authority_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                orders,
+                false,
+            )?;
+        }
+
+        let (vault_info, coin_added, pc_added) = match swap_direction {
+            SwapDirection::Coin2PC => (amm_coin_vault_info, amount_in_received, 0),
+            SwapDirection::PC2Coin => (amm_pc_vault_info, 0, amount_in_received),
+        };
+        let pool_coin_after = total_coin_without_take_pnl
+            .checked_add(coin_added)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        let pool_pc_after = total_pc_without_take_pnl
+            .checked_add(pc_added)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        Self::token_transfer_to_vault(
+            source_vault_token,
+            user_source_info,
+            vault_info,
+            source_owner_info,
+            zap.amount_in,
+        )?;
+        Invokers::token_mint_to(
+            token_program_info.clone(),
+            amm_lp_mint_info.clone(),
+            user_dest_lp_info.clone(),
+            amm_authority_info.clone(),
+            AUTHORITY_AMM,
+            amm.nonce as u8,
+            mint_lp_amount,
+        )?;
+        amm.lp_amount = amm
+            .lp_amount
+            .checked_add(mint_lp_amount)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+
+        Self::record_swap(
+            &mut amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            &swap_amounts,
+            unix_timestamp,
+        );
+
+        // only the input side of the pool grows, the swap output stays in the vault
+        target_orders.calc_pnl_x = x1
+            .checked_add(Calculator::normalize_decimal_v2(
+                pc_added,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            ))
+            .ok_or(AmmError::CheckedAddOverflow)?
+            .checked_sub(U128::from(delta_x))
+            .ok_or(AmmError::CheckedSubOverflow)?
+            .as_u128();
+        target_orders.calc_pnl_y = y1
+            .checked_add(Calculator::normalize_decimal_v2(
+                coin_added,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            ))
+            .ok_or(AmmError::CheckedAddOverflow)?
+            .checked_sub(U128::from(delta_y))
+            .ok_or(AmmError::CheckedSubOverflow)?
+            .as_u128();
+        let (coin_amount, pc_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (zap.amount_in, 0),
+            SwapDirection::PC2Coin => (0, zap.amount_in),
+        };
+        let deposit_data = DepositReturnData {
+            coin_amount,
+            pc_amount,
+            lp_amount: mint_lp_amount,
+            pool_coin_amount: pool_coin_after,
+            pool_pc_amount: pool_pc_after,
+            pool_lp_supply: amm.lp_amount,
+        };
+        set_return_data(&AmmReturnData::Deposit(deposit_data).pack());
+        amm.recent_epoch = epoch;
+        Ok(())
+    }
+
+    pub fn process_withdrawpnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        const ACCOUNT_LEN: usize = 17;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let input_account_len = accounts.len();
+        if input_account_len != ACCOUNT_LEN && input_account_len != ACCOUNT_LEN + 1 {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_open_orders_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let user_pnl_coin_info = next_account_info(account_info_iter)?;
+        let user_pnl_pc_info = next_account_info(account_info_iter)?;
+        let pnl_owner_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+
+        let market_program_info = next_account_info(account_info_iter)?;
+        let market_info = next_account_info(account_info_iter)?;
+        let market_event_queue_info = next_account_info(account_info_iter)?;
+        let _market_coin_vault_info = next_account_info(account_info_iter)?;
+        let _market_pc_vault_info = next_account_info(account_info_iter)?;
+        let _market_vault_signer = next_account_info(account_info_iter)?;
+        let mut _referrer_pc_wallet = None;
+        if input_account_len == ACCOUNT_LEN + 1 {
+            _referrer_pc_wallet = Some(next_account_info(account_info_iter)?);
+            let referrer_pc_token =
+                Self::unpack_token_account(&_referrer_pc_wallet.unwrap(), token_program_info.key)?;
+            check_assert_eq!(
+                referrer_pc_token.owner,
+                config_feature::referrer_pc_wallet::id(),
+                "referrer_pc_owner",
+                AmmError::InvalidOwner
+            );
+        }
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        if amm_info.owner != program_id {
+            return Err(AmmError::InvalidOwner.into());
+        }
+        let enable_orderbook;
+        if AmmStatus::from_u64(amm.status).orderbook_permission() {
+            enable_orderbook = true;
+        } else {
+            enable_orderbook = false;
+        }
+
+        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
+        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
+        }
+        let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
+
+        if !pnl_owner_info.is_signer
+            || (*pnl_owner_info.key != config_feature::amm_owner::ID
+                && *pnl_owner_info.key != amm_config.pnl_owner)
+        {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        // withdrawpnl in all status except Uninitialized
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
+            msg!(&format!("withdrawpnl: status {}", identity(amm.status)));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        check_assert_eq!(
+            *market_info.key,
+            amm.market,
+            "market",
+            AmmError::InvalidMarket
+        );
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        check_assert_eq!(
+            *market_program_info.key,
+            amm.market_program,
+            "market_program",
+            AmmError::InvalidMarketProgram
+        );
+        check_assert_eq!(
+            *amm_open_orders_info.key,
+            amm.open_orders,
+            "open_orders",
+            AmmError::InvalidOpenOrders
+        );
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let coin_vault_token = Self::coin_vault_token(
+            &amm,
+            amm_coin_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let pc_vault_token = Self::pc_vault_token(
+            &amm,
+            amm_pc_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.token_program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.token_program.key)?;
+        let user_pnl_coin =
+            Self::unpack_token_account(&user_pnl_coin_info, coin_vault_token.token_program.key)?;
+        let user_pnl_pc =
+            Self::unpack_token_account(&user_pnl_pc_info, pc_vault_token.token_program.key)?;
+        let mut target_orders =
+            TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+        if amm_coin_vault.mint != amm.coin_vault_mint || user_pnl_coin.mint != amm.coin_vault_mint {
+            return Err(AmmError::InvalidCoinMint.into());
+        }
+        if amm_pc_vault.mint != amm.pc_vault_mint || user_pnl_pc.mint != amm.pc_vault_mint {
+            return Err(AmmError::InvalidPCMint.into());
+        }
+
+        // calc the remaining total_pc & total_coin
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) = if enable_orderbook {
+            check_assert_eq!(
+                *market_info.key,
+                amm.market,
+                "market",
+                AmmError::InvalidMarket
+            );
+            check_assert_eq!(
+                *amm_open_orders_info.key,
+                amm.open_orders,
+                "open_orders",
+                AmmError::InvalidOpenOrders
+            );
+            let (market_state, open_orders) = Self::load_serum_market_order(
                 market_info,
                 amm_open_orders_info,
                 amm_authority_info,
@@ -1844,21 +3023,19 @@ impl Processor {
             && amm.state_data.need_take_pnl_pc <= amm_pc_vault.amount
         {
             // coin & pc is enough, transfer directly
-            Invokers::token_transfer_with_authority(
-                token_program_info.clone(),
-                amm_coin_vault_info.clone(),
-                user_pnl_coin_info.clone(),
-                amm_authority_info.clone(),
-                AUTHORITY_AMM,
+            Self::token_transfer_from_vault(
+                &coin_vault_token,
+                amm_coin_vault_info,
+                user_pnl_coin_info,
+                amm_authority_info,
                 amm.nonce as u8,
                 amm.state_data.need_take_pnl_coin,
             )?;
-            Invokers::token_transfer_with_authority(
-                token_program_info.clone(),
-                amm_pc_vault_info.clone(),
-                user_pnl_pc_info.clone(),
-                amm_authority_info.clone(),
-                AUTHORITY_AMM,
+            Self::token_transfer_from_vault(
+                &pc_vault_token,
+                amm_pc_vault_info,
+                user_pnl_pc_info,
+                amm_authority_info,
                 amm.nonce as u8,
                 amm.state_data.need_take_pnl_pc,
             )?;
@@ -1877,18 +3054,351 @@ impl Processor {
         Ok(())
     }
 
+    /// Processes an [WithdrawProtocolFee](enum.Instruction.html).
+    pub fn process_withdraw_protocol_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        const ACCOUNT_LEN: usize = 9;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        if accounts.len() != ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let user_coin_info = next_account_info(account_info_iter)?;
+        let user_pc_info = next_account_info(account_info_iter)?;
+        let pnl_owner_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
+        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
+        }
+        let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
+        if !pnl_owner_info.is_signer
+            || (*pnl_owner_info.key != config_feature::amm_owner::ID
+                && *pnl_owner_info.key != amm_config.pnl_owner)
+        {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
+            msg!(&format!(
+                "withdraw_protocol_fee: status {}",
+                identity(amm.status)
+            ));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        let coin_vault_token = Self::coin_vault_token(
+            &amm,
+            amm_coin_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let pc_vault_token = Self::pc_vault_token(
+            &amm,
+            amm_pc_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.token_program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.token_program.key)?;
+        let user_coin =
+            Self::unpack_token_account(&user_coin_info, coin_vault_token.token_program.key)?;
+        let user_pc = Self::unpack_token_account(&user_pc_info, pc_vault_token.token_program.key)?;
+        if user_coin.mint != amm.coin_vault_mint {
+            return Err(AmmError::InvalidCoinMint.into());
+        }
+        if user_pc.mint != amm.pc_vault_mint {
+            return Err(AmmError::InvalidPCMint.into());
+        }
+
+        // the fees of an orderbook pool may be in the orderbook, what is left stays claimable
+        let coin_amount = amm.state_data.protocol_fee_coin.min(amm_coin_vault.amount);
+        let pc_amount = amm.state_data.protocol_fee_pc.min(amm_pc_vault.amount);
+        msg!(arrform!(
+            LOG_SIZE,
+            "withdraw_protocol_fee coin:{}, pc:{}, protocol_fee_coin:{}, protocol_fee_pc:{}",
+            coin_amount,
+            pc_amount,
+            identity(amm.state_data.protocol_fee_coin),
+            identity(amm.state_data.protocol_fee_pc)
+        )
+        .as_str());
+        if coin_amount != 0 {
+            Self::token_transfer_from_vault(
+                &coin_vault_token,
+                amm_coin_vault_info,
+                user_coin_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                coin_amount,
+            )?;
+        }
+        if pc_amount != 0 {
+            Self::token_transfer_from_vault(
+                &pc_vault_token,
+                amm_pc_vault_info,
+                user_pc_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                pc_amount,
+            )?;
+        }
+        amm.state_data.protocol_fee_coin -= coin_amount;
+        amm.state_data.protocol_fee_pc -= pc_amount;
+        amm.recent_epoch = Clock::get()?.epoch;
+
+        Ok(())
+    }
+
     /// Processes an [Withdraw](enum.Instruction.html).
     pub fn process_withdraw(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         withdraw: WithdrawInstruction,
     ) -> ProgramResult {
+        Self::withdraw_liquidity(program_id, accounts, withdraw, WithdrawOut::Both)
+    }
+
+    /// Withdraws exactly amount_out of the base side, burning the lp it needs rounded up.
+    pub fn process_withdraw_base_out(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        withdraw: WithdrawInstructionBaseOut,
+    ) -> ProgramResult {
+        Self::withdraw_liquidity(
+            program_id,
+            accounts,
+            WithdrawInstruction {
+                amount: withdraw.max_lp_amount,
+                min_coin_amount: None,
+                min_pc_amount: None,
+                deadline: withdraw.deadline,
+            },
+            WithdrawOut::BaseOut(withdraw),
+        )
+    }
+
+    /// Withdraws like `process_withdraw`, then swaps the side the user doesn't take into the
+    /// other through the pool, so only coin or only pc leaves the vaults.
+    pub fn process_zap_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        zap: ZapWithdrawInstruction,
+    ) -> ProgramResult {
+        Self::withdraw_liquidity(
+            program_id,
+            accounts,
+            WithdrawInstruction {
+                amount: zap.amount,
+                min_coin_amount: None,
+                min_pc_amount: None,
+                deadline: zap.deadline,
+            },
+            WithdrawOut::Zap {
+                min_amount_out: zap.min_amount_out,
+            },
+        )
+    }
+
+    /// Amounts of a zap withdraw of `coin_amount` and `pc_amount`, the side the user doesn't
+    /// take being swapped against the pool left after the withdraw.
+    #[allow(clippy::too_many_arguments)]
+    pub fn zap_withdraw_amounts(
+        amm: &AmmInfo,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        coin_amount: u64,
+        pc_amount: u64,
+        swap_direction: SwapDirection,
+        destination_transfer_fee_config: Option<&TransferFeeConfig>,
+        epoch: u64,
+        unix_timestamp: u64,
+    ) -> Result<ZapWithdrawAmounts, ProgramError> {
+        let pool_pc_after_withdraw = total_pc_without_take_pnl
+            .checked_sub(pc_amount)
+            .ok_or(AmmError::CheckedSubOverflow)?;
+        let pool_coin_after_withdraw = total_coin_without_take_pnl
+            .checked_sub(coin_amount)
+            .ok_or(AmmError::CheckedSubOverflow)?;
+        let (swap_amount_in, withdraw_amount_out) = match swap_direction {
+            SwapDirection::Coin2PC => (coin_amount, pc_amount),
+            SwapDirection::PC2Coin => (pc_amount, coin_amount),
+        };
+        // the withdrawn side is swapped inside the pool, only the final transfer pays a transfer fee
+        let swap = Self::swap_amounts(
+            amm,
+            pool_pc_after_withdraw,
+            pool_coin_after_withdraw,
+            swap_direction,
+            SwapAmount::BaseIn(swap_amount_in),
+            0,
+            None,
+            None,
+            epoch,
+            unix_timestamp,
+        )?;
+        let amount_out = withdraw_amount_out
+            .checked_add(swap.amount_out)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        let amount_out_received = Self::amount_received_after_transfer_fee(
+            destination_transfer_fee_config,
+            amount_out,
+            epoch,
+        )?;
+        Ok(ZapWithdrawAmounts {
+            pool_pc_after_withdraw,
+            pool_coin_after_withdraw,
+            swap,
+            amount_out,
+            amount_out_received,
+        })
+    }
+
+    /// Lp to burn for exactly `base_out.amount_out` of its base side, rounded up in favor of the
+    /// pool, and the coin and pc it pays out: exactly amount_out of the base side, the other
+    /// side at the pool ratio of that lp, rounded down.
+    pub fn withdraw_base_out_amounts(
+        lp_amount: u64,
+        total_coin_without_take_pnl: u64,
+        total_pc_without_take_pnl: u64,
+        base_out: &WithdrawInstructionBaseOut,
+    ) -> Result<(u64, u64, u64), ProgramError> {
+        let (total_base, total_other) = match base_out.base_side {
+            0 => (total_coin_without_take_pnl, total_pc_without_take_pnl),
+            1 => (total_pc_without_take_pnl, total_coin_without_take_pnl),
+            _ => return Err(AmmError::InvalidInput.into()),
+        };
+        // amount = amount_out / total_base_amount * lp_mint.supply, rounded up in favor of the pool
+        let withdraw_lp_amount = InvariantPool {
+            token_input: base_out.amount_out,
+            token_total: total_base,
+        }
+        .exchange_token_to_pool(lp_amount, RoundDirection::Ceiling)
+        .ok_or(AmmError::CalculationExRateFailure)?;
+        if withdraw_lp_amount > base_out.max_lp_amount {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        // the rounded up lp covers at least amount_out, pay out exactly that
+        let other_amount = InvariantPool {
+            token_input: withdraw_lp_amount,
+            token_total: lp_amount,
+        }
+        .exchange_pool_to_token(total_other, RoundDirection::Floor)
+        .ok_or(AmmError::CalculationExRateFailure)?;
+        Ok(match base_out.base_side {
+            0 => (withdraw_lp_amount, base_out.amount_out, other_amount),
+            _ => (withdraw_lp_amount, other_amount, base_out.amount_out),
+        })
+    }
+
+    /// Checks the mint of the lp account a deposit credits and returns its owner, the recipient.
+    /// As before, the signer needn't own it, its owner is only read for the log.
+    pub fn deposit_recipient(
+        user_dest_lp: &spl_token::state::Account,
+        lp_mint: &Pubkey,
+    ) -> Result<Pubkey, AmmError> {
+        if user_dest_lp.mint != *lp_mint {
+            return Err(AmmError::InvalidTokenLP);
+        }
+        Ok(user_dest_lp.owner)
+    }
+
+    /// Checks the mints of the coin and pc accounts a withdraw credits and returns their owners,
+    /// the recipients. As before, the signer needn't own them, and each side may be credited to
+    /// an account of a different owner.
+    pub fn withdraw_recipients(
+        user_dest_coin: &spl_token::state::Account,
+        user_dest_pc: &spl_token::state::Account,
+        amm_coin_vault: &spl_token::state::Account,
+        amm_pc_vault: &spl_token::state::Account,
+    ) -> Result<(Pubkey, Pubkey), AmmError> {
+        if user_dest_coin.mint != amm_coin_vault.mint {
+            return Err(AmmError::InvalidCoinMint);
+        }
+        if user_dest_pc.mint != amm_pc_vault.mint {
+            return Err(AmmError::InvalidPCMint);
+        }
+        Ok((user_dest_coin.owner, user_dest_pc.owner))
+    }
+
+    /// Return data of a withdraw burning lp_amount and sending coin_amount_out and pc_amount_out
+    /// from the vaults, pool_lp_supply being the lp supply after the burn.
+    pub fn withdraw_return_data(
+        lp_amount: u64,
+        coin_amount_out: u64,
+        pc_amount_out: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        pool_lp_supply: u64,
+    ) -> Result<WithdrawReturnData, ProgramError> {
+        Ok(WithdrawReturnData {
+            lp_amount,
+            coin_amount: coin_amount_out,
+            pc_amount: pc_amount_out,
+            pool_coin_amount: total_coin_without_take_pnl
+                .checked_sub(coin_amount_out)
+                .ok_or(AmmError::CheckedSubOverflow)?,
+            pool_pc_amount: total_pc_without_take_pnl
+                .checked_sub(pc_amount_out)
+                .ok_or(AmmError::CheckedSubOverflow)?,
+            pool_lp_supply,
+        })
+    }
+
+    /// Withdraws withdraw.amount of lp and pays out the vault tokens as `out` asks,
+    /// withdraw.amount being the lp cap of a base out withdraw.
+    fn withdraw_liquidity(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        withdraw: WithdrawInstruction,
+        out: WithdrawOut,
+    ) -> ProgramResult {
+        Self::check_deadline(withdraw.deadline)?;
         const ACCOUNT_LEN: usize = 20;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let zap = matches!(out, WithdrawOut::Zap { .. });
+        // a zap withdraw credits a single destination account and takes no padding accounts
+        let account_len = if zap { ACCOUNT_LEN - 1 } else { ACCOUNT_LEN };
         let input_account_len = accounts.len();
-        if input_account_len != ACCOUNT_LEN
-            && input_account_len != ACCOUNT_LEN + 1
-            && input_account_len != ACCOUNT_LEN + 2
-            && input_account_len != ACCOUNT_LEN + 3
+        let has_padding =
+            !zap && (input_account_len == ACCOUNT_LEN + 2 || input_account_len == ACCOUNT_LEN + 3);
+        if input_account_len != account_len && input_account_len != account_len + 1 && !has_padding
         {
             return Err(AmmError::WrongAccountsNumber.into());
         }
@@ -1902,7 +3412,7 @@ impl Processor {
         let amm_lp_mint_info = next_account_info(account_info_iter)?;
         let amm_coin_vault_info = next_account_info(account_info_iter)?;
         let amm_pc_vault_info = next_account_info(account_info_iter)?;
-        if input_account_len == ACCOUNT_LEN + 2 || input_account_len == ACCOUNT_LEN + 3 {
+        if has_padding {
             let _padding_account_info1 = next_account_info(account_info_iter)?;
             let _padding_account_info2 = next_account_info(account_info_iter)?;
         }
@@ -1915,7 +3425,12 @@ impl Processor {
 
         let user_source_lp_info = next_account_info(account_info_iter)?;
         let user_dest_coin_info = next_account_info(account_info_iter)?;
-        let user_dest_pc_info = next_account_info(account_info_iter)?;
+        // the single destination of a zap withdraw holds either mint, it is checked as both
+        let user_dest_pc_info = if zap {
+            user_dest_coin_info
+        } else {
+            next_account_info(account_info_iter)?
+        };
         let source_lp_owner_info = next_account_info(account_info_iter)?;
 
         let market_event_q_info = next_account_info(account_info_iter)?;
@@ -1923,7 +3438,7 @@ impl Processor {
         let market_asks_info = next_account_info(account_info_iter)?;
 
         let mut referrer_pc_wallet = None;
-        if input_account_len == ACCOUNT_LEN + 1 || input_account_len == ACCOUNT_LEN + 3 {
+        if input_account_len == account_len + 1 || input_account_len == ACCOUNT_LEN + 3 {
             referrer_pc_wallet = Some(next_account_info(account_info_iter)?);
             if *referrer_pc_wallet.unwrap().key != Pubkey::default() {
                 let referrer_pc_token = Self::unpack_token_account(
@@ -1946,12 +3461,26 @@ impl Processor {
             return Err(AmmError::InvalidSignAccount.into());
         }
         let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
         let mut target_orders =
             TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
 
         if !AmmStatus::from_u64(amm.status).withdraw_permission() {
             return Err(AmmError::InvalidStatus.into());
         }
+        // the swap leg of a zap withdraw needs the pool to be tradable
+        if zap {
+            if !AmmStatus::from_u64(amm.status).swap_permission() {
+                return Err(AmmError::InvalidStatus.into());
+            }
+            if amm.status == AmmStatus::WaitingTrade.into_u64()
+                && (Clock::get()?.unix_timestamp as u64) < amm.state_data.pool_open_time
+            {
+                return Err(AmmError::InvalidStatus.into());
+            }
+        }
         if *amm_authority_info.key
             != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
         {
@@ -1992,12 +3521,54 @@ impl Processor {
             AmmError::InvalidPoolMint
         );
 
+        let coin_vault_token = Self::coin_vault_token(
+            &amm,
+            amm_coin_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let pc_vault_token = Self::pc_vault_token(
+            &amm,
+            amm_pc_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
         let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-        let user_dest_coin =
-            Self::unpack_token_account(&user_dest_coin_info, spl_token_program_id)?;
-        let user_dest_pc = Self::unpack_token_account(&user_dest_pc_info, spl_token_program_id)?;
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.token_program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.token_program.key)?;
+        let ((coin_recipient, pc_recipient), zap_direction) = if zap {
+            let user_dest = Self::unpack_token_account(
+                &user_dest_coin_info,
+                Self::token_program_id(user_dest_coin_info)?,
+            )?;
+            // the user takes the side matching the destination mint, the other side is swapped into it
+            let swap_direction = if user_dest.mint == amm_coin_vault.mint {
+                SwapDirection::PC2Coin
+            } else if user_dest.mint == amm_pc_vault.mint {
+                SwapDirection::Coin2PC
+            } else {
+                return Err(AmmError::InvalidUserToken.into());
+            };
+            // a zap credits a single account
+            ((user_dest.owner, user_dest.owner), Some(swap_direction))
+        } else {
+            let user_dest_coin = Self::unpack_token_account(
+                &user_dest_coin_info,
+                coin_vault_token.token_program.key,
+            )?;
+            let user_dest_pc =
+                Self::unpack_token_account(&user_dest_pc_info, pc_vault_token.token_program.key)?;
+            (
+                Self::withdraw_recipients(
+                    &user_dest_coin,
+                    &user_dest_pc,
+                    &amm_coin_vault,
+                    &amm_pc_vault,
+                )?,
+                None,
+            )
+        };
 
         let lp_mint = Self::unpack_mint(&amm_lp_mint_info, spl_token_program_id)?;
         let user_source_lp =
@@ -2005,11 +3576,13 @@ impl Processor {
         if user_source_lp.mint != *amm_lp_mint_info.key {
             return Err(AmmError::InvalidTokenLP.into());
         }
-        if withdraw.amount > user_source_lp.amount {
-            return Err(AmmError::InsufficientFunds.into());
-        }
-        if withdraw.amount > lp_mint.supply || withdraw.amount >= amm.lp_amount {
-            return Err(AmmError::NotAllowZeroLP.into());
+        if !matches!(out, WithdrawOut::BaseOut(_)) {
+            if withdraw.amount > user_source_lp.amount {
+                return Err(AmmError::InsufficientFunds.into());
+            }
+            if withdraw.amount > lp_mint.supply || withdraw.amount >= amm.lp_amount {
+                return Err(AmmError::NotAllowZeroLP.into());
+            }
         }
         let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) = if enable_orderbook {
             // check account
@@ -2094,14 +3667,11 @@ impl Processor {
                 amm.nonce as u8,
             )?;
 
-            if identity(market_state.coin_mint) != amm_coin_vault.mint.to_aligned_bytes()
-                || identity(market_state.coin_mint) != user_dest_coin.mint.to_aligned_bytes()
-            {
+            // the destination mints were checked against the vault mints
+            if identity(market_state.coin_mint) != amm_coin_vault.mint.to_aligned_bytes() {
                 return Err(AmmError::InvalidCoinMint.into());
             }
-            if identity(market_state.pc_mint) != amm_pc_vault.mint.to_aligned_bytes()
-                || identity(market_state.pc_mint) != user_dest_pc.mint.to_aligned_bytes()
-            {
+            if identity(market_state.pc_mint) != amm_pc_vault.mint.to_aligned_bytes() {
                 return Err(AmmError::InvalidPCMint.into());
             }
             Calculator::calc_total_without_take_pnl(
@@ -2132,6 +3702,12 @@ impl Processor {
             amm.sys_decimal_value,
         );
 
+        let unix_timestamp = Clock::get()?.unix_timestamp as u64;
+        amm.update_price_cumulative(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            unix_timestamp,
+        );
         // calc and update pnl
         let mut delta_x: u128 = 0;
         let mut delta_y: u128 = 0;
@@ -2146,78 +3722,170 @@ impl Processor {
             )?;
         }
 
-        // coin_amount / total_coin_amount = amount / lp_mint.supply => coin_amount = total_coin_amount * amount / pool_mint.supply
-        let invariant = InvariantPool {
-            token_input: withdraw.amount,
-            token_total: amm.lp_amount,
+        let (withdraw_amount, coin_amount, pc_amount) = match out {
+            WithdrawOut::BaseOut(base_out) => {
+                let (lp_amount, coin_amount, pc_amount) = Self::withdraw_base_out_amounts(
+                    amm.lp_amount,
+                    total_coin_without_take_pnl,
+                    total_pc_without_take_pnl,
+                    &base_out,
+                )?;
+                if lp_amount > user_source_lp.amount {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                if lp_amount > lp_mint.supply || lp_amount >= amm.lp_amount {
+                    return Err(AmmError::NotAllowZeroLP.into());
+                }
+                (lp_amount, coin_amount, pc_amount)
+            }
+            _ => {
+                // coin_amount / total_coin_amount = amount / lp_mint.supply => coin_amount = total_coin_amount * amount / pool_mint.supply
+                let invariant = InvariantPool {
+                    token_input: withdraw.amount,
+                    token_total: amm.lp_amount,
+                };
+                let coin_amount = invariant
+                    .exchange_pool_to_token(total_coin_without_take_pnl, RoundDirection::Floor)
+                    .ok_or(AmmError::CalculationExRateFailure)?;
+                let pc_amount = invariant
+                    .exchange_pool_to_token(total_pc_without_take_pnl, RoundDirection::Floor)
+                    .ok_or(AmmError::CalculationExRateFailure)?;
+                (withdraw.amount, coin_amount, pc_amount)
+            }
         };
-        let coin_amount = invariant
-            .exchange_pool_to_token(total_coin_without_take_pnl, RoundDirection::Floor)
-            .ok_or(AmmError::CalculationExRateFailure)?;
-        let pc_amount = invariant
-            .exchange_pool_to_token(total_pc_without_take_pnl, RoundDirection::Floor)
-            .ok_or(AmmError::CalculationExRateFailure)?;
-
-        encode_ray_log(WithdrawLog {
-            log_type: LogType::Withdraw.into_u8(),
-            withdraw_lp: withdraw.amount,
-            user_lp: user_source_lp.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            pool_lp: amm.lp_amount,
-            calc_pnl_x: target_orders.calc_pnl_x,
-            calc_pnl_y: target_orders.calc_pnl_y,
-            out_coin: coin_amount,
-            out_pc: pc_amount,
-        });
-        if withdraw.amount == 0 || coin_amount == 0 || pc_amount == 0 {
-            return Err(AmmError::InvalidInput.into());
-        }
 
-        if coin_amount < amm_coin_vault.amount && pc_amount < amm_pc_vault.amount {
-            if withdraw.min_coin_amount.is_some() && withdraw.min_pc_amount.is_some() {
-                if withdraw.min_coin_amount.unwrap() > coin_amount
-                    || withdraw.min_pc_amount.unwrap() > pc_amount
-                {
+        let epoch = Clock::get()?.epoch;
+        // the vault tokens leaving the pool
+        let (coin_amount_out, pc_amount_out) = match (out, zap_direction) {
+            (WithdrawOut::Zap { min_amount_out }, Some(swap_direction)) => {
+                if withdraw_amount == 0 || coin_amount == 0 || pc_amount == 0 {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                let (dest_vault_token, dest_vault_info, dest_vault_amount) = match swap_direction {
+                    SwapDirection::Coin2PC => {
+                        (&pc_vault_token, amm_pc_vault_info, amm_pc_vault.amount)
+                    }
+                    SwapDirection::PC2Coin => (
+                        &coin_vault_token,
+                        amm_coin_vault_info,
+                        amm_coin_vault.amount,
+                    ),
+                };
+                let amounts = Self::zap_withdraw_amounts(
+                    &amm,
+                    total_pc_without_take_pnl,
+                    total_coin_without_take_pnl,
+                    coin_amount,
+                    pc_amount,
+                    swap_direction,
+                    Self::vault_transfer_fee_config(dest_vault_token)?.as_ref(),
+                    epoch,
+                    unix_timestamp,
+                )?;
+                encode_ray_log(ZapWithdrawLog {
+                    log_type: LogType::ZapWithdraw.into_u8(),
+                    withdraw_lp: withdraw_amount,
+                    min_out: min_amount_out,
+                    direction: swap_direction as u64,
+                    user_lp: user_source_lp.amount,
+                    pool_coin: total_coin_without_take_pnl,
+                    pool_pc: total_pc_without_take_pnl,
+                    pool_lp: amm.lp_amount,
+                    calc_pnl_x: target_orders.calc_pnl_x,
+                    calc_pnl_y: target_orders.calc_pnl_y,
+                    out_coin: coin_amount,
+                    out_pc: pc_amount,
+                    swap_out: amounts.swap.amount_out,
+                    out_amount: amounts.amount_out_received,
+                    recipient: coin_recipient,
+                });
+                if amounts.amount_out_received < min_amount_out {
                     return Err(AmmError::ExceededSlippage.into());
                 }
+                if amounts.amount_out >= dest_vault_amount {
+                    // calc error
+                    return Err(AmmError::TakePnlError.into());
+                }
+                Self::token_transfer_from_vault(
+                    dest_vault_token,
+                    dest_vault_info,
+                    user_dest_coin_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    amounts.amount_out,
+                )?;
+                Self::record_swap(
+                    &mut amm,
+                    amounts.pool_pc_after_withdraw,
+                    amounts.pool_coin_after_withdraw,
+                    swap_direction,
+                    &amounts.swap,
+                    unix_timestamp,
+                );
+                // only the destination side of the pool shrinks, the swapped side stays in the vault
+                match swap_direction {
+                    SwapDirection::Coin2PC => (0, amounts.amount_out),
+                    SwapDirection::PC2Coin => (amounts.amount_out, 0),
+                }
             }
-            Invokers::token_transfer_with_authority(
-                token_program_info.clone(),
-                amm_coin_vault_info.clone(),
-                user_dest_coin_info.clone(),
-                amm_authority_info.clone(),
-                AUTHORITY_AMM,
-                amm.nonce as u8,
-                coin_amount,
-            )?;
-            Invokers::token_transfer_with_authority(
-                token_program_info.clone(),
-                amm_pc_vault_info.clone(),
-                user_dest_pc_info.clone(),
-                amm_authority_info.clone(),
-                AUTHORITY_AMM,
-                amm.nonce as u8,
-                pc_amount,
-            )?;
-            Invokers::token_burn(
-                token_program_info.clone(),
-                user_source_lp_info.clone(),
-                amm_lp_mint_info.clone(),
-                source_lp_owner_info.clone(),
-                withdraw.amount,
-            )?;
-            amm.lp_amount = amm.lp_amount.checked_sub(withdraw.amount).unwrap();
-        } else {
-            // calc error
-            return Err(AmmError::TakePnlError.into());
-        }
-
-        // step4: update target_orders.calc_pnl_x & target_orders.calc_pnl_y
-        target_orders.calc_pnl_x = x1
-            .checked_sub(Calculator::normalize_decimal_v2(
-                pc_amount,
-                amm.pc_decimals,
+            _ => {
+                encode_ray_log(WithdrawLog {
+                    log_type: LogType::Withdraw.into_u8(),
+                    withdraw_lp: withdraw_amount,
+                    user_lp: user_source_lp.amount,
+                    pool_coin: total_coin_without_take_pnl,
+                    pool_pc: total_pc_without_take_pnl,
+                    pool_lp: amm.lp_amount,
+                    calc_pnl_x: target_orders.calc_pnl_x,
+                    calc_pnl_y: target_orders.calc_pnl_y,
+                    out_coin: coin_amount,
+                    out_pc: pc_amount,
+                    coin_recipient,
+                    pc_recipient,
+                });
+                if withdraw_amount == 0 || coin_amount == 0 || pc_amount == 0 {
+                    return Err(AmmError::InvalidInput.into());
+                }
+
+                if coin_amount < amm_coin_vault.amount && pc_amount < amm_pc_vault.amount {
+                    Self::check_withdraw_slippage(&withdraw, coin_amount, pc_amount)?;
+                    Self::token_transfer_from_vault(
+                        &coin_vault_token,
+                        amm_coin_vault_info,
+                        user_dest_coin_info,
+                        amm_authority_info,
+                        amm.nonce as u8,
+                        coin_amount,
+                    )?;
+                    Self::token_transfer_from_vault(
+                        &pc_vault_token,
+                        amm_pc_vault_info,
+                        user_dest_pc_info,
+                        amm_authority_info,
+                        amm.nonce as u8,
+                        pc_amount,
+                    )?;
+                } else {
+                    // calc error
+                    return Err(AmmError::TakePnlError.into());
+                }
+                (coin_amount, pc_amount)
+            }
+        };
+        Invokers::token_burn(
+            token_program_info.clone(),
+            user_source_lp_info.clone(),
+            amm_lp_mint_info.clone(),
+            source_lp_owner_info.clone(),
+            withdraw_amount,
+        )?;
+        amm.lp_amount = amm.lp_amount.checked_sub(withdraw_amount).unwrap();
+
+        // step4: update target_orders.calc_pnl_x & target_orders.calc_pnl_y
+        target_orders.calc_pnl_x = x1
+            .checked_sub(Calculator::normalize_decimal_v2(
+                pc_amount_out,
+                amm.pc_decimals,
                 amm.sys_decimal_value,
             ))
             .unwrap()
@@ -2226,7 +3894,7 @@ impl Processor {
             .as_u128();
         target_orders.calc_pnl_y = y1
             .checked_sub(Calculator::normalize_decimal_v2(
-                coin_amount,
+                coin_amount_out,
                 amm.coin_decimals,
                 amm.sys_decimal_value,
             ))
@@ -2234,16 +3902,31 @@ impl Processor {
             .checked_sub(U128::from(delta_y))
             .unwrap()
             .as_u128();
-        amm.recent_epoch = Clock::get()?.epoch;
+        let withdraw_data = Self::withdraw_return_data(
+            withdraw_amount,
+            coin_amount_out,
+            pc_amount_out,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            amm.lp_amount,
+        )?;
+        set_return_data(&AmmReturnData::Withdraw(withdraw_data).pack());
+        amm.recent_epoch = epoch;
         Ok(())
     }
 
-    pub fn process_swap_base_in(
+    /// Names the accounts of a SwapBaseIn or SwapBaseOut. The market accounts sit in the
+    /// middle of the instruction accounts, they are only read for a pool with orderbook
+    /// permission.
+    fn swap_v1_accounts<'a, 'b: 'a>(
         program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        swap: SwapInstructionBaseIn,
-    ) -> ProgramResult {
+        accounts: &'a [AccountInfo<'b>],
+        referral_fee_bps: u16,
+    ) -> Result<SwapAccounts<'a, 'b>, ProgramError> {
         const ACCOUNT_LEN: usize = 17;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, referral_info) = Self::split_referral_account(accounts, referral_fee_bps)?;
         let input_account_len = accounts.len();
         if input_account_len != ACCOUNT_LEN && input_account_len != ACCOUNT_LEN + 1 {
             return Err(AmmError::WrongAccountsNumber.into());
@@ -2261,14 +3944,6 @@ impl Processor {
         let amm_pc_vault_info = next_account_info(account_info_iter)?;
 
         let market_program_info = next_account_info(account_info_iter)?;
-
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        let enable_orderbook;
-        if AmmStatus::from_u64(amm.status).orderbook_permission() {
-            enable_orderbook = true;
-        } else {
-            enable_orderbook = false;
-        }
         let market_info = next_account_info(account_info_iter)?;
         let market_bids_info = next_account_info(account_info_iter)?;
         let market_asks_info = next_account_info(account_info_iter)?;
@@ -2280,6 +3955,198 @@ impl Processor {
         let user_source_info = next_account_info(account_info_iter)?;
         let user_destination_info = next_account_info(account_info_iter)?;
         let user_source_owner = next_account_info(account_info_iter)?;
+        Ok(SwapAccounts {
+            token_program_info,
+            amm_info,
+            amm_authority_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            user_source_info,
+            user_destination_info,
+            user_source_owner,
+            market_accounts: Some(SwapMarketAccounts {
+                amm_open_orders_info,
+                market_program_info,
+                market_info,
+                market_bids_info,
+                market_asks_info,
+                market_event_queue_info,
+                market_coin_vault_info,
+                market_pc_vault_info,
+                market_vault_signer,
+            }),
+            token_2022_accounts,
+            observation_info,
+            referral_info,
+        })
+    }
+
+    /// Names the accounts of a SwapBaseInV2 or SwapBaseOutV2, which take no market accounts.
+    fn swap_v2_accounts<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+        referral_fee_bps: u16,
+    ) -> Result<SwapAccounts<'a, 'b>, ProgramError> {
+        const ACCOUNT_LEN: usize = 8;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, referral_info) = Self::split_referral_account(accounts, referral_fee_bps)?;
+        if accounts.len() != ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let account_info_iter = &mut accounts.iter();
+        Ok(SwapAccounts {
+            token_program_info: next_account_info(account_info_iter)?,
+            amm_info: next_account_info(account_info_iter)?,
+            amm_authority_info: next_account_info(account_info_iter)?,
+            amm_coin_vault_info: next_account_info(account_info_iter)?,
+            amm_pc_vault_info: next_account_info(account_info_iter)?,
+            user_source_info: next_account_info(account_info_iter)?,
+            user_destination_info: next_account_info(account_info_iter)?,
+            user_source_owner: next_account_info(account_info_iter)?,
+            market_accounts: None,
+            token_2022_accounts,
+            observation_info,
+            referral_info,
+        })
+    }
+
+    /// Names the accounts of a SwapBaseInLimit, whose `SWAP_MARKET_ACCOUNT_LEN` market accounts
+    /// follow the user accounts and are only given for an orderbook pool.
+    fn swap_limit_accounts<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<SwapAccounts<'a, 'b>, ProgramError> {
+        const ACCOUNT_LEN: usize = 8;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let market_accounts = match accounts.len() {
+            ACCOUNT_LEN => None,
+            len if len == ACCOUNT_LEN + SWAP_MARKET_ACCOUNT_LEN => {
+                Some(Self::swap_market_accounts(&accounts[ACCOUNT_LEN..]))
+            }
+            _ => return Err(AmmError::WrongAccountsNumber.into()),
+        };
+        let account_info_iter = &mut accounts.iter();
+        Ok(SwapAccounts {
+            token_program_info: next_account_info(account_info_iter)?,
+            amm_info: next_account_info(account_info_iter)?,
+            amm_authority_info: next_account_info(account_info_iter)?,
+            amm_coin_vault_info: next_account_info(account_info_iter)?,
+            amm_pc_vault_info: next_account_info(account_info_iter)?,
+            user_source_info: next_account_info(account_info_iter)?,
+            user_destination_info: next_account_info(account_info_iter)?,
+            user_source_owner: next_account_info(account_info_iter)?,
+            market_accounts,
+            token_2022_accounts,
+            observation_info,
+            // a limit swap carries no referral account
+            referral_info: None,
+        })
+    }
+
+    /// Refuses a pool with orderbook permission for the swaps that take no market accounts.
+    /// The status transitions of `process_swap` keep the orderbook permission, WaitingTrade
+    /// becomes SwapOnly and OrderBookOnly becomes Initialized, so the status before them decides.
+    fn check_swap_without_orderbook(status: u64) -> ProgramResult {
+        if AmmStatus::from_u64(status).orderbook_permission() {
+            msg!(&format!(
+                "swap without market accounts: orderbook status {}",
+                status
+            ));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        Ok(())
+    }
+
+    pub fn process_swap_base_in(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseIn,
+    ) -> ProgramResult {
+        Self::check_deadline(swap.deadline)?;
+        let swap_accounts = Self::swap_v1_accounts(program_id, accounts, swap.referral_fee_bps)?;
+        let swap_data = Self::process_swap(program_id, &swap_accounts, SwapRequest::BaseIn(swap))?;
+        set_return_data(&AmmReturnData::Swap(swap_data).pack());
+        Ok(())
+    }
+
+    pub fn process_swap_base_out(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseOut,
+    ) -> ProgramResult {
+        Self::check_deadline(swap.deadline)?;
+        let swap_accounts = Self::swap_v1_accounts(program_id, accounts, swap.referral_fee_bps)?;
+        let swap_data = Self::process_swap(program_id, &swap_accounts, SwapRequest::BaseOut(swap))?;
+        set_return_data(&AmmReturnData::Swap(swap_data).pack());
+        Ok(())
+    }
+
+    /// Swaps an exact input against a pool that has no orderbook, without the market accounts.
+    pub fn process_swap_base_in_v2(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseIn,
+    ) -> ProgramResult {
+        Self::check_deadline(swap.deadline)?;
+        let swap_accounts = Self::swap_v2_accounts(program_id, accounts, swap.referral_fee_bps)?;
+        Self::check_swap_without_orderbook(
+            AmmInfo::load_checked(swap_accounts.amm_info, program_id)?.status,
+        )?;
+        let swap_data = Self::process_swap(program_id, &swap_accounts, SwapRequest::BaseIn(swap))?;
+        set_return_data(&AmmReturnData::Swap(swap_data).pack());
+        Ok(())
+    }
+
+    /// Swaps for an exact output against a pool that has no orderbook, without the market accounts.
+    pub fn process_swap_base_out_v2(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseOut,
+    ) -> ProgramResult {
+        Self::check_deadline(swap.deadline)?;
+        let swap_accounts = Self::swap_v2_accounts(program_id, accounts, swap.referral_fee_bps)?;
+        Self::check_swap_without_orderbook(
+            AmmInfo::load_checked(swap_accounts.amm_info, program_id)?.status,
+        )?;
+        let swap_data = Self::process_swap(program_id, &swap_accounts, SwapRequest::BaseOut(swap))?;
+        set_return_data(&AmmReturnData::Swap(swap_data).pack());
+        Ok(())
+    }
+
+    /// Swaps against one pool, shared by every swap instruction and each route hop.
+    /// The pool status transitions, the account checks, the price cumulative, the logs,
+    /// the orderbook cancels and settles, the transfers and the swap records all live here.
+    /// A pool with orderbook permission requires the market accounts, the pools without
+    /// one never read them. Returns the swap result, left for the caller to report.
+    pub fn process_swap<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        accounts: &SwapAccounts<'a, 'b>,
+        request: SwapRequest,
+    ) -> Result<SwapReturnData, ProgramError> {
+        let SwapAccounts {
+            token_program_info,
+            amm_info,
+            amm_authority_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            user_source_info,
+            user_destination_info,
+            user_source_owner,
+            ..
+        } = *accounts;
+        let name = match request {
+            SwapRequest::BaseIn(_) => "swap_base_in",
+            SwapRequest::BaseOut(_) => "swap_base_out",
+            SwapRequest::BaseInLimit(_) => "swap_base_in_limit",
+            SwapRequest::RouteHop(_) => "route_swap_hop",
+        };
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
         if !user_source_owner.is_signer {
             return Err(AmmError::InvalidSignAccount.into());
         }
@@ -2289,7 +4156,6 @@ impl Processor {
             "spl_token_program",
             AmmError::InvalidSplTokenProgram
         );
-        let spl_token_program_id = token_program_info.key;
         if *amm_authority_info.key
             != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
         {
@@ -2307,7 +4173,6 @@ impl Processor {
             "pc_vault",
             AmmError::InvalidPCVault
         );
-
         if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
             return Err(AmmError::InvalidUserToken.into());
         }
@@ -2317,22 +4182,41 @@ impl Processor {
             return Err(AmmError::InvalidUserToken.into());
         }
 
+        let coin_vault_token = Self::coin_vault_token(
+            &amm,
+            amm_coin_vault_info,
+            token_program_info,
+            accounts.token_2022_accounts.as_ref(),
+        )?;
+        let pc_vault_token = Self::pc_vault_token(
+            &amm,
+            amm_pc_vault_info,
+            token_program_info,
+            accounts.token_2022_accounts.as_ref(),
+        )?;
         let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-
-        let user_source = Self::unpack_token_account(&user_source_info, spl_token_program_id)?;
-        let user_destination =
-            Self::unpack_token_account(&user_destination_info, spl_token_program_id)?;
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.token_program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.token_program.key)?;
+
+        // the user accounts are matched against the vault mints below
+        let user_source = Self::unpack_token_account(
+            &user_source_info,
+            Self::token_program_id(user_source_info)?,
+        )?;
+        let user_destination = Self::unpack_token_account(
+            &user_destination_info,
+            Self::token_program_id(user_destination_info)?,
+        )?;
 
         if !AmmStatus::from_u64(amm.status).swap_permission() {
-            msg!(&format!("swap_base_in: status {}", identity(amm.status)));
+            msg!(&format!("{}: status {}", name, identity(amm.status)));
             let clock = Clock::get()?;
             if amm.status == AmmStatus::OrderBookOnly.into_u64()
                 && (clock.unix_timestamp as u64) >= amm.state_data.orderbook_to_init_time
             {
                 amm.status = AmmStatus::Initialized.into_u64();
-                msg!("swap_base_in: OrderBook to Initialized");
+                msg!(&format!("{}: OrderBook to Initialized", name));
             } else {
                 return Err(AmmError::InvalidStatus.into());
             }
@@ -2342,7 +4226,7 @@ impl Processor {
                 return Err(AmmError::InvalidStatus.into());
             } else {
                 amm.status = AmmStatus::SwapOnly.into_u64();
-                msg!("swap_base_in: WaitingTrade to SwapOnly");
+                msg!(&format!("{}: WaitingTrade to SwapOnly", name));
             }
         }
 
@@ -2350,45 +4234,30 @@ impl Processor {
         let total_coin_without_take_pnl;
         let mut bids: Vec<LeafNode> = Vec::new();
         let mut asks: Vec<LeafNode> = Vec::new();
-        if enable_orderbook {
-            check_assert_eq!(
-                *amm_open_orders_info.key,
-                amm.open_orders,
-                "open_orders",
-                AmmError::InvalidOpenOrders
-            );
-            check_assert_eq!(
-                *market_program_info.key,
-                amm.market_program,
-                "market_program",
-                AmmError::InvalidMarketProgram
-            );
-            check_assert_eq!(
-                *market_info.key,
-                amm.market,
-                "market",
-                AmmError::InvalidMarket
-            );
-            let (market_state, open_orders) = Processor::load_serum_market_order(
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
+        let market_accounts = if AmmStatus::from_u64(amm.status).orderbook_permission() {
+            let market = match accounts.market_accounts.as_ref() {
+                Some(market) => market,
+                None => {
+                    msg!(&format!(
+                        "{}: missing market accounts of an orderbook pool",
+                        name
+                    ));
+                    return Err(AmmError::WrongAccountsNumber.into());
+                }
+            };
+            (
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                bids,
+                asks,
+            ) = Self::load_swap_market(
                 &amm,
-                false,
+                market,
+                amm_authority_info,
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
             )?;
-            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
-            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
-            (bids, asks) = Self::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
-            (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-                Calculator::calc_total_without_take_pnl(
-                    amm_pc_vault.amount,
-                    amm_coin_vault.amount,
-                    &open_orders,
-                    &amm,
-                    &market_state,
-                    &market_event_queue_info,
-                    &amm_open_orders_info,
-                )?;
+            Some(market)
         } else {
             (total_pc_without_take_pnl, total_coin_without_take_pnl) =
                 Calculator::calc_total_without_take_pnl_no_orderbook(
@@ -2396,7 +4265,24 @@ impl Processor {
                     amm_coin_vault.amount,
                     &amm,
                 )?;
-        }
+            None
+        };
+        let clock = Clock::get()?;
+        let epoch = clock.epoch;
+        let unix_timestamp = clock.unix_timestamp as u64;
+        amm.update_price_cumulative(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            unix_timestamp,
+        );
+        Self::write_observation(
+            program_id,
+            amm_info,
+            &amm,
+            accounts.observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        )?;
 
         let swap_direction;
         if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
@@ -2408,660 +4294,1127 @@ impl Processor {
         } else {
             return Err(AmmError::InvalidUserToken.into());
         }
-        if user_source.amount < swap.amount_in {
+        Self::check_referral_account(&amm, accounts.referral_info, &user_source.mint)?;
+        let (source_vault_token, destination_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (&coin_vault_token, &pc_vault_token),
+            SwapDirection::PC2Coin => (&pc_vault_token, &coin_vault_token),
+        };
+        let source_transfer_fee_config = Self::vault_transfer_fee_config(source_vault_token)?;
+        let destination_transfer_fee_config =
+            Self::vault_transfer_fee_config(destination_vault_token)?;
+        let swap_amounts = |amount, referral_fee_bps| {
+            Self::swap_amounts(
+                &amm,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                amount,
+                referral_fee_bps,
+                source_transfer_fee_config.as_ref(),
+                destination_transfer_fee_config.as_ref(),
+                epoch,
+                unix_timestamp,
+            )
+        };
+        let swap_base_in_log = |amount_in, minimum_out, out_amount, referral_fee| {
             encode_ray_log(SwapBaseInLog {
                 log_type: LogType::SwapBaseIn.into_u8(),
-                amount_in: swap.amount_in,
-                minimum_out: swap.minimum_amount_out,
+                amount_in,
+                minimum_out,
                 direction: swap_direction as u64,
                 user_source: user_source.amount,
                 pool_coin: total_coin_without_take_pnl,
                 pool_pc: total_pc_without_take_pnl,
-                out_amount: 0,
-            });
-            return Err(AmmError::InsufficientFunds.into());
-        }
-        let swap_fee = U128::from(swap.amount_in)
-            .checked_mul(amm.fees.swap_fee_numerator.into())
-            .unwrap()
-            .checked_ceil_div(amm.fees.swap_fee_denominator.into())
-            .unwrap()
-            .0;
-        let swap_in_after_deduct_fee = U128::from(swap.amount_in).checked_sub(swap_fee).unwrap();
-        let swap_amount_out = Calculator::swap_token_amount_base_in(
-            swap_in_after_deduct_fee,
-            total_pc_without_take_pnl.into(),
-            total_coin_without_take_pnl.into(),
-            swap_direction,
-        )
-        .as_u64();
-        encode_ray_log(SwapBaseInLog {
-            log_type: LogType::SwapBaseIn.into_u8(),
-            amount_in: swap.amount_in,
-            minimum_out: swap.minimum_amount_out,
-            direction: swap_direction as u64,
-            user_source: user_source.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            out_amount: swap_amount_out,
-        });
-        if swap_amount_out < swap.minimum_amount_out {
-            return Err(AmmError::ExceededSlippage.into());
-        }
-        if swap_amount_out == 0 || swap.amount_in == 0 {
-            return Err(AmmError::InvalidInput.into());
-        }
+                out_amount,
+                referral_fee,
+            })
+        };
 
-        match swap_direction {
-            SwapDirection::Coin2PC => {
-                if swap_amount_out >= total_pc_without_take_pnl {
+        let amounts = match request {
+            SwapRequest::BaseIn(swap) => {
+                let amounts =
+                    swap_amounts(SwapAmount::BaseIn(swap.amount_in), swap.referral_fee_bps)?;
+                if user_source.amount < swap.amount_in {
+                    swap_base_in_log(
+                        swap.amount_in,
+                        swap.minimum_amount_out,
+                        0,
+                        amounts.referral_fee,
+                    );
                     return Err(AmmError::InsufficientFunds.into());
                 }
-
-                if enable_orderbook {
-                    // coin -> pc, need cancel buy order
-                    if !bids.is_empty() {
-                        let mut amm_order_ids_vec = Vec::new();
-                        let mut order_ids = [0u64; 8];
-                        let mut count = 0;
-                        // fetch cancel order ids{
-                        for order in bids.into_iter() {
-                            order_ids[count] = order.client_order_id();
-                            count += 1;
-                            if count == 8 {
-                                amm_order_ids_vec.push(order_ids);
-                                order_ids = [0u64; 8];
-                                count = 0;
-                            }
-                        }
-                        if count != 0 {
-                            amm_order_ids_vec.push(order_ids);
-                        }
-                        for ids in amm_order_ids_vec.iter() {
-                            Invokers::invoke_dex_cancel_orders_by_client_order_ids(
-                                market_program_info.clone(),
-                                market_info.clone(),
-                                market_bids_info.clone(),
-                                market_asks_info.clone(),
-                                amm_open_orders_info.clone(),
-                                amm_authority_info.clone(),
-                                market_event_queue_info.clone(),
-                                AUTHORITY_AMM,
-                                amm.nonce as u8,
-                                *ids,
-                            )?;
-                        }
-                    }
-
-                    if swap_amount_out > amm_pc_vault.amount {
-                        // need settle funds
-                        Invokers::invoke_dex_settle_funds(
-                            market_program_info.clone(),
-                            market_info.clone(),
-                            amm_open_orders_info.clone(),
-                            amm_authority_info.clone(),
-                            market_coin_vault_info.clone(),
-                            market_pc_vault_info.clone(),
-                            amm_coin_vault_info.clone(),
-                            amm_pc_vault_info.clone(),
-                            market_vault_signer.clone(),
-                            token_program_info.clone(),
-                            Some(&amm_pc_vault_info.clone()),
-                            AUTHORITY_AMM,
-                            amm.nonce as u8,
-                        )?;
-                    }
-                }
-                // deposit source coin to amm_coin_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_source_owner.clone(),
+                swap_base_in_log(
                     swap.amount_in,
-                )?;
-                // withdraw amm_pc_vault to destination pc
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap_amount_out,
-                )?;
-                // update state_data data
+                    swap.minimum_amount_out,
+                    amounts.amount_out_received,
+                    amounts.referral_fee,
+                );
+                if amounts.amount_out_received < swap.minimum_amount_out {
+                    return Err(AmmError::ExceededSlippage.into());
+                }
+                if amounts.amount_out_received == 0 || amounts.amount_in_received == 0 {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                amounts
+            }
+            SwapRequest::BaseOut(swap) => {
+                let amounts =
+                    swap_amounts(SwapAmount::BaseOut(swap.amount_out), swap.referral_fee_bps)?;
+                encode_ray_log(SwapBaseOutLog {
+                    log_type: LogType::SwapBaseOut.into_u8(),
+                    max_in: swap.max_amount_in,
+                    amount_out: swap.amount_out,
+                    direction: swap_direction as u64,
+                    user_source: user_source.amount,
+                    pool_coin: total_coin_without_take_pnl,
+                    pool_pc: total_pc_without_take_pnl,
+                    deduct_in: amounts.amount_in,
+                    referral_fee: amounts.referral_fee,
+                    user_deduct_in: amounts.user_amount_in,
+                });
+                if user_source.amount < amounts.user_amount_in {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                if swap.max_amount_in < amounts.user_amount_in {
+                    return Err(AmmError::ExceededSlippage.into());
+                }
+                if amounts.amount_in_received == 0 || swap.amount_out == 0 {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                amounts
+            }
+            SwapRequest::BaseInLimit(swap) => {
+                if swap.limit_price == 0 {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                if user_source.amount < swap.amount_in {
+                    swap_base_in_log(swap.amount_in, swap.minimum_amount_out, 0, 0);
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                let swap_amount_in = Self::limit_swap_amount_in(
+                    &amm,
+                    total_pc_without_take_pnl,
+                    total_coin_without_take_pnl,
+                    swap_direction,
+                    swap.amount_in,
+                    swap.limit_price,
+                    source_transfer_fee_config.as_ref(),
+                    epoch,
+                    unix_timestamp,
+                )?;
+                if swap_amount_in == 0 {
+                    msg!("swap_base_in_limit: pool price at or beyond the limit");
+                    return Err(AmmError::ExceededSlippage.into());
+                }
+                let amounts = swap_amounts(SwapAmount::BaseIn(swap_amount_in), 0)?;
+                // amount_in logs the filled part only
+                swap_base_in_log(
+                    swap_amount_in,
+                    swap.minimum_amount_out,
+                    amounts.amount_out_received,
+                    0,
+                );
+                if amounts.amount_out_received < swap.minimum_amount_out {
+                    return Err(AmmError::ExceededSlippage.into());
+                }
+                if amounts.amount_out_received == 0 || amounts.amount_in_received == 0 {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                amounts
+            }
+            SwapRequest::RouteHop(amount_in) => {
+                if user_source.amount < amount_in {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                let amounts = swap_amounts(SwapAmount::BaseIn(amount_in), 0)?;
+                if amounts.amount_out_received == 0 || amounts.amount_in_received == 0 {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                amounts
+            }
+        };
+
+        if let Some(market) = market_accounts {
+            let (orders, destination_vault_amount) = match swap_direction {
+                // coin -> pc, need cancel buy order
+                SwapDirection::Coin2PC => (&bids, amm_pc_vault.amount),
+                // pc -> coin, need cancel sell order
+                SwapDirection::PC2Coin => (&asks, amm_coin_vault.amount),
+            };
+            Self::cancel_swap_side_orders(
+                &amm,
+                market,
+                token_program_info,
+                amm_authority_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                orders,
+                amounts.amount_out > destination_vault_amount,
+            )?;
+        }
+        Self::transfer_swap_amounts(
+            &amounts,
+            swap_direction,
+            &coin_vault_token,
+            amm_coin_vault_info,
+            &pc_vault_token,
+            amm_pc_vault_info,
+            user_source_info,
+            user_destination_info,
+            user_source_owner,
+            amm_authority_info,
+            amm.nonce as u8,
+            accounts.referral_info,
+        )?;
+        Self::record_swap(
+            &mut amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            &amounts,
+            unix_timestamp,
+        );
+        amm.recent_epoch = epoch;
+
+        Ok(Self::swap_return_data(
+            &amm,
+            swap_direction,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            &amounts,
+        ))
+    }
+
+    /// Part of `amount_in` a SwapBaseInLimit fills: the largest input, transfer fee included,
+    /// whose swap leaves the pool price within `limit_price`. The rest stays with the user.
+    #[allow(clippy::too_many_arguments)]
+    pub fn limit_swap_amount_in(
+        amm: &AmmInfo,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+        limit_price: u64,
+        source_transfer_fee_config: Option<&TransferFeeConfig>,
+        epoch: u64,
+        unix_timestamp: u64,
+    ) -> Result<u64, ProgramError> {
+        let amount_in_received_max = Calculator::swap_amount_in_to_price(
+            limit_price,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            amm,
+            amm.swap_fee_numerator_at(unix_timestamp),
+            swap_direction,
+        );
+        if amount_in_received_max == 0 {
+            return Ok(0);
+        }
+        let mut amount_in_max = Self::amount_required_before_transfer_fee(
+            source_transfer_fee_config,
+            amount_in_received_max,
+            epoch,
+        )?;
+        // the inverse transfer fee can round up past the amount the pool may receive
+        if Self::amount_received_after_transfer_fee(
+            source_transfer_fee_config,
+            amount_in_max,
+            epoch,
+        )? > amount_in_received_max
+        {
+            amount_in_max -= 1;
+        }
+        Ok(std::cmp::min(amount_in, amount_in_max))
+    }
+
+    /// Swaps up to an exact input, stopping at the limit price. An orderbook pool takes its
+    /// market accounts after the user accounts.
+    pub fn process_swap_base_in_limit(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseInLimit,
+    ) -> ProgramResult {
+        Self::check_deadline(swap.deadline)?;
+        let swap_accounts = Self::swap_limit_accounts(program_id, accounts)?;
+        let swap_data =
+            Self::process_swap(program_id, &swap_accounts, SwapRequest::BaseInLimit(swap))?;
+        set_return_data(&AmmReturnData::Swap(swap_data).pack());
+        Ok(())
+    }
+
+    /// Computes the amounts of a swap against the pool totals, shared by every swap path.
+    /// The transfer fee configs are those of the source and destination vault mints, None
+    /// for a leg whose tokens are already in the pool.
+    #[allow(clippy::too_many_arguments)]
+    pub fn swap_amounts(
+        amm: &AmmInfo,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount: SwapAmount,
+        referral_fee_bps: u16,
+        source_transfer_fee_config: Option<&TransferFeeConfig>,
+        destination_transfer_fee_config: Option<&TransferFeeConfig>,
+        epoch: u64,
+        unix_timestamp: u64,
+    ) -> Result<SwapAmounts, ProgramError> {
+        let total_out = match swap_direction {
+            SwapDirection::Coin2PC => total_pc_without_take_pnl,
+            SwapDirection::PC2Coin => total_coin_without_take_pnl,
+        };
+        let swap_fee_numerator = amm.swap_fee_numerator_at(unix_timestamp);
+        let amounts = match amount {
+            SwapAmount::BaseIn(user_amount_in) => {
+                // the referral fee is taken from the input, the pool swaps the rest
+                let referral_fee = Self::referral_fee(user_amount_in, referral_fee_bps);
+                let amount_in = user_amount_in
+                    .checked_sub(referral_fee)
+                    .ok_or(AmmError::CheckedSubOverflow)?;
+                // token-2022 transfer fees are withheld from both legs, the curve only sees what moves
+                let amount_in_received = Self::amount_received_after_transfer_fee(
+                    source_transfer_fee_config,
+                    amount_in,
+                    epoch,
+                )?;
+                let swap_fee = Calculator::swap_fee_amount_in(
+                    amount_in_received,
+                    swap_fee_numerator,
+                    amm.fees.swap_fee_denominator,
+                );
+                let swap_in_after_deduct_fee = U128::from(amount_in_received)
+                    .checked_sub(swap_fee)
+                    .ok_or(AmmError::CheckedSubOverflow)?;
+                let amount_out = Calculator::swap_token_amount_base_in(
+                    swap_in_after_deduct_fee,
+                    total_pc_without_take_pnl.into(),
+                    total_coin_without_take_pnl.into(),
+                    swap_direction,
+                );
+                let amount_out = Calculator::to_u64(amount_out.as_u128())?;
+                SwapAmounts {
+                    user_amount_in,
+                    amount_in,
+                    amount_in_received,
+                    amount_out,
+                    amount_out_received: Self::amount_received_after_transfer_fee(
+                        destination_transfer_fee_config,
+                        amount_out,
+                        epoch,
+                    )?,
+                    swap_fee: swap_fee.as_u64(),
+                    referral_fee,
+                }
+            }
+            SwapAmount::BaseOut(amount_out_received) => {
+                let amount_out = Self::amount_required_before_transfer_fee(
+                    destination_transfer_fee_config,
+                    amount_out_received,
+                    epoch,
+                )?;
+                if amount_out >= total_out {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                let swap_in_before_add_fee = Calculator::swap_token_amount_base_out(
+                    amount_out.into(),
+                    total_pc_without_take_pnl.into(),
+                    total_coin_without_take_pnl.into(),
+                    swap_direction,
+                );
+                // swap_in_after_add_fee * (1 - 0.0025) = swap_in_before_add_fee
+                // swap_in_after_add_fee = swap_in_before_add_fee / (1 - 0.0025)
+                let amount_in_received = Calculator::swap_amount_in_add_fee(
+                    swap_in_before_add_fee,
+                    swap_fee_numerator,
+                    amm.fees.swap_fee_denominator,
+                );
+                let swap_fee = amount_in_received
+                    .checked_sub(swap_in_before_add_fee.as_u64())
+                    .ok_or(AmmError::CheckedSubOverflow)?;
+                let amount_in = Self::amount_required_before_transfer_fee(
+                    source_transfer_fee_config,
+                    amount_in_received,
+                    epoch,
+                )?;
+                // the referral fee is charged on top of what the pool requires
+                let referral_fee = Self::referral_fee(amount_in, referral_fee_bps);
+                SwapAmounts {
+                    user_amount_in: amount_in
+                        .checked_add(referral_fee)
+                        .ok_or(AmmError::CheckedAddOverflow)?,
+                    amount_in,
+                    amount_in_received,
+                    amount_out,
+                    amount_out_received,
+                    swap_fee,
+                    referral_fee,
+                }
+            }
+        };
+        if amounts.amount_out >= total_out {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        Ok(amounts)
+    }
+
+    /// Moves the swap input to the source vault, the output to the user and the referral fee.
+    #[allow(clippy::too_many_arguments)]
+    fn transfer_swap_amounts<'a>(
+        amounts: &SwapAmounts,
+        swap_direction: SwapDirection,
+        coin_vault_token: &VaultToken<'_, 'a>,
+        amm_coin_vault_info: &AccountInfo<'a>,
+        pc_vault_token: &VaultToken<'_, 'a>,
+        amm_pc_vault_info: &AccountInfo<'a>,
+        user_source_info: &AccountInfo<'a>,
+        user_destination_info: &AccountInfo<'a>,
+        user_source_owner: &AccountInfo<'a>,
+        amm_authority_info: &AccountInfo<'a>,
+        nonce: u8,
+        referral_info: Option<&AccountInfo<'a>>,
+    ) -> ProgramResult {
+        let (
+            source_vault_token,
+            source_vault_info,
+            destination_vault_token,
+            destination_vault_info,
+        ) = match swap_direction {
+            SwapDirection::Coin2PC => (
+                coin_vault_token,
+                amm_coin_vault_info,
+                pc_vault_token,
+                amm_pc_vault_info,
+            ),
+            SwapDirection::PC2Coin => (
+                pc_vault_token,
+                amm_pc_vault_info,
+                coin_vault_token,
+                amm_coin_vault_info,
+            ),
+        };
+        Self::token_transfer_to_vault(
+            source_vault_token,
+            user_source_info,
+            source_vault_info,
+            user_source_owner,
+            amounts.amount_in,
+        )?;
+        Self::token_transfer_from_vault(
+            destination_vault_token,
+            destination_vault_info,
+            user_destination_info,
+            amm_authority_info,
+            nonce,
+            amounts.amount_out,
+        )?;
+        if let Some(referral_info) = referral_info {
+            Self::token_transfer_to_vault(
+                source_vault_token,
+                user_source_info,
+                referral_info,
+                user_source_owner,
+                amounts.referral_fee,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Adds a swap to the pool volumes and fees, the protocol fee and the volatility accumulator.
+    pub fn record_swap(
+        amm: &mut AmmInfo,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amounts: &SwapAmounts,
+        unix_timestamp: u64,
+    ) {
+        match swap_direction {
+            SwapDirection::Coin2PC => {
                 amm.state_data.swap_coin_in_amount = amm
                     .state_data
                     .swap_coin_in_amount
-                    .checked_add(swap.amount_in.into())
+                    .checked_add(amounts.amount_in_received.into())
                     .unwrap();
                 amm.state_data.swap_pc_out_amount = amm
                     .state_data
                     .swap_pc_out_amount
-                    .checked_add(swap_amount_out.into())
+                    .checked_add(amounts.amount_out.into())
                     .unwrap();
                 // charge coin as swap fee
                 amm.state_data.swap_acc_coin_fee = amm
                     .state_data
                     .swap_acc_coin_fee
-                    .checked_add(swap_fee.as_u64())
+                    .checked_add(amounts.swap_fee)
                     .unwrap();
             }
             SwapDirection::PC2Coin => {
-                if swap_amount_out >= total_coin_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-
-                if enable_orderbook {
-                    // pc -> coin, need cancel sell order
-                    if !asks.is_empty() {
-                        let mut amm_order_ids_vec = Vec::new();
-                        let mut order_ids = [0u64; 8];
-                        let mut count = 0;
-                        // fetch cancel order ids{
-                        for order in asks.into_iter() {
-                            order_ids[count] = order.client_order_id();
-                            count += 1;
-                            if count == 8 {
-                                amm_order_ids_vec.push(order_ids);
-                                order_ids = [0u64; 8];
-                                count = 0;
-                            }
-                        }
-                        if count != 0 {
-                            amm_order_ids_vec.push(order_ids);
-                        }
-                        for ids in amm_order_ids_vec.iter() {
-                            Invokers::invoke_dex_cancel_orders_by_client_order_ids(
-                                market_program_info.clone(),
-                                market_info.clone(),
-                                market_bids_info.clone(),
-                                market_asks_info.clone(),
-                                amm_open_orders_info.clone(),
-                                amm_authority_info.clone(),
-                                market_event_queue_info.clone(),
-                                AUTHORITY_AMM,
-                                amm.nonce as u8,
-                                *ids,
-                            )?;
-                        }
-                    }
-
-                    if swap_amount_out > amm_coin_vault.amount {
-                        Invokers::invoke_dex_settle_funds(
-                            market_program_info.clone(),
-                            market_info.clone(),
-                            amm_open_orders_info.clone(),
-                            amm_authority_info.clone(),
-                            market_coin_vault_info.clone(),
-                            market_pc_vault_info.clone(),
-                            amm_coin_vault_info.clone(),
-                            amm_pc_vault_info.clone(),
-                            market_vault_signer.clone(),
-                            token_program_info.clone(),
-                            Some(&amm_pc_vault_info.clone()),
-                            AUTHORITY_AMM,
-                            amm.nonce as u8,
-                        )?;
-                    }
-                }
-                // deposit source pc to amm_pc_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap.amount_in,
-                )?;
-                // withdraw amm_coin_vault to destination coin
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap_amount_out,
-                )?;
-                // update state_data data
                 amm.state_data.swap_pc_in_amount = amm
                     .state_data
                     .swap_pc_in_amount
-                    .checked_add(swap.amount_in.into())
+                    .checked_add(amounts.amount_in_received.into())
                     .unwrap();
                 amm.state_data.swap_coin_out_amount = amm
                     .state_data
                     .swap_coin_out_amount
-                    .checked_add(swap_amount_out.into())
+                    .checked_add(amounts.amount_out.into())
                     .unwrap();
                 // charge pc as swap fee
                 amm.state_data.swap_acc_pc_fee = amm
                     .state_data
                     .swap_acc_pc_fee
-                    .checked_add(swap_fee.as_u64())
+                    .checked_add(amounts.swap_fee)
                     .unwrap();
             }
         };
-        amm.recent_epoch = Clock::get()?.epoch;
-
-        Ok(())
+        Self::accrue_protocol_fee(amm, swap_direction, amounts.swap_fee);
+        Self::update_swap_volatility(
+            amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            amounts.amount_in_received,
+            amounts.amount_out,
+            unix_timestamp,
+        );
     }
 
-    pub fn process_swap_base_out(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        swap: SwapInstructionBaseOut,
-    ) -> ProgramResult {
-        const SWAP_ACCOUNT_NUM: usize = 17;
-        let input_account_len = accounts.len();
-        if input_account_len != SWAP_ACCOUNT_NUM && input_account_len != SWAP_ACCOUNT_NUM + 1 {
-            return Err(AmmError::WrongAccountsNumber.into());
+    /// Sets the protocol share of a swap fee, charged in the swap input token, aside
+    /// for the pnl owner to claim with WithdrawProtocolFee.
+    pub fn accrue_protocol_fee(amm: &mut AmmInfo, swap_direction: SwapDirection, swap_fee: u64) {
+        let protocol_fee = amm.protocol_fee(swap_fee);
+        match swap_direction {
+            SwapDirection::Coin2PC => {
+                amm.state_data.protocol_fee_coin = amm
+                    .state_data
+                    .protocol_fee_coin
+                    .checked_add(protocol_fee)
+                    .unwrap();
+            }
+            SwapDirection::PC2Coin => {
+                amm.state_data.protocol_fee_pc = amm
+                    .state_data
+                    .protocol_fee_pc
+                    .checked_add(protocol_fee)
+                    .unwrap();
+            }
         }
-        let account_info_iter = &mut accounts.iter();
-        let token_program_info = next_account_info(account_info_iter)?;
+    }
 
-        let amm_info = next_account_info(account_info_iter)?;
-        let amm_authority_info = next_account_info(account_info_iter)?;
-        let amm_open_orders_info = next_account_info(account_info_iter)?;
-        if input_account_len == SWAP_ACCOUNT_NUM + 1 {
-            let _amm_target_orders_info = next_account_info(account_info_iter)?;
+    /// Swap result reported as the return data, with the pool reserves the swap leaves.
+    pub fn swap_return_data(
+        amm: &AmmInfo,
+        swap_direction: SwapDirection,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        amounts: &SwapAmounts,
+    ) -> SwapReturnData {
+        // the protocol fee is set aside for the pnl owner, out of the pool reserves
+        let protocol_fee = amm.protocol_fee(amounts.swap_fee);
+        let (pool_pc_amount, pool_coin_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (
+                total_pc_without_take_pnl.saturating_sub(amounts.amount_out),
+                total_coin_without_take_pnl
+                    .saturating_add(amounts.amount_in_received)
+                    .saturating_sub(protocol_fee),
+            ),
+            SwapDirection::PC2Coin => (
+                total_pc_without_take_pnl
+                    .saturating_add(amounts.amount_in_received)
+                    .saturating_sub(protocol_fee),
+                total_coin_without_take_pnl.saturating_sub(amounts.amount_out),
+            ),
+        };
+        SwapReturnData {
+            direction: swap_direction as u64,
+            amount_in: amounts.user_amount_in,
+            amount_out: amounts.amount_out_received,
+            swap_fee: amounts.swap_fee,
+            protocol_fee,
+            referral_fee: amounts.referral_fee,
+            pool_coin_amount,
+            pool_pc_amount,
         }
-        let amm_coin_vault_info = next_account_info(account_info_iter)?;
-        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+    }
 
-        let market_program_info = next_account_info(account_info_iter)?;
+    /// Adds the price move of a swap to the pool volatility accumulator.
+    fn update_swap_volatility(
+        amm: &mut AmmInfo,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+        amount_out: u64,
+        unix_timestamp: u64,
+    ) {
+        let (total_pc_after, total_coin_after) = match swap_direction {
+            SwapDirection::Coin2PC => (
+                total_pc_without_take_pnl.saturating_sub(amount_out),
+                total_coin_without_take_pnl.saturating_add(amount_in),
+            ),
+            SwapDirection::PC2Coin => (
+                total_pc_without_take_pnl.saturating_add(amount_in),
+                total_coin_without_take_pnl.saturating_sub(amount_out),
+            ),
+        };
+        let price_move_bps = Calculator::price_move_bps(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            total_pc_after,
+            total_coin_after,
+        );
+        amm.update_volatility(price_move_bps, unix_timestamp);
+    }
 
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        let enable_orderbook;
-        if AmmStatus::from_u64(amm.status).orderbook_permission() {
-            enable_orderbook = true;
-        } else {
-            enable_orderbook = false;
+    /// Splits the `RouteSwapBaseIn` hop accounts, each optionally followed by its market
+    /// accounts, recognized by the amm open orders leading them, then by its
+    /// `[spl_token_2022, coin_mint, pc_mint]` accounts.
+    pub fn split_route_hops<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        mut accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Vec<RouteHopAccounts<'a, 'b>>, ProgramError> {
+        let mut hops = Vec::new();
+        while !accounts.is_empty() {
+            if accounts.len() < ROUTE_HOP_ACCOUNT_LEN {
+                return Err(AmmError::WrongAccountsNumber.into());
+            }
+            let (hop, rest) = accounts.split_at(ROUTE_HOP_ACCOUNT_LEN);
+            accounts = rest;
+            let amm_open_orders = AmmInfo::load_checked(&hop[0], program_id)?.open_orders;
+            let mut market_accounts = None;
+            if accounts.len() >= SWAP_MARKET_ACCOUNT_LEN && *accounts[0].key == amm_open_orders {
+                let (market, rest) = accounts.split_at(SWAP_MARKET_ACCOUNT_LEN);
+                accounts = rest;
+                market_accounts = Some(Self::swap_market_accounts(market));
+            }
+            let mut token_2022_accounts = None;
+            if accounts.len() >= TOKEN_2022_ACCOUNT_LEN && *accounts[0].key == spl_token_2022::id()
+            {
+                let (token_2022, rest) = accounts.split_at(TOKEN_2022_ACCOUNT_LEN);
+                accounts = rest;
+                token_2022_accounts = Some(Token2022Accounts {
+                    token_program: &token_2022[0],
+                    coin_mint: &token_2022[1],
+                    pc_mint: &token_2022[2],
+                });
+            }
+            hops.push(RouteHopAccounts {
+                amm_info: &hop[0],
+                amm_authority_info: &hop[1],
+                amm_coin_vault_info: &hop[2],
+                amm_pc_vault_info: &hop[3],
+                user_destination_info: &hop[4],
+                market_accounts,
+                token_2022_accounts,
+            });
         }
+        if hops.is_empty() {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        Ok(hops)
+    }
 
-        let market_info = next_account_info(account_info_iter)?;
-        let market_bids_info = next_account_info(account_info_iter)?;
-        let market_asks_info = next_account_info(account_info_iter)?;
-        let market_event_queue_info = next_account_info(account_info_iter)?;
-        let market_coin_vault_info = next_account_info(account_info_iter)?;
-        let market_pc_vault_info = next_account_info(account_info_iter)?;
-        let market_vault_signer = next_account_info(account_info_iter)?;
-
-        let user_source_info = next_account_info(account_info_iter)?;
-        let user_destination_info = next_account_info(account_info_iter)?;
-        let user_source_owner = next_account_info(account_info_iter)?;
-        if !user_source_owner.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
+    /// Names the `SWAP_MARKET_ACCOUNT_LEN` market accounts of a swap through an orderbook pool.
+    fn swap_market_accounts<'a, 'b: 'a>(
+        market: &'a [AccountInfo<'b>],
+    ) -> SwapMarketAccounts<'a, 'b> {
+        SwapMarketAccounts {
+            amm_open_orders_info: &market[0],
+            market_program_info: &market[1],
+            market_info: &market[2],
+            market_bids_info: &market[3],
+            market_asks_info: &market[4],
+            market_event_queue_info: &market[5],
+            market_coin_vault_info: &market[6],
+            market_pc_vault_info: &market[7],
+            market_vault_signer: &market[8],
         }
+    }
 
+    /// Checks the market accounts of an orderbook pool and loads its amm orders,
+    /// returns the pool amounts without the pnl not yet taken, with the bids and asks.
+    fn load_swap_market<'a, 'b: 'a>(
+        amm: &AmmInfo,
+        market: &SwapMarketAccounts<'a, 'b>,
+        amm_authority_info: &'a AccountInfo<'b>,
+        pc_vault_amount: u64,
+        coin_vault_amount: u64,
+    ) -> Result<(u64, u64, Vec<LeafNode>, Vec<LeafNode>), ProgramError> {
         check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
-        );
-        let spl_token_program_id = token_program_info.key;
-        let authority = Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?;
-        check_assert_eq!(
-            *amm_authority_info.key,
-            authority,
-            "authority",
-            AmmError::InvalidProgramAddress
+            *market.amm_open_orders_info.key,
+            amm.open_orders,
+            "open_orders",
+            AmmError::InvalidOpenOrders
         );
         check_assert_eq!(
-            *amm_coin_vault_info.key,
-            amm.coin_vault,
-            "coin_vault",
-            AmmError::InvalidCoinVault
+            *market.market_program_info.key,
+            amm.market_program,
+            "market_program",
+            AmmError::InvalidMarketProgram
         );
         check_assert_eq!(
-            *amm_pc_vault_info.key,
-            amm.pc_vault,
-            "pc_vault",
-            AmmError::InvalidPCVault
+            *market.market_info.key,
+            amm.market,
+            "market",
+            AmmError::InvalidMarket
         );
+        let (market_state, open_orders) = Processor::load_serum_market_order(
+            market.market_info,
+            market.amm_open_orders_info,
+            amm_authority_info,
+            amm,
+            false,
+        )?;
+        let bids_orders = market_state.load_bids_checked(market.market_bids_info)?;
+        let asks_orders = market_state.load_asks_checked(market.market_asks_info)?;
+        let (bids, asks) = Self::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl(
+                pc_vault_amount,
+                coin_vault_amount,
+                &open_orders,
+                amm,
+                &market_state,
+                market.market_event_queue_info,
+                market.amm_open_orders_info,
+            )?;
+        Ok((
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            bids,
+            asks,
+        ))
+    }
 
-        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
-            return Err(AmmError::InvalidUserToken.into());
+    /// Cancels the amm orders on the side a swap takes from, and settles the open orders
+    /// into the vaults when the destination vault alone can't pay the swap.
+    #[allow(clippy::too_many_arguments)]
+    fn cancel_swap_side_orders<'a, 'b: 'a>(
+        amm: &AmmInfo,
+        market: &SwapMarketAccounts<'a, 'b>,
+        token_program_info: &'a AccountInfo<'b>,
+        amm_authority_info: &'a AccountInfo<'b>,
+        amm_coin_vault_info: &'a AccountInfo<'b>,
+        amm_pc_vault_info: &'a AccountInfo<'b>,
+        orders: &[LeafNode],
+        need_settle: bool,
+    ) -> ProgramResult {
+        for orders in orders.chunks(8) {
+            let mut order_ids = [0u64; 8];
+            for (order_id, order) in order_ids.iter_mut().zip(orders) {
+                *order_id = order.client_order_id();
+            }
+            Invokers::invoke_dex_cancel_orders_by_client_order_ids(
+                market.market_program_info.clone(),
+                market.market_info.clone(),
+                market.market_bids_info.clone(),
+                market.market_asks_info.clone(),
+                market.amm_open_orders_info.clone(),
+                amm_authority_info.clone(),
+                market.market_event_queue_info.clone(),
+                AUTHORITY_AMM,
+                amm.nonce as u8,
+                order_ids,
+            )?;
         }
-        if *user_destination_info.key == amm.pc_vault
-            || *user_destination_info.key == amm.coin_vault
-        {
-            return Err(AmmError::InvalidUserToken.into());
+        if need_settle {
+            Invokers::invoke_dex_settle_funds(
+                market.market_program_info.clone(),
+                market.market_info.clone(),
+                market.amm_open_orders_info.clone(),
+                amm_authority_info.clone(),
+                market.market_coin_vault_info.clone(),
+                market.market_pc_vault_info.clone(),
+                amm_coin_vault_info.clone(),
+                amm_pc_vault_info.clone(),
+                market.market_vault_signer.clone(),
+                token_program_info.clone(),
+                Some(amm_pc_vault_info),
+                AUTHORITY_AMM,
+                amm.nonce as u8,
+            )?;
         }
+        Ok(())
+    }
 
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+    /// Swaps `amount_in` of the user source through one route hop,
+    /// returns the hop result, its amount_out being what the hop destination receives.
+    /// The hop logs nothing, the route logs once with RouteSwapBaseInLog.
+    fn route_swap_hop<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        token_program_info: &'a AccountInfo<'b>,
+        hop: &RouteHopAccounts<'a, 'b>,
+        user_source_info: &'a AccountInfo<'b>,
+        user_source_owner: &'a AccountInfo<'b>,
+        amount_in: u64,
+    ) -> Result<SwapReturnData, ProgramError> {
+        let swap_accounts = SwapAccounts {
+            token_program_info,
+            amm_info: hop.amm_info,
+            amm_authority_info: hop.amm_authority_info,
+            amm_coin_vault_info: hop.amm_coin_vault_info,
+            amm_pc_vault_info: hop.amm_pc_vault_info,
+            user_source_info,
+            user_destination_info: hop.user_destination_info,
+            user_source_owner,
+            market_accounts: hop.market_accounts,
+            token_2022_accounts: hop.token_2022_accounts,
+            observation_info: None,
+            referral_info: None,
+        };
+        Self::process_swap(program_id, &swap_accounts, SwapRequest::RouteHop(amount_in))
+    }
 
-        let user_source = Self::unpack_token_account(&user_source_info, spl_token_program_id)?;
-        let user_destination =
-            Self::unpack_token_account(&user_destination_info, spl_token_program_id)?;
+    /// Swaps an exact input through several pools, checking the slippage on the final leg only.
+    pub fn process_route_swap_base_in(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseIn,
+    ) -> ProgramResult {
+        Self::check_deadline(swap.deadline)?;
+        const ACCOUNT_LEN: usize = 3;
+        if accounts.len() < ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let (accounts, hop_accounts) = accounts.split_at(ACCOUNT_LEN);
+        let token_program_info = &accounts[0];
+        let user_source_owner = &accounts[1];
+        let user_source_info = &accounts[2];
+        let hops = Self::split_route_hops(program_id, hop_accounts)?;
 
-        if !AmmStatus::from_u64(amm.status).swap_permission() {
-            msg!(&format!("swap_base_out: status {}", identity(amm.status)));
-            let clock = Clock::get()?;
-            if amm.status == AmmStatus::OrderBookOnly.into_u64()
-                && (clock.unix_timestamp as u64) >= amm.state_data.orderbook_to_init_time
+        if !user_source_owner.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        let user_source = Self::unpack_token_account(
+            &user_source_info,
+            Self::token_program_id(user_source_info)?,
+        )?;
+        if swap.amount_in == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        // hops carry no referral account
+        if swap.referral_fee_bps != 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        // each hop spends what the previous hop destination received
+        let mut hop_source_info = user_source_info;
+        let mut hop_amount_in = swap.amount_in;
+        let mut hops_data = Vec::with_capacity(hops.len());
+        for hop in hops.iter() {
+            let hop_data = Self::route_swap_hop(
+                program_id,
+                token_program_info,
+                hop,
+                hop_source_info,
+                user_source_owner,
+                hop_amount_in,
+            )?;
+            hop_amount_in = hop_data.amount_out;
+            hop_source_info = hop.user_destination_info;
+            hops_data.push(hop_data);
+        }
+        encode_ray_log(RouteSwapBaseInLog {
+            log_type: LogType::RouteSwapBaseIn.into_u8(),
+            amount_in: swap.amount_in,
+            minimum_out: swap.minimum_amount_out,
+            hops: hops.len() as u64,
+            user_source: user_source.amount,
+            first_amm: *hops[0].amm_info.key,
+            last_amm: *hops[hops.len() - 1].amm_info.key,
+            out_amount: hop_amount_in,
+        });
+        if hop_amount_in < swap.minimum_amount_out {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        set_return_data(&AmmReturnData::RouteSwap(hops_data).pack());
+
+        Ok(())
+    }
+
+    /// Flash loan fee of `amount`, rounded up.
+    fn flash_loan_fee(amm: &AmmInfo, amount: u64) -> Result<u64, ProgramError> {
+        Ok(Calculator::to_u64(
+            U128::from(amount)
+                .checked_mul(amm.flash_loan_fee_numerator.into())
+                .ok_or(AmmError::CheckedMulOverflow)?
+                .checked_ceil_div(TEN_THOUSAND.into())
+                .ok_or(AmmError::CheckedDivOverflow)?
+                .0
+                .as_u128(),
+        )?)
+    }
+
+    /// Requires the current instruction to be top level, followed by a FlashLoanEnd of the amm
+    /// carrying the lent amounts. A cpi caller could otherwise lend without the repayment being
+    /// checked.
+    pub fn check_flash_loan_end(
+        program_id: &Pubkey,
+        amm_key: &Pubkey,
+        instructions_info: &AccountInfo,
+        flash_loan: FlashLoanInstruction,
+    ) -> ProgramResult {
+        let current_index = load_current_index_checked(instructions_info)? as usize;
+        let current = load_instruction_at_checked(current_index, instructions_info)?;
+        if current.program_id != *program_id {
+            return Err(AmmError::InvalidFlashLoan.into());
+        }
+        let mut index = current_index + 1;
+        while let Ok(instruction) = load_instruction_at_checked(index, instructions_info) {
+            if instruction.program_id == *program_id
+                && instruction
+                    .accounts
+                    .first()
+                    .map_or(false, |account| account.pubkey == *amm_key)
             {
-                amm.status = AmmStatus::Initialized.into_u64();
-                msg!("swap_base_out: OrderBook to Initialized");
-            } else {
-                return Err(AmmError::InvalidStatus.into());
-            }
-        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
-            let clock = Clock::get()?;
-            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
-                return Err(AmmError::InvalidStatus.into());
-            } else {
-                amm.status = AmmStatus::SwapOnly.into_u64();
-                msg!("swap_base_out: WaitingTrade to SwapOnly");
+                // the first FlashLoanEnd of the amm closes the loan
+                if let Ok(AmmInstruction::FlashLoanEnd(end)) =
+                    AmmInstruction::unpack(&instruction.data)
+                {
+                    if end == flash_loan {
+                        return Ok(());
+                    }
+                    return Err(AmmError::InvalidFlashLoan.into());
+                }
             }
+            index += 1;
         }
+        Err(AmmError::InvalidFlashLoan.into())
+    }
 
-        let total_pc_without_take_pnl;
-        let total_coin_without_take_pnl;
-        let mut bids: Vec<LeafNode> = Vec::new();
-        let mut asks: Vec<LeafNode> = Vec::new();
-        if enable_orderbook {
-            check_assert_eq!(
-                *amm_open_orders_info.key,
-                amm.open_orders,
-                "open_orders",
-                AmmError::InvalidOpenOrders
-            );
-            check_assert_eq!(
-                *market_program_info.key,
-                amm.market_program,
-                "market_program",
-                AmmError::InvalidMarketProgram
-            );
-            check_assert_eq!(
-                *market_info.key,
-                amm.market,
-                "market",
-                AmmError::InvalidMarket
-            );
-            let (market_state, open_orders) = Processor::load_serum_market_order(
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
-                &amm,
-                false,
-            )?;
-            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
-            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
-            (bids, asks) = Self::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
-            (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-                Calculator::calc_total_without_take_pnl(
-                    amm_pc_vault.amount,
-                    amm_coin_vault.amount,
-                    &open_orders,
-                    &amm,
-                    &market_state,
-                    &market_event_queue_info,
-                    &amm_open_orders_info,
-                )?;
-        } else {
-            (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-                Calculator::calc_total_without_take_pnl_no_orderbook(
-                    amm_pc_vault.amount,
-                    amm_coin_vault.amount,
-                    &amm,
-                )?;
+    /// Lends vault tokens until the FlashLoanEnd of the same transaction.
+    pub fn process_flash_loan_begin(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        flash_loan: FlashLoanInstruction,
+    ) -> ProgramResult {
+        const ACCOUNT_LEN: usize = 8;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        if accounts.len() != ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
         }
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let user_coin_info = next_account_info(account_info_iter)?;
+        let user_pc_info = next_account_info(account_info_iter)?;
+        let instructions_info = next_account_info(account_info_iter)?;
 
-        let swap_direction;
-        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
-            swap_direction = SwapDirection::Coin2PC
-        } else if user_source.mint == amm_pc_vault.mint
-            && user_destination.mint == amm_coin_vault.mint
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
         {
-            swap_direction = SwapDirection::PC2Coin
-        } else {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        check_assert_eq!(
+            *instructions_info.key,
+            instructions::id(),
+            "instructions_sysvar",
+            AmmError::InvalidSysProgramAddress
+        );
+        if *user_coin_info.key == amm.pc_vault || *user_coin_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        if *user_pc_info.key == amm.pc_vault || *user_pc_info.key == amm.coin_vault {
             return Err(AmmError::InvalidUserToken.into());
         }
 
-        let swap_in_before_add_fee = Calculator::swap_token_amount_base_out(
-            swap.amount_out.into(),
-            total_pc_without_take_pnl.into(),
-            total_coin_without_take_pnl.into(),
-            swap_direction,
-        );
-        // swap_in_after_add_fee * (1 - 0.0025) = swap_in_before_add_fee
-        // swap_in_after_add_fee = swap_in_before_add_fee / (1 - 0.0025)
-        let swap_in_after_add_fee = swap_in_before_add_fee
-            .checked_mul(amm.fees.swap_fee_denominator.into())
-            .unwrap()
-            .checked_ceil_div(
-                (amm.fees
-                    .swap_fee_denominator
-                    .checked_sub(amm.fees.swap_fee_numerator)
-                    .unwrap())
-                .into(),
-            )
-            .unwrap()
-            .0
-            .as_u64();
-        let swap_fee = swap_in_after_add_fee
-            .checked_sub(swap_in_before_add_fee.as_u64())
-            .unwrap();
-        encode_ray_log(SwapBaseOutLog {
-            log_type: LogType::SwapBaseOut.into_u8(),
-            max_in: swap.max_amount_in,
-            amount_out: swap.amount_out,
-            direction: swap_direction as u64,
-            user_source: user_source.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            deduct_in: swap_in_after_add_fee,
-        });
-        if user_source.amount < swap_in_after_add_fee {
-            return Err(AmmError::InsufficientFunds.into());
+        let coin_vault_token = Self::coin_vault_token(
+            &amm,
+            amm_coin_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let pc_vault_token = Self::pc_vault_token(
+            &amm,
+            amm_pc_vault_info,
+            token_program_info,
+            token_2022_accounts.as_ref(),
+        )?;
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.token_program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.token_program.key)?;
+
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            msg!(&format!(
+                "flash_loan_begin: status {}",
+                identity(amm.status)
+            ));
+            return Err(AmmError::InvalidStatus.into());
         }
-        if swap.max_amount_in < swap_in_after_add_fee {
-            return Err(AmmError::ExceededSlippage.into());
+        // the orderbook cycle settles and places orders against the vaults
+        if AmmStatus::from_u64(amm.status).orderbook_permission()
+            && amm.state != AmmState::IdleState.into_u64()
+        {
+            msg!(&format!("flash_loan_begin: state {}", identity(amm.state)));
+            return Err(AmmError::InvalidStatus.into());
         }
-        if swap_in_after_add_fee == 0 || swap.amount_out == 0 {
+        if amm.flash_loan_fee_numerator == 0 {
+            return Err(AmmError::InvalidFlashLoan.into());
+        }
+        if flash_loan.coin_amount == 0 && flash_loan.pc_amount == 0 {
             return Err(AmmError::InvalidInput.into());
         }
+        if flash_loan.coin_amount > amm_coin_vault.amount
+            || flash_loan.pc_amount > amm_pc_vault.amount
+        {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        Self::check_flash_loan_end(program_id, amm_info.key, instructions_info, flash_loan)?;
+
+        // credited to the pool statistics by the FlashLoanEnd, once repaid
+        let coin_fee = Self::flash_loan_fee(&amm, flash_loan.coin_amount)?;
+        let pc_fee = Self::flash_loan_fee(&amm, flash_loan.pc_amount)?;
+        if flash_loan.coin_amount != 0 {
+            amm.flash_loan_coin_repay = amm_coin_vault
+                .amount
+                .checked_add(coin_fee)
+                .ok_or(AmmError::CheckedAddOverflow)?;
+            Self::token_transfer_from_vault(
+                &coin_vault_token,
+                amm_coin_vault_info,
+                user_coin_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                flash_loan.coin_amount,
+            )?;
+        }
+        if flash_loan.pc_amount != 0 {
+            amm.flash_loan_pc_repay = amm_pc_vault
+                .amount
+                .checked_add(pc_fee)
+                .ok_or(AmmError::CheckedAddOverflow)?;
+            Self::token_transfer_from_vault(
+                &pc_vault_token,
+                amm_pc_vault_info,
+                user_pc_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                flash_loan.pc_amount,
+            )?;
+        }
+        encode_ray_log(FlashLoanLog {
+            log_type: LogType::FlashLoan.into_u8(),
+            coin_amount: flash_loan.coin_amount,
+            pc_amount: flash_loan.pc_amount,
+            pool_coin: amm_coin_vault.amount,
+            pool_pc: amm_pc_vault.amount,
+            coin_fee,
+            pc_fee,
+        });
+        amm.recent_epoch = Clock::get()?.epoch;
 
-        match swap_direction {
-            SwapDirection::Coin2PC => {
-                if swap.amount_out >= total_pc_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-
-                if enable_orderbook {
-                    // coin -> pc, need cancel buy order
-                    if !bids.is_empty() {
-                        let mut amm_order_ids_vec = Vec::new();
-                        let mut order_ids = [0u64; 8];
-                        let mut count = 0;
-                        // fetch cancel order ids
-                        for order in bids.into_iter() {
-                            order_ids[count] = order.client_order_id();
-                            count += 1;
-                            if count == 8 {
-                                amm_order_ids_vec.push(order_ids);
-                                order_ids = [0u64; 8];
-                                count = 0;
-                            }
-                        }
-                        if count != 0 {
-                            amm_order_ids_vec.push(order_ids);
-                        }
-                        for ids in amm_order_ids_vec.iter() {
-                            Invokers::invoke_dex_cancel_orders_by_client_order_ids(
-                                market_program_info.clone(),
-                                market_info.clone(),
-                                market_bids_info.clone(),
-                                market_asks_info.clone(),
-                                amm_open_orders_info.clone(),
-                                amm_authority_info.clone(),
-                                market_event_queue_info.clone(),
-                                AUTHORITY_AMM,
-                                amm.nonce as u8,
-                                *ids,
-                            )?;
-                        }
-                    }
-                    if swap.amount_out > amm_pc_vault.amount {
-                        // need settle funds
-                        Invokers::invoke_dex_settle_funds(
-                            market_program_info.clone(),
-                            market_info.clone(),
-                            amm_open_orders_info.clone(),
-                            amm_authority_info.clone(),
-                            market_coin_vault_info.clone(),
-                            market_pc_vault_info.clone(),
-                            amm_coin_vault_info.clone(),
-                            amm_pc_vault_info.clone(),
-                            market_vault_signer.clone(),
-                            token_program_info.clone(),
-                            Some(&amm_pc_vault_info.clone()),
-                            AUTHORITY_AMM,
-                            amm.nonce as u8,
-                        )?;
-                    }
-                }
-                // deposit source coin to amm_coin_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap_in_after_add_fee,
-                )?;
-                // withdraw amm_pc_vault to destination pc
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap.amount_out,
-                )?;
-                // update state_data data
-                amm.state_data.swap_coin_in_amount = amm
-                    .state_data
-                    .swap_coin_in_amount
-                    .checked_add(swap_in_after_add_fee.into())
-                    .unwrap();
-                amm.state_data.swap_pc_out_amount = amm
-                    .state_data
-                    .swap_pc_out_amount
-                    .checked_add(Calculator::to_u128(swap.amount_out)?)
-                    .unwrap();
-                // charge coin as swap fee
-                amm.state_data.swap_acc_coin_fee = amm
-                    .state_data
-                    .swap_acc_coin_fee
-                    .checked_add(swap_fee)
-                    .unwrap();
-            }
-            SwapDirection::PC2Coin => {
-                if swap.amount_out >= total_coin_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
+        Ok(())
+    }
 
-                if enable_orderbook {
-                    // pc -> coin, need cancel sell order
-                    if !asks.is_empty() {
-                        let mut amm_order_ids_vec = Vec::new();
-                        let mut order_ids = [0u64; 8];
-                        let mut count = 0;
-                        // fetch cancel order ids
-                        for order in asks.into_iter() {
-                            order_ids[count] = order.client_order_id();
-                            count += 1;
-                            if count == 8 {
-                                amm_order_ids_vec.push(order_ids);
-                                order_ids = [0u64; 8];
-                                count = 0;
-                            }
-                        }
-                        if count != 0 {
-                            amm_order_ids_vec.push(order_ids);
-                        }
-                        for ids in amm_order_ids_vec.iter() {
-                            Invokers::invoke_dex_cancel_orders_by_client_order_ids(
-                                market_program_info.clone(),
-                                market_info.clone(),
-                                market_bids_info.clone(),
-                                market_asks_info.clone(),
-                                amm_open_orders_info.clone(),
-                                amm_authority_info.clone(),
-                                market_event_queue_info.clone(),
-                                AUTHORITY_AMM,
-                                amm.nonce as u8,
-                                *ids,
-                            )?;
-                        }
-                    }
-                    if swap.amount_out > amm_coin_vault.amount {
-                        Invokers::invoke_dex_settle_funds(
-                            market_program_info.clone(),
-                            market_info.clone(),
-                            amm_open_orders_info.clone(),
-                            amm_authority_info.clone(),
-                            market_asks_info.clone(),
-                            market_pc_vault_info.clone(),
-                            amm_coin_vault_info.clone(),
-                            amm_pc_vault_info.clone(),
-                            market_vault_signer.clone(),
-                            token_program_info.clone(),
-                            Some(&amm_pc_vault_info.clone()),
-                            AUTHORITY_AMM,
-                            amm.nonce as u8,
-                        )?;
-                    }
-                }
+    /// Checks the vaults were repaid with the fee and closes the flash loan.
+    pub fn process_flash_loan_end(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        flash_loan: FlashLoanInstruction,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
 
-                // deposit source pc to amm_pc_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap_in_after_add_fee,
-                )?;
-                // withdraw amm_coin_vault to destination coin
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap.amount_out,
-                )?;
-                // update state_data data
-                amm.state_data.swap_pc_in_amount = amm
-                    .state_data
-                    .swap_pc_in_amount
-                    .checked_add(swap_in_after_add_fee.into())
-                    .unwrap();
-                amm.state_data.swap_coin_out_amount = amm
-                    .state_data
-                    .swap_coin_out_amount
-                    .checked_add(swap.amount_out.into())
-                    .unwrap();
-                // charge pc as swap fee
-                amm.state_data.swap_acc_pc_fee = amm
-                    .state_data
-                    .swap_acc_pc_fee
-                    .checked_add(swap_fee)
-                    .unwrap();
-            }
-        };
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if !amm.is_flash_loan_active() {
+            return Err(AmmError::InvalidFlashLoan.into());
+        }
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        let amm_coin_vault = Self::unpack_token_account(
+            &amm_coin_vault_info,
+            Self::token_program_id(amm_coin_vault_info)?,
+        )?;
+        let amm_pc_vault = Self::unpack_token_account(
+            &amm_pc_vault_info,
+            Self::token_program_id(amm_pc_vault_info)?,
+        )?;
+        // the fee numerator can't change while the flash loan is open
+        let coin_fee = Self::flash_loan_fee(&amm, flash_loan.coin_amount)?;
+        let pc_fee = Self::flash_loan_fee(&amm, flash_loan.pc_amount)?;
+        Self::close_flash_loan(
+            &mut amm,
+            amm_coin_vault.amount,
+            amm_pc_vault.amount,
+            coin_fee,
+            pc_fee,
+        )?;
         amm.recent_epoch = Clock::get()?.epoch;
 
         Ok(())
     }
 
+    /// Closes the flash loan once the vaults hold the amounts they had before it, plus the fee,
+    /// and only then counts the fee in the swap fee statistics.
+    pub fn close_flash_loan(
+        amm: &mut AmmInfo,
+        coin_vault_amount: u64,
+        pc_vault_amount: u64,
+        coin_fee: u64,
+        pc_fee: u64,
+    ) -> ProgramResult {
+        if coin_vault_amount < amm.flash_loan_coin_repay
+            || pc_vault_amount < amm.flash_loan_pc_repay
+        {
+            return Err(AmmError::FlashLoanNotRepaid.into());
+        }
+        amm.state_data.swap_acc_coin_fee = amm
+            .state_data
+            .swap_acc_coin_fee
+            .checked_add(coin_fee)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        amm.state_data.swap_acc_pc_fee = amm
+            .state_data
+            .swap_acc_pc_fee
+            .checked_add(pc_fee)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        amm.flash_loan_coin_repay = 0;
+        amm.flash_loan_pc_repay = 0;
+        Ok(())
+    }
+
     pub fn process_migrate_to_openbook(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
@@ -3092,6 +5445,9 @@ impl Processor {
         let new_market_info = next_account_info(account_info_iter)?;
         let admin_info = next_account_info(account_info_iter)?;
         let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
         if !admin_info.is_signer || *admin_info.key != config_feature::amm_owner::ID {
             return Err(AmmError::InvalidSignAccount.into());
         }
@@ -3490,8 +5846,14 @@ impl Processor {
             pnl_coin_amount = 0;
         }
 
-        let amm_coin_vault = Self::unpack_token_account(&amm_coin_vault_info, &spl_token::id())?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, &spl_token::id())?;
+        let amm_coin_vault = Self::unpack_token_account(
+            &amm_coin_vault_info,
+            Self::token_program_id(amm_coin_vault_info)?,
+        )?;
+        let amm_pc_vault = Self::unpack_token_account(
+            &amm_pc_vault_info,
+            Self::token_program_id(amm_pc_vault_info)?,
+        )?;
         let lp_mint = Self::unpack_mint(&amm_lp_mint_info, &spl_token::id())?;
         let (market_state, open_orders) = Self::load_serum_market_order(
             market_info,
@@ -3506,17 +5868,26 @@ impl Processor {
         if identity(market_state.pc_mint) != amm_pc_vault.mint.to_aligned_bytes() {
             return Err(AmmError::InvalidPCMint.into());
         }
-        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
+        let (open_orders_pc_amount, open_orders_coin_amount) =
+            Calculator::calc_exact_vault_in_serum(
                 &open_orders,
-                &amm,
                 &market_state,
                 &market_event_queue_info,
                 &amm_open_orders_info,
             )?;
-        let pool_info_data = GetPoolData {
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault
+                    .amount
+                    .checked_add(open_orders_pc_amount)
+                    .ok_or(AmmError::CheckedAddOverflow)?,
+                amm_coin_vault
+                    .amount
+                    .checked_add(open_orders_coin_amount)
+                    .ok_or(AmmError::CheckedAddOverflow)?,
+                &amm,
+            )?;
+        let mut pool_info_data = GetPoolData {
             status: amm.status,
             coin_decimals: amm.coin_decimals,
             pc_decimals: amm.pc_decimals,
@@ -3528,15 +5899,25 @@ impl Processor {
             pool_lp_supply: amm.lp_amount,
             pool_open_time: amm.state_data.pool_open_time,
             amm_id: amm_info.key.to_string(),
+            vault_pc_amount: amm_pc_vault.amount,
+            vault_coin_amount: amm_coin_vault.amount,
+            open_orders_pc_amount,
+            open_orders_coin_amount,
+            ..Default::default()
         };
+        pool_info_data.fill_amm_info(&amm, Clock::get()?.unix_timestamp as u64);
         return Ok(pool_info_data);
     }
 
+    /// Simulates a swap base in, also returning the amounts of the shared swap core.
+    /// The accounts may be followed by the `[spl_token_2022, coin_mint, pc_mint]` accounts
+    /// of a pool with a Spl Token 2022 vault.
     fn simulate_swap_base_in(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         simulate: SimulateInstruction,
-    ) -> Result<GetSwapBaseInData, ProgramError> {
+    ) -> Result<(GetSwapBaseInData, SwapAmounts), ProgramError> {
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
         let account_info_iter = &mut accounts.iter();
 
         let amm_info = next_account_info(account_info_iter)?;
@@ -3568,6 +5949,7 @@ impl Processor {
         Self::check_account_readonly(user_source_owner)?;
 
         let mut swap_base_in: GetSwapBaseInData = Default::default();
+        let mut amounts: SwapAmounts = Default::default();
         if let Some(swap) = simulate.swap_base_in_value {
             swap_base_in.amount_in = swap.amount_in;
 
@@ -3647,14 +6029,24 @@ impl Processor {
                 false,
             )?;
 
-            let amm_coin_vault =
-                Self::unpack_token_account(&amm_coin_vault_info, &spl_token::id())?;
-            let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, &spl_token::id())?;
+            let amm_coin_vault = Self::unpack_token_account(
+                &amm_coin_vault_info,
+                Self::token_program_id(amm_coin_vault_info)?,
+            )?;
+            let amm_pc_vault = Self::unpack_token_account(
+                &amm_pc_vault_info,
+                Self::token_program_id(amm_pc_vault_info)?,
+            )?;
             let lp_mint = Self::unpack_mint(&amm_lp_mint_info, &spl_token::id())?;
 
-            let user_source = Self::unpack_token_account(&user_source_info, &spl_token::id())?;
-            let user_destination =
-                Self::unpack_token_account(&user_destination_info, &spl_token::id())?;
+            let user_source = Self::unpack_token_account(
+                &user_source_info,
+                Self::token_program_id(user_source_info)?,
+            )?;
+            let user_destination = Self::unpack_token_account(
+                &user_destination_info,
+                Self::token_program_id(user_destination_info)?,
+            )?;
 
             // let target_orders = TargetOrders::load_mut_checked(&target_orders_info, program_id, amm_info.key)?;
 
@@ -3689,70 +6081,97 @@ impl Processor {
             swap_base_in.pool_data.pool_coin_amount = total_coin_without_take_pnl;
             swap_base_in.pool_data.amm_id = amm_info.key.to_string();
 
-            let swap_fee = U128::from(swap.amount_in)
-                .checked_mul(amm.fees.swap_fee_numerator.into())
-                .unwrap()
-                .checked_ceil_div(amm.fees.swap_fee_denominator.into())
-                .unwrap()
-                .0;
-            let swap_in_after_deduct_fee =
-                U128::from(swap.amount_in).checked_sub(swap_fee).unwrap();
-            let swap_amount_out = Calculator::swap_token_amount_base_in(
-                swap_in_after_deduct_fee,
-                total_pc_without_take_pnl.into(),
-                total_coin_without_take_pnl.into(),
+            let clock = Clock::get()?;
+            swap_base_in.swap_fee_numerator =
+                amm.swap_fee_numerator_at(clock.unix_timestamp as u64);
+            swap_base_in.swap_fee_denominator = amm.fees.swap_fee_denominator;
+            let coin_transfer_fee_config = Self::simulate_vault_transfer_fee_config(
+                amm_coin_vault_info,
+                amm.coin_vault_mint,
+                token_2022_accounts
+                    .as_ref()
+                    .map(|accounts| accounts.coin_mint),
+                AmmError::InvalidCoinMint,
+            )?;
+            let pc_transfer_fee_config = Self::simulate_vault_transfer_fee_config(
+                amm_pc_vault_info,
+                amm.pc_vault_mint,
+                token_2022_accounts
+                    .as_ref()
+                    .map(|accounts| accounts.pc_mint),
+                AmmError::InvalidPCMint,
+            )?;
+            let (source_transfer_fee_config, destination_transfer_fee_config) = match swap_direction
+            {
+                SwapDirection::Coin2PC => (coin_transfer_fee_config, pc_transfer_fee_config),
+                SwapDirection::PC2Coin => (pc_transfer_fee_config, coin_transfer_fee_config),
+            };
+            amounts = Self::swap_amounts(
+                &amm,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
                 swap_direction,
-            )
-            .as_u64();
-            swap_base_in.minimum_amount_out = swap_amount_out;
+                SwapAmount::BaseIn(swap.amount_in),
+                0,
+                source_transfer_fee_config.as_ref(),
+                destination_transfer_fee_config.as_ref(),
+                clock.epoch,
+                clock.unix_timestamp as u64,
+            )?;
+            // the pool moves by what reaches the source vault and leaves the destination vault
+            let (swap_amount_in, swap_amount_out) =
+                (amounts.amount_in_received, amounts.amount_out);
+            swap_base_in.minimum_amount_out = amounts.amount_out_received;
             match swap_direction {
                 SwapDirection::Coin2PC => {
                     // coin -> pc, need cancel buy order
                     let token_pc_after_swap = total_pc_without_take_pnl
                         .checked_sub(swap_amount_out)
-                        .unwrap();
+                        .ok_or(AmmError::CheckedSubOverflow)?;
                     let token_coin_after_swap = total_coin_without_take_pnl
-                        .checked_add(swap.amount_in)
-                        .unwrap();
+                        .checked_add(swap_amount_in)
+                        .ok_or(AmmError::CheckedAddOverflow)?;
 
                     let swap_price_before = total_pc_without_take_pnl
                         .checked_div(total_coin_without_take_pnl)
-                        .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                     let swap_price_after = token_pc_after_swap
                         .checked_div(token_coin_after_swap)
-                        .unwrap();
-                    swap_base_in.price_impact =
-                        (swap_price_before.checked_sub(swap_price_after).unwrap())
-                            .checked_mul(1000000)
-                            .unwrap()
-                            .checked_div(swap_price_before)
-                            .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
+                    swap_base_in.price_impact = swap_price_before
+                        .checked_sub(swap_price_after)
+                        .ok_or(AmmError::CheckedSubOverflow)?
+                        .checked_mul(1000000)
+                        .ok_or(AmmError::CheckedMulOverflow)?
+                        .checked_div(swap_price_before)
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                 }
                 SwapDirection::PC2Coin => {
                     // pc -> coin, need cancel sell order
                     let token_pc_after_swap = total_pc_without_take_pnl
-                        .checked_add(swap.amount_in)
-                        .unwrap();
+                        .checked_add(swap_amount_in)
+                        .ok_or(AmmError::CheckedAddOverflow)?;
                     let token_coin_after_swap = total_coin_without_take_pnl
                         .checked_sub(swap_amount_out)
-                        .unwrap();
+                        .ok_or(AmmError::CheckedSubOverflow)?;
 
                     let swap_price_before = total_pc_without_take_pnl
                         .checked_div(total_coin_without_take_pnl)
-                        .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                     let swap_price_after = token_pc_after_swap
                         .checked_div(token_coin_after_swap)
-                        .unwrap();
-                    swap_base_in.price_impact =
-                        (swap_price_after.checked_sub(swap_price_before).unwrap())
-                            .checked_mul(1000000)
-                            .unwrap()
-                            .checked_div(swap_price_before)
-                            .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
+                    swap_base_in.price_impact = swap_price_after
+                        .checked_sub(swap_price_before)
+                        .ok_or(AmmError::CheckedSubOverflow)?
+                        .checked_mul(1000000)
+                        .ok_or(AmmError::CheckedMulOverflow)?
+                        .checked_div(swap_price_before)
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                 }
             }
         }
-        return Ok(swap_base_in);
+        return Ok((swap_base_in, amounts));
     }
 
     fn simulate_swap_base_out(
@@ -3760,6 +6179,7 @@ impl Processor {
         accounts: &[AccountInfo],
         simulate: SimulateInstruction,
     ) -> Result<GetSwapBaseOutData, ProgramError> {
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
         let account_info_iter = &mut accounts.iter();
 
         let amm_info = next_account_info(account_info_iter)?;
@@ -3869,14 +6289,24 @@ impl Processor {
                 false,
             )?;
 
-            let amm_coin_vault =
-                Self::unpack_token_account(&amm_coin_vault_info, &spl_token::id())?;
-            let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, &spl_token::id())?;
+            let amm_coin_vault = Self::unpack_token_account(
+                &amm_coin_vault_info,
+                Self::token_program_id(amm_coin_vault_info)?,
+            )?;
+            let amm_pc_vault = Self::unpack_token_account(
+                &amm_pc_vault_info,
+                Self::token_program_id(amm_pc_vault_info)?,
+            )?;
             let lp_mint = Self::unpack_mint(&amm_lp_mint_info, &spl_token::id())?;
 
-            let user_swap_source = Self::unpack_token_account(&user_source_info, &spl_token::id())?;
-            let user_swap_destination =
-                Self::unpack_token_account(&user_destination_info, &spl_token::id())?;
+            let user_swap_source = Self::unpack_token_account(
+                &user_source_info,
+                Self::token_program_id(user_source_info)?,
+            )?;
+            let user_swap_destination = Self::unpack_token_account(
+                &user_destination_info,
+                Self::token_program_id(user_destination_info)?,
+            )?;
 
             // let target_orders = TargetOrders::load_mut_checked(&target_orders_info, program_id, amm_info.key)?;
 
@@ -3912,74 +6342,94 @@ impl Processor {
             swap_base_out.pool_data.pool_coin_amount = total_coin_without_take_pnl;
             swap_base_out.pool_data.amm_id = amm_info.key.to_string();
 
-            let swap_in_before_add_fee = Calculator::swap_token_amount_base_out(
-                swap.amount_out.into(),
-                total_pc_without_take_pnl.into(),
-                total_coin_without_take_pnl.into(),
+            let clock = Clock::get()?;
+            swap_base_out.swap_fee_numerator =
+                amm.swap_fee_numerator_at(clock.unix_timestamp as u64);
+            swap_base_out.swap_fee_denominator = amm.fees.swap_fee_denominator;
+            let coin_transfer_fee_config = Self::simulate_vault_transfer_fee_config(
+                amm_coin_vault_info,
+                amm.coin_vault_mint,
+                token_2022_accounts
+                    .as_ref()
+                    .map(|accounts| accounts.coin_mint),
+                AmmError::InvalidCoinMint,
+            )?;
+            let pc_transfer_fee_config = Self::simulate_vault_transfer_fee_config(
+                amm_pc_vault_info,
+                amm.pc_vault_mint,
+                token_2022_accounts
+                    .as_ref()
+                    .map(|accounts| accounts.pc_mint),
+                AmmError::InvalidPCMint,
+            )?;
+            let (source_transfer_fee_config, destination_transfer_fee_config) = match swap_direction
+            {
+                SwapDirection::Coin2PC => (coin_transfer_fee_config, pc_transfer_fee_config),
+                SwapDirection::PC2Coin => (pc_transfer_fee_config, coin_transfer_fee_config),
+            };
+            let amounts = Self::swap_amounts(
+                &amm,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
                 swap_direction,
-            );
-
-            // swap_in_after_add_fee * (1 - 0.0025) = swap_in_before_add_fee
-            // swap_in_after_add_fee = swap_in_before_add_fee / (1 - 0.0025)
-            let swap_in_after_add_fee = swap_in_before_add_fee
-                .checked_mul(amm.fees.swap_fee_denominator.into())
-                .unwrap()
-                .checked_ceil_div(
-                    (amm.fees
-                        .swap_fee_denominator
-                        .checked_sub(amm.fees.swap_fee_numerator)
-                        .unwrap())
-                    .into(),
-                )
-                .unwrap()
-                .0
-                .as_u64();
-            swap_base_out.max_amount_in = swap_in_after_add_fee;
-
+                SwapAmount::BaseOut(swap.amount_out),
+                0,
+                source_transfer_fee_config.as_ref(),
+                destination_transfer_fee_config.as_ref(),
+                clock.epoch,
+                clock.unix_timestamp as u64,
+            )?;
+            // the transfer fees of both legs are included
+            swap_base_out.max_amount_in = amounts.user_amount_in;
+            // the pool moves by what reaches the source vault and leaves the destination vault
+            let (swap_amount_in, swap_amount_out) =
+                (amounts.amount_in_received, amounts.amount_out);
             match swap_direction {
                 SwapDirection::Coin2PC => {
                     // coin -> pc, need cancel buy order
                     let token_pc_after_swap = total_pc_without_take_pnl
-                        .checked_sub(swap.amount_out)
-                        .unwrap();
+                        .checked_sub(swap_amount_out)
+                        .ok_or(AmmError::CheckedSubOverflow)?;
                     let token_coin_after_swap = total_coin_without_take_pnl
-                        .checked_add(swap_in_after_add_fee)
-                        .unwrap();
+                        .checked_add(swap_amount_in)
+                        .ok_or(AmmError::CheckedAddOverflow)?;
 
                     let swap_price_before = total_pc_without_take_pnl
                         .checked_div(total_coin_without_take_pnl)
-                        .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                     let swap_price_after = token_pc_after_swap
                         .checked_div(token_coin_after_swap)
-                        .unwrap();
-                    swap_base_out.price_impact =
-                        (swap_price_before.checked_sub(swap_price_after).unwrap())
-                            .checked_mul(1000000)
-                            .unwrap()
-                            .checked_div(swap_price_before)
-                            .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
+                    swap_base_out.price_impact = swap_price_before
+                        .checked_sub(swap_price_after)
+                        .ok_or(AmmError::CheckedSubOverflow)?
+                        .checked_mul(1000000)
+                        .ok_or(AmmError::CheckedMulOverflow)?
+                        .checked_div(swap_price_before)
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                 }
                 SwapDirection::PC2Coin => {
                     // pc -> coin, need cancel sell order
                     let token_pc_after_swap = total_pc_without_take_pnl
-                        .checked_add(swap_in_after_add_fee)
-                        .unwrap();
+                        .checked_add(swap_amount_in)
+                        .ok_or(AmmError::CheckedAddOverflow)?;
                     let token_coin_after_swap = total_coin_without_take_pnl
-                        .checked_sub(swap.amount_out)
-                        .unwrap();
+                        .checked_sub(swap_amount_out)
+                        .ok_or(AmmError::CheckedSubOverflow)?;
 
                     let swap_price_before = total_pc_without_take_pnl
                         .checked_div(total_coin_without_take_pnl)
-                        .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                     let swap_price_after = token_pc_after_swap
                         .checked_div(token_coin_after_swap)
-                        .unwrap();
-                    swap_base_out.price_impact =
-                        (swap_price_after.checked_sub(swap_price_before).unwrap())
-                            .checked_mul(1000000)
-                            .unwrap()
-                            .checked_div(swap_price_before)
-                            .unwrap();
+                        .ok_or(AmmError::CheckedDivOverflow)?;
+                    swap_base_out.price_impact = swap_price_after
+                        .checked_sub(swap_price_before)
+                        .ok_or(AmmError::CheckedSubOverflow)?
+                        .checked_mul(1000000)
+                        .ok_or(AmmError::CheckedMulOverflow)?
+                        .checked_div(swap_price_before)
+                        .ok_or(AmmError::CheckedDivOverflow)?;
                 }
             }
         }
@@ -4257,44 +6707,840 @@ impl Processor {
         return Ok(run_crank_data);
     }
 
-    /// simulate_info
-    pub fn process_simulate_info(
+    /// Splits the accounts of a simulated route into the simulate_swap_base_in accounts of
+    /// each hop, each optionally followed by its `[spl_token_2022, coin_mint, pc_mint]` accounts.
+    pub fn split_simulate_route_hops<'a, 'b: 'a>(
+        mut accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Vec<&'a [AccountInfo<'b>]>, ProgramError> {
+        let mut hops = Vec::new();
+        while !accounts.is_empty() {
+            if accounts.len() < SIMULATE_SWAP_ACCOUNT_LEN {
+                return Err(AmmError::WrongAccountsNumber.into());
+            }
+            let mut hop_len = SIMULATE_SWAP_ACCOUNT_LEN;
+            if accounts.len() >= hop_len + TOKEN_2022_ACCOUNT_LEN
+                && *accounts[hop_len].key == spl_token_2022::id()
+            {
+                hop_len += TOKEN_2022_ACCOUNT_LEN;
+            }
+            let (hop, rest) = accounts.split_at(hop_len);
+            accounts = rest;
+            hops.push(hop);
+        }
+        if hops.is_empty() {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        Ok(hops)
+    }
+
+    /// Chains simulate_swap_base_in over the accounts of each hop, what the user receives
+    /// from a hop, after the destination transfer fee, being the input of the next.
+    fn simulate_route_swap_base_in(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         simulate: SimulateInstruction,
-    ) -> ProgramResult {
-        let param = simulate.param;
-        match SimulateParams::from_u64(param as u64) {
-            SimulateParams::PoolInfo => {
-                let pool_info_data = Self::simulate_pool_info(program_id, accounts).unwrap();
-                msg!("GetPoolData: {}", pool_info_data.to_json());
-            }
-            SimulateParams::RunCrankInfo => {
-                let run_crank_data = Self::simulate_run_crank(program_id, accounts).unwrap();
-                msg!("RunCrankData: {}", run_crank_data.to_json());
-            }
-            SimulateParams::SwapBaseInInfo => {
-                let swap_base_in_data =
-                    Self::simulate_swap_base_in(program_id, accounts, simulate).unwrap();
-                msg!("GetSwapBaseInData: {}", swap_base_in_data.to_json());
-            }
-            SimulateParams::SwapBaseOutInfo => {
-                let swap_base_out_data =
-                    Self::simulate_swap_base_out(program_id, accounts, simulate).unwrap();
-                msg!("GetSwapBaseOutData: {}", swap_base_out_data.to_json());
+    ) -> Result<GetRouteSwapData, ProgramError> {
+        let hops = Self::split_simulate_route_hops(accounts)?;
+        let mut route_swap: GetRouteSwapData = Default::default();
+        if let Some(swap) = simulate.swap_base_in_value {
+            route_swap.amount_in = swap.amount_in;
+            route_swap.minimum_amount_out = swap.minimum_amount_out;
+            let mut amount_in = swap.amount_in;
+            for hop_accounts in hops {
+                let (hop, amounts) = Self::simulate_swap_base_in(
+                    program_id,
+                    hop_accounts,
+                    SimulateInstruction {
+                        swap_base_in_value: Some(SwapInstructionBaseIn { amount_in, ..swap }),
+                        ..simulate
+                    },
+                )?;
+                route_swap.hops.push(GetRouteSwapHopData {
+                    amm_id: hop.pool_data.amm_id,
+                    amount_in,
+                    amount_out: amounts.amount_out_received,
+                    swap_fee: amounts.swap_fee,
+                    price_impact: hop.price_impact,
+                });
+                amount_in = amounts.amount_out_received;
             }
+            route_swap.amount_out = amount_in;
         }
-        return Ok(());
+        Ok(route_swap)
     }
 
-    pub fn do_idle_state(args: account_parser::IdleArgs) -> ProgramResult {
-        let account_parser::IdleArgs {
-            program_id: _,
-            total_coin_without_take_pnl,
-            total_pc_without_take_pnl,
-            amm,
-            bids,
-            asks,
+    /// Transfer fee of a simulated vault, read from the mint of the trailing Spl Token 2022 accounts.
+    fn simulate_vault_transfer_fee_config(
+        vault_info: &AccountInfo,
+        vault_mint: Pubkey,
+        mint_info: Option<&AccountInfo>,
+        mint_error: AmmError,
+    ) -> Result<Option<TransferFeeConfig>, ProgramError> {
+        if *vault_info.owner != spl_token_2022::id() {
+            return Ok(None);
+        }
+        let mint_info = mint_info.ok_or(AmmError::WrongAccountsNumber)?;
+        check_assert_eq!(*mint_info.key, vault_mint, "vault_mint", mint_error);
+        Self::mint_transfer_fee_config(mint_info)
+    }
+
+    /// Loads the pool of a simulated deposit or withdraw and takes the pnl on a copy of it,
+    /// leaving the accounts untouched. Also returns the coin and pc transfer fees of
+    /// Spl Token 2022 vaults.
+    fn simulate_liquidity_pool(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        take_pnl: impl Fn(&AmmInfo) -> bool,
+    ) -> Result<
+        (
+            AmmInfo,
+            GetPoolData,
+            Option<TransferFeeConfig>,
+            Option<TransferFeeConfig>,
+        ),
+        ProgramError,
+    > {
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let account_info_iter = &mut accounts.iter();
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_open_orders_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let amm_lp_mint_info = next_account_info(account_info_iter)?;
+        let market_info = next_account_info(account_info_iter)?;
+        let market_event_queue_info = next_account_info(account_info_iter)?;
+
+        let mut amm = *AmmInfo::load_checked(&amm_info, program_id)?;
+        let authority = Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?;
+        Self::check_account_readonly(amm_info)?;
+        Self::check_account_readonly(amm_open_orders_info)?;
+        Self::check_account_readonly(amm_target_orders_info)?;
+        Self::check_account_readonly(amm_coin_vault_info)?;
+        Self::check_account_readonly(amm_pc_vault_info)?;
+        Self::check_account_readonly(amm_lp_mint_info)?;
+        Self::check_account_readonly(market_info)?;
+        Self::check_account_readonly(market_event_queue_info)?;
+        check_assert_eq!(
+            *amm_authority_info.key,
+            authority,
+            "authority",
+            AmmError::InvalidProgramAddress
+        );
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        check_assert_eq!(
+            *amm_lp_mint_info.key,
+            amm.lp_mint,
+            "lp_mint",
+            AmmError::InvalidPoolMint
+        );
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let target_orders =
+            TargetOrders::load_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+
+        let amm_coin_vault = Self::unpack_token_account(
+            &amm_coin_vault_info,
+            Self::token_program_id(amm_coin_vault_info)?,
+        )?;
+        let amm_pc_vault = Self::unpack_token_account(
+            &amm_pc_vault_info,
+            Self::token_program_id(amm_pc_vault_info)?,
+        )?;
+        let coin_transfer_fee_config = Self::simulate_vault_transfer_fee_config(
+            amm_coin_vault_info,
+            amm.coin_vault_mint,
+            token_2022_accounts
+                .as_ref()
+                .map(|accounts| accounts.coin_mint),
+            AmmError::InvalidCoinMint,
+        )?;
+        let pc_transfer_fee_config = Self::simulate_vault_transfer_fee_config(
+            amm_pc_vault_info,
+            amm.pc_vault_mint,
+            token_2022_accounts
+                .as_ref()
+                .map(|accounts| accounts.pc_mint),
+            AmmError::InvalidPCMint,
+        )?;
+        let lp_mint = Self::unpack_mint(&amm_lp_mint_info, &spl_token::id())?;
+        let (open_orders_pc_amount, open_orders_coin_amount) =
+            if AmmStatus::from_u64(amm.status).orderbook_permission() {
+                check_assert_eq!(
+                    *amm_open_orders_info.key,
+                    amm.open_orders,
+                    "open_orders",
+                    AmmError::InvalidOpenOrders
+                );
+                check_assert_eq!(
+                    *market_info.key,
+                    amm.market,
+                    "market",
+                    AmmError::InvalidMarket
+                );
+                let (market_state, open_orders) = Self::load_serum_market_order(
+                    market_info,
+                    amm_open_orders_info,
+                    amm_authority_info,
+                    &amm,
+                    false,
+                )?;
+                Calculator::calc_exact_vault_in_serum(
+                    &open_orders,
+                    &market_state,
+                    &market_event_queue_info,
+                    &amm_open_orders_info,
+                )?
+            } else {
+                (0, 0)
+            };
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault
+                    .amount
+                    .checked_add(open_orders_pc_amount)
+                    .ok_or(AmmError::CheckedAddOverflow)?,
+                amm_coin_vault
+                    .amount
+                    .checked_add(open_orders_coin_amount)
+                    .ok_or(AmmError::CheckedAddOverflow)?,
+                &amm,
+            )?;
+        if take_pnl(&amm) {
+            let x1 = Calculator::normalize_decimal_v2(
+                total_pc_without_take_pnl,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            );
+            let y1 = Calculator::normalize_decimal_v2(
+                total_coin_without_take_pnl,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            );
+            Self::calc_take_pnl(
+                &target_orders,
+                &mut amm,
+                &mut total_pc_without_take_pnl,
+                &mut total_coin_without_take_pnl,
+                x1.as_u128().into(),
+                y1.as_u128().into(),
+            )?;
+        }
+        let mut pool_data = GetPoolData {
+            status: amm.status,
+            coin_decimals: amm.coin_decimals,
+            pc_decimals: amm.pc_decimals,
+            lp_decimals: lp_mint.decimals.into(),
+            pool_pc_amount: total_pc_without_take_pnl,
+            pool_coin_amount: total_coin_without_take_pnl,
+            pnl_pc_amount: Calculator::restore_decimal(
+                target_orders.calc_pnl_x.into(),
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            )
+            .as_u64(),
+            pnl_coin_amount: Calculator::restore_decimal(
+                target_orders.calc_pnl_y.into(),
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            )
+            .as_u64(),
+            pool_lp_supply: amm.lp_amount,
+            pool_open_time: amm.state_data.pool_open_time,
+            amm_id: amm_info.key.to_string(),
+            vault_pc_amount: amm_pc_vault.amount,
+            vault_coin_amount: amm_coin_vault.amount,
+            open_orders_pc_amount,
+            open_orders_coin_amount,
+            ..Default::default()
+        };
+        pool_data.fill_amm_info(&amm, Clock::get()?.unix_timestamp as u64);
+        Ok((
+            amm,
+            pool_data,
+            coin_transfer_fee_config,
+            pc_transfer_fee_config,
+        ))
+    }
+
+    fn simulate_deposit(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        simulate: SimulateInstruction,
+    ) -> Result<GetDepositData, ProgramError> {
+        let mut deposit_data: GetDepositData = Default::default();
+        if let Some(deposit) = simulate.deposit_value {
+            let (amm, pool_data, coin_transfer_fee_config, pc_transfer_fee_config) =
+                Self::simulate_liquidity_pool(program_id, accounts, |_| true)?;
+            if !AmmStatus::from_u64(amm.status).deposit_permission() {
+                msg!("simulate_deposit: status {}", identity(amm.status));
+                return Err(AmmError::InvalidStatus.into());
+            }
+            deposit_data = Self::deposit_data(
+                &amm,
+                pool_data,
+                &deposit,
+                coin_transfer_fee_config.as_ref(),
+                pc_transfer_fee_config.as_ref(),
+                Clock::get()?.epoch,
+            )?;
+        }
+        Ok(deposit_data)
+    }
+
+    /// Deposit preview against the pool amounts after pnl of pool_data, computed and checked
+    /// like process_deposit.
+    pub fn deposit_data(
+        amm: &AmmInfo,
+        pool_data: GetPoolData,
+        deposit: &DepositInstruction,
+        coin_transfer_fee_config: Option<&TransferFeeConfig>,
+        pc_transfer_fee_config: Option<&TransferFeeConfig>,
+        epoch: u64,
+    ) -> Result<GetDepositData, ProgramError> {
+        if amm.lp_amount == 0 {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+        let amounts = Self::deposit_amounts(
+            amm,
+            pool_data.pool_pc_amount,
+            pool_data.pool_coin_amount,
+            deposit.max_coin_amount,
+            deposit.max_pc_amount,
+            deposit.base_side,
+            coin_transfer_fee_config,
+            pc_transfer_fee_config,
+            epoch,
+        )?;
+        Self::check_deposit_amounts(
+            &amounts,
+            deposit.max_coin_amount,
+            deposit.max_pc_amount,
+            deposit.base_side,
+            deposit.other_amount_min,
+        )?;
+        Ok(GetDepositData {
+            pool_data,
+            max_coin_amount: deposit.max_coin_amount,
+            max_pc_amount: deposit.max_pc_amount,
+            base_side: deposit.base_side,
+            deduct_coin: amounts.deduct_coin_amount,
+            deduct_pc: amounts.deduct_pc_amount,
+            mint_lp: amounts.mint_lp_amount,
+        })
+    }
+
+    fn simulate_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        simulate: SimulateInstruction,
+    ) -> Result<GetWithdrawData, ProgramError> {
+        let mut withdraw_data: GetWithdrawData = Default::default();
+        if let Some(withdraw) = simulate.withdraw_value {
+            // like process_withdraw, the pnl isn't taken from a WithdrawOnly pool
+            let (amm, pool_data, coin_transfer_fee_config, pc_transfer_fee_config) =
+                Self::simulate_liquidity_pool(program_id, accounts, |amm| {
+                    amm.status != AmmStatus::WithdrawOnly.into_u64()
+                })?;
+            if !AmmStatus::from_u64(amm.status).withdraw_permission() {
+                msg!("simulate_withdraw: status {}", identity(amm.status));
+                return Err(AmmError::InvalidStatus.into());
+            }
+            withdraw_data = Self::withdraw_data(
+                &amm,
+                pool_data,
+                withdraw.amount,
+                coin_transfer_fee_config.as_ref(),
+                pc_transfer_fee_config.as_ref(),
+                Clock::get()?.epoch,
+            )?;
+        }
+        Ok(withdraw_data)
+    }
+
+    /// Withdraw preview of amount lp against the pool amounts after pnl of pool_data,
+    /// computed like process_withdraw.
+    pub fn withdraw_data(
+        amm: &AmmInfo,
+        pool_data: GetPoolData,
+        amount: u64,
+        coin_transfer_fee_config: Option<&TransferFeeConfig>,
+        pc_transfer_fee_config: Option<&TransferFeeConfig>,
+        epoch: u64,
+    ) -> Result<GetWithdrawData, ProgramError> {
+        if amount >= amm.lp_amount {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+        let invariant = InvariantPool {
+            token_input: amount,
+            token_total: amm.lp_amount,
+        };
+        let coin_amount = invariant
+            .exchange_pool_to_token(pool_data.pool_coin_amount, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        let pc_amount = invariant
+            .exchange_pool_to_token(pool_data.pool_pc_amount, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        Ok(GetWithdrawData {
+            pool_data,
+            amount,
+            coin_amount,
+            pc_amount,
+            coin_amount_received: Self::amount_received_after_transfer_fee(
+                coin_transfer_fee_config,
+                coin_amount,
+                epoch,
+            )?,
+            pc_amount_received: Self::amount_received_after_transfer_fee(
+                pc_transfer_fee_config,
+                pc_amount,
+                epoch,
+            )?,
+        })
+    }
+
+    /// simulate_info
+    pub fn process_simulate_info(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        simulate: SimulateInstruction,
+    ) -> ProgramResult {
+        let param = simulate.param;
+        let return_data = match SimulateParams::from_u64(param as u64)? {
+            SimulateParams::PoolInfo => {
+                let pool_info_data = Self::simulate_pool_info(program_id, accounts)?;
+                msg!("GetPoolData: {}", pool_info_data.to_json());
+                SimulateReturnData::PoolInfo(pool_info_data)
+            }
+            SimulateParams::RunCrankInfo => {
+                let run_crank_data = Self::simulate_run_crank(program_id, accounts)?;
+                msg!("RunCrankData: {}", run_crank_data.to_json());
+                SimulateReturnData::RunCrank(run_crank_data)
+            }
+            SimulateParams::SwapBaseInInfo => {
+                let swap_base_in_data =
+                    Self::simulate_swap_base_in(program_id, accounts, simulate)?.0;
+                msg!("GetSwapBaseInData: {}", swap_base_in_data.to_json());
+                SimulateReturnData::SwapBaseIn(swap_base_in_data)
+            }
+            SimulateParams::SwapBaseOutInfo => {
+                let swap_base_out_data =
+                    Self::simulate_swap_base_out(program_id, accounts, simulate)?;
+                msg!("GetSwapBaseOutData: {}", swap_base_out_data.to_json());
+                SimulateReturnData::SwapBaseOut(swap_base_out_data)
+            }
+            SimulateParams::RouteSwapBaseIn => {
+                let route_swap_data =
+                    Self::simulate_route_swap_base_in(program_id, accounts, simulate)?;
+                msg!("GetRouteSwapData: {}", route_swap_data.to_json());
+                SimulateReturnData::RouteSwapBaseIn(route_swap_data)
+            }
+            SimulateParams::DepositInfo => {
+                let deposit_data = Self::simulate_deposit(program_id, accounts, simulate)?;
+                msg!("GetDepositData: {}", deposit_data.to_json());
+                SimulateReturnData::Deposit(deposit_data)
+            }
+            SimulateParams::WithdrawInfo => {
+                let withdraw_data = Self::simulate_withdraw(program_id, accounts, simulate)?;
+                msg!("GetWithdrawData: {}", withdraw_data.to_json());
+                SimulateReturnData::Withdraw(withdraw_data)
+            }
+        };
+        set_return_data(&return_data.pack());
+        return Ok(());
+    }
+
+    /// Returns the pool TWAP over a window starting at an observation of the observation account.
+    pub fn process_get_twap(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        get_twap: GetTwapInstruction,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_open_orders_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let market_info = next_account_info(account_info_iter)?;
+        let market_event_queue_info = next_account_info(account_info_iter)?;
+        let observation_info = next_account_info(account_info_iter)?;
+
+        let amm = AmmInfo::load_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) = Self::observed_pool_totals(
+            program_id,
+            &amm,
+            amm_authority_info,
+            amm_open_orders_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            market_info,
+            market_event_queue_info,
+        )?;
+
+        let now = Clock::get()?.unix_timestamp as u64;
+        // a zero window only asks for the current cumulative
+        let start = if get_twap.window != 0 {
+            Self::check_observation_address(program_id, amm_info.key, observation_info)?;
+            let ObservationStateRef {
+                header,
+                observations,
+            } = ObservationStateRef::load_checked(observation_info, program_id, amm_info.key)?;
+            let window_start = now
+                .checked_sub(get_twap.window)
+                .ok_or(AmmError::InvalidInput)?;
+            let start = header.observation_before(&observations, window_start);
+            if start.is_none() {
+                msg!("get_twap: no observation at or before {}", window_start);
+                return Err(AmmError::InvalidInput.into());
+            }
+            start
+        } else {
+            None
+        };
+        let twap_data = Self::twap_data(
+            &amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            now,
+            start,
+        )?;
+        msg!("GetTwapData: {}", twap_data.to_json());
+        set_return_data(&AmmReturnData::Twap(twap_data).pack());
+        Ok(())
+    }
+
+    /// Pool amounts the TWAP and the observations are taken at, checking the amm accounts.
+    #[allow(clippy::too_many_arguments)]
+    fn observed_pool_totals<'a>(
+        program_id: &Pubkey,
+        amm: &AmmInfo,
+        amm_authority_info: &AccountInfo<'a>,
+        amm_open_orders_info: &AccountInfo<'a>,
+        amm_coin_vault_info: &AccountInfo<'a>,
+        amm_pc_vault_info: &AccountInfo<'a>,
+        market_info: &AccountInfo<'a>,
+        market_event_queue_info: &AccountInfo<'a>,
+    ) -> Result<(u64, u64), ProgramError> {
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        let amm_coin_vault = Self::unpack_token_account(
+            &amm_coin_vault_info,
+            Self::token_program_id(amm_coin_vault_info)?,
+        )?;
+        let amm_pc_vault = Self::unpack_token_account(
+            &amm_pc_vault_info,
+            Self::token_program_id(amm_pc_vault_info)?,
+        )?;
+
+        let total_pc_without_take_pnl;
+        let total_coin_without_take_pnl;
+        if AmmStatus::from_u64(amm.status).orderbook_permission() {
+            if *amm_authority_info.key
+                != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+            {
+                return Err(AmmError::InvalidProgramAddress.into());
+            }
+            check_assert_eq!(
+                *amm_open_orders_info.key,
+                amm.open_orders,
+                "open_orders",
+                AmmError::InvalidOpenOrders
+            );
+            check_assert_eq!(
+                *market_info.key,
+                amm.market,
+                "market",
+                AmmError::InvalidMarket
+            );
+            let (market_state, open_orders) = Self::load_serum_market_order(
+                market_info,
+                amm_open_orders_info,
+                amm_authority_info,
+                amm,
+                false,
+            )?;
+            (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl(
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                    &open_orders,
+                    amm,
+                    &market_state,
+                    &market_event_queue_info,
+                    &amm_open_orders_info,
+                )?;
+        } else {
+            (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl_no_orderbook(
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                    amm,
+                )?;
+        }
+        Ok((total_pc_without_take_pnl, total_coin_without_take_pnl))
+    }
+
+    /// Accumulates the pool price up to now and writes it to the observation account, by anyone.
+    pub fn process_write_observation(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_open_orders_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let market_info = next_account_info(account_info_iter)?;
+        let market_event_queue_info = next_account_info(account_info_iter)?;
+        let observation_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) = Self::observed_pool_totals(
+            program_id,
+            &amm,
+            amm_authority_info,
+            amm_open_orders_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            market_info,
+            market_event_queue_info,
+        )?;
+        amm.update_price_cumulative(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        );
+        Self::write_observation(
+            program_id,
+            amm_info,
+            &amm,
+            Some(observation_info),
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        )
+    }
+
+    /// TWAP from the `start` observation to `now`. Observations are only written by the swaps
+    /// they are passed to and by WriteObservation, but the cumulative they hold is kept by every
+    /// pool update, so the TWAP is exact over gaps in the ring, which only move the start of the
+    /// window back.
+    pub fn twap_data(
+        amm: &AmmInfo,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        now: u64,
+        start: Option<Observation>,
+    ) -> Result<GetTwapData, ProgramError> {
+        let coin_price_cumulative =
+            amm.price_cumulative_at(total_pc_without_take_pnl, total_coin_without_take_pnl, now);
+        let mut twap_data = GetTwapData {
+            coin_price_cumulative,
+            observation_time: now,
+            ..Default::default()
+        };
+        let start = match start {
+            Some(start) => start,
+            None => return Ok(twap_data),
+        };
+        if start.timestamp >= now {
+            return Err(AmmError::InvalidInput.into());
+        }
+        let elapsed = (now - start.timestamp) as u128;
+        let coin_twap = coin_price_cumulative.wrapping_sub(start.coin_price_cumulative) / elapsed;
+        twap_data.window_start_time = start.timestamp;
+        twap_data.coin_twap = Calculator::q64_price_to_spot_price(
+            coin_twap,
+            amm.pc_decimals,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        if twap_data.coin_twap != 0 {
+            twap_data.pc_twap = Calculator::to_u64(
+                (U256::from(amm.sys_decimal_value) * U256::from(amm.sys_decimal_value)
+                    / U256::from(twap_data.coin_twap))
+                .min(U256::from(u64::MAX))
+                .as_u128(),
+            )?;
+        }
+        Ok(twap_data)
+    }
+
+    /// Creates the observation account of an amm, paid by anyone.
+    pub fn process_initialize_observation(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        init: InitializeObservationInstruction,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let payer_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let observation_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        if *system_program_info.key != solana_program::system_program::id() {
+            return Err(AmmError::InvalidSysProgramAddress.into());
+        }
+        AmmInfo::load_checked(&amm_info, program_id)?;
+        let (pda, bump_seed) = get_associated_address_and_bump_seed(
+            program_id,
+            amm_info.key,
+            OBSERVATION_ASSOCIATED_SEED,
+            program_id,
+        );
+        if pda != *observation_info.key || observation_info.owner != system_program_info.key {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        let data_size = ObservationState::account_len(init.capacity.into());
+        if init.capacity == 0 || data_size > MAX_PERMITTED_DATA_INCREASE {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        let pda_signer_seeds: &[&[_]] = &[
+            &program_id.to_bytes(),
+            &amm_info.key.to_bytes(),
+            OBSERVATION_ASSOCIATED_SEED,
+            &[bump_seed],
+        ];
+        let required_lamports = Rent::get()?
+            .minimum_balance(data_size)
+            .max(1)
+            .saturating_sub(observation_info.lamports());
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(
+                    payer_info.key,
+                    observation_info.key,
+                    required_lamports,
+                ),
+                &[
+                    payer_info.clone(),
+                    observation_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(observation_info.key, data_size as u64),
+            &[observation_info.clone(), system_program_info.clone()],
+            &[&pda_signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(observation_info.key, &program_id),
+            &[observation_info.clone(), system_program_info.clone()],
+            &[&pda_signer_seeds],
+        )?;
+
+        let mut observation = ObservationState::load_mut(observation_info)?;
+        observation.header.amm = *amm_info.key;
+        observation.header.capacity = init.capacity.into();
+        observation.header.len = 0;
+        observation.header.index = 0;
+
+        Ok(())
+    }
+
+    /// Grows the observation account of an amm to a larger capacity, paid by anyone.
+    pub fn process_grow_observation(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        grow: GrowObservationInstruction,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let payer_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let observation_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        if *system_program_info.key != solana_program::system_program::id() {
+            return Err(AmmError::InvalidSysProgramAddress.into());
+        }
+        AmmInfo::load_checked(&amm_info, program_id)?;
+        Self::check_observation_address(program_id, amm_info.key, observation_info)?;
+        let capacity =
+            ObservationState::load_mut_checked(observation_info, program_id, amm_info.key)?
+                .header
+                .capacity;
+        let new_capacity: u64 = grow.capacity.into();
+        let data_size = ObservationState::account_len(new_capacity);
+        if new_capacity <= capacity
+            || data_size - observation_info.data_len() > MAX_PERMITTED_DATA_INCREASE
+        {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        let required_lamports = Rent::get()?
+            .minimum_balance(data_size)
+            .saturating_sub(observation_info.lamports());
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(
+                    payer_info.key,
+                    observation_info.key,
+                    required_lamports,
+                ),
+                &[
+                    payer_info.clone(),
+                    observation_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
+        observation_info.realloc(data_size, true)?;
+        // the new slots join the ring once the latest observation reaches its end
+        let mut observation = ObservationState::load_mut(observation_info)?;
+        observation.header.capacity = new_capacity;
+
+        Ok(())
+    }
+
+    pub fn do_idle_state(args: account_parser::IdleArgs) -> ProgramResult {
+        let account_parser::IdleArgs {
+            program_id: _,
+            total_coin_without_take_pnl,
+            total_pc_without_take_pnl,
+            amm,
+            bids,
+            asks,
             target,
         } = args;
 
@@ -4771,11 +8017,15 @@ impl Processor {
             .checked_add(open_orders.native_pc_free)
             .unwrap()
             .checked_sub(amm.state_data.need_take_pnl_pc)
+            .unwrap()
+            .checked_sub(amm.state_data.protocol_fee_pc)
             .unwrap();
         let mut coin_avaliable = coin_vault_amount
             .checked_add(open_orders.native_coin_free)
             .unwrap()
             .checked_sub(amm.state_data.need_take_pnl_coin)
+            .unwrap()
+            .checked_sub(amm.state_data.protocol_fee_coin)
             .unwrap();
         for i in place_orders_cur..place_orders_num {
             let payer = amm_pc_vault_info.clone();
@@ -4982,12 +8232,16 @@ impl Processor {
                     .checked_add(open_orders.native_pc_free)
                     .unwrap()
                     .checked_sub(amm.state_data.need_take_pnl_pc)
+                    .unwrap()
+                    .checked_sub(amm.state_data.protocol_fee_pc)
                     .unwrap();
                 coin_avaliable = coin_vault
                     .amount
                     .checked_add(open_orders.native_coin_free)
                     .unwrap()
                     .checked_sub(amm.state_data.need_take_pnl_coin)
+                    .unwrap()
+                    .checked_sub(amm.state_data.protocol_fee_coin)
                     .unwrap();
             }
         }
@@ -5200,6 +8454,9 @@ impl Processor {
             return Err(AmmError::InvalidSplTokenProgram.into());
         }
         let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
         if *amm_authority_info.key
             != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
         {
@@ -5286,6 +8543,14 @@ impl Processor {
                     None => return Err(AmmError::InvalidInput.into()),
                 };
                 if AmmStatus::valid_status(value) {
+                    Self::check_orderbook_vault_program(
+                        AmmStatus::from_u64(value),
+                        amm_coin_vault_info.owner,
+                    )?;
+                    Self::check_orderbook_vault_program(
+                        AmmStatus::from_u64(value),
+                        amm_pc_vault_info.owner,
+                    )?;
                     amm.status = value as u64;
                     set_valid = true;
                 }
@@ -5375,7 +8640,7 @@ impl Processor {
                     None => return Err(AmmError::InvalidInput.into()),
                 };
                 fees.validate()?;
-                amm.fees = fees;
+                amm.set_fees(fees);
                 set_valid = true;
             }
             AmmParams::AmmOwner => {
@@ -5438,13 +8703,64 @@ impl Processor {
                 )?;
                 set_valid = true;
             }
+            AmmParams::FlashLoanFee => {
+                let value = match setparams.value {
+                    Some(a) => a,
+                    None => return Err(AmmError::InvalidInput.into()),
+                };
+                if value < TEN_THOUSAND {
+                    amm.flash_loan_fee_numerator = value as u32;
+                    set_valid = true;
+                }
+            }
+            AmmParams::DynamicFeeMin => {
+                let value = match setparams.value {
+                    Some(a) => a,
+                    None => return Err(AmmError::InvalidInput.into()),
+                };
+                // the floor the dynamic fee decays to, zero falls back to the base fee
+                if value <= amm.fees.swap_fee_numerator && value <= u32::MAX as u64 {
+                    amm.dynamic_fee_min_numerator = value as u32;
+                    set_valid = true;
+                }
+            }
+            AmmParams::DynamicFeeMax => {
+                let value = match setparams.value {
+                    Some(a) => a,
+                    None => return Err(AmmError::InvalidInput.into()),
+                };
+                // zero disables the dynamic fee
+                if value == 0
+                    || (value >= amm.fees.swap_fee_numerator
+                        && value < amm.fees.swap_fee_denominator
+                        && value <= u32::MAX as u64)
+                {
+                    amm.dynamic_fee_max_numerator = value as u32;
+                    set_valid = true;
+                }
+            }
+            AmmParams::ProtocolFeeRate => {
+                // the pool takes the rate of the config, swaps don't read the config. A rate
+                // updated in the config reaches a pool only through this param
+                let amm_config_info = next_account_info(account_info_iter)?;
+                let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
+                if pda != *amm_config_info.key || amm_config_info.owner != program_id {
+                    return Err(AmmError::InvalidConfigAccount.into());
+                }
+                let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
+                amm.protocol_fee_rate = amm_config.protocol_fee_rate as u32;
+                set_valid = true;
+            }
             _ => {
                 return Err(AmmError::InvalidInput.into());
             }
         }
         if set_valid {
-            amm.state = AmmState::CancelAllOrdersState.into_u64();
-            amm.reset_flag = AmmResetFlag::ResetYes.into_u64();
+            // the protocol fee rate doesn't change the orders the pool places
+            if param != AmmParams::ProtocolFeeRate.into_u64() as u8 {
+                amm.state = AmmState::CancelAllOrdersState.into_u64();
+                amm.reset_flag = AmmResetFlag::ResetYes.into_u64();
+            }
         } else {
             return Err(AmmError::InvalidParamsSet.into());
         }
@@ -5482,6 +8798,9 @@ impl Processor {
         }
 
         let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
         Self::check_accounts(
             program_id,
             &amm,
@@ -5839,6 +9158,9 @@ impl Processor {
             return Err(AmmError::InvalidSplTokenProgram.into());
         }
         let amm = AmmInfo::load_checked(&amm_info, program_id)?;
+        if amm.is_flash_loan_active() {
+            return Err(AmmError::FlashLoanInProgress.into());
+        }
         if *amm_authority_info.key
             != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
         {
@@ -6040,92 +9362,372 @@ impl Processor {
                 let create_pool_fee = config_args.create_pool_fee.unwrap();
                 amm_config.create_pool_fee = create_pool_fee;
             }
+            3 => {
+                let protocol_fee_rate = config_args.protocol_fee_rate.unwrap();
+                if protocol_fee_rate > TEN_THOUSAND {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                amm_config.protocol_fee_rate = protocol_fee_rate;
+            }
             _ => {
                 return Err(AmmError::InvalidInput.into());
             }
         }
-
-        return Ok(());
+
+        return Ok(());
+    }
+
+    /// Processes an [Instruction](enum.Instruction.html).
+    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
+        let instruction = AmmInstruction::unpack(input)?;
+        match instruction {
+            AmmInstruction::PreInitialize(_init_arg) => {
+                unimplemented!("This instruction is not supported, please use Initialize2")
+            }
+            AmmInstruction::Initialize(_init1) => {
+                unimplemented!("This instruction is not supported, please use Initialize2")
+            }
+            AmmInstruction::Initialize2(init2) => {
+                Self::process_initialize2(program_id, accounts, init2)
+            }
+            AmmInstruction::MonitorStep(monitor) => {
+                Self::process_monitor_step(program_id, accounts, monitor)
+            }
+            AmmInstruction::Deposit(deposit) => {
+                Self::process_deposit(program_id, accounts, deposit)
+            }
+            AmmInstruction::Withdraw(withdraw) => {
+                Self::process_withdraw(program_id, accounts, withdraw)
+            }
+            AmmInstruction::MigrateToOpenBook => {
+                Self::process_migrate_to_openbook(program_id, accounts)
+            }
+            AmmInstruction::SetParams(setparams) => {
+                Self::process_set_params(program_id, accounts, setparams)
+            }
+            AmmInstruction::WithdrawPnl => Self::process_withdrawpnl(program_id, accounts),
+            AmmInstruction::WithdrawSrm(withdrawsrm) => {
+                Self::process_withdraw_srm(program_id, accounts, withdrawsrm)
+            }
+            AmmInstruction::SwapBaseIn(swap) => {
+                Self::process_swap_base_in(program_id, accounts, swap)
+            }
+            AmmInstruction::SwapBaseOut(swap) => {
+                Self::process_swap_base_out(program_id, accounts, swap)
+            }
+            AmmInstruction::SwapBaseInV2(swap) => {
+                Self::process_swap_base_in_v2(program_id, accounts, swap)
+            }
+            AmmInstruction::SwapBaseOutV2(swap) => {
+                Self::process_swap_base_out_v2(program_id, accounts, swap)
+            }
+            AmmInstruction::SwapBaseInLimit(swap) => {
+                Self::process_swap_base_in_limit(program_id, accounts, swap)
+            }
+            AmmInstruction::ZapDeposit(zap) => Self::process_zap_deposit(program_id, accounts, zap),
+            AmmInstruction::ZapWithdraw(zap) => {
+                Self::process_zap_withdraw(program_id, accounts, zap)
+            }
+            AmmInstruction::WithdrawBaseOut(withdraw) => {
+                Self::process_withdraw_base_out(program_id, accounts, withdraw)
+            }
+            AmmInstruction::RouteSwapBaseIn(swap) => {
+                Self::process_route_swap_base_in(program_id, accounts, swap)
+            }
+            AmmInstruction::FlashLoanBegin(flash_loan) => {
+                Self::process_flash_loan_begin(program_id, accounts, flash_loan)
+            }
+            AmmInstruction::FlashLoanEnd(flash_loan) => {
+                Self::process_flash_loan_end(program_id, accounts, flash_loan)
+            }
+            AmmInstruction::SimulateInfo(simulate) => {
+                Self::process_simulate_info(program_id, accounts, simulate)
+            }
+            AmmInstruction::AdminCancelOrders(cancel) => {
+                Self::process_admin_cancel_orders(program_id, accounts, cancel)
+            }
+            AmmInstruction::CreateConfigAccount => {
+                Self::process_create_config(program_id, accounts)
+            }
+            AmmInstruction::UpdateConfigAccount(config_args) => {
+                Self::process_update_config(program_id, accounts, config_args)
+            }
+            AmmInstruction::GetTwap(get_twap) => {
+                Self::process_get_twap(program_id, accounts, get_twap)
+            }
+            AmmInstruction::InitializeObservation(init) => {
+                Self::process_initialize_observation(program_id, accounts, init)
+            }
+            AmmInstruction::GrowObservation(grow) => {
+                Self::process_grow_observation(program_id, accounts, grow)
+            }
+            AmmInstruction::WriteObservation => {
+                Self::process_write_observation(program_id, accounts)
+            }
+            AmmInstruction::WithdrawProtocolFee => {
+                Self::process_withdraw_protocol_fee(program_id, accounts)
+            }
+        }
+    }
+}
+
+pub mod account_parser {
+    use super::*;
+    pub struct IdleArgs<'a> {
+        pub program_id: &'a Pubkey,
+        pub total_coin_without_take_pnl: &'a mut u64,
+        pub total_pc_without_take_pnl: &'a mut u64,
+        pub amm: &'a mut AmmInfo,
+        pub bids: &'a Vec<LeafNode>,
+        pub asks: &'a Vec<LeafNode>,
+        pub target: &'a mut TargetOrders,
+    }
+    impl<'a> IdleArgs<'a> {
+        pub fn with_parsed_args(
+            program_id: &'a Pubkey,
+            spl_token_program_id: &'a Pubkey,
+            amm: &mut AmmInfo,
+            accounts: &'a [AccountInfo],
+            f: impl FnOnce(IdleArgs) -> ProgramResult,
+        ) -> ProgramResult {
+            if accounts.len() != 11 {
+                return Err(AmmError::WrongAccountsNumber.into());
+            }
+            let &[ref amm_info, ref _market_program_info, ref market_info, ref market_bids_info, ref market_asks_info, ref market_event_queue_info, ref market_authority_info, ref amm_open_orders_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
+                array_ref![accounts, 0, 11];
+
+            let amm_coin_vault =
+                Processor::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
+            let amm_pc_vault =
+                Processor::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+            let mut target =
+                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+
+            let (market_state, open_orders) = Processor::load_serum_market_order(
+                market_info,
+                amm_open_orders_info,
+                market_authority_info,
+                &amm,
+                false,
+            )?;
+            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
+            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
+            let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
+
+            let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl(
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                    &open_orders,
+                    &amm,
+                    &market_state,
+                    &market_event_queue_info,
+                    &amm_open_orders_info,
+                )?;
+
+            let args = IdleArgs {
+                program_id,
+                total_coin_without_take_pnl: &mut total_coin_without_take_pnl,
+                total_pc_without_take_pnl: &mut total_pc_without_take_pnl,
+                amm,
+                bids: &bids,
+                asks: &asks,
+                target: &mut target,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CancelAllOrdersArgs<'a, 'b: 'a> {
+        pub program_id: &'a Pubkey,
+        pub limit: u16,
+        pub market_program_info: &'a AccountInfo<'b>,
+        pub market_info: &'a AccountInfo<'b>,
+        pub amm_open_orders_info: &'a AccountInfo<'b>,
+        pub amm_authority_info: &'a AccountInfo<'b>,
+        pub market_event_queue_info: &'a AccountInfo<'b>,
+        pub market_coin_vault_info: &'a AccountInfo<'b>,
+        pub market_pc_vault_info: &'a AccountInfo<'b>,
+        pub market_bids_info: &'a AccountInfo<'b>,
+        pub market_asks_info: &'a AccountInfo<'b>,
+        pub market_vault_signer: &'a AccountInfo<'b>,
+        pub token_program_info: &'a AccountInfo<'b>,
+        pub amm_coin_vault_info: &'a AccountInfo<'b>,
+        pub amm_pc_vault_info: &'a AccountInfo<'b>,
+
+        pub referrer_pc_account: Option<&'a AccountInfo<'b>>,
+
+        pub amm: &'a mut AmmInfo,
+        pub open_orders: &'a OpenOrders,
+        pub target: &'a mut TargetOrders,
+        pub bids: &'a Vec<LeafNode>,
+        pub asks: &'a Vec<LeafNode>,
+    }
+    impl<'a, 'b: 'a> CancelAllOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args(
+            program_id: &'a Pubkey,
+            limit: u16,
+            amm: &mut AmmInfo,
+            accounts: &'a [AccountInfo<'b>],
+            referrer_pc_wallet: Option<&'a AccountInfo<'b>>,
+            f: impl FnOnce(CancelAllOrdersArgs) -> ProgramResult,
+        ) -> ProgramResult {
+            if accounts.len() != 15 {
+                return Err(AmmError::WrongAccountsNumber.into());
+            }
+            let &[ref amm_info, ref market_program_info, ref market_info, ref amm_open_orders_info, ref amm_authority_info, ref market_event_queue_info, ref market_coin_vault_info, ref market_pc_vault_info, ref market_bids_info, ref market_asks_info, ref market_vault_signer, ref token_program_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
+                array_ref![accounts, 0, 15];
+
+            let mut target =
+                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+            let (market_state, open_orders) = Processor::load_serum_market_order(
+                market_info,
+                amm_open_orders_info,
+                amm_authority_info,
+                &amm,
+                true,
+            )?;
+            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
+            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
+            let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
+
+            let args = CancelAllOrdersArgs {
+                program_id,
+                limit,
+                market_program_info,
+                market_info,
+                amm_open_orders_info,
+                amm_authority_info,
+                market_event_queue_info,
+                market_coin_vault_info,
+                market_pc_vault_info,
+                market_bids_info,
+                market_asks_info,
+                market_vault_signer,
+                token_program_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                referrer_pc_account: referrer_pc_wallet,
+                amm,
+                open_orders: &open_orders,
+                target: &mut target,
+                bids: &bids,
+                asks: &asks,
+            };
+            f(args)
+        }
     }
 
-    /// Processes an [Instruction](enum.Instruction.html).
-    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
-        let instruction = AmmInstruction::unpack(input)?;
-        match instruction {
-            AmmInstruction::PreInitialize(_init_arg) => {
-                unimplemented!("This instruction is not supported, please use Initialize2")
-            }
-            AmmInstruction::Initialize(_init1) => {
-                unimplemented!("This instruction is not supported, please use Initialize2")
-            }
-            AmmInstruction::Initialize2(init2) => {
-                Self::process_initialize2(program_id, accounts, init2)
-            }
-            AmmInstruction::MonitorStep(monitor) => {
-                Self::process_monitor_step(program_id, accounts, monitor)
-            }
-            AmmInstruction::Deposit(deposit) => {
-                Self::process_deposit(program_id, accounts, deposit)
-            }
-            AmmInstruction::Withdraw(withdraw) => {
-                Self::process_withdraw(program_id, accounts, withdraw)
-            }
-            AmmInstruction::MigrateToOpenBook => {
-                Self::process_migrate_to_openbook(program_id, accounts)
-            }
-            AmmInstruction::SetParams(setparams) => {
-                Self::process_set_params(program_id, accounts, setparams)
-            }
-            AmmInstruction::WithdrawPnl => Self::process_withdrawpnl(program_id, accounts),
-            AmmInstruction::WithdrawSrm(withdrawsrm) => {
-                Self::process_withdraw_srm(program_id, accounts, withdrawsrm)
-            }
-            AmmInstruction::SwapBaseIn(swap) => {
-                Self::process_swap_base_in(program_id, accounts, swap)
-            }
-            AmmInstruction::SwapBaseOut(swap) => {
-                Self::process_swap_base_out(program_id, accounts, swap)
-            }
-            AmmInstruction::SimulateInfo(simulate) => {
-                Self::process_simulate_info(program_id, accounts, simulate)
-            }
-            AmmInstruction::AdminCancelOrders(cancel) => {
-                Self::process_admin_cancel_orders(program_id, accounts, cancel)
-            }
-            AmmInstruction::CreateConfigAccount => {
-                Self::process_create_config(program_id, accounts)
-            }
-            AmmInstruction::UpdateConfigAccount(config_args) => {
-                Self::process_update_config(program_id, accounts, config_args)
+    pub struct PlanOrderBookArgs<'a> {
+        pub program_id: &'a Pubkey,
+        pub limit: u16,
+        pub total_coin_without_take_pnl: u64,
+        pub total_pc_without_take_pnl: u64,
+        pub amm: &'a mut AmmInfo,
+        pub target: &'a mut TargetOrders,
+    }
+    impl<'a> PlanOrderBookArgs<'a> {
+        pub fn with_parsed_args(
+            program_id: &'a Pubkey,
+            spl_token_program_id: &'a Pubkey,
+            limit: u16,
+            amm: &mut AmmInfo,
+            accounts: &'a [AccountInfo],
+            f: impl FnOnce(PlanOrderBookArgs) -> ProgramResult,
+        ) -> ProgramResult {
+            if accounts.len() != 8 {
+                return Err(AmmError::WrongAccountsNumber.into());
             }
+            let &[ref amm_info, ref market_info, ref market_event_queue_info, ref amm_authority_info, ref amm_open_orders_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
+                array_ref![accounts, 0, 8];
+
+            let amm_coin_vault =
+                Processor::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
+            let amm_pc_vault =
+                Processor::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+            let mut target =
+                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+            let (market_state, open_orders) = Processor::load_serum_market_order(
+                market_info,
+                amm_open_orders_info,
+                amm_authority_info,
+                &amm,
+                false,
+            )?;
+            let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl(
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                    &open_orders,
+                    &amm,
+                    &market_state,
+                    &market_event_queue_info,
+                    &amm_open_orders_info,
+                )?;
+
+            let args = PlanOrderBookArgs {
+                program_id,
+                limit,
+                total_coin_without_take_pnl,
+                total_pc_without_take_pnl,
+                amm,
+                target: &mut target,
+            };
+            f(args)
         }
     }
-}
 
-pub mod account_parser {
-    use super::*;
-    pub struct IdleArgs<'a> {
+    pub struct PlaceOrdersArgs<'a, 'b: 'a> {
         pub program_id: &'a Pubkey,
-        pub total_coin_without_take_pnl: &'a mut u64,
-        pub total_pc_without_take_pnl: &'a mut u64,
+        pub limit: u16,
+
+        pub amm_authority_info: &'a AccountInfo<'b>,
+        pub amm_open_orders_info: &'a AccountInfo<'b>,
+        pub market_program_info: &'a AccountInfo<'b>,
+        pub market_info: &'a AccountInfo<'b>,
+        pub market_request_queue_info: &'a AccountInfo<'b>,
+        pub amm_coin_vault_info: &'a AccountInfo<'b>,
+        pub amm_pc_vault_info: &'a AccountInfo<'b>,
+        pub market_coin_vault_info: &'a AccountInfo<'b>,
+        pub market_pc_vault_info: &'a AccountInfo<'b>,
+        pub token_program_info: &'a AccountInfo<'b>,
+        pub rent_info: &'a AccountInfo<'b>,
+
+        pub market_event_queue_info: &'a AccountInfo<'b>,
+        pub market_bids_info: &'a AccountInfo<'b>,
+        pub market_asks_info: &'a AccountInfo<'b>,
+
+        pub srm_token_account: Option<&'a AccountInfo<'b>>,
+
         pub amm: &'a mut AmmInfo,
+        pub open_orders: &'a OpenOrders,
         pub bids: &'a Vec<LeafNode>,
         pub asks: &'a Vec<LeafNode>,
         pub target: &'a mut TargetOrders,
+
+        pub total_coin_without_take_pnl: u64,
+        pub total_pc_without_take_pnl: u64,
+        pub coin_vault_amount: u64,
+        pub pc_vault_amount: u64,
     }
-    impl<'a> IdleArgs<'a> {
+    impl<'a, 'b: 'a> PlaceOrdersArgs<'a, 'b> {
         pub fn with_parsed_args(
             program_id: &'a Pubkey,
             spl_token_program_id: &'a Pubkey,
+            limit: u16,
             amm: &mut AmmInfo,
-            accounts: &'a [AccountInfo],
-            f: impl FnOnce(IdleArgs) -> ProgramResult,
+            accounts: &'a [AccountInfo<'b>],
+            srm_token_account: Option<&'a AccountInfo<'b>>,
+            f: impl FnOnce(PlaceOrdersArgs) -> ProgramResult,
         ) -> ProgramResult {
-            if accounts.len() != 11 {
+            if accounts.len() != 17 {
                 return Err(AmmError::WrongAccountsNumber.into());
             }
-            let &[ref amm_info, ref _market_program_info, ref market_info, ref market_bids_info, ref market_asks_info, ref market_event_queue_info, ref market_authority_info, ref amm_open_orders_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
-                array_ref![accounts, 0, 11];
+            let accounts = array_ref![accounts, 0, 17];
+            let (new_orders_accounts, data_accounts) = array_refs![accounts, 15, 2];
+            let &[ref amm_info, ref amm_authority_info, ref amm_open_orders_info, ref market_program_info, ref market_info, ref market_request_queue_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref market_coin_vault_info, ref market_pc_vault_info, ref token_program_info, ref rent_info, ref market_event_queue_info, ref market_bids_info, ref market_asks_info] =
+                array_ref![new_orders_accounts, 0, 15];
+            let &[ref amm_target_orders_info, ref _clock_info] = array_ref![data_accounts, 0, 2];
 
             let amm_coin_vault =
                 Processor::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
@@ -6133,43 +9735,127 @@ pub mod account_parser {
                 Processor::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
             let mut target =
                 TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-
             let (market_state, open_orders) = Processor::load_serum_market_order(
                 market_info,
                 amm_open_orders_info,
-                market_authority_info,
+                amm_authority_info,
+                &amm,
+                false,
+            )?;
+            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
+            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
+            let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
+            let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl(
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                    &open_orders,
+                    &amm,
+                    &market_state,
+                    &market_event_queue_info,
+                    &amm_open_orders_info,
+                )?;
+
+            let args = PlaceOrdersArgs {
+                program_id,
+                limit,
+
+                amm_authority_info,
+                amm_open_orders_info,
+                market_program_info,
+                market_info,
+                market_request_queue_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                market_coin_vault_info,
+                market_pc_vault_info,
+                token_program_info,
+                rent_info,
+
+                market_event_queue_info,
+                market_bids_info,
+                market_asks_info,
+                srm_token_account,
+
+                amm,
+                open_orders: &open_orders,
+                bids: &bids,
+                asks: &asks,
+                target: &mut target,
+
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+
+                coin_vault_amount: amm_coin_vault.amount,
+                pc_vault_amount: amm_pc_vault.amount,
+            };
+            f(args)
+        }
+    }
+
+    pub struct PurgeOrderArgs<'a, 'b: 'a> {
+        pub program_id: &'a Pubkey,
+        pub limit: u16,
+        pub market_program_info: &'a AccountInfo<'b>,
+        pub market_info: &'a AccountInfo<'b>,
+        pub amm_open_orders_info: &'a AccountInfo<'b>,
+        pub amm_authority_info: &'a AccountInfo<'b>,
+        pub market_event_queue_info: &'a AccountInfo<'b>,
+        pub market_bids_info: &'a AccountInfo<'b>,
+        pub market_asks_info: &'a AccountInfo<'b>,
+        pub amm: &'a mut AmmInfo,
+        pub target: &'a TargetOrders,
+        pub bids: &'a Vec<LeafNode>,
+        pub asks: &'a Vec<LeafNode>,
+    }
+    impl<'a, 'b: 'a> PurgeOrderArgs<'a, 'b> {
+        pub fn with_parsed_args(
+            program_id: &'a Pubkey,
+            _spl_token_program_id: &'a Pubkey,
+            limit: u16,
+            amm: &mut AmmInfo,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(PurgeOrderArgs) -> ProgramResult,
+        ) -> ProgramResult {
+            if accounts.len() != 9 {
+                return Err(AmmError::WrongAccountsNumber.into());
+            }
+            let &[ref amm_info, ref market_program_info, ref market_info, ref amm_open_orders_info, ref amm_authority_info, ref market_event_queue_info, ref market_bids_info, ref market_asks_info, ref amm_target_orders_info] =
+                array_ref![accounts, 0, 9];
+
+            let target =
+                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+            let (market_state, open_orders) = Processor::load_serum_market_order(
+                market_info,
+                amm_open_orders_info,
+                amm_authority_info,
                 &amm,
-                false,
+                true,
             )?;
             let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
             let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
             let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
 
-            let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
-                Calculator::calc_total_without_take_pnl(
-                    amm_pc_vault.amount,
-                    amm_coin_vault.amount,
-                    &open_orders,
-                    &amm,
-                    &market_state,
-                    &market_event_queue_info,
-                    &amm_open_orders_info,
-                )?;
-
-            let args = IdleArgs {
+            let args = PurgeOrderArgs {
                 program_id,
-                total_coin_without_take_pnl: &mut total_coin_without_take_pnl,
-                total_pc_without_take_pnl: &mut total_pc_without_take_pnl,
+                limit,
+                market_program_info,
+                market_info,
+                amm_open_orders_info,
+                amm_authority_info,
+                market_event_queue_info,
+                market_bids_info,
+                market_asks_info,
                 amm,
+                target: &target,
                 bids: &bids,
                 asks: &asks,
-                target: &mut target,
             };
             f(args)
         }
     }
 
-    pub struct CancelAllOrdersArgs<'a, 'b: 'a> {
+    pub struct CancelOrderArgs<'a, 'b: 'a> {
         pub program_id: &'a Pubkey,
         pub limit: u16,
         pub market_program_info: &'a AccountInfo<'b>,
@@ -6177,38 +9863,36 @@ pub mod account_parser {
         pub amm_open_orders_info: &'a AccountInfo<'b>,
         pub amm_authority_info: &'a AccountInfo<'b>,
         pub market_event_queue_info: &'a AccountInfo<'b>,
-        pub market_coin_vault_info: &'a AccountInfo<'b>,
-        pub market_pc_vault_info: &'a AccountInfo<'b>,
         pub market_bids_info: &'a AccountInfo<'b>,
         pub market_asks_info: &'a AccountInfo<'b>,
-        pub market_vault_signer: &'a AccountInfo<'b>,
-        pub token_program_info: &'a AccountInfo<'b>,
-        pub amm_coin_vault_info: &'a AccountInfo<'b>,
-        pub amm_pc_vault_info: &'a AccountInfo<'b>,
-
-        pub referrer_pc_account: Option<&'a AccountInfo<'b>>,
-
         pub amm: &'a mut AmmInfo,
         pub open_orders: &'a OpenOrders,
         pub target: &'a mut TargetOrders,
         pub bids: &'a Vec<LeafNode>,
         pub asks: &'a Vec<LeafNode>,
+
+        pub coin_amount: u64,
+        pub pc_amount: u64,
     }
-    impl<'a, 'b: 'a> CancelAllOrdersArgs<'a, 'b> {
+    impl<'a, 'b: 'a> CancelOrderArgs<'a, 'b> {
         pub fn with_parsed_args(
             program_id: &'a Pubkey,
+            spl_token_program_id: &'a Pubkey,
             limit: u16,
             amm: &mut AmmInfo,
             accounts: &'a [AccountInfo<'b>],
-            referrer_pc_wallet: Option<&'a AccountInfo<'b>>,
-            f: impl FnOnce(CancelAllOrdersArgs) -> ProgramResult,
+            f: impl FnOnce(CancelOrderArgs) -> ProgramResult,
         ) -> ProgramResult {
-            if accounts.len() != 15 {
+            if accounts.len() != 11 {
                 return Err(AmmError::WrongAccountsNumber.into());
             }
-            let &[ref amm_info, ref market_program_info, ref market_info, ref amm_open_orders_info, ref amm_authority_info, ref market_event_queue_info, ref market_coin_vault_info, ref market_pc_vault_info, ref market_bids_info, ref market_asks_info, ref market_vault_signer, ref token_program_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
-                array_ref![accounts, 0, 15];
+            let &[ref amm_info, ref market_program_info, ref market_info, ref amm_open_orders_info, ref amm_authority_info, ref market_event_queue_info, ref market_bids_info, ref market_asks_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
+                array_ref![accounts, 0, 11];
 
+            let amm_coin_vault =
+                Processor::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
+            let amm_pc_vault =
+                Processor::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
             let mut target =
                 TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
             let (market_state, open_orders) = Processor::load_serum_market_order(
@@ -6222,532 +9906,1645 @@ pub mod account_parser {
             let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
             let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
 
-            let args = CancelAllOrdersArgs {
-                program_id,
-                limit,
-                market_program_info,
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
-                market_event_queue_info,
-                market_coin_vault_info,
-                market_pc_vault_info,
-                market_bids_info,
-                market_asks_info,
-                market_vault_signer,
-                token_program_info,
-                amm_coin_vault_info,
-                amm_pc_vault_info,
-                referrer_pc_account: referrer_pc_wallet,
-                amm,
-                open_orders: &open_orders,
-                target: &mut target,
-                bids: &bids,
-                asks: &asks,
-            };
-            f(args)
+            let args = CancelOrderArgs {
+                program_id,
+                limit,
+                market_program_info,
+                market_info,
+                amm_open_orders_info,
+                amm_authority_info,
+                market_event_queue_info,
+                market_bids_info,
+                market_asks_info,
+                amm,
+                open_orders: &open_orders,
+                target: &mut target,
+                bids: &bids,
+                asks: &asks,
+                coin_amount: amm_coin_vault.amount,
+                pc_amount: amm_pc_vault.amount,
+            };
+            f(args)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{ObservationHeader, OBSERVATION_HEADER_LEN};
+
+    #[test]
+    fn test_calc_take_pnl() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 2, 9, 1000000, 1).unwrap();
+        let mut target = TargetOrders::default();
+        target.calc_pnl_x = 900000000000000;
+        target.calc_pnl_y = 150000000000000000000000000;
+
+        let mut total_pc_without_take_pnl = 1343675125663;
+        let mut total_coin_without_take_pnl = 117837534493793;
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+
+        let (delta_x, delta_y) = Processor::calc_take_pnl(
+            &target,
+            &mut amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            x1.as_u128().into(),
+            y1.as_u128().into(),
+        )
+        .unwrap();
+        println!("delta_x:{}, delta_y:{}", delta_x, delta_y);
+    }
+
+    #[test]
+    fn test_calc_pnl_precision() {
+        // init
+        let mut amm = AmmInfo::default();
+        let init_pc_amount = 5434000000u64;
+        let init_coin_amount = 100000000000000u64;
+        let liquidity = Calculator::to_u64(
+            U128::from(init_pc_amount)
+                .checked_mul(init_coin_amount.into())
+                .unwrap()
+                .integer_sqrt()
+                .as_u128(),
+        )
+        .unwrap();
+        amm.initialize(0, 0, 5, 9, 1000000000, 7803).unwrap();
+        amm.lp_amount = liquidity;
+
+        let x =
+            Calculator::normalize_decimal_v2(5434000000, amm.pc_decimals, amm.sys_decimal_value);
+        let y = Calculator::normalize_decimal_v2(
+            100000000000000,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        let mut target = TargetOrders::default();
+        target.calc_pnl_x = x.as_u128();
+        target.calc_pnl_y = y.as_u128();
+        println!(
+             "init_pc_amount:{}, init_coin_amount:{}, liquidity:{}, sys_decimal_value:{}, calc_pnl_x:{}, calc_pnl_y:{}",
+             init_pc_amount, init_coin_amount, liquidity, identity(amm.sys_decimal_value), identity(target.calc_pnl_x), identity(target.calc_pnl_y)
+         );
+
+        // withdraw
+        let withdraw_lp = 2577470628u64;
+        let mut total_pc_without_take_pnl = init_pc_amount;
+        let mut total_coin_without_take_pnl = init_coin_amount;
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+
+        let (delta_x, delta_y) = Processor::calc_take_pnl(
+            &target,
+            &mut amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            x1.as_u128().into(),
+            y1.as_u128().into(),
+        )
+        .unwrap();
+        println!("delta_x:{}, delta_y:{}", delta_x, delta_y);
+        // coin_amount / total_coin_amount = amount / lp_mint.supply => coin_amount = total_coin_amount * amount / pool_mint.supply
+        let invariant = InvariantPool {
+            token_input: withdraw_lp,
+            token_total: amm.lp_amount,
+        };
+        let coin_amount = invariant
+            .exchange_pool_to_token(total_coin_without_take_pnl, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)
+            .unwrap();
+        let pc_amount = invariant
+            .exchange_pool_to_token(total_pc_without_take_pnl, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)
+            .unwrap();
+
+        amm.lp_amount = amm.lp_amount.checked_sub(withdraw_lp).unwrap();
+        target.calc_pnl_x = x1
+            .checked_sub(Calculator::normalize_decimal_v2(
+                pc_amount,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_x))
+            .unwrap()
+            .as_u128();
+        target.calc_pnl_y = y1
+            .checked_sub(Calculator::normalize_decimal_v2(
+                coin_amount,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_y))
+            .unwrap()
+            .as_u128();
+        total_pc_without_take_pnl = total_pc_without_take_pnl.checked_sub(pc_amount).unwrap();
+        total_coin_without_take_pnl = total_coin_without_take_pnl
+            .checked_sub(coin_amount)
+            .unwrap();
+        println!(
+             "withdraw calc_pnl_x:{}, calc_pnl_y:{}, total_pc_without_take_pnl:{}, total_coin_without_take_pnl:{}",
+             identity(target.calc_pnl_x), identity(target.calc_pnl_y), total_pc_without_take_pnl, total_coin_without_take_pnl
+         );
+
+        // withdraw 2
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+
+        let (delta_x, delta_y) = Processor::calc_take_pnl(
+            &target,
+            &mut amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            x1.as_u128().into(),
+            y1.as_u128().into(),
+        )
+        .unwrap();
+        println!("delta_x:{}, delta_y:{}", delta_x, delta_y);
+    }
+
+    #[test]
+    fn test_supported_mint_extension() {
+        assert!(Processor::is_supported_mint_extension(
+            &ExtensionType::TransferFeeConfig
+        ));
+        assert!(Processor::is_supported_mint_extension(
+            &ExtensionType::MetadataPointer
+        ));
+        // the pool prices raw amounts and must be the only one moving its vault tokens
+        for extension in [
+            ExtensionType::InterestBearingConfig,
+            ExtensionType::TransferHook,
+            ExtensionType::PermanentDelegate,
+        ] {
+            assert!(!Processor::is_supported_mint_extension(&extension));
+        }
+    }
+
+    #[test]
+    fn test_orderbook_vault_program() {
+        for status in [AmmStatus::Initialized, AmmStatus::OrderBookOnly] {
+            assert!(Processor::check_orderbook_vault_program(status, &spl_token::id()).is_ok());
+            assert_eq!(
+                Processor::check_orderbook_vault_program(status, &spl_token_2022::id()),
+                Err(AmmError::InvalidStatus.into())
+            );
+        }
+        for status in [AmmStatus::SwapOnly, AmmStatus::WaitingTrade] {
+            assert!(
+                Processor::check_orderbook_vault_program(status, &spl_token_2022::id()).is_ok()
+            );
+        }
+
+        // a token-2022 vault of an orderbook pool is refused before its mint is read
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.status = AmmStatus::Initialized.into_u64();
+        let (vault_key, token_program_key) = (Pubkey::new_unique(), spl_token::id());
+        let token_2022_key = spl_token_2022::id();
+        let (mut vault_lamports, mut token_program_lamports) = (0, 0);
+        let (mut vault_data, mut token_program_data) = (vec![], vec![]);
+        let vault_info = AccountInfo::new(
+            &vault_key,
+            false,
+            true,
+            &mut vault_lamports,
+            &mut vault_data,
+            &token_2022_key,
+            false,
+            0,
+        );
+        let token_program_info = AccountInfo::new(
+            &token_program_key,
+            false,
+            false,
+            &mut token_program_lamports,
+            &mut token_program_data,
+            &token_program_key,
+            true,
+            0,
+        );
+        assert_eq!(
+            Processor::coin_vault_token(&amm, &vault_info, &token_program_info, None).err(),
+            Some(AmmError::InvalidStatus.into())
+        );
+        amm.status = AmmStatus::SwapOnly.into_u64();
+        assert_eq!(
+            Processor::coin_vault_token(&amm, &vault_info, &token_program_info, None).err(),
+            Some(AmmError::WrongAccountsNumber.into())
+        );
+    }
+
+    #[test]
+    fn test_check_swap_without_orderbook() {
+        // OrderBookOnly turns Initialized in the swap, both keep the orderbook
+        for status in [AmmStatus::Initialized, AmmStatus::OrderBookOnly] {
+            assert_eq!(
+                Processor::check_swap_without_orderbook(status.into_u64()),
+                Err(AmmError::InvalidStatus.into())
+            );
+        }
+        // WaitingTrade turns SwapOnly in the swap
+        for status in [AmmStatus::SwapOnly, AmmStatus::WaitingTrade] {
+            assert!(Processor::check_swap_without_orderbook(status.into_u64()).is_ok());
         }
     }
 
-    pub struct PlanOrderBookArgs<'a> {
-        pub program_id: &'a Pubkey,
-        pub limit: u16,
-        pub total_coin_without_take_pnl: u64,
-        pub total_pc_without_take_pnl: u64,
-        pub amm: &'a mut AmmInfo,
-        pub target: &'a mut TargetOrders,
+    #[test]
+    fn test_swap_base_in() {
+        let amount_in = 212854295571_u64;
+        let total_coin_without_take_pnl = 77043918330755_u64;
+        let total_pc_without_take_pnl = 1511361338135_u64;
+
+        let swap_direction = SwapDirection::Coin2PC;
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 2, 9, 1000000, 1).unwrap();
+
+        let swap_fee = U128::from(amount_in)
+            .checked_mul(amm.fees.swap_fee_numerator.into())
+            .unwrap()
+            .checked_ceil_div(amm.fees.swap_fee_denominator.into())
+            .unwrap()
+            .0;
+
+        let swap_in_after_deduct_fee = U128::from(amount_in).checked_sub(swap_fee).unwrap();
+        let swap_amount_out = Calculator::swap_token_amount_base_in(
+            swap_in_after_deduct_fee,
+            total_pc_without_take_pnl.into(),
+            total_coin_without_take_pnl.into(),
+            swap_direction,
+        )
+        .as_u64();
+
+        println!("swap_amount_out:{}", swap_amount_out);
     }
-    impl<'a> PlanOrderBookArgs<'a> {
-        pub fn with_parsed_args(
-            program_id: &'a Pubkey,
-            spl_token_program_id: &'a Pubkey,
-            limit: u16,
-            amm: &mut AmmInfo,
-            accounts: &'a [AccountInfo],
-            f: impl FnOnce(PlanOrderBookArgs) -> ProgramResult,
-        ) -> ProgramResult {
-            if accounts.len() != 8 {
-                return Err(AmmError::WrongAccountsNumber.into());
-            }
-            let &[ref amm_info, ref market_info, ref market_event_queue_info, ref amm_authority_info, ref amm_open_orders_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
-                array_ref![accounts, 0, 8];
 
-            let amm_coin_vault =
-                Processor::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-            let amm_pc_vault =
-                Processor::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-            let mut target =
-                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-            let (market_state, open_orders) = Processor::load_serum_market_order(
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
+    #[test]
+    fn test_swap_amount_in_to_price() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        let total_coin_without_take_pnl = 1_000_000_000_000_000_u64;
+        let total_pc_without_take_pnl = 150_000_000_000_000_u64;
+        let price = |pc: u64, coin: u64| {
+            let pc = Calculator::normalize_decimal_v2(pc, amm.pc_decimals, amm.sys_decimal_value);
+            let coin =
+                Calculator::normalize_decimal_v2(coin, amm.coin_decimals, amm.sys_decimal_value);
+            (pc * U128::from(amm.sys_decimal_value) / coin).as_u64()
+        };
+        let cur_price = price(total_pc_without_take_pnl, total_coin_without_take_pnl);
+        assert_eq!(cur_price, 150_000_000_000);
+
+        for (swap_direction, limit_price) in [
+            (SwapDirection::Coin2PC, cur_price * 99 / 100),
+            (SwapDirection::PC2Coin, cur_price * 101 / 100),
+        ] {
+            let amount_in = Calculator::swap_amount_in_to_price(
+                limit_price,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
                 &amm,
-                false,
-            )?;
-            let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-                Calculator::calc_total_without_take_pnl(
-                    amm_pc_vault.amount,
-                    amm_coin_vault.amount,
-                    &open_orders,
+                0,
+                swap_direction,
+            );
+            assert!(amount_in > 0);
+            // the fill stops at the limit, a larger input crosses it
+            for (amount_in, crossed) in [(amount_in, false), (amount_in * 101 / 100, true)] {
+                let amount_out = Calculator::swap_token_amount_base_in(
+                    amount_in.into(),
+                    total_pc_without_take_pnl.into(),
+                    total_coin_without_take_pnl.into(),
+                    swap_direction,
+                )
+                .as_u64();
+                let post_price = match swap_direction {
+                    SwapDirection::Coin2PC => price(
+                        total_pc_without_take_pnl - amount_out,
+                        total_coin_without_take_pnl + amount_in,
+                    ),
+                    SwapDirection::PC2Coin => price(
+                        total_pc_without_take_pnl + amount_in,
+                        total_coin_without_take_pnl - amount_out,
+                    ),
+                };
+                let within_limit = match swap_direction {
+                    SwapDirection::Coin2PC => post_price >= limit_price,
+                    SwapDirection::PC2Coin => post_price <= limit_price,
+                };
+                assert_eq!(within_limit, !crossed);
+            }
+            // no fill once the pool price is at the limit
+            assert_eq!(
+                Calculator::swap_amount_in_to_price(
+                    cur_price,
+                    total_pc_without_take_pnl,
+                    total_coin_without_take_pnl,
                     &amm,
-                    &market_state,
-                    &market_event_queue_info,
-                    &amm_open_orders_info,
-                )?;
-
-            let args = PlanOrderBookArgs {
-                program_id,
-                limit,
-                total_coin_without_take_pnl,
-                total_pc_without_take_pnl,
-                amm,
-                target: &mut target,
-            };
-            f(args)
+                    0,
+                    swap_direction,
+                ),
+                0
+            );
         }
     }
 
-    pub struct PlaceOrdersArgs<'a, 'b: 'a> {
-        pub program_id: &'a Pubkey,
-        pub limit: u16,
+    #[test]
+    fn test_limit_swap_amount_in() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        let total_coin_without_take_pnl = 1_000_000_000_000_000_u64;
+        let total_pc_without_take_pnl = 150_000_000_000_000_u64;
+        let price = |pc: u64, coin: u64| {
+            let pc = Calculator::normalize_decimal_v2(pc, amm.pc_decimals, amm.sys_decimal_value);
+            let coin =
+                Calculator::normalize_decimal_v2(coin, amm.coin_decimals, amm.sys_decimal_value);
+            (pc * U128::from(amm.sys_decimal_value) / coin).as_u64()
+        };
+        let cur_price = price(total_pc_without_take_pnl, total_coin_without_take_pnl);
+        let source_fee_config = transfer_fee_config(100);
+
+        for (swap_direction, limit_price) in [
+            (SwapDirection::Coin2PC, cur_price * 99 / 100),
+            (SwapDirection::PC2Coin, cur_price * 101 / 100),
+        ] {
+            for fee_config in [None, Some(&source_fee_config)] {
+                let fill = |amount_in| {
+                    Processor::limit_swap_amount_in(
+                        &amm,
+                        total_pc_without_take_pnl,
+                        total_coin_without_take_pnl,
+                        swap_direction,
+                        amount_in,
+                        limit_price,
+                        fee_config,
+                        0,
+                        0,
+                    )
+                    .unwrap()
+                };
+                let amount_in = fill(u64::MAX);
+                assert!(amount_in > 0);
+                // a smaller order fills whole
+                assert_eq!(fill(amount_in / 2), amount_in / 2);
+
+                // the swap fee stays in the vault, the fill stops at the limit price and
+                // a slightly larger one crosses it
+                for (amount_in, crossed) in [(amount_in, false), (amount_in * 1001 / 1000, true)] {
+                    let amounts = Processor::swap_amounts(
+                        &amm,
+                        total_pc_without_take_pnl,
+                        total_coin_without_take_pnl,
+                        swap_direction,
+                        SwapAmount::BaseIn(amount_in),
+                        0,
+                        fee_config,
+                        None,
+                        0,
+                        0,
+                    )
+                    .unwrap();
+                    let (post_price, within_limit) = match swap_direction {
+                        SwapDirection::Coin2PC => {
+                            let post_price = price(
+                                total_pc_without_take_pnl - amounts.amount_out,
+                                total_coin_without_take_pnl + amounts.amount_in_received,
+                            );
+                            (post_price, post_price >= limit_price)
+                        }
+                        SwapDirection::PC2Coin => {
+                            let post_price = price(
+                                total_pc_without_take_pnl + amounts.amount_in_received,
+                                total_coin_without_take_pnl - amounts.amount_out,
+                            );
+                            (post_price, post_price <= limit_price)
+                        }
+                    };
+                    assert_eq!(within_limit, !crossed, "post price {}", post_price);
+                }
+            }
+        }
+    }
 
-        pub amm_authority_info: &'a AccountInfo<'b>,
-        pub amm_open_orders_info: &'a AccountInfo<'b>,
-        pub market_program_info: &'a AccountInfo<'b>,
-        pub market_info: &'a AccountInfo<'b>,
-        pub market_request_queue_info: &'a AccountInfo<'b>,
-        pub amm_coin_vault_info: &'a AccountInfo<'b>,
-        pub amm_pc_vault_info: &'a AccountInfo<'b>,
-        pub market_coin_vault_info: &'a AccountInfo<'b>,
-        pub market_pc_vault_info: &'a AccountInfo<'b>,
-        pub token_program_info: &'a AccountInfo<'b>,
-        pub rent_info: &'a AccountInfo<'b>,
+    #[test]
+    fn test_spot_price() {
+        // 1.5 pc per coin, 9 coin decimals, 6 pc decimals
+        let price = Calculator::spot_price(1_500_000, 1_000_000_000, 6, 9, 1_000_000);
+        assert_eq!(price, 1_500_000);
+        // an empty pool has no price
+        assert_eq!(Calculator::spot_price(1_500_000, 0, 6, 9, 1_000_000), 0);
+        assert_eq!(
+            Calculator::spot_price(u64::MAX, 1, 0, 9, 1_000_000),
+            u64::MAX
+        );
 
-        pub market_event_queue_info: &'a AccountInfo<'b>,
-        pub market_bids_info: &'a AccountInfo<'b>,
-        pub market_asks_info: &'a AccountInfo<'b>,
+        // a TWAP over a constant price is the spot price
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        let (total_pc, total_coin) = (1_500_000_u64, 1_000_000_000_u64);
+        amm.update_price_cumulative(total_pc, total_coin, 1_000);
+        amm.update_price_cumulative(total_pc, total_coin, 1_060);
+        let coin_twap = identity(amm.coin_price_cumulative) / 60;
+        assert_eq!(
+            Calculator::q64_price_to_spot_price(
+                coin_twap,
+                amm.pc_decimals,
+                amm.coin_decimals,
+                amm.sys_decimal_value
+            ),
+            // Q64.64 truncation costs at most one unit
+            Calculator::spot_price(
+                total_pc,
+                total_coin,
+                amm.pc_decimals,
+                amm.coin_decimals,
+                amm.sys_decimal_value
+            ) - 1
+        );
+        assert_eq!(
+            Calculator::q64_price_to_spot_price(u128::MAX, 0, 9, 1_000_000_000),
+            u64::MAX
+        );
+    }
 
-        pub srm_token_account: Option<&'a AccountInfo<'b>>,
+    #[test]
+    fn test_twap_over_observation_gaps() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 6, 6, 1000000, 1).unwrap();
+        let mut header = ObservationHeader {
+            capacity: 4,
+            ..Default::default()
+        };
+        let mut observations = vec![Observation::default(); 4];
+        let observe = |amm: &AmmInfo| Observation {
+            timestamp: amm.price_cumulative_time,
+            coin_price_cumulative: amm.coin_price_cumulative,
+            liquidity: 0,
+        };
+        // a price of 2 observed at 100 and 110
+        amm.update_price_cumulative(2_000, 1_000, 100);
+        header.write(&mut observations, observe(&amm));
+        amm.update_price_cumulative(2_000, 1_000, 110);
+        header.write(&mut observations, observe(&amm));
+        // the price moves to 4 at 150 and to 1 at 200 without an observation
+        amm.update_price_cumulative(2_000, 1_000, 150);
+        amm.update_price_cumulative(4_000, 1_000, 200);
+
+        // a window from 150 starts at the observation of 110
+        let start = header.observation_before(&observations, 150);
+        assert_eq!(start.map(|o| o.timestamp), Some(110));
+        let twap_data = Processor::twap_data(&amm, 1_000, 1_000, 300, start).unwrap();
+        assert_eq!(twap_data.window_start_time, 110);
+        assert_eq!(twap_data.observation_time, 300);
+        // (2 * 40 + 4 * 50 + 1 * 100) / 190
+        let sys_decimal_value = amm.sys_decimal_value;
+        assert_eq!(twap_data.coin_twap, 2 * sys_decimal_value);
+        assert_eq!(twap_data.pc_twap, sys_decimal_value / 2);
+
+        // without a start only the cumulative is read
+        let twap_data = Processor::twap_data(&amm, 1_000, 1_000, 300, None).unwrap();
+        assert_eq!(
+            twap_data.coin_price_cumulative,
+            amm.price_cumulative_at(1_000, 1_000, 300)
+        );
+        assert_eq!(twap_data.coin_twap, 0);
+    }
 
-        pub amm: &'a mut AmmInfo,
-        pub open_orders: &'a OpenOrders,
-        pub bids: &'a Vec<LeafNode>,
-        pub asks: &'a Vec<LeafNode>,
-        pub target: &'a mut TargetOrders,
+    #[test]
+    fn test_observation_account() {
+        let program_id = Pubkey::new_unique();
+        let amm_key = Pubkey::new_unique();
+        let (observation_key, _) = get_associated_address_and_bump_seed(
+            &program_id,
+            &amm_key,
+            OBSERVATION_ASSOCIATED_SEED,
+            &program_id,
+        );
+        let mut data = vec![0u8; ObservationState::account_len(2)];
+        *bytemuck::from_bytes_mut::<ObservationHeader>(&mut data[..OBSERVATION_HEADER_LEN]) =
+            ObservationHeader {
+                amm: amm_key,
+                capacity: 2,
+                ..Default::default()
+            };
+        let other_key = Pubkey::new_unique();
+        let (mut lamports, mut other_lamports) = (0, 0);
+        let mut other_data = data.clone();
+        // the query reads a readonly account
+        let observation_info = AccountInfo::new(
+            &observation_key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &program_id,
+            false,
+            0,
+        );
+        // a program owned account with the header of the amm at another address
+        let other_info = AccountInfo::new(
+            &other_key,
+            false,
+            false,
+            &mut other_lamports,
+            &mut other_data,
+            &program_id,
+            false,
+            0,
+        );
 
-        pub total_coin_without_take_pnl: u64,
-        pub total_pc_without_take_pnl: u64,
-        pub coin_vault_amount: u64,
-        pub pc_vault_amount: u64,
+        Processor::check_observation_address(&program_id, &amm_key, &observation_info).unwrap();
+        assert_eq!(
+            Processor::check_observation_address(&program_id, &amm_key, &other_info).err(),
+            Some(AmmError::InvalidObservationAccount.into())
+        );
+        let state =
+            ObservationStateRef::load_checked(&observation_info, &program_id, &amm_key).unwrap();
+        assert_eq!({ state.header.capacity }, 2);
+        assert_eq!(state.observations.len(), 2);
+        drop(state);
+        assert_eq!(
+            ObservationStateRef::load_checked(&observation_info, &program_id, &other_key).err(),
+            Some(AmmError::InvalidObservationAccount.into())
+        );
     }
-    impl<'a, 'b: 'a> PlaceOrdersArgs<'a, 'b> {
-        pub fn with_parsed_args(
-            program_id: &'a Pubkey,
-            spl_token_program_id: &'a Pubkey,
-            limit: u16,
-            amm: &mut AmmInfo,
-            accounts: &'a [AccountInfo<'b>],
-            srm_token_account: Option<&'a AccountInfo<'b>>,
-            f: impl FnOnce(PlaceOrdersArgs) -> ProgramResult,
-        ) -> ProgramResult {
-            if accounts.len() != 17 {
-                return Err(AmmError::WrongAccountsNumber.into());
-            }
-            let accounts = array_ref![accounts, 0, 17];
-            let (new_orders_accounts, data_accounts) = array_refs![accounts, 15, 2];
-            let &[ref amm_info, ref amm_authority_info, ref amm_open_orders_info, ref market_program_info, ref market_info, ref market_request_queue_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref market_coin_vault_info, ref market_pc_vault_info, ref token_program_info, ref rent_info, ref market_event_queue_info, ref market_bids_info, ref market_asks_info] =
-                array_ref![new_orders_accounts, 0, 15];
-            let &[ref amm_target_orders_info, ref _clock_info] = array_ref![data_accounts, 0, 2];
 
-            let amm_coin_vault =
-                Processor::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-            let amm_pc_vault =
-                Processor::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-            let mut target =
-                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-            let (market_state, open_orders) = Processor::load_serum_market_order(
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
-                &amm,
-                false,
-            )?;
-            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
-            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
-            let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
-            let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-                Calculator::calc_total_without_take_pnl(
-                    amm_pc_vault.amount,
-                    amm_coin_vault.amount,
-                    &open_orders,
-                    &amm,
-                    &market_state,
-                    &market_event_queue_info,
-                    &amm_open_orders_info,
-                )?;
+    #[test]
+    fn test_flash_loan_repay() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.flash_loan_fee_numerator = 9;
+        assert_eq!(Processor::flash_loan_fee(&amm, 0).unwrap(), 0);
+        assert_eq!(Processor::flash_loan_fee(&amm, 10000).unwrap(), 9);
+        // rounded up in favor of the pool
+        assert_eq!(Processor::flash_loan_fee(&amm, 10001).unwrap(), 10);
+        assert_eq!(Processor::flash_loan_fee(&amm, 1).unwrap(), 1);
+
+        amm.flash_loan_coin_repay = 1_000_009;
+        amm.flash_loan_pc_repay = 2_000_000;
+        assert_eq!(
+            Processor::close_flash_loan(&mut amm, 1_000_008, 2_000_000, 9, 0),
+            Err(AmmError::FlashLoanNotRepaid.into())
+        );
+        assert_eq!(
+            Processor::close_flash_loan(&mut amm, 1_000_009, 1_999_999, 9, 0),
+            Err(AmmError::FlashLoanNotRepaid.into())
+        );
+        assert_eq!(identity(amm.flash_loan_coin_repay), 1_000_009);
+        // the fee is only counted once repaid
+        assert_eq!(identity(amm.state_data.swap_acc_coin_fee), 0);
+        // repaying more than owed is fine
+        assert!(Processor::close_flash_loan(&mut amm, 1_000_009, 2_000_001, 9, 0).is_ok());
+        assert_eq!(identity(amm.flash_loan_coin_repay), 0);
+        assert_eq!(identity(amm.flash_loan_pc_repay), 0);
+        assert_eq!(identity(amm.state_data.swap_acc_coin_fee), 9);
+        assert_eq!(identity(amm.state_data.swap_acc_pc_fee), 0);
+    }
 
-            let args = PlaceOrdersArgs {
-                program_id,
-                limit,
+    #[test]
+    fn test_protocol_fee_out_of_reserves() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.protocol_fee_rate = 2_000;
+        amm.state_data.need_take_pnl_coin = 7;
+        Processor::accrue_protocol_fee(&mut amm, SwapDirection::Coin2PC, 250);
+        Processor::accrue_protocol_fee(&mut amm, SwapDirection::PC2Coin, 100);
+        Processor::accrue_protocol_fee(&mut amm, SwapDirection::PC2Coin, 4);
+        // counted apart from the pnl
+        assert_eq!(identity(amm.state_data.protocol_fee_coin), 50);
+        assert_eq!(identity(amm.state_data.protocol_fee_pc), 20);
+        assert_eq!(identity(amm.state_data.need_take_pnl_coin), 7);
+        assert_eq!(identity(amm.state_data.need_take_pnl_pc), 0);
+        assert_eq!(
+            Calculator::calc_total_without_take_pnl_no_orderbook(1_000, 2_000, &amm).unwrap(),
+            (980, 1_943)
+        );
+        assert_eq!(
+            Calculator::calc_total_without_take_pnl_no_orderbook(19, 2_000, &amm),
+            Err(AmmError::CheckedSubOverflow)
+        );
+    }
 
-                amm_authority_info,
-                amm_open_orders_info,
-                market_program_info,
-                market_info,
-                market_request_queue_info,
-                amm_coin_vault_info,
-                amm_pc_vault_info,
-                market_coin_vault_info,
-                market_pc_vault_info,
-                token_program_info,
-                rent_info,
+    fn transfer_fee_config(transfer_fee_basis_points: u16) -> TransferFeeConfig {
+        let transfer_fee = spl_token_2022::extension::transfer_fee::TransferFee {
+            epoch: 0u64.into(),
+            maximum_fee: u64::MAX.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        };
+        TransferFeeConfig {
+            older_transfer_fee: transfer_fee,
+            newer_transfer_fee: transfer_fee,
+            ..Default::default()
+        }
+    }
 
-                market_event_queue_info,
-                market_bids_info,
-                market_asks_info,
-                srm_token_account,
+    #[test]
+    fn test_swap_amounts() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.protocol_fee_rate = 2_000;
+        let total_coin_without_take_pnl = 1_000_000_000_000_u64;
+        let total_pc_without_take_pnl = 150_000_000_000_u64;
+        let fee_config = transfer_fee_config(100);
+        let swap = |direction, amount, referral_fee_bps, fee_config: Option<&TransferFeeConfig>| {
+            Processor::swap_amounts(
+                &amm,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                direction,
+                amount,
+                referral_fee_bps,
+                fee_config,
+                fee_config,
+                0,
+                0,
+            )
+        };
 
-                amm,
-                open_orders: &open_orders,
-                bids: &bids,
-                asks: &asks,
-                target: &mut target,
+        let amounts = swap(
+            SwapDirection::Coin2PC,
+            SwapAmount::BaseIn(1_000_000),
+            0,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            amounts,
+            SwapAmounts {
+                user_amount_in: 1_000_000,
+                amount_in: 1_000_000,
+                amount_in_received: 1_000_000,
+                amount_out: 149_624,
+                amount_out_received: 149_624,
+                swap_fee: 2_500,
+                referral_fee: 0,
+            }
+        );
 
+        // the referral comes off the input, the transfer fees off both legs
+        let amounts = swap(
+            SwapDirection::Coin2PC,
+            SwapAmount::BaseIn(1_000_000),
+            50,
+            Some(&fee_config),
+        )
+        .unwrap();
+        assert_eq!(
+            amounts,
+            SwapAmounts {
+                user_amount_in: 1_000_000,
+                amount_in: 995_000,
+                amount_in_received: 985_050,
+                amount_out: 147_387,
+                amount_out_received: 145_913,
+                swap_fee: 2_463,
+                referral_fee: 5_000,
+            }
+        );
+        let mut recorded = amm;
+        Processor::record_swap(
+            &mut recorded,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            SwapDirection::Coin2PC,
+            &amounts,
+            0,
+        );
+        assert_eq!(identity(recorded.state_data.swap_coin_in_amount), 985_050);
+        assert_eq!(identity(recorded.state_data.swap_pc_out_amount), 147_387);
+        assert_eq!(identity(recorded.state_data.swap_acc_coin_fee), 2_463);
+        assert_eq!(identity(recorded.state_data.protocol_fee_coin), 492);
+        assert_eq!(
+            Processor::swap_return_data(
+                &amm,
+                SwapDirection::Coin2PC,
                 total_pc_without_take_pnl,
                 total_coin_without_take_pnl,
+                &amounts,
+            ),
+            SwapReturnData {
+                direction: SwapDirection::Coin2PC as u64,
+                amount_in: 1_000_000,
+                amount_out: 145_913,
+                swap_fee: 2_463,
+                protocol_fee: 492,
+                referral_fee: 5_000,
+                pool_coin_amount: 1_000_000_984_558,
+                pool_pc_amount: 149_999_852_613,
+            }
+        );
 
-                coin_vault_amount: amm_coin_vault.amount,
-                pc_vault_amount: amm_pc_vault.amount,
-            };
-            f(args)
+        // the user receives the exact output, the referral is charged on top of the input
+        let amounts = swap(
+            SwapDirection::PC2Coin,
+            SwapAmount::BaseOut(100_000),
+            50,
+            Some(&fee_config),
+        )
+        .unwrap();
+        assert_eq!(
+            amounts,
+            SwapAmounts {
+                user_amount_in: 15_420,
+                amount_in: 15_344,
+                amount_in_received: 15_190,
+                amount_out: 101_011,
+                amount_out_received: 100_000,
+                swap_fee: 38,
+                referral_fee: 76,
+            }
+        );
+
+        // the pool can't pay out its whole side
+        for (direction, amount_out) in [
+            (SwapDirection::Coin2PC, total_pc_without_take_pnl),
+            (SwapDirection::PC2Coin, total_coin_without_take_pnl),
+        ] {
+            assert_eq!(
+                swap(direction, SwapAmount::BaseOut(amount_out), 0, None),
+                Err(AmmError::InsufficientFunds.into())
+            );
         }
+        assert_eq!(
+            swap(
+                SwapDirection::PC2Coin,
+                SwapAmount::BaseOut(total_coin_without_take_pnl - 1_000),
+                0,
+                Some(&fee_config),
+            ),
+            Err(AmmError::InsufficientFunds.into())
+        );
     }
 
-    pub struct PurgeOrderArgs<'a, 'b: 'a> {
-        pub program_id: &'a Pubkey,
-        pub limit: u16,
-        pub market_program_info: &'a AccountInfo<'b>,
-        pub market_info: &'a AccountInfo<'b>,
-        pub amm_open_orders_info: &'a AccountInfo<'b>,
-        pub amm_authority_info: &'a AccountInfo<'b>,
-        pub market_event_queue_info: &'a AccountInfo<'b>,
-        pub market_bids_info: &'a AccountInfo<'b>,
-        pub market_asks_info: &'a AccountInfo<'b>,
-        pub amm: &'a mut AmmInfo,
-        pub target: &'a TargetOrders,
-        pub bids: &'a Vec<LeafNode>,
-        pub asks: &'a Vec<LeafNode>,
+    #[test]
+    fn test_withdraw_base_out_amounts() {
+        let lp_amount = 1_000_000_u64;
+        let (total_coin, total_pc) = (3_000_000_000_u64, 7_000_000_u64);
+        let base_out = |base_side, amount_out| WithdrawInstructionBaseOut {
+            max_lp_amount: u64::MAX,
+            amount_out,
+            base_side,
+            deadline: None,
+        };
+
+        // 1000 coin needs 1000 * 1_000_000 / 3_000_000_000 = 0.33 lp, rounded up to 1
+        assert_eq!(
+            Processor::withdraw_base_out_amounts(
+                lp_amount,
+                total_coin,
+                total_pc,
+                &base_out(0, 1000)
+            ),
+            Ok((1, 1000, 7))
+        );
+        // 1000 pc needs 142.86 lp, rounded up to 143, which pays 429_000 coin
+        assert_eq!(
+            Processor::withdraw_base_out_amounts(
+                lp_amount,
+                total_coin,
+                total_pc,
+                &base_out(1, 1000)
+            ),
+            Ok((143, 429_000, 1000))
+        );
+        // the base side pays exactly amount_out, one lp less would not cover it
+        for (base_side, total_base) in [(0, total_coin), (1, total_pc)] {
+            let amount_out = 123_456;
+            let (lp, coin_amount, pc_amount) = Processor::withdraw_base_out_amounts(
+                lp_amount,
+                total_coin,
+                total_pc,
+                &base_out(base_side, amount_out),
+            )
+            .unwrap();
+            let paid = if base_side == 0 {
+                coin_amount
+            } else {
+                pc_amount
+            };
+            assert_eq!(paid, amount_out);
+            assert!(
+                U128::from(lp) * U128::from(total_base)
+                    >= U128::from(amount_out) * U128::from(lp_amount)
+            );
+            assert!(
+                U128::from(lp - 1) * U128::from(total_base)
+                    < U128::from(amount_out) * U128::from(lp_amount)
+            );
+        }
+
+        // max_lp_amount caps the rounded up lp
+        let mut capped = base_out(1, 1000);
+        capped.max_lp_amount = 143;
+        assert!(
+            Processor::withdraw_base_out_amounts(lp_amount, total_coin, total_pc, &capped).is_ok()
+        );
+        capped.max_lp_amount = 142;
+        assert_eq!(
+            Processor::withdraw_base_out_amounts(lp_amount, total_coin, total_pc, &capped),
+            Err(AmmError::ExceededSlippage.into())
+        );
+        assert_eq!(
+            Processor::withdraw_base_out_amounts(
+                lp_amount,
+                total_coin,
+                total_pc,
+                &base_out(2, 1000)
+            ),
+            Err(AmmError::InvalidInput.into())
+        );
     }
-    impl<'a, 'b: 'a> PurgeOrderArgs<'a, 'b> {
-        pub fn with_parsed_args(
-            program_id: &'a Pubkey,
-            _spl_token_program_id: &'a Pubkey,
-            limit: u16,
-            amm: &mut AmmInfo,
-            accounts: &'a [AccountInfo<'b>],
-            f: impl FnOnce(PurgeOrderArgs) -> ProgramResult,
-        ) -> ProgramResult {
-            if accounts.len() != 9 {
-                return Err(AmmError::WrongAccountsNumber.into());
-            }
-            let &[ref amm_info, ref market_program_info, ref market_info, ref amm_open_orders_info, ref amm_authority_info, ref market_event_queue_info, ref market_bids_info, ref market_asks_info, ref amm_target_orders_info] =
-                array_ref![accounts, 0, 9];
 
-            let target =
-                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-            let (market_state, open_orders) = Processor::load_serum_market_order(
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
+    #[test]
+    fn test_zap_withdraw_amounts() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        let total_coin_without_take_pnl = 1_000_000_000_000_u64;
+        let total_pc_without_take_pnl = 150_000_000_000_u64;
+        // one percent of the pool withdrawn
+        let (coin_amount, pc_amount) = (10_000_000_000_u64, 1_500_000_000_u64);
+        let fee_config = transfer_fee_config(100);
+
+        for (swap_direction, swap_amount_in, withdraw_amount_out) in [
+            (SwapDirection::Coin2PC, coin_amount, pc_amount),
+            (SwapDirection::PC2Coin, pc_amount, coin_amount),
+        ] {
+            let amounts = Processor::zap_withdraw_amounts(
                 &amm,
-                true,
-            )?;
-            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
-            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
-            let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
-
-            let args = PurgeOrderArgs {
-                program_id,
-                limit,
-                market_program_info,
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
-                market_event_queue_info,
-                market_bids_info,
-                market_asks_info,
-                amm,
-                target: &target,
-                bids: &bids,
-                asks: &asks,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                coin_amount,
+                pc_amount,
+                swap_direction,
+                Some(&fee_config),
+                0,
+                0,
+            )
+            .unwrap();
+            assert_eq!(
+                amounts.pool_pc_after_withdraw,
+                total_pc_without_take_pnl - pc_amount
+            );
+            assert_eq!(
+                amounts.pool_coin_after_withdraw,
+                total_coin_without_take_pnl - coin_amount
+            );
+            // the swap leg trades against the pool left after the withdraw, without transfer fee
+            let swap = |total_pc, total_coin| {
+                Processor::swap_amounts(
+                    &amm,
+                    total_pc,
+                    total_coin,
+                    swap_direction,
+                    SwapAmount::BaseIn(swap_amount_in),
+                    0,
+                    None,
+                    None,
+                    0,
+                    0,
+                )
+                .unwrap()
             };
-            f(args)
+            assert_eq!(
+                amounts.swap,
+                swap(
+                    amounts.pool_pc_after_withdraw,
+                    amounts.pool_coin_after_withdraw
+                )
+            );
+            assert!(
+                amounts.swap.amount_out
+                    < swap(total_pc_without_take_pnl, total_coin_without_take_pnl).amount_out
+            );
+            // only the final transfer pays the transfer fee
+            assert_eq!(
+                amounts.amount_out,
+                withdraw_amount_out + amounts.swap.amount_out
+            );
+            assert_eq!(
+                amounts.amount_out_received,
+                Processor::amount_received_after_transfer_fee(
+                    Some(&fee_config),
+                    amounts.amount_out,
+                    0
+                )
+                .unwrap()
+            );
         }
+        assert_eq!(
+            Processor::zap_withdraw_amounts(
+                &amm,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                total_coin_without_take_pnl + 1,
+                pc_amount,
+                SwapDirection::Coin2PC,
+                None,
+                0,
+                0,
+            ),
+            Err(AmmError::CheckedSubOverflow.into())
+        );
     }
 
-    pub struct CancelOrderArgs<'a, 'b: 'a> {
-        pub program_id: &'a Pubkey,
-        pub limit: u16,
-        pub market_program_info: &'a AccountInfo<'b>,
-        pub market_info: &'a AccountInfo<'b>,
-        pub amm_open_orders_info: &'a AccountInfo<'b>,
-        pub amm_authority_info: &'a AccountInfo<'b>,
-        pub market_event_queue_info: &'a AccountInfo<'b>,
-        pub market_bids_info: &'a AccountInfo<'b>,
-        pub market_asks_info: &'a AccountInfo<'b>,
-        pub amm: &'a mut AmmInfo,
-        pub open_orders: &'a OpenOrders,
-        pub target: &'a mut TargetOrders,
-        pub bids: &'a Vec<LeafNode>,
-        pub asks: &'a Vec<LeafNode>,
-
-        pub coin_amount: u64,
-        pub pc_amount: u64,
+    #[test]
+    fn test_transfer_fee_amounts() {
+        let fee_config = transfer_fee_config(100);
+        assert_eq!(
+            Processor::amount_received_after_transfer_fee(Some(&fee_config), 1_000_003, 0).unwrap(),
+            990_002
+        );
+        // the required amount is the smallest one that delivers the amount after the fee
+        let required =
+            Processor::amount_required_before_transfer_fee(Some(&fee_config), 990_002, 0).unwrap();
+        assert_eq!(required, 1_000_003);
+        assert_eq!(
+            Processor::amount_received_after_transfer_fee(Some(&fee_config), required - 1, 0)
+                .unwrap(),
+            990_001
+        );
+        assert_eq!(
+            Processor::amount_received_after_transfer_fee(None, 1_000_003, 0).unwrap(),
+            1_000_003
+        );
+        assert_eq!(
+            Processor::amount_required_before_transfer_fee(None, 990_002, 0).unwrap(),
+            990_002
+        );
     }
-    impl<'a, 'b: 'a> CancelOrderArgs<'a, 'b> {
-        pub fn with_parsed_args(
-            program_id: &'a Pubkey,
-            spl_token_program_id: &'a Pubkey,
-            limit: u16,
-            amm: &mut AmmInfo,
-            accounts: &'a [AccountInfo<'b>],
-            f: impl FnOnce(CancelOrderArgs) -> ProgramResult,
-        ) -> ProgramResult {
-            if accounts.len() != 11 {
-                return Err(AmmError::WrongAccountsNumber.into());
-            }
-            let &[ref amm_info, ref market_program_info, ref market_info, ref amm_open_orders_info, ref amm_authority_info, ref market_event_queue_info, ref market_bids_info, ref market_asks_info, ref amm_coin_vault_info, ref amm_pc_vault_info, ref amm_target_orders_info] =
-                array_ref![accounts, 0, 11];
 
-            let amm_coin_vault =
-                Processor::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-            let amm_pc_vault =
-                Processor::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-            let mut target =
-                TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-            let (market_state, open_orders) = Processor::load_serum_market_order(
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
+    #[test]
+    fn test_deposit_amounts() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.lp_amount = 1_500_000_000;
+        let total_coin_without_take_pnl = 3_000_000_000_u64;
+        let total_pc_without_take_pnl = 2_000_000_000_u64;
+        let coin_fee_config = transfer_fee_config(100);
+        let pc_fee_config = transfer_fee_config(50);
+        let deposit = |max_coin_amount, max_pc_amount, base_side| {
+            Processor::deposit_amounts(
                 &amm,
-                true,
-            )?;
-            let bids_orders = market_state.load_bids_checked(&market_bids_info)?;
-            let asks_orders = market_state.load_asks_checked(&market_asks_info)?;
-            let (bids, asks) = Processor::get_amm_orders(&open_orders, bids_orders, asks_orders)?;
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                max_coin_amount,
+                max_pc_amount,
+                base_side,
+                Some(&coin_fee_config),
+                Some(&pc_fee_config),
+                0,
+            )
+            .unwrap()
+        };
 
-            let args = CancelOrderArgs {
-                program_id,
-                limit,
-                market_program_info,
-                market_info,
-                amm_open_orders_info,
-                amm_authority_info,
-                market_event_queue_info,
-                market_bids_info,
-                market_asks_info,
-                amm,
-                open_orders: &open_orders,
-                target: &mut target,
-                bids: &bids,
-                asks: &asks,
-                coin_amount: amm_coin_vault.amount,
-                pc_amount: amm_pc_vault.amount,
-            };
-            f(args)
-        }
+        // base coin, the pc side pairs with the coin the vault receives
+        let amounts = deposit(1_000_003, 700_000, 0);
+        assert_eq!(
+            amounts,
+            DepositAmounts {
+                deduct_coin_amount: 1_000_003,
+                deduct_pc_amount: 663_319,
+                coin_amount_received: 990_002,
+                pc_amount_received: 660_002,
+                mint_lp_amount: 495_001,
+            }
+        );
+        assert_eq!(
+            Processor::check_deposit_amounts(&amounts, 1_000_003, 700_000, 0, Some(663_319)),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::check_deposit_amounts(&amounts, 1_000_003, 663_318, 0, None),
+            Err(AmmError::ExceededSlippage)
+        );
+        assert_eq!(
+            Processor::check_deposit_amounts(&amounts, 1_000_003, 700_000, 0, Some(663_320)),
+            Err(AmmError::ExceededSlippage)
+        );
+
+        // base pc
+        let amounts = deposit(1_600_000, 1_000_000, 1);
+        assert_eq!(
+            amounts,
+            DepositAmounts {
+                deduct_coin_amount: 1_507_576,
+                deduct_pc_amount: 1_000_000,
+                coin_amount_received: 1_492_500,
+                pc_amount_received: 995_000,
+                mint_lp_amount: 746_250,
+            }
+        );
+        assert_eq!(
+            Processor::check_deposit_amounts(&amounts, 1_507_576, 1_000_000, 1, None),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::check_deposit_amounts(&amounts, 1_507_575, 1_000_000, 1, None),
+            Err(AmmError::ExceededSlippage)
+        );
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
 
     #[test]
-    fn test_calc_take_pnl() {
+    fn test_deposit_return_data() {
         let mut amm = AmmInfo::default();
-        amm.initialize(0, 0, 2, 9, 1000000, 1).unwrap();
-        let mut target = TargetOrders::default();
-        target.calc_pnl_x = 900000000000000;
-        target.calc_pnl_y = 150000000000000000000000000;
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.lp_amount = 1_500_000_000;
+        let coin_fee_config = transfer_fee_config(100);
+        let pc_fee_config = transfer_fee_config(50);
+        let amounts = Processor::deposit_amounts(
+            &amm,
+            2_000_000_000,
+            3_000_000_000,
+            1_000_003,
+            700_000,
+            0,
+            Some(&coin_fee_config),
+            Some(&pc_fee_config),
+            0,
+        )
+        .unwrap();
+        let lp_supply = amm.lp_amount + amounts.mint_lp_amount;
+        let deposit_data =
+            Processor::deposit_return_data(&amounts, 2_000_000_000, 3_000_000_000, lp_supply)
+                .unwrap();
+        // the user side is debited with the transfer fees, the pool only grows by what the vaults receive
+        assert_eq!(
+            deposit_data,
+            DepositReturnData {
+                coin_amount: 1_000_003,
+                pc_amount: 663_319,
+                lp_amount: 495_001,
+                pool_coin_amount: 3_000_990_002,
+                pool_pc_amount: 2_000_660_002,
+                pool_lp_supply: 1_500_495_001,
+            }
+        );
+        let data = AmmReturnData::Deposit(deposit_data).pack();
+        assert_eq!(
+            AmmReturnData::unpack(&data),
+            Some(AmmReturnData::Deposit(deposit_data))
+        );
+        assert_eq!(
+            Processor::deposit_return_data(&amounts, 2_000_000_000, u64::MAX, lp_supply),
+            Err(AmmError::CheckedAddOverflow.into())
+        );
+    }
 
-        let mut total_pc_without_take_pnl = 1343675125663;
-        let mut total_coin_without_take_pnl = 117837534493793;
-        let x1 = Calculator::normalize_decimal_v2(
-            total_pc_without_take_pnl,
-            amm.pc_decimals,
-            amm.sys_decimal_value,
+    #[test]
+    fn test_simulate_deposit_withdraw_data() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.lp_amount = 1_500_000_000;
+        let pool_data = GetPoolData {
+            pool_coin_amount: 3_000_000_000,
+            pool_pc_amount: 2_000_000_000,
+            pool_lp_supply: amm.lp_amount,
+            ..Default::default()
+        };
+        let coin_fee_config = transfer_fee_config(100);
+        let pc_fee_config = transfer_fee_config(50);
+        let deposit = DepositInstruction {
+            max_coin_amount: 1_000_003,
+            max_pc_amount: 700_000,
+            base_side: 0,
+            ..Default::default()
+        };
+        // the same amounts as the deposit itself, transfer fees included
+        let deposit_data = Processor::deposit_data(
+            &amm,
+            pool_data.clone(),
+            &deposit,
+            Some(&coin_fee_config),
+            Some(&pc_fee_config),
+            0,
+        )
+        .unwrap();
+        let amounts = Processor::deposit_amounts(
+            &amm,
+            2_000_000_000,
+            3_000_000_000,
+            1_000_003,
+            700_000,
+            0,
+            Some(&coin_fee_config),
+            Some(&pc_fee_config),
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                deposit_data.deduct_coin,
+                deposit_data.deduct_pc,
+                deposit_data.mint_lp
+            ),
+            (
+                amounts.deduct_coin_amount,
+                amounts.deduct_pc_amount,
+                amounts.mint_lp_amount
+            )
         );
-        let y1 = Calculator::normalize_decimal_v2(
-            total_coin_without_take_pnl,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
+        assert_eq!(deposit_data.deduct_pc, 663_319);
+        // the other side can't exceed its max amount
+        assert_eq!(
+            Processor::deposit_data(
+                &amm,
+                pool_data.clone(),
+                &DepositInstruction {
+                    max_pc_amount: 663_318,
+                    ..deposit
+                },
+                Some(&coin_fee_config),
+                Some(&pc_fee_config),
+                0,
+            ),
+            Err(AmmError::ExceededSlippage.into())
         );
 
-        let (delta_x, delta_y) = Processor::calc_take_pnl(
-            &target,
-            &mut amm,
-            &mut total_pc_without_take_pnl,
-            &mut total_coin_without_take_pnl,
-            x1.as_u128().into(),
-            y1.as_u128().into(),
+        let withdraw_data = Processor::withdraw_data(
+            &amm,
+            pool_data.clone(),
+            750,
+            Some(&coin_fee_config),
+            Some(&pc_fee_config),
+            0,
         )
         .unwrap();
-        println!("delta_x:{}, delta_y:{}", delta_x, delta_y);
+        assert_eq!(
+            (
+                withdraw_data.coin_amount,
+                withdraw_data.pc_amount,
+                withdraw_data.coin_amount_received,
+                withdraw_data.pc_amount_received
+            ),
+            (1500, 1000, 1485, 995)
+        );
+        assert_eq!(
+            Processor::withdraw_data(&amm, pool_data, amm.lp_amount, None, None, 0),
+            Err(AmmError::NotAllowZeroLP.into())
+        );
     }
 
     #[test]
-    fn test_calc_pnl_precision() {
-        // init
-        let mut amm = AmmInfo::default();
-        let init_pc_amount = 5434000000u64;
-        let init_coin_amount = 100000000000000u64;
-        let liquidity = Calculator::to_u64(
-            U128::from(init_pc_amount)
-                .checked_mul(init_coin_amount.into())
-                .unwrap()
-                .integer_sqrt()
-                .as_u128(),
-        )
-        .unwrap();
-        amm.initialize(0, 0, 5, 9, 1000000000, 7803).unwrap();
-        amm.lp_amount = liquidity;
+    fn test_deposit_withdraw_recipients() {
+        let token_account = |mint, owner| spl_token::state::Account {
+            mint,
+            owner,
+            ..Default::default()
+        };
+        // a vault program signs and credits a beneficiary wallet
+        let lp_mint = Pubkey::new_unique();
+        let beneficiary = Pubkey::new_unique();
+        assert_eq!(
+            Processor::deposit_recipient(&token_account(lp_mint, beneficiary), &lp_mint),
+            Ok(beneficiary)
+        );
+        assert_eq!(
+            Processor::deposit_recipient(
+                &token_account(Pubkey::new_unique(), beneficiary),
+                &lp_mint
+            ),
+            Err(AmmError::InvalidTokenLP)
+        );
 
-        let x =
-            Calculator::normalize_decimal_v2(5434000000, amm.pc_decimals, amm.sys_decimal_value);
-        let y = Calculator::normalize_decimal_v2(
-            100000000000000,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
+        let (coin_mint, pc_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let amm_coin_vault = token_account(coin_mint, Pubkey::new_unique());
+        let amm_pc_vault = token_account(pc_mint, Pubkey::new_unique());
+        // the coin and pc may go to different owners
+        let (coin_owner, pc_owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        assert_eq!(
+            Processor::withdraw_recipients(
+                &token_account(coin_mint, coin_owner),
+                &token_account(pc_mint, pc_owner),
+                &amm_coin_vault,
+                &amm_pc_vault,
+            ),
+            Ok((coin_owner, pc_owner))
         );
-        let mut target = TargetOrders::default();
-        target.calc_pnl_x = x.as_u128();
-        target.calc_pnl_y = y.as_u128();
-        println!(
-             "init_pc_amount:{}, init_coin_amount:{}, liquidity:{}, sys_decimal_value:{}, calc_pnl_x:{}, calc_pnl_y:{}",
-             init_pc_amount, init_coin_amount, liquidity, identity(amm.sys_decimal_value), identity(target.calc_pnl_x), identity(target.calc_pnl_y)
-         );
+        assert_eq!(
+            Processor::withdraw_recipients(
+                &token_account(pc_mint, coin_owner),
+                &token_account(pc_mint, pc_owner),
+                &amm_coin_vault,
+                &amm_pc_vault,
+            ),
+            Err(AmmError::InvalidCoinMint)
+        );
+        assert_eq!(
+            Processor::withdraw_recipients(
+                &token_account(coin_mint, coin_owner),
+                &token_account(coin_mint, pc_owner),
+                &amm_coin_vault,
+                &amm_pc_vault,
+            ),
+            Err(AmmError::InvalidPCMint)
+        );
+    }
 
-        // withdraw
-        let withdraw_lp = 2577470628u64;
-        let mut total_pc_without_take_pnl = init_pc_amount;
-        let mut total_coin_without_take_pnl = init_coin_amount;
-        let x1 = Calculator::normalize_decimal_v2(
-            total_pc_without_take_pnl,
-            amm.pc_decimals,
-            amm.sys_decimal_value,
+    #[test]
+    fn test_check_withdraw_slippage() {
+        let withdraw = |min_coin_amount, min_pc_amount| WithdrawInstruction {
+            amount: 1000,
+            min_coin_amount,
+            min_pc_amount,
+            deadline: None,
+        };
+        assert_eq!(
+            Processor::check_withdraw_slippage(&withdraw(None, None), 0, 0),
+            Ok(())
         );
-        let y1 = Calculator::normalize_decimal_v2(
-            total_coin_without_take_pnl,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
+        assert_eq!(
+            Processor::check_withdraw_slippage(&withdraw(Some(100), Some(200)), 100, 200),
+            Ok(())
+        );
+        assert_eq!(
+            Processor::check_withdraw_slippage(&withdraw(Some(100), Some(200)), 100, 199),
+            Err(AmmError::ExceededSlippage.into())
         );
+        // a bound sent on its own is still checked
+        assert_eq!(
+            Processor::check_withdraw_slippage(&withdraw(Some(100), None), 99, u64::MAX),
+            Err(AmmError::ExceededSlippage.into())
+        );
+        assert_eq!(
+            Processor::check_withdraw_slippage(&withdraw(None, Some(200)), u64::MAX, 199),
+            Err(AmmError::ExceededSlippage.into())
+        );
+        assert_eq!(
+            Processor::check_withdraw_slippage(&withdraw(None, Some(200)), 0, 200),
+            Ok(())
+        );
+    }
 
-        let (delta_x, delta_y) = Processor::calc_take_pnl(
-            &target,
-            &mut amm,
-            &mut total_pc_without_take_pnl,
-            &mut total_coin_without_take_pnl,
-            x1.as_u128().into(),
-            y1.as_u128().into(),
+    #[test]
+    fn test_withdraw_return_data() {
+        let lp_amount = 1_500_000_000_u64;
+        let (total_coin, total_pc) = (3_000_000_000_u64, 2_000_000_000_u64);
+        let (withdraw_lp, coin_amount, pc_amount) = Processor::withdraw_base_out_amounts(
+            lp_amount,
+            total_coin,
+            total_pc,
+            &WithdrawInstructionBaseOut {
+                max_lp_amount: u64::MAX,
+                amount_out: 1000,
+                base_side: 1,
+                deadline: None,
+            },
         )
         .unwrap();
-        println!("delta_x:{}, delta_y:{}", delta_x, delta_y);
-        // coin_amount / total_coin_amount = amount / lp_mint.supply => coin_amount = total_coin_amount * amount / pool_mint.supply
-        let invariant = InvariantPool {
-            token_input: withdraw_lp,
-            token_total: amm.lp_amount,
+        let withdraw_data = Processor::withdraw_return_data(
+            withdraw_lp,
+            coin_amount,
+            pc_amount,
+            total_pc,
+            total_coin,
+            lp_amount - withdraw_lp,
+        )
+        .unwrap();
+        assert_eq!(
+            withdraw_data,
+            WithdrawReturnData {
+                lp_amount: 750,
+                coin_amount: 1500,
+                pc_amount: 1000,
+                pool_coin_amount: 2_999_998_500,
+                pool_pc_amount: 1_999_999_000,
+                pool_lp_supply: 1_499_999_250,
+            }
+        );
+        let data = AmmReturnData::Withdraw(withdraw_data).pack();
+        assert_eq!(
+            AmmReturnData::unpack(&data),
+            Some(AmmReturnData::Withdraw(withdraw_data))
+        );
+        // a zap withdraw only sends out of the destination vault
+        assert_eq!(
+            Processor::withdraw_return_data(750, 0, 2000, total_pc, total_coin, 1_499_999_250)
+                .map(|data| (data.pool_coin_amount, data.pool_pc_amount)),
+            Ok((total_coin, 1_999_998_000))
+        );
+        assert_eq!(
+            Processor::withdraw_return_data(750, total_coin + 1, 0, total_pc, total_coin, 0),
+            Err(AmmError::CheckedSubOverflow.into())
+        );
+    }
+
+    #[test]
+    fn test_check_flash_loan_end() {
+        use solana_program::{
+            instruction::Instruction,
+            sysvar::instructions::{
+                construct_instructions_data, BorrowedAccountMeta, BorrowedInstruction,
+            },
         };
-        let coin_amount = invariant
-            .exchange_pool_to_token(total_coin_without_take_pnl, RoundDirection::Floor)
-            .ok_or(AmmError::CalculationExRateFailure)
-            .unwrap();
-        let pc_amount = invariant
-            .exchange_pool_to_token(total_pc_without_take_pnl, RoundDirection::Floor)
-            .ok_or(AmmError::CalculationExRateFailure)
-            .unwrap();
 
-        amm.lp_amount = amm.lp_amount.checked_sub(withdraw_lp).unwrap();
-        target.calc_pnl_x = x1
-            .checked_sub(Calculator::normalize_decimal_v2(
-                pc_amount,
-                amm.pc_decimals,
-                amm.sys_decimal_value,
-            ))
-            .unwrap()
-            .checked_sub(U128::from(delta_x))
-            .unwrap()
-            .as_u128();
-        target.calc_pnl_y = y1
-            .checked_sub(Calculator::normalize_decimal_v2(
-                coin_amount,
-                amm.coin_decimals,
-                amm.sys_decimal_value,
-            ))
-            .unwrap()
-            .checked_sub(U128::from(delta_y))
-            .unwrap()
-            .as_u128();
-        total_pc_without_take_pnl = total_pc_without_take_pnl.checked_sub(pc_amount).unwrap();
-        total_coin_without_take_pnl = total_coin_without_take_pnl
-            .checked_sub(coin_amount)
-            .unwrap();
-        println!(
-             "withdraw calc_pnl_x:{}, calc_pnl_y:{}, total_pc_without_take_pnl:{}, total_coin_without_take_pnl:{}",
-             identity(target.calc_pnl_x), identity(target.calc_pnl_y), total_pc_without_take_pnl, total_coin_without_take_pnl
-         );
+        let program_id = Pubkey::new_unique();
+        let (amm_key, other_amm_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (coin_vault, pc_vault) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (user_coin, user_pc) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let authority = Pubkey::new_unique();
+        let begin = crate::instruction::flash_loan_begin(
+            &program_id,
+            &amm_key,
+            &authority,
+            &coin_vault,
+            &pc_vault,
+            &user_coin,
+            &user_pc,
+            100,
+            0,
+        )
+        .unwrap();
+        let end = crate::instruction::flash_loan_end(
+            &program_id,
+            &amm_key,
+            &coin_vault,
+            &pc_vault,
+            100,
+            0,
+        )
+        .unwrap();
+        let other_end = crate::instruction::flash_loan_end(
+            &program_id,
+            &other_amm_key,
+            &coin_vault,
+            &pc_vault,
+            100,
+            0,
+        )
+        .unwrap();
+        let short_end =
+            crate::instruction::flash_loan_end(&program_id, &amm_key, &coin_vault, &pc_vault, 1, 0)
+                .unwrap();
+        let mut cpi_begin = begin.clone();
+        cpi_begin.program_id = Pubkey::new_unique();
+
+        let check = |instructions: &[&Instruction]| {
+            let borrowed = instructions
+                .iter()
+                .map(|instruction| BorrowedInstruction {
+                    program_id: &instruction.program_id,
+                    accounts: instruction
+                        .accounts
+                        .iter()
+                        .map(|meta| BorrowedAccountMeta {
+                            pubkey: &meta.pubkey,
+                            is_signer: meta.is_signer,
+                            is_writable: meta.is_writable,
+                        })
+                        .collect(),
+                    data: &instruction.data,
+                })
+                .collect::<Vec<_>>();
+            // the current index is left at 0, the begin instruction
+            let mut data = construct_instructions_data(&borrowed);
+            let mut lamports = 0;
+            let key = instructions::id();
+            let owner = solana_program::sysvar::id();
+            let instructions_info = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &owner,
+                false,
+                0,
+            );
+            Processor::check_flash_loan_end(
+                &program_id,
+                &amm_key,
+                &instructions_info,
+                FlashLoanInstruction {
+                    coin_amount: 100,
+                    pc_amount: 0,
+                },
+            )
+        };
 
-        // withdraw 2
-        let x1 = Calculator::normalize_decimal_v2(
-            total_pc_without_take_pnl,
-            amm.pc_decimals,
-            amm.sys_decimal_value,
+        assert!(check(&[&begin, &end]).is_ok());
+        assert!(check(&[&begin, &other_end, &end]).is_ok());
+        // no FlashLoanEnd
+        assert_eq!(check(&[&begin]), Err(AmmError::InvalidFlashLoan.into()));
+        // a FlashLoanEnd of another amm
+        assert_eq!(
+            check(&[&begin, &other_end]),
+            Err(AmmError::InvalidFlashLoan.into())
         );
-        let y1 = Calculator::normalize_decimal_v2(
-            total_coin_without_take_pnl,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
+        // the first FlashLoanEnd of the amm must carry the lent amounts
+        assert_eq!(
+            check(&[&begin, &short_end]),
+            Err(AmmError::InvalidFlashLoan.into())
+        );
+        assert_eq!(
+            check(&[&begin, &short_end, &end]),
+            Err(AmmError::InvalidFlashLoan.into())
+        );
+        // the begin was invoked through a cpi of another program
+        assert_eq!(
+            check(&[&cpi_begin, &end]),
+            Err(AmmError::InvalidFlashLoan.into())
         );
+    }
 
-        let (delta_x, delta_y) = Processor::calc_take_pnl(
-            &target,
-            &mut amm,
-            &mut total_pc_without_take_pnl,
-            &mut total_coin_without_take_pnl,
-            x1.as_u128().into(),
-            y1.as_u128().into(),
-        )
-        .unwrap();
-        println!("delta_x:{}, delta_y:{}", delta_x, delta_y);
+    #[test]
+    fn test_split_route_hops() {
+        let program_id = Pubkey::new_unique();
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.open_orders = Pubkey::new_unique();
+        amm.status = AmmStatus::Initialized.into_u64();
+        let orderbook_amm_data = bytemuck::bytes_of(&amm).to_vec();
+        amm.status = AmmStatus::SwapOnly.into_u64();
+        let swap_only_amm_data = bytemuck::bytes_of(&amm).to_vec();
+        let other = || (Pubkey::new_unique(), Pubkey::default(), vec![]);
+
+        // an orderbook hop with its market accounts, then a hop with its token-2022 accounts
+        let mut accounts = vec![(Pubkey::new_unique(), program_id, orderbook_amm_data)];
+        accounts.extend((0..4).map(|_| other()));
+        accounts.push((amm.open_orders, Pubkey::default(), vec![]));
+        accounts.extend((0..8).map(|_| other()));
+        accounts.push((Pubkey::new_unique(), program_id, swap_only_amm_data));
+        accounts.extend((0..4).map(|_| other()));
+        accounts.push((spl_token_2022::id(), Pubkey::default(), vec![]));
+        accounts.extend((0..2).map(|_| other()));
+        let mut lamports = vec![0u64; accounts.len()];
+        let infos = accounts
+            .iter_mut()
+            .zip(lamports.iter_mut())
+            .map(|((key, owner, data), lamports)| {
+                AccountInfo::new(key, false, true, lamports, data, owner, false, 0)
+            })
+            .collect::<Vec<_>>();
+
+        let hops = Processor::split_route_hops(&program_id, &infos).unwrap();
+        assert_eq!(hops.len(), 2);
+        let market = hops[0].market_accounts.as_ref().unwrap();
+        assert_eq!(*market.amm_open_orders_info.key, amm.open_orders);
+        assert_eq!(market.market_vault_signer.key, infos[13].key);
+        assert!(hops[0].token_2022_accounts.is_none());
+        assert_eq!(hops[1].amm_info.key, infos[14].key);
+        assert!(hops[1].market_accounts.is_none());
+        let token_2022 = hops[1].token_2022_accounts.as_ref().unwrap();
+        assert_eq!(token_2022.pc_mint.key, infos[21].key);
+
+        // without the market accounts the hop splits alone, route_swap_hop then refuses it
+        let hops = Processor::split_route_hops(&program_id, &infos[..5]).unwrap();
+        assert!(hops[0].market_accounts.is_none());
+        // the second hop is cut short
+        assert_eq!(
+            Processor::split_route_hops(&program_id, &infos[..16]).err(),
+            Some(AmmError::WrongAccountsNumber.into())
+        );
     }
 
     #[test]
-    fn test_swap_base_in() {
-        let amount_in = 212854295571_u64;
-        let total_coin_without_take_pnl = 77043918330755_u64;
-        let total_pc_without_take_pnl = 1511361338135_u64;
+    fn test_split_simulate_route_hops() {
+        // a hop, a hop with its token-2022 accounts, then a hop
+        let mut keys = (0..SIMULATE_SWAP_ACCOUNT_LEN)
+            .map(|_| Pubkey::new_unique())
+            .collect::<Vec<_>>();
+        keys.extend((0..SIMULATE_SWAP_ACCOUNT_LEN).map(|_| Pubkey::new_unique()));
+        keys.extend([
+            spl_token_2022::id(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        ]);
+        keys.extend((0..SIMULATE_SWAP_ACCOUNT_LEN).map(|_| Pubkey::new_unique()));
+        let owner = Pubkey::default();
+        let mut lamports = vec![0u64; keys.len()];
+        let mut data = vec![vec![]; keys.len()];
+        let infos = keys
+            .iter()
+            .zip(lamports.iter_mut())
+            .zip(data.iter_mut())
+            .map(|((key, lamports), data)| {
+                AccountInfo::new(key, false, false, lamports, data, &owner, false, 0)
+            })
+            .collect::<Vec<_>>();
+
+        let hops = Processor::split_simulate_route_hops(&infos).unwrap();
+        assert_eq!(
+            hops.iter().map(|hop| hop.len()).collect::<Vec<_>>(),
+            vec![13, 16, 13]
+        );
+        assert_eq!(hops[1][0].key, infos[13].key);
+        assert_eq!(hops[2][0].key, infos[29].key);
+        for len in [0, 12, 28, 41] {
+            assert_eq!(
+                Processor::split_simulate_route_hops(&infos[..len]).err(),
+                Some(AmmError::WrongAccountsNumber.into())
+            );
+        }
 
-        let swap_direction = SwapDirection::Coin2PC;
+        // the next hop swaps what the user receives, after the destination transfer fee
         let mut amm = AmmInfo::default();
-        amm.initialize(0, 0, 2, 9, 1000000, 1).unwrap();
-
-        let swap_fee = U128::from(amount_in)
-            .checked_mul(amm.fees.swap_fee_numerator.into())
-            .unwrap()
-            .checked_ceil_div(amm.fees.swap_fee_denominator.into())
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        let pc_fee_config = transfer_fee_config(50);
+        let hop = |direction, amount_in, source, destination| {
+            Processor::swap_amounts(
+                &amm,
+                150_000_000_000,
+                1_000_000_000_000,
+                direction,
+                SwapAmount::BaseIn(amount_in),
+                0,
+                source,
+                destination,
+                0,
+                0,
+            )
             .unwrap()
-            .0;
-
-        let swap_in_after_deduct_fee = U128::from(amount_in).checked_sub(swap_fee).unwrap();
-        let swap_amount_out = Calculator::swap_token_amount_base_in(
-            swap_in_after_deduct_fee,
-            total_pc_without_take_pnl.into(),
-            total_coin_without_take_pnl.into(),
-            swap_direction,
-        )
-        .as_u64();
+        };
+        let first = hop(
+            SwapDirection::Coin2PC,
+            1_000_000_000,
+            None,
+            Some(&pc_fee_config),
+        );
+        assert_eq!(
+            first.amount_out_received,
+            first.amount_out - (first.amount_out * 50 + 9_999) / 10_000
+        );
+        let second = hop(
+            SwapDirection::PC2Coin,
+            first.amount_out_received,
+            Some(&pc_fee_config),
+            None,
+        );
+        assert_eq!(
+            second.amount_in_received,
+            first.amount_out_received - (first.amount_out_received * 50 + 9_999) / 10_000
+        );
+        assert_eq!(
+            U128::from(second.swap_fee),
+            Calculator::swap_fee_amount_in(second.amount_in_received, 25, 10000)
+        );
+    }
 
-        println!("swap_amount_out:{}", swap_amount_out);
+    #[test]
+    fn test_zap_swap_amount() {
+        let total_coin_without_take_pnl = 1_000_000_000_000_u64;
+        let total_pc_without_take_pnl = 150_000_000_000_u64;
+        let (swap_fee_numerator, swap_fee_denominator) = (25u64, 10000u64);
+        for (swap_direction, amount_in) in [
+            (SwapDirection::Coin2PC, 3_000_000_000_u64),
+            (SwapDirection::PC2Coin, 450_000_000_u64),
+        ] {
+            let (reserve_in, reserve_out) = match swap_direction {
+                SwapDirection::Coin2PC => (total_coin_without_take_pnl, total_pc_without_take_pnl),
+                SwapDirection::PC2Coin => (total_pc_without_take_pnl, total_coin_without_take_pnl),
+            };
+            let swap_amount = Calculator::zap_swap_amount(
+                amount_in,
+                reserve_in,
+                swap_fee_numerator,
+                swap_fee_denominator,
+            )
+            .unwrap();
+            // a little over half is swapped to cover the price impact and the fee
+            assert!(swap_amount > amount_in / 2 && swap_amount < amount_in * 51 / 100);
+            let swap_fee = swap_amount * swap_fee_numerator / swap_fee_denominator;
+            let swap_amount_out = Calculator::swap_token_amount_base_in(
+                (swap_amount - swap_fee).into(),
+                total_pc_without_take_pnl.into(),
+                total_coin_without_take_pnl.into(),
+                swap_direction,
+            )
+            .as_u64();
+            // the rest and the output are in the pool ratio after the swap
+            let lhs =
+                U128::from(amount_in - swap_amount) * U128::from(reserve_out - swap_amount_out);
+            let rhs = U128::from(swap_amount_out) * U128::from(reserve_in + swap_amount);
+            let diff = if lhs > rhs { lhs - rhs } else { rhs - lhs };
+            assert!(diff * U128::from(1_000_000u64) <= rhs);
+        }
+        assert_eq!(Calculator::zap_swap_amount(0, 1000, 25, 10000), Ok(0));
     }
 }
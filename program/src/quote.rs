@@ -0,0 +1,409 @@
+//! Off-chain quotes for the `client` feature
+//!
+//! Computes swap amounts from account snapshots, without a validator and
+//! without simulating a transaction. The pool amounts and the swap amounts
+//! come from the same `Calculator` and `Processor` helpers as the on-chain
+//! swap paths, `Processor::swap_amounts` included, so a quote matches what
+//! `SwapBaseIn` and `SwapBaseOut` would transfer against the same accounts,
+//! epoch and clock.
+//!
+//! The snapshot is made of the deserialized `AmmInfo` and `TargetOrders`,
+//! the coin and pc vault balances and, for pools with orderbook permission,
+//! the amm `OpenOrders` and the events of the market event queue that were
+//! not consumed yet. Spl Token 2022 mints also pass their `TransferFeeConfig`.
+
+use crate::{
+    error::AmmError,
+    math::{Calculator, SwapDirection, U256},
+    processor::{Processor, SwapAmount},
+    state::{AmmInfo, AmmStatus, TargetOrders},
+};
+use serum_dex::state::{Event, OpenOrders};
+use solana_program::program_error::ProgramError;
+use spl_token_2022::extension::transfer_fee::TransferFeeConfig;
+
+/// Orderbook side of a pool snapshot
+pub struct OrderbookSnapshot<'a> {
+    /// the amm open orders account, `AmmInfo::open_orders`
+    pub open_orders: &'a OpenOrders,
+    /// events of the market event queue not consumed yet, oldest first
+    pub events: &'a [Event],
+}
+
+/// Account snapshot of a pool, as loaded by the swap instructions
+pub struct PoolSnapshot<'a> {
+    pub amm: AmmInfo,
+    pub target_orders: &'a TargetOrders,
+    pub coin_vault_amount: u64,
+    pub pc_vault_amount: u64,
+    /// required when the pool status has orderbook permission
+    pub orderbook: Option<OrderbookSnapshot<'a>>,
+    /// transfer fee extension of a Spl Token 2022 coin mint
+    pub coin_transfer_fee_config: Option<&'a TransferFeeConfig>,
+    /// transfer fee extension of a Spl Token 2022 pc mint
+    pub pc_transfer_fee_config: Option<&'a TransferFeeConfig>,
+    /// epoch of the quoted transaction, for the transfer fees
+    pub epoch: u64,
+    /// unix timestamp of the quoted transaction, for the open time and the dynamic fee
+    pub unix_timestamp: u64,
+}
+
+/// Amounts of a quoted swap
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SwapQuote {
+    /// debited from the user source account, referral fee included
+    pub amount_in: u64,
+    /// credited to the user destination account
+    pub amount_out: u64,
+    /// pool swap fee, in the input token
+    pub swap_fee: u64,
+    /// referral fee, in the input token
+    pub referral_fee: u64,
+    pub total_pc_without_take_pnl: u64,
+    pub total_coin_without_take_pnl: u64,
+}
+
+impl<'a> PoolSnapshot<'a> {
+    /// Status the swap instructions run with, after the open time transitions.
+    pub fn swap_status(&self) -> Result<AmmStatus, ProgramError> {
+        let status = AmmStatus::from_u64(self.amm.status);
+        if !status.swap_permission() {
+            if self.amm.status == AmmStatus::OrderBookOnly.into_u64()
+                && self.unix_timestamp >= self.amm.state_data.orderbook_to_init_time
+            {
+                return Ok(AmmStatus::Initialized);
+            }
+            return Err(AmmError::InvalidStatus.into());
+        } else if self.amm.status == AmmStatus::WaitingTrade.into_u64() {
+            if self.unix_timestamp < self.amm.state_data.pool_open_time {
+                return Err(AmmError::InvalidStatus.into());
+            }
+            return Ok(AmmStatus::SwapOnly);
+        }
+        Ok(status)
+    }
+
+    /// Pool amounts without the pnl not yet taken, like `Calculator::calc_total_without_take_pnl`.
+    pub fn total_without_take_pnl(&self, status: AmmStatus) -> Result<(u64, u64), ProgramError> {
+        let (pc_total_in_serum, coin_total_in_serum) = if status.orderbook_permission() {
+            let orderbook = self.orderbook.as_ref().ok_or(AmmError::InvalidOpenOrders)?;
+            Calculator::apply_serum_fills(
+                orderbook.open_orders.native_pc_total,
+                orderbook.open_orders.native_coin_total,
+                orderbook.events.iter(),
+                &self.amm.open_orders,
+            )
+        } else {
+            (0, 0)
+        };
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                self.pc_vault_amount
+                    .checked_add(pc_total_in_serum)
+                    .ok_or(AmmError::CheckedAddOverflow)?,
+                self.coin_vault_amount
+                    .checked_add(coin_total_in_serum)
+                    .ok_or(AmmError::CheckedAddOverflow)?,
+                &self.amm,
+            )?;
+        Ok((total_pc_without_take_pnl, total_coin_without_take_pnl))
+    }
+
+    /// Takes the pnl on the snapshot like the deposit and withdraw instructions,
+    /// returning the pool amounts left to the liquidity providers.
+    pub fn take_pnl(&mut self) -> Result<(u64, u64), ProgramError> {
+        let status = AmmStatus::from_u64(self.amm.status);
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+            self.total_without_take_pnl(status)?;
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            self.amm.pc_decimals,
+            self.amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            self.amm.coin_decimals,
+            self.amm.sys_decimal_value,
+        );
+        Processor::calc_take_pnl(
+            self.target_orders,
+            &mut self.amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            U256::from(x1.as_u128()),
+            U256::from(y1.as_u128()),
+        )?;
+        Ok((total_pc_without_take_pnl, total_coin_without_take_pnl))
+    }
+
+    fn transfer_fee_configs(
+        &self,
+        swap_direction: SwapDirection,
+    ) -> (Option<&'a TransferFeeConfig>, Option<&'a TransferFeeConfig>) {
+        match swap_direction {
+            SwapDirection::Coin2PC => (self.coin_transfer_fee_config, self.pc_transfer_fee_config),
+            SwapDirection::PC2Coin => (self.pc_transfer_fee_config, self.coin_transfer_fee_config),
+        }
+    }
+
+    /// Quotes `SwapBaseIn` of `amount_in`, the slippage check is left to the caller.
+    pub fn swap_base_in(
+        &self,
+        amount_in: u64,
+        referral_fee_bps: u16,
+        swap_direction: SwapDirection,
+    ) -> Result<SwapQuote, ProgramError> {
+        self.quote(
+            SwapAmount::BaseIn(amount_in),
+            referral_fee_bps,
+            swap_direction,
+        )
+    }
+
+    /// Quotes `SwapBaseOut` of `amount_out`, the slippage check is left to the caller.
+    pub fn swap_base_out(
+        &self,
+        amount_out: u64,
+        referral_fee_bps: u16,
+        swap_direction: SwapDirection,
+    ) -> Result<SwapQuote, ProgramError> {
+        self.quote(
+            SwapAmount::BaseOut(amount_out),
+            referral_fee_bps,
+            swap_direction,
+        )
+    }
+
+    /// Amounts of `Processor::swap_amounts` against the snapshot, like the swap instructions.
+    fn quote(
+        &self,
+        amount: SwapAmount,
+        referral_fee_bps: u16,
+        swap_direction: SwapDirection,
+    ) -> Result<SwapQuote, ProgramError> {
+        let status = self.swap_status()?;
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            self.total_without_take_pnl(status)?;
+        let (source_fee_config, destination_fee_config) = self.transfer_fee_configs(swap_direction);
+        let amounts = Processor::swap_amounts(
+            &self.amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            amount,
+            referral_fee_bps,
+            source_fee_config,
+            destination_fee_config,
+            self.epoch,
+            self.unix_timestamp,
+        )?;
+        let nothing_swapped = match amount {
+            SwapAmount::BaseIn(_) => {
+                amounts.amount_out_received == 0 || amounts.amount_in_received == 0
+            }
+            SwapAmount::BaseOut(amount_out) => amounts.amount_in_received == 0 || amount_out == 0,
+        };
+        if nothing_swapped {
+            return Err(AmmError::InvalidInput.into());
+        }
+        Ok(SwapQuote {
+            amount_in: amounts.user_amount_in,
+            amount_out: amounts.amount_out_received,
+            swap_fee: amounts.swap_fee,
+            referral_fee: amounts.referral_fee,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytemuck::Zeroable;
+    use serum_dex::{fees::FeeTier, matching::Side, state::EventView, state::ToAlignedBytes};
+    use solana_program::pubkey::Pubkey;
+
+    fn snapshot(target_orders: &TargetOrders) -> PoolSnapshot {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 9, 6, 1000000, 1).unwrap();
+        amm.status = AmmStatus::SwapOnly.into_u64();
+        amm.open_orders = Pubkey::new_unique();
+        PoolSnapshot {
+            amm,
+            target_orders,
+            coin_vault_amount: 77_043_918_330_755,
+            pc_vault_amount: 1_511_361_338_135,
+            orderbook: None,
+            coin_transfer_fee_config: None,
+            pc_transfer_fee_config: None,
+            epoch: 0,
+            unix_timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_quote_swap_base_in() {
+        let target_orders = TargetOrders::zeroed();
+        let pool = snapshot(&target_orders);
+        let amount_in = 212_854_295_571_u64;
+        let quote = pool
+            .swap_base_in(amount_in, 0, SwapDirection::Coin2PC)
+            .unwrap();
+        // what process_swap_base_in transfers against the same vaults
+        assert_eq!(
+            quote,
+            SwapQuote {
+                amount_in,
+                amount_out: 4_153_651_556,
+                swap_fee: 532_135_739,
+                referral_fee: 0,
+                total_pc_without_take_pnl: 1_511_361_338_135,
+                total_coin_without_take_pnl: 77_043_918_330_755,
+            }
+        );
+
+        // both paths round for the pool, the same output never costs more than the quoted input
+        let quote_out = pool
+            .swap_base_out(quote.amount_out, 0, SwapDirection::Coin2PC)
+            .unwrap();
+        assert_eq!(quote_out.amount_out, 4_153_651_556);
+        assert_eq!(quote_out.amount_in, 212_854_295_522);
+        assert_eq!(quote_out.swap_fee, 532_135_739);
+
+        // waiting pools only quote after their open time
+        let mut pool = pool;
+        pool.amm.status = AmmStatus::WaitingTrade.into_u64();
+        pool.amm.state_data.pool_open_time = 100;
+        assert_eq!(
+            pool.swap_base_in(amount_in, 0, SwapDirection::Coin2PC),
+            Err(AmmError::InvalidStatus.into())
+        );
+        pool.unix_timestamp = 100;
+        assert_eq!(
+            pool.swap_base_in(amount_in, 0, SwapDirection::Coin2PC),
+            Ok(quote)
+        );
+    }
+
+    #[test]
+    fn test_quote_without_take_pnl() {
+        let target_orders = TargetOrders::zeroed();
+        let mut pool = snapshot(&target_orders);
+        // the pnl not yet taken and the protocol fees are not swapped against
+        pool.amm.state_data.need_take_pnl_pc = 20_000_000;
+        pool.amm.state_data.need_take_pnl_coin = 1_000_000_000;
+        pool.amm.state_data.protocol_fee_pc = 1_000_000;
+        pool.amm.state_data.protocol_fee_coin = 50_000_000;
+
+        let quote = pool
+            .swap_base_in(212_854_295_571, 0, SwapDirection::Coin2PC)
+            .unwrap();
+        assert_eq!(
+            quote,
+            SwapQuote {
+                amount_in: 212_854_295_571,
+                amount_out: 4_153_650_295,
+                swap_fee: 532_135_739,
+                referral_fee: 0,
+                total_pc_without_take_pnl: 1_511_340_338_135,
+                total_coin_without_take_pnl: 77_042_868_330_755,
+            }
+        );
+        let quote = pool
+            .swap_base_in(1_000_000_000, 0, SwapDirection::PC2Coin)
+            .unwrap();
+        assert_eq!(quote.amount_out, 50_815_538_183);
+        assert_eq!(quote.swap_fee, 2_500_000);
+    }
+
+    fn transfer_fee_config(transfer_fee_basis_points: u16) -> TransferFeeConfig {
+        let transfer_fee = spl_token_2022::extension::transfer_fee::TransferFee {
+            epoch: 0u64.into(),
+            maximum_fee: u64::MAX.into(),
+            transfer_fee_basis_points: transfer_fee_basis_points.into(),
+        };
+        TransferFeeConfig {
+            older_transfer_fee: transfer_fee,
+            newer_transfer_fee: transfer_fee,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_quote_token_2022_dynamic_fee() {
+        let target_orders = TargetOrders::zeroed();
+        let coin_fee_config = transfer_fee_config(100);
+        let pc_fee_config = transfer_fee_config(50);
+        let mut pool = snapshot(&target_orders);
+        pool.coin_transfer_fee_config = Some(&coin_fee_config);
+        pool.pc_transfer_fee_config = Some(&pc_fee_config);
+        // recent price moves raise the swap fee from 25 to 75 / 10000
+        pool.amm.dynamic_fee_max_numerator = 100;
+        pool.amm.volatility_accumulator = 500;
+        assert_eq!(pool.amm.swap_fee_numerator_at(pool.unix_timestamp), 75);
+
+        // the referral comes off the input, 212_641_441_276 leave the user of which
+        // 210_515_026_863 reach the vault after the coin transfer fee, the dynamic fee is
+        // charged on those, and 4_087_590_584 leave the pc vault before the pc transfer fee
+        let quote = pool
+            .swap_base_in(212_854_295_571, 10, SwapDirection::Coin2PC)
+            .unwrap();
+        assert_eq!(quote.amount_in, 212_854_295_571);
+        assert_eq!(quote.amount_out, 4_067_152_631);
+        assert_eq!(quote.swap_fee, 1_578_862_702);
+        assert_eq!(quote.referral_fee, 212_854_295);
+
+        // 1_010_101_011 leave the coin vault so that the exact output arrives, the pc
+        // vault must receive 19_965_029 and the referral is charged on top of the input
+        let quote = pool
+            .swap_base_out(1_000_000_000, 10, SwapDirection::PC2Coin)
+            .unwrap();
+        assert_eq!(quote.amount_out, 1_000_000_000);
+        assert_eq!(quote.amount_in, 20_085_421);
+        assert_eq!(quote.swap_fee, 149_738);
+        assert_eq!(quote.referral_fee, 20_065);
+    }
+
+    #[test]
+    fn test_quote_orderbook_fills() {
+        let target_orders = TargetOrders::zeroed();
+        let mut pool = snapshot(&target_orders);
+        pool.amm.status = AmmStatus::Initialized.into_u64();
+        assert_eq!(
+            pool.total_without_take_pnl(AmmStatus::Initialized),
+            Err(AmmError::InvalidOpenOrders.into())
+        );
+
+        let mut open_orders = OpenOrders::zeroed();
+        open_orders.native_pc_total = 1_000_000;
+        open_orders.native_coin_total = 0;
+        // a maker bid of the amm filled, not yet consumed from the event queue
+        let fill = |owner: &Pubkey| {
+            Event::new(EventView::Fill {
+                side: Side::Bid,
+                maker: true,
+                native_qty_paid: 600_000,
+                native_qty_received: 4_000_000_000,
+                native_fee_or_rebate: 0,
+                order_id: 0,
+                owner: owner.to_aligned_bytes(),
+                owner_slot: 0,
+                fee_tier: FeeTier::Base,
+                client_order_id: None,
+            })
+        };
+        let events = [fill(&pool.amm.open_orders), fill(&Pubkey::new_unique())];
+        pool.orderbook = Some(OrderbookSnapshot {
+            open_orders: &open_orders,
+            events: &events,
+        });
+        assert_eq!(
+            pool.total_without_take_pnl(AmmStatus::Initialized),
+            Ok((
+                pool.pc_vault_amount + 400_000,
+                pool.coin_vault_amount + 4_000_000_000
+            ))
+        );
+    }
+}
@@ -21,7 +21,7 @@ use solana_program::{
 };
 
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
-use bytemuck::{from_bytes, from_bytes_mut, Pod, Zeroable};
+use bytemuck::{cast_slice, cast_slice_mut, from_bytes, from_bytes_mut, Pod, Zeroable};
 use safe_transmute::{self, trivial::TriviallyTransmutable};
 use serde::{Deserialize, Serialize};
 use std::{
@@ -37,6 +37,12 @@ pub const TEN_THOUSAND: u64 = 10000;
 /// Maximum number of orders that can be processed in a single operation
 pub const MAX_ORDER_LIMIT: usize = 10;
 
+/// Seconds for the volatility accumulator to decay back to zero
+pub const VOLATILITY_DECAY_PERIOD: u64 = 300;
+
+/// Share of the recent price move charged on top of the dynamic fee floor, 1 / VOLATILITY_FEE_DIVISOR
+pub const VOLATILITY_FEE_DIVISOR: u64 = 10;
+
 #[cfg(not(test))]
 pub fn get_recent_epoch() -> Result<u64, ProgramError> {
     use solana_program::{clock::Clock, sysvar::Sysvar};
@@ -235,6 +241,7 @@ impl TargetOrders {
     }
 }
 
+#[cfg_attr(feature = "client", derive(Debug))]
 #[repr(u64)]
 pub enum AmmStatus {
     Uninitialized = 0u64,
@@ -337,6 +344,7 @@ impl AmmStatus {
     }
 }
 
+#[cfg_attr(feature = "client", derive(Debug))]
 #[repr(u64)]
 pub enum AmmState {
     InvlidState = 0u64,
@@ -402,6 +410,12 @@ pub enum AmmParams {
     ClearOpenTime = 15u64,
     Seperate = 16u64,
     UpdateOpenOrder = 17u64,
+    FlashLoanFee = 18u64,
+    DynamicFeeMin = 19u64,
+    DynamicFeeMax = 20u64,
+    /// Copies `AmmConfig::protocol_fee_rate` into the pool. Pools don't follow the config,
+    /// so every pool needs this param after the config rate changes. Carries no value
+    ProtocolFeeRate = 21u64,
 }
 impl AmmParams {
     pub fn from_u64(state: u64) -> Self {
@@ -424,6 +438,10 @@ impl AmmParams {
             15u64 => AmmParams::ClearOpenTime,
             16u64 => AmmParams::Seperate,
             17u64 => AmmParams::UpdateOpenOrder,
+            18u64 => AmmParams::FlashLoanFee,
+            19u64 => AmmParams::DynamicFeeMin,
+            20u64 => AmmParams::DynamicFeeMax,
+            21u64 => AmmParams::ProtocolFeeRate,
             _ => unreachable!(),
         }
     }
@@ -448,6 +466,10 @@ impl AmmParams {
             AmmParams::ClearOpenTime => 15u64,
             AmmParams::Seperate => 16u64,
             AmmParams::UpdateOpenOrder => 17u64,
+            AmmParams::FlashLoanFee => 18u64,
+            AmmParams::DynamicFeeMin => 19u64,
+            AmmParams::DynamicFeeMax => 20u64,
+            AmmParams::ProtocolFeeRate => 21u64,
         }
     }
 }
@@ -607,8 +629,10 @@ pub struct StateData {
     pub total_pnl_coin: u64,
     /// ido pool open time
     pub pool_open_time: u64,
-    /// padding for future updates
-    pub padding: [u64; 2],
+    /// protocol share of the coin swap fees, claimable by the pnl owner
+    pub protocol_fee_coin: u64,
+    /// protocol share of the pc swap fees, claimable by the pnl owner
+    pub protocol_fee_pc: u64,
     /// switch from orderbookonly to init
     pub orderbook_to_init_time: u64,
 
@@ -634,7 +658,8 @@ impl StateData {
         self.total_pnl_pc = 0u64;
         self.total_pnl_coin = 0u64;
         self.pool_open_time = open_time;
-        self.padding = Zeroable::zeroed();
+        self.protocol_fee_coin = 0u64;
+        self.protocol_fee_pc = 0u64;
         self.orderbook_to_init_time = 0u64;
         self.swap_coin_in_amount = 0u128;
         self.swap_pc_out_amount = 0u128;
@@ -715,8 +740,24 @@ pub struct AmmInfo {
     pub market_program: Pubkey,
     /// target_orders key
     pub target_orders: Pubkey,
-    /// padding
-    pub padding1: [u64; 8],
+    /// cumulative coin price in pc, Q64.64 price of the raw amounts times seconds,
+    /// wraps on overflow
+    pub coin_price_cumulative: u128,
+    /// coin vault balance required by FlashLoanEnd, zero when no coin is lent
+    pub flash_loan_coin_repay: u64,
+    /// pc vault balance required by FlashLoanEnd, zero when no pc is lent
+    pub flash_loan_pc_repay: u64,
+    /// unix timestamp of the last price cumulative update
+    pub price_cumulative_time: u64,
+    /// floor of the dynamic swap fee, over fees.swap_fee_denominator, the fee decays to once
+    /// the price is calm. At most fees.swap_fee_numerator, zero keeps the base fee as the floor
+    pub dynamic_fee_min_numerator: u32,
+    /// upper bound of the dynamic swap fee, over fees.swap_fee_denominator, zero disables it
+    pub dynamic_fee_max_numerator: u32,
+    /// recent price moves in basis points, decays linearly over VOLATILITY_DECAY_PERIOD
+    pub volatility_accumulator: u64,
+    /// unix timestamp of the last volatility accumulator update
+    pub volatility_update_time: u64,
     /// amm owner key
     pub amm_owner: Pubkey,
     /// pool lp amount
@@ -725,8 +766,15 @@ pub struct AmmInfo {
     pub client_order_id: u64,
     /// recent epoch
     pub recent_epoch: u64,
-    /// padding
-    pub padding2: u64,
+    /// flash loan fee numerator, TEN_THOUSAND as denominator, zero disables flash loans.
+    /// Kept out of `fees`, whose 64 bytes are also the `SetParams` Fees instruction data,
+    /// and set on its own with `AmmParams::FlashLoanFee`
+    pub flash_loan_fee_numerator: u32,
+    /// protocol share of the swap fee, TEN_THOUSAND as denominator, accrued in the
+    /// state_data protocol fee amounts. Copied from `AmmConfig::protocol_fee_rate` at
+    /// initialization and by the admin with `AmmParams::ProtocolFeeRate`, as swaps don't take
+    /// the config
+    pub protocol_fee_rate: u32,
 }
 impl_loadable!(AmmInfo);
 
@@ -858,13 +906,108 @@ impl AmmInfo {
         self.min_price_multiplier = 1;
         self.max_price_multiplier = 1000000000;
         self.client_order_id = 0;
-        self.padding1 = Zeroable::zeroed();
+        self.coin_price_cumulative = 0;
+        self.flash_loan_coin_repay = 0;
+        self.flash_loan_pc_repay = 0;
+        self.price_cumulative_time = 0;
+        self.dynamic_fee_min_numerator = 0;
+        self.dynamic_fee_max_numerator = 0;
+        self.volatility_accumulator = 0;
+        self.volatility_update_time = 0;
         self.recent_epoch = get_recent_epoch().unwrap();
-        self.padding2 = Zeroable::zeroed();
+        self.flash_loan_fee_numerator = 0;
+        self.protocol_fee_rate = 0;
 
         Ok(())
     }
 
+    /// Coin price cumulative extrapolated to `unix_timestamp`,
+    /// assuming `total_pc` and `total_coin` held since the last update.
+    pub fn price_cumulative_at(&self, total_pc: u64, total_coin: u64, unix_timestamp: u64) -> u128 {
+        let elapsed = unix_timestamp.saturating_sub(self.price_cumulative_time);
+        if self.price_cumulative_time == 0 || elapsed == 0 || total_pc == 0 || total_coin == 0 {
+            return self.coin_price_cumulative;
+        }
+        // Q64.64, the shifted u64 amount divided by a non zero u64 always fits in u128
+        let coin_price = ((total_pc as u128) << 64) / total_coin as u128;
+        self.coin_price_cumulative
+            .wrapping_add(coin_price.wrapping_mul(elapsed as u128))
+    }
+
+    /// Accumulates the pool prices up to `unix_timestamp`.
+    /// Must be called before the pool amounts change, with the amounts that held until now.
+    pub fn update_price_cumulative(&mut self, total_pc: u64, total_coin: u64, unix_timestamp: u64) {
+        if unix_timestamp <= self.price_cumulative_time {
+            return;
+        }
+        self.coin_price_cumulative = self.price_cumulative_at(total_pc, total_coin, unix_timestamp);
+        self.price_cumulative_time = unix_timestamp;
+    }
+
+    /// Volatility accumulator decayed to `unix_timestamp`.
+    pub fn volatility_at(&self, unix_timestamp: u64) -> u64 {
+        let elapsed = unix_timestamp.saturating_sub(self.volatility_update_time);
+        if elapsed >= VOLATILITY_DECAY_PERIOD {
+            return 0;
+        }
+        (self.volatility_accumulator as u128 * (VOLATILITY_DECAY_PERIOD - elapsed) as u128
+            / VOLATILITY_DECAY_PERIOD as u128) as u64
+    }
+
+    /// Adds the price move of a swap, in basis points, to the decayed volatility accumulator.
+    pub fn update_volatility(&mut self, price_move_bps: u64, unix_timestamp: u64) {
+        self.volatility_accumulator = self
+            .volatility_at(unix_timestamp)
+            .saturating_add(price_move_bps);
+        self.volatility_update_time = unix_timestamp;
+    }
+
+    /// Swap fee numerator over fees.swap_fee_denominator charged at `unix_timestamp`.
+    /// With the dynamic fee enabled, a share of the recent price moves is added to the
+    /// dynamic fee min, or to the base fee when no min is set, and the result is capped by
+    /// the dynamic fee max, never going below that floor nor reaching the denominator.
+    pub fn swap_fee_numerator_at(&self, unix_timestamp: u64) -> u64 {
+        let base = self.fees.swap_fee_numerator;
+        if self.dynamic_fee_max_numerator == 0 {
+            return base;
+        }
+        let floor = match self.dynamic_fee_min_numerator {
+            0 => base,
+            min => min as u64,
+        };
+        let volatility_fee = self.volatility_at(unix_timestamp) as u128
+            * self.fees.swap_fee_denominator as u128
+            / (TEN_THOUSAND as u128 * VOLATILITY_FEE_DIVISOR as u128);
+        let fee = (floor as u128)
+            .saturating_add(volatility_fee)
+            .min(self.dynamic_fee_max_numerator as u128) as u64;
+        fee.max(floor)
+            .min(self.fees.swap_fee_denominator.saturating_sub(1))
+    }
+
+    /// Sets new fees, dropping the dynamic fee bounds they no longer allow:
+    /// the min above the swap fee, the max below it or not under the denominator.
+    pub fn set_fees(&mut self, fees: Fees) {
+        self.fees = fees;
+        if self.dynamic_fee_min_numerator as u64 > fees.swap_fee_numerator {
+            self.dynamic_fee_min_numerator = 0;
+        }
+        let max = self.dynamic_fee_max_numerator as u64;
+        if max != 0 && (max < fees.swap_fee_numerator || max >= fees.swap_fee_denominator) {
+            self.dynamic_fee_max_numerator = 0;
+        }
+    }
+
+    /// Protocol share of a swap fee, rounded down in favor of the liquidity providers.
+    pub fn protocol_fee(&self, swap_fee: u64) -> u64 {
+        (swap_fee as u128 * self.protocol_fee_rate as u128 / TEN_THOUSAND as u128) as u64
+    }
+
+    /// A flash loan is open between FlashLoanBegin and FlashLoanEnd of the same transaction.
+    pub fn is_flash_loan_active(&self) -> bool {
+        self.flash_loan_coin_repay != 0 || self.flash_loan_pc_repay != 0
+    }
+
     pub fn incr_client_order_id(&mut self) -> u64 {
         self.client_order_id = self.client_order_id.wrapping_add(1);
         if self.client_order_id == 0 {
@@ -884,8 +1027,11 @@ pub struct AmmConfig {
     pub cancel_owner: Pubkey,
     /// pending
     pub pending_1: [u64; 28],
+    /// protocol share of the swap fee, TEN_THOUSAND as denominator, copied to new pools and
+    /// to existing ones by `AmmParams::ProtocolFeeRate`
+    pub protocol_fee_rate: u64,
     /// pending
-    pub pending_2: [u64; 31],
+    pub pending_2: [u64; 30],
     /// init amm pool fee amount
     pub create_pool_fee: u64,
 }
@@ -926,6 +1072,188 @@ impl AmmConfig {
     }
 }
 
+/// Header of the observation account, followed by `capacity` `Observation` slots
+#[cfg_attr(feature = "client", derive(Debug))]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct ObservationHeader {
+    /// amm the observations belong to
+    pub amm: Pubkey,
+    /// observation slots allocated after the header
+    pub capacity: u64,
+    /// observation slots in rotation, only grows into new slots once the ring reaches its end
+    pub len: u64,
+    /// slot of the latest observation
+    pub index: u64,
+    /// padding
+    pub padding: [u64; 5],
+}
+impl_loadable!(ObservationHeader);
+
+impl ObservationHeader {
+    /// Writes an observation into the ring, at most one per timestamp.
+    pub fn write(&mut self, observations: &mut [Observation], observation: Observation) {
+        if self.len == 0 {
+            if self.capacity == 0 {
+                return;
+            }
+            self.len = 1;
+            self.index = 0;
+        } else {
+            if observations[self.index as usize].timestamp >= observation.timestamp {
+                return;
+            }
+            // new slots join the ring only at its end, so the ring stays in time order
+            if self.index + 1 == self.len && self.len < self.capacity {
+                self.len += 1;
+            }
+            self.index = (self.index + 1) % self.len;
+        }
+        observations[self.index as usize] = observation;
+    }
+
+    /// Observations in rotation, from the oldest to the latest.
+    pub fn ordered(&self, observations: &[Observation]) -> Vec<Observation> {
+        let len = self.len as usize;
+        let oldest = (self.index as usize + 1) % len.max(1);
+        (0..len).map(|i| observations[(oldest + i) % len]).collect()
+    }
+
+    /// Latest observation written at or before `unix_timestamp`, the start of a TWAP window.
+    pub fn observation_before(
+        &self,
+        observations: &[Observation],
+        unix_timestamp: u64,
+    ) -> Option<Observation> {
+        self.ordered(observations)
+            .into_iter()
+            .rev()
+            // a zero timestamp is written before the pool price was first accumulated
+            .find(|observation| {
+                observation.timestamp != 0 && observation.timestamp <= unix_timestamp
+            })
+    }
+}
+
+/// A timestamped pool sample, see `AmmInfo::coin_price_cumulative`
+#[cfg_attr(feature = "client", derive(Debug))]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Observation {
+    pub timestamp: u64,
+    pub coin_price_cumulative: u128,
+    /// sqrt(pool coin amount * pool pc amount) when the observation was written
+    pub liquidity: u64,
+}
+impl_loadable!(Observation);
+
+pub const OBSERVATION_HEADER_LEN: usize = size_of::<ObservationHeader>();
+
+/// Observation ring buffer of an amm, stored in its observation account
+pub struct ObservationState<'a> {
+    pub header: RefMut<'a, ObservationHeader>,
+    pub observations: RefMut<'a, [Observation]>,
+}
+
+impl<'a> ObservationState<'a> {
+    /// Account data length needed to hold `capacity` observations
+    pub fn account_len(capacity: u64) -> usize {
+        OBSERVATION_HEADER_LEN + capacity as usize * size_of::<Observation>()
+    }
+
+    /// load the header and observations without checking the header
+    pub fn load_mut(account: &'a AccountInfo) -> Result<Self, ProgramError> {
+        let data = account.try_borrow_mut_data()?;
+        if data.len() < OBSERVATION_HEADER_LEN
+            || (data.len() - OBSERVATION_HEADER_LEN) % size_of::<Observation>() != 0
+        {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        let (header, observations) = RefMut::map_split(data, |data| {
+            let (header, observations) = data.split_at_mut(OBSERVATION_HEADER_LEN);
+            (
+                from_bytes_mut::<ObservationHeader>(header),
+                cast_slice_mut::<u8, Observation>(observations),
+            )
+        });
+        Ok(Self {
+            header,
+            observations,
+        })
+    }
+
+    /// load_mut_checked
+    pub fn load_mut_checked(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm: &Pubkey,
+    ) -> Result<Self, ProgramError> {
+        if account.owner != program_id {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        let state = Self::load_mut(account)?;
+        if state.header.amm != *amm || state.header.capacity != state.observations.len() as u64 {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        Ok(state)
+    }
+}
+
+/// Read-only observation ring buffer of an amm, for queries
+pub struct ObservationStateRef<'a> {
+    pub header: Ref<'a, ObservationHeader>,
+    pub observations: Ref<'a, [Observation]>,
+}
+
+impl<'a> ObservationStateRef<'a> {
+    /// load_checked
+    pub fn load_checked(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm: &Pubkey,
+    ) -> Result<Self, ProgramError> {
+        if account.owner != program_id {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        let data = account.try_borrow_data()?;
+        if data.len() < OBSERVATION_HEADER_LEN
+            || (data.len() - OBSERVATION_HEADER_LEN) % size_of::<Observation>() != 0
+        {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        let (header, observations) = Ref::map_split(data, |data| {
+            let (header, observations) = data.split_at(OBSERVATION_HEADER_LEN);
+            (
+                from_bytes::<ObservationHeader>(header),
+                cast_slice::<u8, Observation>(observations),
+            )
+        });
+        if header.amm != *amm || header.capacity != observations.len() as u64 {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        Ok(Self {
+            header,
+            observations,
+        })
+    }
+}
+
+/// Observations in rotation of raw observation account data, from the oldest to the latest.
+pub fn ordered_observations(data: &[u8]) -> Result<Vec<Observation>, ProgramError> {
+    if data.len() < OBSERVATION_HEADER_LEN
+        || (data.len() - OBSERVATION_HEADER_LEN) % size_of::<Observation>() != 0
+    {
+        return Err(AmmError::InvalidObservationAccount.into());
+    }
+    let (header, observations) = data.split_at(OBSERVATION_HEADER_LEN);
+    let header = from_bytes::<ObservationHeader>(header);
+    let observations = cast_slice::<u8, Observation>(observations);
+    if header.len as usize > observations.len() || header.index >= header.len.max(1) {
+        return Err(AmmError::InvalidObservationAccount.into());
+    }
+    Ok(header.ordered(observations))
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct LastOrderDistance {
@@ -942,15 +1270,21 @@ pub enum SimulateParams {
     SwapBaseInInfo = 1u64,
     SwapBaseOutInfo = 2u64,
     RunCrankInfo = 3u64,
+    DepositInfo = 4u64,
+    WithdrawInfo = 5u64,
+    RouteSwapBaseIn = 6u64,
 }
 impl SimulateParams {
-    pub fn from_u64(flag: u64) -> Self {
+    pub fn from_u64(flag: u64) -> Result<Self, ProgramError> {
         match flag {
-            0u64 => SimulateParams::PoolInfo,
-            1u64 => SimulateParams::SwapBaseInInfo,
-            2u64 => SimulateParams::SwapBaseOutInfo,
-            3u64 => SimulateParams::RunCrankInfo,
-            _ => unreachable!(),
+            0u64 => Ok(SimulateParams::PoolInfo),
+            1u64 => Ok(SimulateParams::SwapBaseInInfo),
+            2u64 => Ok(SimulateParams::SwapBaseOutInfo),
+            3u64 => Ok(SimulateParams::RunCrankInfo),
+            4u64 => Ok(SimulateParams::DepositInfo),
+            5u64 => Ok(SimulateParams::WithdrawInfo),
+            6u64 => Ok(SimulateParams::RouteSwapBaseIn),
+            _ => Err(ProgramError::InvalidInstructionData),
         }
     }
 
@@ -960,6 +1294,9 @@ impl SimulateParams {
             SimulateParams::SwapBaseInInfo => 1u64,
             SimulateParams::SwapBaseOutInfo => 2u64,
             SimulateParams::RunCrankInfo => 3u64,
+            SimulateParams::DepositInfo => 4u64,
+            SimulateParams::WithdrawInfo => 5u64,
+            SimulateParams::RouteSwapBaseIn => 6u64,
         }
     }
 }
@@ -979,6 +1316,13 @@ impl RunCrankData {
     }
 }
 
+/// Version of the `GetPoolData` fields, 1 for payloads without a `version` field.
+pub const GET_POOL_DATA_VERSION: u64 = 2;
+
+fn get_pool_data_legacy_version() -> u64 {
+    1
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct GetPoolData {
     pub status: u64,
@@ -993,8 +1337,85 @@ pub struct GetPoolData {
     pub pool_lp_supply: u64,
     pub pool_open_time: u64,
     pub amm_id: String,
+    // fields below were added in GET_POOL_DATA_VERSION 2, decoding older json gives version 1
+    // and zero for the others
+    #[serde(default = "get_pool_data_legacy_version")]
+    pub version: u64,
+    #[serde(default)]
+    pub state: u64,
+    #[serde(default)]
+    pub min_separate_numerator: u64,
+    #[serde(default)]
+    pub min_separate_denominator: u64,
+    #[serde(default)]
+    pub trade_fee_numerator: u64,
+    #[serde(default)]
+    pub trade_fee_denominator: u64,
+    #[serde(default)]
+    pub pnl_numerator: u64,
+    #[serde(default)]
+    pub pnl_denominator: u64,
+    #[serde(default)]
+    pub swap_fee_numerator: u64,
+    #[serde(default)]
+    pub swap_fee_denominator: u64,
+    // pc per coin of the pool amounts, decimal adjusted and scaled by sys_decimal_value
+    #[serde(default)]
+    pub spot_price: u64,
+    #[serde(default)]
+    pub sys_decimal_value: u64,
+    // where the pool amounts sit, the pool amounts also deduct the pnl not yet taken
+    #[serde(default)]
+    pub vault_pc_amount: u64,
+    #[serde(default)]
+    pub vault_coin_amount: u64,
+    #[serde(default)]
+    pub open_orders_pc_amount: u64,
+    #[serde(default)]
+    pub open_orders_coin_amount: u64,
+    // cumulative swap volumes of the pool
+    #[serde(default)]
+    pub swap_coin_in_amount: u128,
+    #[serde(default)]
+    pub swap_pc_out_amount: u128,
+    #[serde(default)]
+    pub swap_acc_pc_fee: u64,
+    #[serde(default)]
+    pub swap_pc_in_amount: u128,
+    #[serde(default)]
+    pub swap_coin_out_amount: u128,
+    #[serde(default)]
+    pub swap_acc_coin_fee: u64,
 }
 impl GetPoolData {
+    /// Fills the versioned fields read from the amm, after the pool amounts are set.
+    /// The swap fee is the one charged at `unix_timestamp`, dynamic fee included.
+    pub fn fill_amm_info(&mut self, amm: &AmmInfo, unix_timestamp: u64) {
+        self.version = GET_POOL_DATA_VERSION;
+        self.state = amm.state;
+        self.min_separate_numerator = amm.fees.min_separate_numerator;
+        self.min_separate_denominator = amm.fees.min_separate_denominator;
+        self.trade_fee_numerator = amm.fees.trade_fee_numerator;
+        self.trade_fee_denominator = amm.fees.trade_fee_denominator;
+        self.pnl_numerator = amm.fees.pnl_numerator;
+        self.pnl_denominator = amm.fees.pnl_denominator;
+        self.swap_fee_numerator = amm.swap_fee_numerator_at(unix_timestamp);
+        self.swap_fee_denominator = amm.fees.swap_fee_denominator;
+        self.sys_decimal_value = amm.sys_decimal_value;
+        self.spot_price = Calculator::spot_price(
+            self.pool_pc_amount,
+            self.pool_coin_amount,
+            amm.pc_decimals,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        self.swap_coin_in_amount = amm.state_data.swap_coin_in_amount;
+        self.swap_pc_out_amount = amm.state_data.swap_pc_out_amount;
+        self.swap_acc_pc_fee = amm.state_data.swap_acc_pc_fee;
+        self.swap_pc_in_amount = amm.state_data.swap_pc_in_amount;
+        self.swap_coin_out_amount = amm.state_data.swap_coin_out_amount;
+        self.swap_acc_coin_fee = amm.state_data.swap_acc_coin_fee;
+    }
     pub fn to_json(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
@@ -1009,6 +1430,9 @@ pub struct GetSwapBaseInData {
     pub amount_in: u64,
     pub minimum_amount_out: u64,
     pub price_impact: u64,
+    // effective swap fee, raised by the dynamic fee
+    pub swap_fee_numerator: u64,
+    pub swap_fee_denominator: u64,
 }
 impl GetSwapBaseInData {
     pub fn to_json(&self) -> String {
@@ -1025,6 +1449,9 @@ pub struct GetSwapBaseOutData {
     pub max_amount_in: u64,
     pub amount_out: u64,
     pub price_impact: u64,
+    // effective swap fee, raised by the dynamic fee
+    pub swap_fee_numerator: u64,
+    pub swap_fee_denominator: u64,
 }
 impl GetSwapBaseOutData {
     pub fn to_json(&self) -> String {
@@ -1035,9 +1462,297 @@ impl GetSwapBaseOutData {
     }
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GetRouteSwapHopData {
+    pub amm_id: String,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    // charged in the hop source token
+    pub swap_fee: u64,
+    pub price_impact: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GetRouteSwapData {
+    pub amount_in: u64,
+    pub minimum_amount_out: u64,
+    // output of the last hop
+    pub amount_out: u64,
+    pub hops: Vec<GetRouteSwapHopData>,
+}
+impl GetRouteSwapData {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Self {
+        serde_json::from_str(data).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GetDepositData {
+    pub pool_data: GetPoolData,
+    pub max_coin_amount: u64,
+    pub max_pc_amount: u64,
+    pub base_side: u64,
+    // debited from the user, Spl Token 2022 transfer fees included, and lp minted
+    // at the current ratio, after pnl
+    pub deduct_coin: u64,
+    pub deduct_pc: u64,
+    pub mint_lp: u64,
+}
+impl GetDepositData {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Self {
+        serde_json::from_str(data).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GetWithdrawData {
+    pub pool_data: GetPoolData,
+    pub amount: u64,
+    // sent from the pool vaults at the current ratio, after pnl
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    // credited to the user, after the Spl Token 2022 transfer fees
+    pub coin_amount_received: u64,
+    pub pc_amount_received: u64,
+}
+impl GetWithdrawData {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Self {
+        serde_json::from_str(data).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GetTwapData {
+    // coin price cumulative at observation_time, the current time
+    pub coin_price_cumulative: u128,
+    pub observation_time: u64,
+    // timestamp of the observation the window starts at
+    pub window_start_time: u64,
+    // time weighted price of one coin in pc over the window, scaled like `GetPoolData::spot_price`
+    pub coin_twap: u64,
+    // reciprocal of coin_twap, price of one pc in coin at the same scale
+    pub pc_twap: u64,
+}
+impl GetTwapData {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Self {
+        serde_json::from_str(data).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwapReturnData {
+    pub direction: u64,
+    // debited from the user source, including the referral fee
+    pub amount_in: u64,
+    // credited to the user destination
+    pub amount_out: u64,
+    // charged in the source token
+    pub swap_fee: u64,
+    pub protocol_fee: u64,
+    pub referral_fee: u64,
+    // pool token vault without pnl, after the swap
+    pub pool_coin_amount: u64,
+    pub pool_pc_amount: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DepositReturnData {
+    // debited from the user
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    // minted to the user
+    pub lp_amount: u64,
+    // pool token vault without pnl and lp supply, after the deposit
+    pub pool_coin_amount: u64,
+    pub pool_pc_amount: u64,
+    pub pool_lp_supply: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WithdrawReturnData {
+    // burnt from the user
+    pub lp_amount: u64,
+    // sent from the pool vaults
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    // pool token vault without pnl and lp supply, after the withdraw
+    pub pool_coin_amount: u64,
+    pub pool_pc_amount: u64,
+    pub pool_lp_supply: u64,
+}
+
+/// Result of a successful swap, deposit or withdraw, set as the instruction return data
+/// so that cpi callers don't have to re-read the token balances.
+/// A route swap returns the result of each of its hops, a GetTwap its TWAP.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AmmReturnData {
+    Swap(SwapReturnData),
+    Deposit(DepositReturnData),
+    Withdraw(WithdrawReturnData),
+    /// result of each hop of a RouteSwapBaseIn, in order
+    RouteSwap(Vec<SwapReturnData>),
+    /// result of a GetTwap
+    Twap(GetTwapData),
+}
+impl AmmReturnData {
+    pub fn pack(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+    /// Decodes the data returned by `get_return_data` after a cpi into the amm.
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        bincode::deserialize(data).ok()
+    }
+}
+
+/// Version byte leading the `SimulateReturnData` encoding, bumped on layout changes.
+pub const SIMULATE_RETURN_DATA_VERSION: u8 = 2;
+
+/// Result of a SimulateInfo instruction, set as the instruction return data next to the
+/// msg! json, so that quotes survive log truncation and can be read after a cpi.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SimulateReturnData {
+    PoolInfo(GetPoolData),
+    SwapBaseIn(GetSwapBaseInData),
+    SwapBaseOut(GetSwapBaseOutData),
+    RunCrank(RunCrankData),
+    Deposit(GetDepositData),
+    Withdraw(GetWithdrawData),
+    RouteSwapBaseIn(GetRouteSwapData),
+}
+impl SimulateReturnData {
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = vec![SIMULATE_RETURN_DATA_VERSION];
+        buf.extend_from_slice(&bincode::serialize(self).unwrap());
+        buf
+    }
+    /// Decodes the data returned by `get_return_data` after a cpi into the amm, or the
+    /// return data of a simulated transaction. Other versions are rejected.
+    pub fn unpack(data: &[u8]) -> Option<Self> {
+        let (version, rest) = data.split_first()?;
+        if *version != SIMULATE_RETURN_DATA_VERSION {
+            return None;
+        }
+        bincode::deserialize(rest).ok()
+    }
+    /// Decodes the base64 return data reported by the simulateTransaction rpc.
+    pub fn unpack_base64(data: &str) -> Option<Self> {
+        Self::unpack(&base64::decode_config(data, base64::STANDARD).ok()?)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::math::U128;
+
+    #[test]
+    fn test_get_pool_data_json_compat() {
+        let v1 = r#"{"status":6,"coin_decimals":9,"pc_decimals":6,"lp_decimals":9,"pool_pc_amount":1500000,"pool_coin_amount":1000000000,"pnl_pc_amount":0,"pnl_coin_amount":0,"pool_lp_supply":100,"pool_open_time":0,"amm_id":"amm"}"#;
+        let pool_data = GetPoolData::from_json(v1);
+        assert_eq!(pool_data.version, 1);
+        assert_eq!(pool_data.pool_coin_amount, 1000000000);
+
+        let mut amm = AmmInfo::default();
+        amm.pc_decimals = 6;
+        amm.coin_decimals = 9;
+        amm.sys_decimal_value = 1_000_000;
+        amm.fees.initialize().unwrap();
+        let mut pool_data = pool_data;
+        pool_data.fill_amm_info(&amm, 0);
+        assert_eq!(pool_data.version, GET_POOL_DATA_VERSION);
+        assert_eq!(pool_data.spot_price, 1_500_000);
+        assert_eq!(pool_data.trade_fee_numerator, 25);
+        assert_eq!(pool_data.swap_fee_numerator, 25);
+        assert_eq!(GetPoolData::from_json(&pool_data.to_json()), pool_data);
+
+        // the effective swap fee, raised by the recent price moves until they decay
+        amm.dynamic_fee_max_numerator = 100;
+        amm.volatility_accumulator = 500;
+        pool_data.fill_amm_info(&amm, 0);
+        assert_eq!(pool_data.swap_fee_numerator, 75);
+        pool_data.fill_amm_info(&amm, VOLATILITY_DECAY_PERIOD);
+        assert_eq!(pool_data.swap_fee_numerator, 25);
+    }
+
+    #[test]
+    fn test_simulate_return_data_pack() {
+        let route = SimulateReturnData::RouteSwapBaseIn(GetRouteSwapData {
+            amount_in: 1000,
+            minimum_amount_out: 900,
+            amount_out: 950,
+            hops: vec![GetRouteSwapHopData {
+                amm_id: Pubkey::new_unique().to_string(),
+                amount_in: 1000,
+                amount_out: 950,
+                swap_fee: 3,
+                price_impact: 10,
+            }],
+        });
+        let data = route.pack();
+        assert_eq!(data[0], SIMULATE_RETURN_DATA_VERSION);
+        assert_eq!(SimulateReturnData::unpack(&data), Some(route.clone()));
+        assert_eq!(
+            SimulateReturnData::unpack_base64(&base64::encode(&data)),
+            Some(route)
+        );
+        let mut data = data;
+        data[0] = SIMULATE_RETURN_DATA_VERSION + 1;
+        assert_eq!(SimulateReturnData::unpack(&data), None);
+        assert_eq!(SimulateReturnData::unpack(&[]), None);
+    }
+
+    #[test]
+    fn test_amm_return_data_pack() {
+        let swap = AmmReturnData::Swap(SwapReturnData {
+            direction: 1,
+            amount_in: 1000,
+            amount_out: 990,
+            swap_fee: 3,
+            protocol_fee: 1,
+            referral_fee: 0,
+            pool_coin_amount: 1_000_000,
+            pool_pc_amount: 2_000_000,
+        });
+        assert_eq!(AmmReturnData::unpack(&swap.pack()), Some(swap));
+        let withdraw = AmmReturnData::Withdraw(WithdrawReturnData {
+            lp_amount: 10,
+            ..Default::default()
+        });
+        assert_eq!(AmmReturnData::unpack(&withdraw.pack()), Some(withdraw));
+        let route_swap = AmmReturnData::RouteSwap(vec![
+            SwapReturnData {
+                amount_in: 1000,
+                amount_out: 990,
+                ..Default::default()
+            },
+            SwapReturnData {
+                direction: 1,
+                amount_in: 990,
+                amount_out: 495,
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(AmmReturnData::unpack(&route_swap.pack()), Some(route_swap));
+        let twap = AmmReturnData::Twap(GetTwapData {
+            coin_twap: 2_000_000,
+            pc_twap: 500_000,
+            ..Default::default()
+        });
+        assert_eq!(AmmReturnData::unpack(&twap.pack()), Some(twap));
+        assert_eq!(AmmReturnData::unpack(&[]), None);
+    }
 
     #[test]
     fn test_amm_info_layout() {
@@ -1072,7 +1787,8 @@ mod test {
         let total_pnl_pc: u64 = 0x12f3456789abcde0;
         let total_pnl_coin: u64 = 0x1f23456789abcde0;
         let pool_open_time: u64 = 0x123456789abcedf0;
-        let padding: [u64; 2] = [0x123456789abecdf0, 0x123456789aebcdf0];
+        let protocol_fee_coin: u64 = 0x123456789abecdf0;
+        let protocol_fee_pc: u64 = 0x123456789aebcdf0;
         let orderbook_to_init_time: u64 = 0x123456789eabcdf0;
         let swap_coin_in_amount: u128 = 0x11002233445566778899aabbccddeeff;
         let swap_pc_out_amount: u128 = 0x11220033445566778899aabbccddeeff;
@@ -1091,19 +1807,20 @@ mod test {
         let market_program = Pubkey::new_unique();
         let target_orders = Pubkey::new_unique();
 
-        let mut padding1: [u64; 8] = [0u64; 8];
-        let mut padding1_data = [0u8; 8 * 8];
-        let mut offset = 0;
-        for i in 0..8 {
-            padding1[i] = u64::MAX - i as u64;
-            padding1_data[offset..offset + 8].copy_from_slice(&padding1[i].to_le_bytes());
-            offset += 8;
-        }
+        let coin_price_cumulative: u128 = 0x123456789abcdef0123456789abcdef0;
+        let flash_loan_coin_repay: u64 = 0x123456789abcdef0;
+        let flash_loan_pc_repay: u64 = 0x0fedcba987654321;
+        let price_cumulative_time: u64 = 0x12345678e9abcdf0;
+        let dynamic_fee_min_numerator: u32 = 0x1234e567;
+        let dynamic_fee_max_numerator: u32 = 0x123e4567;
+        let volatility_accumulator: u64 = 0x12e3456789abcdf0;
+        let volatility_update_time: u64 = 0x1e23456789abcdf0;
         let amm_owner = Pubkey::new_unique();
         let lp_amount: u64 = 0x123456e789abcdf0;
         let client_order_id: u64 = 0x12345e6789abcdf0;
         let recent_epoch: u64 = 0x1234e56789abcdf0;
-        let padding2: u64 = 0x123e456789abcdf0;
+        let flash_loan_fee_numerator: u32 = 0x123e4567;
+        let protocol_fee_rate: u32 = 0x1e234567;
 
         // serialize original data
         let mut pool_data = [0u8; 752];
@@ -1168,9 +1885,9 @@ mod test {
         offset += 8;
         pool_data[offset..offset + 8].copy_from_slice(&pool_open_time.to_le_bytes());
         offset += 8;
-        pool_data[offset..offset + 8].copy_from_slice(&padding[0].to_le_bytes());
+        pool_data[offset..offset + 8].copy_from_slice(&protocol_fee_coin.to_le_bytes());
         offset += 8;
-        pool_data[offset..offset + 8].copy_from_slice(&padding[1].to_le_bytes());
+        pool_data[offset..offset + 8].copy_from_slice(&protocol_fee_pc.to_le_bytes());
         offset += 8;
         pool_data[offset..offset + 8].copy_from_slice(&orderbook_to_init_time.to_le_bytes());
         offset += 8;
@@ -1205,8 +1922,22 @@ mod test {
         offset += 32;
         pool_data[offset..offset + 32].copy_from_slice(&target_orders.to_bytes());
         offset += 32;
-        pool_data[offset..offset + 8 * 8].copy_from_slice(&padding1_data);
-        offset += 8 * 8;
+        pool_data[offset..offset + 16].copy_from_slice(&coin_price_cumulative.to_le_bytes());
+        offset += 16;
+        pool_data[offset..offset + 8].copy_from_slice(&flash_loan_coin_repay.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&flash_loan_pc_repay.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&price_cumulative_time.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 4].copy_from_slice(&dynamic_fee_min_numerator.to_le_bytes());
+        offset += 4;
+        pool_data[offset..offset + 4].copy_from_slice(&dynamic_fee_max_numerator.to_le_bytes());
+        offset += 4;
+        pool_data[offset..offset + 8].copy_from_slice(&volatility_accumulator.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&volatility_update_time.to_le_bytes());
+        offset += 8;
         pool_data[offset..offset + 32].copy_from_slice(&amm_owner.to_bytes());
         offset += 32;
         pool_data[offset..offset + 8].copy_from_slice(&lp_amount.to_le_bytes());
@@ -1215,8 +1946,10 @@ mod test {
         offset += 8;
         pool_data[offset..offset + 8].copy_from_slice(&recent_epoch.to_le_bytes());
         offset += 8;
-        pool_data[offset..offset + 8].copy_from_slice(&padding2.to_le_bytes());
-        offset += 8;
+        pool_data[offset..offset + 4].copy_from_slice(&flash_loan_fee_numerator.to_le_bytes());
+        offset += 4;
+        pool_data[offset..offset + 4].copy_from_slice(&protocol_fee_rate.to_le_bytes());
+        offset += 4;
 
         // len check
         assert_eq!(offset, pool_data.len());
@@ -1284,10 +2017,10 @@ mod test {
         assert_eq!(total_pnl_coin, unpack_total_pnl_coin);
         let unpack_pool_open_time = unpack_data.state_data.pool_open_time;
         assert_eq!(pool_open_time, unpack_pool_open_time);
-        for i in 0..2 {
-            let unpack_padding = unpack_data.state_data.padding[i];
-            assert_eq!(padding[i], unpack_padding);
-        }
+        let unpack_protocol_fee_coin = unpack_data.state_data.protocol_fee_coin;
+        assert_eq!(protocol_fee_coin, unpack_protocol_fee_coin);
+        let unpack_protocol_fee_pc = unpack_data.state_data.protocol_fee_pc;
+        assert_eq!(protocol_fee_pc, unpack_protocol_fee_pc);
         let unpack_orderbook_to_init_time = unpack_data.state_data.orderbook_to_init_time;
         assert_eq!(orderbook_to_init_time, unpack_orderbook_to_init_time);
         let unpack_swap_coin_in_amount = unpack_data.state_data.swap_coin_in_amount;
@@ -1320,10 +2053,22 @@ mod test {
         assert_eq!(market_program, unpack_market_program);
         let unpack_target_orders = unpack_data.target_orders;
         assert_eq!(target_orders, unpack_target_orders);
-        for i in 0..8 {
-            let unpack_padding1 = unpack_data.padding1[i];
-            assert_eq!(padding1[i], unpack_padding1);
-        }
+        let unpack_coin_price_cumulative = unpack_data.coin_price_cumulative;
+        assert_eq!(coin_price_cumulative, unpack_coin_price_cumulative);
+        let unpack_flash_loan_coin_repay = unpack_data.flash_loan_coin_repay;
+        assert_eq!(flash_loan_coin_repay, unpack_flash_loan_coin_repay);
+        let unpack_flash_loan_pc_repay = unpack_data.flash_loan_pc_repay;
+        assert_eq!(flash_loan_pc_repay, unpack_flash_loan_pc_repay);
+        let unpack_price_cumulative_time = unpack_data.price_cumulative_time;
+        assert_eq!(price_cumulative_time, unpack_price_cumulative_time);
+        let unpack_dynamic_fee_min_numerator = unpack_data.dynamic_fee_min_numerator;
+        assert_eq!(dynamic_fee_min_numerator, unpack_dynamic_fee_min_numerator);
+        let unpack_dynamic_fee_max_numerator = unpack_data.dynamic_fee_max_numerator;
+        assert_eq!(dynamic_fee_max_numerator, unpack_dynamic_fee_max_numerator);
+        let unpack_volatility_accumulator = unpack_data.volatility_accumulator;
+        assert_eq!(volatility_accumulator, unpack_volatility_accumulator);
+        let unpack_volatility_update_time = unpack_data.volatility_update_time;
+        assert_eq!(volatility_update_time, unpack_volatility_update_time);
         let unpack_amm_owner = unpack_data.amm_owner;
         assert_eq!(amm_owner, unpack_amm_owner);
         let unpack_lp_amount = unpack_data.lp_amount;
@@ -1332,8 +2077,10 @@ mod test {
         assert_eq!(client_order_id, unpack_client_order_id);
         let unpack_recent_epoch = unpack_data.recent_epoch;
         assert_eq!(recent_epoch, unpack_recent_epoch);
-        let unpack_padding2 = unpack_data.padding2;
-        assert_eq!(padding2, unpack_padding2);
+        let unpack_flash_loan_fee_numerator = unpack_data.flash_loan_fee_numerator;
+        assert_eq!(flash_loan_fee_numerator, unpack_flash_loan_fee_numerator);
+        let unpack_protocol_fee_rate = unpack_data.protocol_fee_rate;
+        assert_eq!(protocol_fee_rate, unpack_protocol_fee_rate);
     }
 
     #[test]
@@ -1560,4 +2307,153 @@ mod test {
         let unpack_free_slot_bits = unpack_data.free_slot_bits;
         assert_eq!(free_slot_bits, unpack_free_slot_bits);
     }
+
+    #[test]
+    fn test_price_cumulative() {
+        let mut amm = AmmInfo::default();
+        // the first update only starts the clock
+        amm.update_price_cumulative(200, 100, 1_000);
+        assert_eq!({ amm.coin_price_cumulative }, 0);
+        amm.update_price_cumulative(200, 100, 1_010);
+        assert_eq!({ amm.coin_price_cumulative }, (2u128 << 64) * 10);
+        // extrapolated at the current price, without an update
+        assert_eq!(amm.price_cumulative_at(100, 100, 1_020), 30u128 << 64);
+        // a stale timestamp is ignored
+        amm.update_price_cumulative(100, 100, 1_005);
+        assert_eq!({ amm.coin_price_cumulative }, (2u128 << 64) * 10);
+        assert_eq!({ amm.price_cumulative_time }, 1_010);
+    }
+
+    #[test]
+    fn test_dynamic_swap_fee() {
+        let mut amm = AmmInfo::default();
+        amm.fees.swap_fee_numerator = 25;
+        amm.fees.swap_fee_denominator = TEN_THOUSAND;
+        amm.update_volatility(400, 1_000);
+        // disabled without an upper bound
+        assert_eq!(amm.swap_fee_numerator_at(1_000), 25);
+
+        amm.dynamic_fee_max_numerator = 100;
+        // a tenth of the 4% move on top of the base fee, decaying back to it
+        assert_eq!(amm.swap_fee_numerator_at(1_000), 65);
+        assert_eq!(
+            amm.swap_fee_numerator_at(1_000 + VOLATILITY_DECAY_PERIOD / 2),
+            45
+        );
+        assert_eq!(
+            amm.swap_fee_numerator_at(1_000 + VOLATILITY_DECAY_PERIOD),
+            25
+        );
+
+        // with a min, the fee decays below the base fee once the price is calm
+        amm.dynamic_fee_min_numerator = 20;
+        assert_eq!(amm.swap_fee_numerator_at(1_000), 60);
+        assert_eq!(
+            amm.swap_fee_numerator_at(1_000 + VOLATILITY_DECAY_PERIOD),
+            20
+        );
+
+        // moves accumulate on the decayed volatility, and the fee is capped
+        amm.update_volatility(1_000, 1_000 + VOLATILITY_DECAY_PERIOD / 2);
+        assert_eq!({ amm.volatility_accumulator }, 1_200);
+        assert_eq!(
+            amm.swap_fee_numerator_at(1_000 + VOLATILITY_DECAY_PERIOD / 2),
+            100
+        );
+
+        assert_eq!(Calculator::price_move_bps(100, 100, 110, 100), 1_000);
+        assert_eq!(Calculator::price_move_bps(100, 100, 100, 125), 2_000);
+
+        // a max left below the floor never lowers the fee under it
+        amm.dynamic_fee_max_numerator = 10;
+        assert_eq!(amm.swap_fee_numerator_at(1_000), 20);
+
+        // new fees drop the bounds they no longer allow
+        amm.dynamic_fee_min_numerator = 20;
+        amm.dynamic_fee_max_numerator = 100;
+        let mut fees = amm.fees;
+        fees.swap_fee_numerator = 30;
+        amm.set_fees(fees);
+        assert_eq!({ amm.dynamic_fee_min_numerator }, 20);
+        assert_eq!({ amm.dynamic_fee_max_numerator }, 100);
+        fees.swap_fee_numerator = 150;
+        amm.set_fees(fees);
+        assert_eq!({ amm.dynamic_fee_min_numerator }, 0);
+        assert_eq!({ amm.dynamic_fee_max_numerator }, 0);
+        assert_eq!(amm.swap_fee_numerator_at(1_000), 150);
+        amm.dynamic_fee_max_numerator = 90;
+        fees.swap_fee_numerator = 25;
+        fees.swap_fee_denominator = 90;
+        amm.set_fees(fees);
+        assert_eq!({ amm.dynamic_fee_max_numerator }, 0);
+        // even a stale max leaves the charged fee under the denominator
+        amm.dynamic_fee_max_numerator = 10_000;
+        amm.update_volatility(1_000_000, 1_000);
+        assert_eq!(amm.swap_fee_numerator_at(1_000), 89);
+        assert_eq!(
+            Calculator::swap_amount_in_add_fee(
+                U128::from(1_000u64),
+                amm.swap_fee_numerator_at(1_000),
+                amm.fees.swap_fee_denominator,
+            ),
+            90_000
+        );
+    }
+
+    #[test]
+    fn test_protocol_fee() {
+        let mut amm = AmmInfo::default();
+        assert_eq!(amm.protocol_fee(25), 0);
+        amm.protocol_fee_rate = 2_000;
+        assert_eq!(amm.protocol_fee(25), 5);
+        // rounded down in favor of the liquidity providers
+        assert_eq!(amm.protocol_fee(4), 0);
+    }
+
+    #[test]
+    fn test_observation_ring() {
+        let observation = |timestamp: u64| Observation {
+            timestamp,
+            ..Default::default()
+        };
+        let timestamps = |header: &ObservationHeader, observations: &[Observation]| {
+            header
+                .ordered(observations)
+                .iter()
+                .map(|o| o.timestamp)
+                .collect::<Vec<u64>>()
+        };
+        let mut header = ObservationHeader {
+            capacity: 3,
+            ..Default::default()
+        };
+        let mut observations = vec![Observation::default(); 3];
+        for timestamp in 1..=4 {
+            header.write(&mut observations, observation(timestamp));
+        }
+        // one observation per timestamp
+        header.write(&mut observations, observation(4));
+        assert_eq!(timestamps(&header, &observations), vec![2, 3, 4]);
+
+        // grown slots are only used once the ring reaches its end
+        header.capacity = 5;
+        observations.resize(5, Observation::default());
+        header.write(&mut observations, observation(5));
+        assert_eq!(timestamps(&header, &observations), vec![3, 4, 5]);
+        header.write(&mut observations, observation(6));
+        header.write(&mut observations, observation(7));
+        assert_eq!(timestamps(&header, &observations), vec![4, 5, 6, 7]);
+        header.write(&mut observations, observation(8));
+        assert_eq!(timestamps(&header, &observations), vec![4, 5, 6, 7, 8]);
+
+        // window starts at the latest observation at or before the requested time
+        let start = |unix_timestamp: u64| {
+            header
+                .observation_before(&observations, unix_timestamp)
+                .map(|o| o.timestamp)
+        };
+        assert_eq!(start(6), Some(6));
+        assert_eq!(start(100), Some(8));
+        assert_eq!(start(3), None);
+    }
 }